@@ -0,0 +1,166 @@
+use std::{
+    fs,
+    net::IpAddr,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ahash::{HashMap, HashMapExt as _};
+use anyhow::{Context as _, Result};
+use futures::{StreamExt as _, stream};
+use reqwest::Client;
+use tokio::sync::Mutex;
+
+use crate::proxy_config::ProxyConfig;
+
+const ABUSEIPDB_URL: &str = "https://api.abuseipdb.com/api/v2/check";
+
+pub struct AbuseCache {
+    cache: HashMap<IpAddr, (u8, u64)>,
+    cache_file: String,
+    ttl: Duration,
+}
+
+impl AbuseCache {
+    #[must_use]
+    pub fn new(cache_file: &str, ttl: Duration) -> Self {
+        Self {
+            cache: HashMap::new(),
+            cache_file: cache_file.to_owned(),
+            ttl,
+        }
+    }
+
+    /// # Errors
+    /// Return error if failed to read file
+    pub fn load_cache(&mut self) -> Result<()> {
+        if !Path::new(&self.cache_file).exists() {
+            return Ok(());
+        }
+
+        self.cache = fs::read_to_string(&self.cache_file)
+            .context("Failed to read abuse cache")?
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                let ip = parts.next()?.parse().ok()?;
+                let score = parts.next()?.parse().ok()?;
+                let checked_at = parts.next()?.parse().ok()?;
+                Some((ip, (score, checked_at)))
+            })
+            .collect();
+
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn get(&self, ip: IpAddr) -> Option<u8> {
+        self.cache
+            .get(&ip)
+            .filter(|(_, checked_at)| now_secs().saturating_sub(*checked_at) < self.ttl.as_secs())
+            .map(|(score, _)| *score)
+    }
+
+    pub fn insert(&mut self, ip: IpAddr, score: u8) {
+        self.cache.insert(ip, (score, now_secs()));
+    }
+
+    /// # Errors
+    /// Return error if failed to save file
+    pub async fn save(&self) -> Result<()> {
+        let content = self
+            .cache
+            .iter()
+            .map(|(ip, (score, checked_at))| format!("{ip} {score} {checked_at}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        tokio::fs::write(&self.cache_file, content)
+            .await
+            .context("Failed to save abuse cache")
+    }
+}
+
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+async fn query_abuse_score(client: &Client, api_key: &str, ip: IpAddr) -> Result<u8> {
+    let body = client
+        .get(format!("{ABUSEIPDB_URL}?ipAddress={ip}"))
+        .header("Key", api_key)
+        .header("Accept", "application/json")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let response: serde_json::Value = serde_json::from_str(&body)?;
+
+    response["data"]["abuseConfidenceScore"]
+        .as_u64()
+        .and_then(|score| u8::try_from(score).ok())
+        .context("Missing abuseConfidenceScore in AbuseIPDB response")
+}
+
+async fn abuse_score(
+    client: &Client,
+    api_key: &str,
+    cache: &Mutex<AbuseCache>,
+    ip: IpAddr,
+) -> Option<u8> {
+    let cached = cache.lock().await.get(ip);
+    if cached.is_some() {
+        return cached;
+    }
+
+    match query_abuse_score(client, api_key, ip).await {
+        Ok(score) => {
+            cache.lock().await.insert(ip, score);
+            Some(score)
+        }
+        Err(e) => {
+            log::debug!("Failed to get abuse score for {ip}: {e}");
+            None
+        }
+    }
+}
+
+/// Attaches the worst abuse score of server and exit address to every proxy
+///
+/// # Errors
+/// Return error if failed to save cache
+pub async fn enrich_abuse_scores(
+    proxies: &mut [ProxyConfig],
+    api_key: &str,
+    cache: &Mutex<AbuseCache>,
+    max_concurrent: usize,
+) -> Result<()> {
+    let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
+
+    let scores = stream::iter(proxies.iter())
+        .map(|proxy| {
+            let client = &client;
+            async move {
+                let server = abuse_score(client, api_key, cache, proxy.address).await;
+                let exit = match proxy.exit_ip {
+                    Some(ip) if ip != proxy.address => {
+                        abuse_score(client, api_key, cache, ip).await
+                    }
+                    _ => None,
+                };
+                server.max(exit)
+            }
+        })
+        .buffered(max_concurrent)
+        .collect::<Vec<_>>()
+        .await;
+
+    for (proxy, score) in proxies.iter_mut().zip(scores) {
+        proxy.abuse_score = score;
+    }
+
+    cache.lock().await.save().await
+}
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::Client;
+use serde_json::json;
+
+pub struct Alerter {
+    client: Client,
+    // Receives `{"text": "..."}` POST requests
+    webhook: Option<String>,
+    // Bot token and chat id
+    telegram: Option<(String, String)>,
+}
+
+impl Alerter {
+    /// # Errors
+    /// Return error if failed to build http client
+    pub fn new(webhook: Option<String>, telegram: Option<(String, String)>) -> Result<Self> {
+        Ok(Self {
+            client: Client::builder().timeout(Duration::from_secs(10)).build()?,
+            webhook,
+            telegram,
+        })
+    }
+
+    #[must_use]
+    pub const fn is_enabled(&self) -> bool {
+        self.webhook.is_some() || self.telegram.is_some()
+    }
+
+    pub async fn send(&self, text: &str) {
+        log::warn!("Alert: {text}");
+
+        if let Some(webhook) = &self.webhook
+            && let Err(e) = self
+                .client
+                .post(webhook)
+                .body(json!({ "text": text }).to_string())
+                .header("Content-Type", "application/json")
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+        {
+            log::warn!("Failed to send webhook alert: {e}");
+        }
+
+        if let Some((token, chat_id)) = &self.telegram
+            && let Err(e) = self
+                .client
+                .post(format!("https://api.telegram.org/bot{token}/sendMessage"))
+                .body(json!({ "chat_id": chat_id, "text": text }).to_string())
+                .header("Content-Type", "application/json")
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+        {
+            log::warn!("Failed to send telegram alert: {e}");
+        }
+    }
+}
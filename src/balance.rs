@@ -0,0 +1,85 @@
+use ahash::HashMap;
+use anyhow::{Result, bail};
+
+use crate::proxy_config::ProxyConfig;
+
+#[derive(Debug, Clone, Copy)]
+enum Dimension {
+    Country,
+    Protocol,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Limit {
+    Max(usize),
+    // Every group is cut to the size of the smallest one
+    Equal,
+}
+
+/// Output composition constraint, written as `country:10` (at most 10
+/// proxies per country) or `protocol:equal` (same count of every protocol)
+#[derive(Debug, Clone, Copy)]
+pub struct BalanceRule {
+    dimension: Dimension,
+    limit: Limit,
+}
+
+impl BalanceRule {
+    /// # Errors
+    /// Return error if dimension is unknown or limit isn't a number or `equal`
+    pub fn parse(rule: &str) -> Result<Self> {
+        let Some((dimension, limit)) = rule.split_once(':') else {
+            bail!("Balance rule {rule} isn't `dimension:limit`");
+        };
+        let dimension = match dimension {
+            "country" => Dimension::Country,
+            "protocol" => Dimension::Protocol,
+            _ => bail!("Unknown balance dimension {dimension}, expected country or protocol"),
+        };
+        let limit = match limit {
+            "equal" => Limit::Equal,
+            limit => match limit.parse() {
+                Ok(max) => Limit::Max(max),
+                Err(_) => bail!("Balance limit {limit} is neither a number nor `equal`"),
+            },
+        };
+        Ok(Self { dimension, limit })
+    }
+
+    fn group(self, proxy: &ProxyConfig) -> String {
+        match self.dimension {
+            Dimension::Country => proxy.country.map(String::from_iter).unwrap_or_default(),
+            Dimension::Protocol => proxy.protocol.clone(),
+        }
+    }
+
+    // Keeps first proxies of every group, so input order decides which stay
+    fn apply(self, proxies: Vec<ProxyConfig>) -> Vec<ProxyConfig> {
+        let mut sizes = HashMap::<String, usize>::default();
+        for proxy in &proxies {
+            *sizes.entry(self.group(proxy)).or_default() += 1;
+        }
+        let max = match self.limit {
+            Limit::Max(max) => max,
+            Limit::Equal => sizes.values().copied().min().unwrap_or_default(),
+        };
+
+        let mut taken = HashMap::<String, usize>::default();
+        proxies
+            .into_iter()
+            .filter(|proxy| {
+                let count = taken.entry(self.group(proxy)).or_default();
+                *count += 1;
+                *count <= max
+            })
+            .collect()
+    }
+}
+
+/// Applies every rule in order to sorted proxies
+#[must_use]
+pub fn balance(proxies: Vec<ProxyConfig>, rules: &[BalanceRule]) -> Vec<ProxyConfig> {
+    rules
+        .iter()
+        .fold(proxies, |proxies, rule| rule.apply(proxies))
+}
@@ -0,0 +1,198 @@
+//! Compact binary format of on-disk caches.
+//!
+//! File starts with magic and format version, followed by records of
+//! length-prefixed strings and little-endian integers. Files without magic
+//! are caches written by older versions in text format, they are parsed as
+//! text once and rewritten as binary on next save.
+
+use std::net::IpAddr;
+
+use anyhow::{Result, bail};
+
+const MAGIC: &[u8; 4] = b"NPXC";
+const VERSION: u8 = 1;
+
+pub enum CacheFile<'a> {
+    Binary(Reader<'a>),
+    // Old text format, one whitespace separated record per line
+    Text(&'a str),
+}
+
+impl<'a> CacheFile<'a> {
+    /// # Errors
+    /// Return error if file is binary of unsupported version, or neither
+    /// binary nor text
+    pub fn detect(data: &'a [u8]) -> Result<Self> {
+        match data.strip_prefix(MAGIC) {
+            Some([VERSION, records @ ..]) => Ok(Self::Binary(Reader { data: records })),
+            Some(_) => bail!("Unsupported cache format version"),
+            None => Ok(Self::Text(str::from_utf8(data)?)),
+        }
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    /// Reads records until end of data, stopping early at malformed one
+    pub fn records<T>(
+        mut self,
+        mut read: impl FnMut(&mut Self) -> Option<T>,
+    ) -> impl Iterator<Item = T> {
+        std::iter::from_fn(move || read(&mut self))
+    }
+
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (bytes, rest) = self.data.split_first_chunk()?;
+        self.data = rest;
+        Some(*bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.take().map(u8::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    pub fn str(&mut self) -> Option<String> {
+        let len = self.take().map(u16::from_le_bytes)?.into();
+        let (bytes, rest) = self.data.split_at_checked(len)?;
+        self.data = rest;
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+
+    pub fn ip(&mut self) -> Option<IpAddr> {
+        match self.u8()? {
+            4 => self.take::<4>().map(IpAddr::from),
+            6 => self.take::<16>().map(IpAddr::from),
+            _ => None,
+        }
+    }
+}
+
+pub struct Writer {
+    data: Vec<u8>,
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Writer {
+    #[must_use]
+    pub fn new() -> Self {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        Self { data }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Strings longer than u16::MAX bytes are cut on char boundary, no
+    // cached key is that long
+    pub fn str(&mut self, value: &str) {
+        let value = &value[..value.floor_char_boundary(u16::MAX.into())];
+        self.data
+            .extend_from_slice(&(value.len() as u16).to_le_bytes());
+        self.data.extend_from_slice(value.as_bytes());
+    }
+
+    pub fn ip(&mut self, value: IpAddr) {
+        match value {
+            IpAddr::V4(ip) => {
+                self.u8(4);
+                self.data.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                self.u8(6);
+                self.data.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip_after_header() {
+        let mut writer = Writer::new();
+        writer.str("example.com");
+        writer.ip("2001:db8::1".parse().unwrap());
+        writer.u64(42);
+        let data = writer.finish();
+        assert!(data.starts_with(MAGIC));
+
+        let Ok(CacheFile::Binary(reader)) = CacheFile::detect(&data) else {
+            panic!("not detected as binary");
+        };
+        let records = reader
+            .records(|r| Some((r.str()?, r.ip()?, r.u64()?)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            [("example.com".to_owned(), "2001:db8::1".parse().unwrap(), 42)]
+        );
+    }
+
+    #[test]
+    fn long_string_is_cut_on_char_boundary() {
+        // 3 byte chars, u16::MAX isn't a multiple of 3 plus the leading 'a'
+        let long = format!("a{}", "€".repeat(30_000));
+        let mut writer = Writer::new();
+        writer.str(&long);
+        writer.str("next");
+        let data = writer.finish();
+
+        let Ok(CacheFile::Binary(reader)) = CacheFile::detect(&data) else {
+            panic!("not detected as binary");
+        };
+        let records = reader.records(Reader::str).collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert!(long.starts_with(&records[0]));
+        assert!(records[0].len() > usize::from(u16::MAX) - 3);
+        assert_eq!(records[1], "next");
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION + 1);
+        assert!(CacheFile::detect(&data).is_err());
+    }
+
+    #[test]
+    fn data_without_magic_is_text() {
+        let data = b"example.com 192.0.2.1\n";
+        assert!(matches!(
+            CacheFile::detect(data),
+            Ok(CacheFile::Text("example.com 192.0.2.1\n"))
+        ));
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ahash::HashMap;
+use anyhow::{Context as _, Result};
+use tokio::io::AsyncWriteExt as _;
+
+use crate::proxy_config::ProxyConfig;
+
+#[derive(clap::Args, Debug)]
+pub struct CanaryArgs {
+    // Access log of the `--canary-url` server, client address first on
+    // every line as in common log format
+    access_log: String,
+
+    #[arg(long, default_value = "canary.tsv")]
+    canary_ledger: String,
+}
+
+// Ledger line of one planted token
+struct Planted {
+    exit_ip: Option<IpAddr>,
+    link: String,
+}
+
+/// Appends `time, token, exit ip, link` lines of proxies canary
+/// credentials were sent through to `path`
+///
+/// # Errors
+/// Return error if failed to write ledger
+pub async fn record(path: &str, proxies: &[ProxyConfig]) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let lines = proxies
+        .iter()
+        .filter_map(|proxy| {
+            let token = proxy.canary.as_ref()?;
+            let exit_ip = proxy.exit_ip.map(|ip| ip.to_string()).unwrap_or_default();
+            Some(format!("{now}\t{token}\t{exit_ip}\t{proxy}\n"))
+        })
+        .collect::<String>();
+
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .context("Failed to open canary ledger")?
+        .write_all(lines.as_bytes())
+        .await
+        .context("Failed to write canary ledger")
+}
+
+/// Flags proxies whose canary token reached the server more than once or
+/// from another address than proxy exit, meaning their operator captured
+/// and replayed it
+///
+/// # Errors
+/// Return error if ledger or access log can't be read
+pub async fn run_canary(canary: &CanaryArgs) -> Result<()> {
+    let ledger = tokio::fs::read_to_string(&canary.canary_ledger)
+        .await
+        .context("Failed to read canary ledger")?;
+    let planted = ledger
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t').skip(1);
+            let token = fields.next()?;
+            let exit_ip = fields.next()?.parse().ok();
+            let link = fields.next()?.to_owned();
+            Some((token.to_owned(), Planted { exit_ip, link }))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let access_log = tokio::fs::read_to_string(&canary.access_log)
+        .await
+        .context("Failed to read access log")?;
+    // Client addresses every token was seen from
+    let mut seen = HashMap::<&str, Vec<&str>>::default();
+    for line in access_log.lines() {
+        let client = line.split_whitespace().next().unwrap_or_default();
+        for token in planted.keys() {
+            if line.contains(token.as_str()) {
+                seen.entry(token.as_str()).or_default().push(client);
+            }
+        }
+    }
+
+    let mut flagged = 0;
+    for (token, clients) in &seen {
+        let Some(planted) = planted.get(*token) else {
+            continue;
+        };
+        let foreign = clients
+            .iter()
+            .filter(|client| {
+                planted
+                    .exit_ip
+                    .is_some_and(|exit_ip| client.parse() != Ok(exit_ip))
+            })
+            .collect::<Vec<_>>();
+        if clients.len() > 1 || !foreign.is_empty() {
+            flagged += 1;
+            println!(
+                "{}\tseen {} times, {} from other addresses",
+                planted.link,
+                clients.len(),
+                foreign.len()
+            );
+        }
+    }
+
+    log::info!(
+        "{flagged} of {} proxies replayed canary credentials, {} tokens never arrived",
+        planted.len(),
+        planted.len() - seen.len()
+    );
+    Ok(())
+}
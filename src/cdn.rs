@@ -0,0 +1,114 @@
+//! Detection of servers behind CDN edge networks.
+//!
+//! CDN-fronted nodes rotate IPs and follow the CDN port policy, so they are
+//! tagged separately from plain servers.
+
+use std::{net::IpAddr, sync::RwLock};
+
+use crate::error::ConfigError;
+
+// Published edge ranges of CDN providers
+const CDN_RANGES: &[(&str, &str)] = &[
+    ("cloudflare", "173.245.48.0/20"),
+    ("cloudflare", "103.21.244.0/22"),
+    ("cloudflare", "103.22.200.0/22"),
+    ("cloudflare", "103.31.4.0/22"),
+    ("cloudflare", "141.101.64.0/18"),
+    ("cloudflare", "108.162.192.0/18"),
+    ("cloudflare", "190.93.240.0/20"),
+    ("cloudflare", "188.114.96.0/20"),
+    ("cloudflare", "197.234.240.0/22"),
+    ("cloudflare", "198.41.128.0/17"),
+    ("cloudflare", "162.158.0.0/15"),
+    ("cloudflare", "104.16.0.0/13"),
+    ("cloudflare", "104.24.0.0/14"),
+    ("cloudflare", "172.64.0.0/13"),
+    ("cloudflare", "131.0.72.0/22"),
+    ("cloudflare", "2400:cb00::/32"),
+    ("cloudflare", "2606:4700::/32"),
+    ("cloudflare", "2803:f800::/32"),
+    ("cloudflare", "2405:b500::/32"),
+    ("cloudflare", "2405:8100::/32"),
+    ("cloudflare", "2a06:98c0::/29"),
+    ("cloudflare", "2c0f:f248::/32"),
+    ("fastly", "23.235.32.0/20"),
+    ("fastly", "43.249.72.0/22"),
+    ("fastly", "103.244.50.0/24"),
+    ("fastly", "103.245.222.0/23"),
+    ("fastly", "103.245.224.0/24"),
+    ("fastly", "104.156.80.0/20"),
+    ("fastly", "140.248.64.0/18"),
+    ("fastly", "140.248.128.0/17"),
+    ("fastly", "146.75.0.0/17"),
+    ("fastly", "151.101.0.0/16"),
+    ("fastly", "157.52.64.0/18"),
+    ("fastly", "167.82.0.0/17"),
+    ("fastly", "167.82.128.0/20"),
+    ("fastly", "167.82.160.0/20"),
+    ("fastly", "167.82.224.0/20"),
+    ("fastly", "172.111.64.0/18"),
+    ("fastly", "185.31.16.0/22"),
+    ("fastly", "199.27.72.0/21"),
+    ("fastly", "199.232.0.0/16"),
+    ("fastly", "2a04:4e40::/32"),
+    ("fastly", "2a04:4e42::/32"),
+    ("gcore", "92.223.64.0/18"),
+];
+
+// Ranges registered at runtime, checked before CDN_RANGES
+static REGISTERED_RANGES: RwLock<Vec<(String, IpAddr, u8)>> = RwLock::new(Vec::new());
+
+fn parse_range(cidr: &str) -> Option<(IpAddr, u8)> {
+    let (addr, len) = cidr.split_once('/')?;
+    let addr = addr.parse::<IpAddr>().ok()?;
+    let len = len.parse::<u8>().ok()?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    (len <= max).then_some((addr, len))
+}
+
+fn contains(network: IpAddr, len: u8, ip: IpAddr) -> bool {
+    match (network, ip.to_canonical()) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// CDN provider owning `ip`, if any
+#[must_use]
+pub fn provider(ip: IpAddr) -> Option<String> {
+    let registered = REGISTERED_RANGES.read().ok().and_then(|ranges| {
+        ranges
+            .iter()
+            .find(|(_, network, len)| contains(*network, *len, ip))
+            .map(|(provider, _, _)| provider.clone())
+    });
+
+    registered.or_else(|| {
+        CDN_RANGES
+            .iter()
+            .find(|(_, cidr)| {
+                parse_range(cidr).is_some_and(|(network, len)| contains(network, len, ip))
+            })
+            .map(|(provider, _)| (*provider).to_owned())
+    })
+}
+
+/// Adds `cidr` range of `provider`, for edges missing from built-in list
+///
+/// # Errors
+/// Return error if range isn't `address/prefix`
+pub fn register_range(provider: &str, cidr: &str) -> Result<(), ConfigError> {
+    let (network, len) =
+        parse_range(cidr).ok_or_else(|| ConfigError::InvalidRange(cidr.to_owned()))?;
+    if let Ok(mut ranges) = REGISTERED_RANGES.write() {
+        ranges.push((provider.to_owned(), network, len));
+    }
+    Ok(())
+}
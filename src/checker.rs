@@ -0,0 +1,985 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use ahash::HashMap;
+use anyhow::{Context as _, Result, bail};
+use base64::Engine as _;
+use futures::{StreamExt as _, stream};
+use rand::{Rng as _, seq::SliceRandom as _};
+use regex::Regex;
+use reqwest::{
+    Client, Response, StatusCode,
+    header::{HeaderMap, HeaderValue, USER_AGENT},
+};
+use ring::digest::{self, SHA256, digest};
+use rustls_pki_types::CertificateDer;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+use webpki::EndEntityCert;
+
+use crate::{
+    engine::TestEngine,
+    events::EventHandler,
+    pipeline::PipelineConfig,
+    proxy_config::{Anonymity, Capacity, ProxyConfig, Timings},
+    quic_probe::probe_quic,
+    seed,
+    socks_client::{self, Fetched},
+    trace,
+    xray_config::Sandbox,
+    xray_process::XrayFeatures,
+    xray_stats::query_outbound_traffic,
+};
+
+pub struct CheckOptions {
+    pub request_timeout: Duration,
+    // Extra attempts for check requests failing on transport level
+    pub retries: u32,
+    pub retry_delay: Duration,
+    // When set, check timeout of pinged proxies is `max(min, factor * rtt)`
+    pub timeout_ping_factor: Option<(u32, Duration)>,
+    pub max_concurrent_checks: usize,
+    // Pools of alternative targets, see `parse_checklist`
+    pub latency_checklist: Vec<Vec<(String, String)>>,
+    // Proxies checked so far, picks target of every pool
+    pub rotation: AtomicUsize,
+    // Checklist is requested in random order for every proxy
+    pub shuffle_checklist: bool,
+    // Random delay up to this before every check request
+    pub jitter: Duration,
+    // Check requests per proxy, checklist is repeated to make them
+    pub samples: u32,
+    // Used for checklist entries without own user agent
+    pub user_agent: Option<String>,
+    pub headers: HeaderMap,
+    pub country: bool,
+    // Returns JSON with `country` code and `ip` of the exit
+    pub country_url: String,
+    // Targets geofenced to a country, exit country is only kept when all
+    // targets of it respond
+    pub country_checks: Vec<([char; 2], String)>,
+    // When set, the response must have exactly this status instead of any 2xx
+    pub expect_status: Option<StatusCode>,
+    // When set, every fetched page must match it, otherwise the proxy is
+    // likely a captive portal or MITM answering 200 for everything
+    pub expect_body: Option<Regex>,
+    // Check domain -> SHA-256 hashes of the leaf certificate SPKI we expect
+    // to see through the proxy
+    pub spki_pins: HashMap<String, Vec<[u8; 32]>>,
+    // Drop proxies presenting another certificate instead of only flagging them
+    pub drop_tls_mismatch: bool,
+    pub anonymity_check: Option<AnonymityCheck>,
+    pub canary_check: Option<CanaryCheck>,
+    pub hold_check: Option<HoldCheck>,
+    pub payload_check: Option<PayloadCheck>,
+    pub capacity_probe: Option<CapacityProbe>,
+    // Port of xray api inbound, enables per proxy traffic accounting
+    pub stats_api_port: Option<u16>,
+    // Host and port of a QUIC server to probe through every proxy
+    pub http3_target: Option<(String, u16)>,
+    pub prefilter: Option<Prefilter>,
+    // Single socks inbound for the whole chunk, routed to proxies by username
+    pub mux_inbound: bool,
+    // Random per run, required by local inbounds
+    pub inbound_password: String,
+    // Only destinations proxies may be asked for, when set
+    pub sandbox: Option<Sandbox>,
+    pub xray_features: XrayFeatures,
+    pub events: Arc<dyn EventHandler>,
+    // Stop checking once enough proxies passed
+    pub target: Option<TargetWorking>,
+    // Stops checking after current requests, killing the chunk's xray
+    pub cancel: CancellationToken,
+    // Check requests failed for lack of local ports or open files since
+    // last chunk, such chunks are checked again in smaller ones
+    pub exhausted: AtomicUsize,
+    // Chunk size and ports of local inbounds come from it
+    pub pipeline: PipelineConfig,
+}
+
+// Raw OS errors of running out of file descriptors (EMFILE, ENFILE) or
+// socket buffers on Windows (WSAENOBUFS)
+const EXHAUSTION_ERRORS: &[i32] = &[23, 24, 10055];
+
+/// Whether request failed because the OS ran out of ephemeral ports or
+/// file descriptors, not because of the proxy
+#[must_use]
+pub fn is_resource_exhaustion(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(io) = error.downcast_ref::<std::io::Error>()
+            && (io.kind() == std::io::ErrorKind::AddrNotAvailable
+                || io
+                    .raw_os_error()
+                    .is_some_and(|code| EXHAUSTION_ERRORS.contains(&code)))
+        {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+// Representative check targets of xray geosite categories, so reachability
+// is checked the way traffic will be routed
+const GEOSITE_TARGETS: &[(&str, &[&str])] = &[
+    (
+        "google",
+        &[
+            "www.google.com/generate_204",
+            "www.gstatic.com/generate_204",
+        ],
+    ),
+    ("youtube", &["www.youtube.com", "i.ytimg.com/generate_204"]),
+    ("telegram", &["telegram.org", "web.telegram.org"]),
+    ("twitter", &["x.com", "abs.twimg.com"]),
+    ("facebook", &["www.facebook.com", "static.xx.fbcdn.net"]),
+    ("instagram", &["www.instagram.com"]),
+    ("discord", &["discord.com", "cdn.discordapp.com"]),
+    ("netflix", &["www.netflix.com", "fast.com"]),
+    ("openai", &["chatgpt.com", "api.openai.com"]),
+    ("github", &["github.com", "raw.githubusercontent.com"]),
+    ("spotify", &["open.spotify.com"]),
+    ("microsoft", &["www.microsoft.com"]),
+    ("apple", &["www.apple.com"]),
+    ("cloudflare", &["www.cloudflare.com/cdn-cgi/trace"]),
+];
+
+/// Parses comma separated `target[@user-agent]` checklist, expanding
+/// `geosite:category` targets to representative URLs of the category.
+///
+/// Entry of `|` separated targets is a pool, every proxy is checked with
+/// the next target of it, so no single target gets requests of a whole run
+/// and rate limits it.
+///
+/// # Errors
+/// Return error if geosite category is unknown
+pub fn parse_checklist(checklist: &str) -> Result<Vec<Vec<(String, String)>>> {
+    let mut pools = Vec::new();
+    for entry in checklist.split(',').filter(|entry| !entry.is_empty()) {
+        if !entry.contains('|') {
+            pools.extend(expand_target(entry)?.into_iter().map(|target| vec![target]));
+            continue;
+        }
+        let mut pool = Vec::new();
+        for target in entry.split('|').filter(|target| !target.is_empty()) {
+            pool.extend(expand_target(target)?);
+        }
+        if !pool.is_empty() {
+            pools.push(pool);
+        }
+    }
+    Ok(pools)
+}
+
+fn expand_target(entry: &str) -> Result<Vec<(String, String)>> {
+    let (target, user_agent) = entry.split_once('@').unwrap_or((entry, ""));
+    let Some(category) = target.strip_prefix("geosite:") else {
+        return Ok(vec![(target.to_owned(), user_agent.to_owned())]);
+    };
+    let Some((_, domains)) = GEOSITE_TARGETS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(category))
+    else {
+        bail!(
+            "Unknown geosite category {category}, known: {}",
+            GEOSITE_TARGETS
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    };
+    Ok(domains
+        .iter()
+        .map(|domain| ((*domain).to_owned(), user_agent.to_owned()))
+        .collect())
+}
+
+/// Uppercased two letter country code
+#[must_use]
+pub fn parse_country_code(code: &str) -> Option<[char; 2]> {
+    match code.to_uppercase().chars().collect::<Vec<_>>()[..] {
+        [first, second] => Some([first, second]),
+        _ => None,
+    }
+}
+
+/// Parses `CC=target` country check, target is a domain or URL like in
+/// checklist
+///
+/// # Errors
+/// Return error if country code isn't two letters or target is missing
+pub fn parse_country_check(check: &str) -> Result<([char; 2], String)> {
+    let Some((code, target)) = check
+        .split_once('=')
+        .filter(|(_, target)| !target.is_empty())
+    else {
+        bail!("Country check {check} isn't CC=target");
+    };
+    let country =
+        parse_country_code(code).with_context(|| format!("Invalid country code {code}"))?;
+    Ok((country, target.to_owned()))
+}
+
+/// Working proxies needed to stop checking the rest
+pub struct TargetWorking {
+    pub count: usize,
+    // When not empty, `count` proxies of every listed country are needed
+    pub countries: Vec<[char; 2]>,
+}
+
+impl TargetWorking {
+    #[must_use]
+    pub fn reached(&self, working: &[ProxyConfig]) -> bool {
+        if self.countries.is_empty() {
+            return working.len() >= self.count;
+        }
+        self.countries.iter().all(|country| {
+            working
+                .iter()
+                .filter(|proxy| proxy.country == Some(*country))
+                .count()
+                >= self.count
+        })
+    }
+}
+
+/// Cheap first pass weeding out dead proxies before the full check
+pub struct Prefilter {
+    pub url: String,
+    pub timeout: Duration,
+}
+
+impl Prefilter {
+    async fn alive(&self, client: &Client) -> bool {
+        client
+            .head(&self.url)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .is_ok()
+    }
+}
+
+pub struct CapacityProbe {
+    pub url: String,
+    pub connections: usize,
+}
+
+impl CapacityProbe {
+    async fn probe(&self, inbound: &LocalInbound, request_timeout: Duration) -> Option<Capacity> {
+        // HTTP/1 only, so every request gets its own connection through the proxy
+        let client = Client::builder()
+            .timeout(request_timeout)
+            .http1_only()
+            .proxy(reqwest::Proxy::all(inbound.url()).ok()?)
+            .build()
+            .ok()?;
+
+        let latencies = futures::future::join_all((0..self.connections).map(|_| async {
+            let start = Instant::now();
+            let resp = client.get(&self.url).send().await.ok()?;
+            resp.bytes().await.ok()?;
+            Some(start.elapsed())
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+        let succeeded = latencies.len();
+        Some(Capacity {
+            succeeded,
+            attempted: self.connections,
+            latency: latencies
+                .into_iter()
+                .sum::<Duration>()
+                .checked_div(succeeded as u32)
+                .unwrap_or_default(),
+        })
+    }
+}
+
+pub struct HoldCheck {
+    // Slow streaming endpoint, `{secs}` is replaced with hold duration
+    pub url: String,
+    pub duration: Duration,
+}
+
+impl HoldCheck {
+    // Returns false if the proxy dropped connection before stream ended
+    async fn holds(&self, client: &Client, request_timeout: Duration) -> bool {
+        let url = self
+            .url
+            .replace("{secs}", &self.duration.as_secs().to_string());
+        let Ok(resp) = client
+            .get(url)
+            .timeout(self.duration + request_timeout)
+            .send()
+            .await
+        else {
+            return false;
+        };
+
+        let start = Instant::now();
+        resp.status().is_success()
+            && resp.bytes().await.is_ok()
+            && start.elapsed() + request_timeout >= self.duration
+    }
+}
+
+/// Downloads payload larger than MTU related failure thresholds and
+/// compares its hash, catching proxies that pass small checks but corrupt
+/// or stall large transfers
+pub struct PayloadCheck {
+    pub url: String,
+    pub sha256: [u8; 32],
+    // Whole download, large payloads take longer than check requests
+    pub timeout: Duration,
+}
+
+impl PayloadCheck {
+    /// Check against hash of payload downloaded without proxy
+    ///
+    /// # Errors
+    /// Return error if download failed
+    pub async fn fetch_reference(url: String, timeout: Duration) -> Result<Self> {
+        let resp = Client::builder()
+            .timeout(timeout)
+            .build()?
+            .get(&url)
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .with_context(|| format!("Failed to download payload {url}"))?;
+        let sha256 = body_sha256(resp)
+            .await
+            .with_context(|| format!("Failed to download payload {url}"))?;
+        Ok(Self {
+            url,
+            sha256,
+            timeout,
+        })
+    }
+
+    /// # Errors
+    /// Return error if `sha256` isn't 64 hex digits
+    pub fn with_hash(url: String, sha256: &str, timeout: Duration) -> Result<Self> {
+        if sha256.len() != 64 || !sha256.is_ascii() {
+            bail!("Payload SHA-256 must be 64 hex digits");
+        }
+        let mut hash = [0; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&sha256[i * 2..i * 2 + 2], 16)
+                .context("Payload SHA-256 isn't hex")?;
+        }
+        Ok(Self {
+            url,
+            sha256: hash,
+            timeout,
+        })
+    }
+
+    // Returns false if payload didn't arrive whole in time
+    async fn intact(&self, client: &Client) -> bool {
+        let Ok(resp) = client.get(&self.url).timeout(self.timeout).send().await else {
+            return false;
+        };
+        resp.status().is_success()
+            && body_sha256(resp)
+                .await
+                .is_ok_and(|hash| hash == self.sha256)
+    }
+}
+
+// Hashes body chunk by chunk instead of holding payload of every proxy
+async fn body_sha256(mut resp: Response) -> reqwest::Result<[u8; 32]> {
+    let mut context = digest::Context::new(&SHA256);
+    while let Some(chunk) = resp.chunk().await? {
+        context.update(&chunk);
+    }
+    let mut sha256 = [0; 32];
+    sha256.copy_from_slice(context.finish().as_ref());
+    Ok(sha256)
+}
+
+/// Sends unique credentials through the proxy to a server the user
+/// controls. Seeing them again in its log means the operator records and
+/// replays traffic, see `canary` mode.
+pub struct CanaryCheck {
+    // Plain http endpoint, `{token}` is replaced with the token
+    pub url: String,
+}
+
+impl CanaryCheck {
+    // Returns planted token, sent also as basic auth password so both
+    // sniffed URLs and credentials can be replayed
+    async fn plant(&self, client: &Client) -> Option<String> {
+        let canary = rand::rng()
+            .sample_iter(rand::distr::Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect::<String>();
+        client
+            .get(self.url.replace("{token}", &canary))
+            .basic_auth("novaprox", Some(&canary))
+            .send()
+            .await
+            .ok()?;
+        Some(canary)
+    }
+}
+
+pub struct AnonymityCheck {
+    // Plain http endpoint echoing request headers back
+    pub echo_url: String,
+    // Our own address, as seen without proxy
+    pub real_ip: IpAddr,
+}
+
+impl AnonymityCheck {
+    const PROTOCOLS: &[&str] = &["http", "https", "socks", "socks5"];
+
+    async fn classify(&self, client: &Client, proxy: &ProxyConfig) -> Option<Anonymity> {
+        if !Self::PROTOCOLS.contains(&proxy.protocol.as_str()) {
+            return None;
+        }
+
+        let echoed = client
+            .get(&self.echo_url)
+            .send()
+            .await
+            .ok()?
+            .text()
+            .await
+            .ok()?;
+        Some(Anonymity::classify(&echoed, self.real_ip))
+    }
+}
+
+/// Parses `domain=base64hash|base64hash,domain2=base64hash`
+///
+/// # Errors
+/// Return error if some hash is not base64 encoded SHA-256
+pub fn parse_spki_pins(pins: &str) -> Result<HashMap<String, Vec<[u8; 32]>>> {
+    pins.split(',')
+        .filter(|pin| !pin.is_empty())
+        .map(|pin| {
+            let (domain, hashes) = pin
+                .split_once('=')
+                .with_context(|| format!("Missing '=' in SPKI pin: {pin}"))?;
+            let hashes = hashes
+                .split('|')
+                .map(|hash| {
+                    base64::engine::general_purpose::STANDARD
+                        .decode(hash)
+                        .ok()
+                        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                        .with_context(|| format!("Invalid SPKI SHA-256 hash: {hash}"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok((domain.to_lowercase(), hashes))
+        })
+        .collect()
+}
+
+fn spki_hash(der: &[u8]) -> Option<[u8; 32]> {
+    let der = CertificateDer::from(der);
+    let cert = EndEntityCert::try_from(&der).ok()?;
+    let hash = digest(&SHA256, cert.subject_public_key_info().as_ref());
+    hash.as_ref().try_into().ok()
+}
+
+impl CheckOptions {
+    /// Hosts every enabled check requests through proxies
+    #[must_use]
+    pub fn check_hosts(&self) -> Vec<String> {
+        let urls = self
+            .latency_checklist
+            .iter()
+            .flatten()
+            .map(|(target, _)| target_url(target))
+            .chain(self.prefilter.as_ref().map(|p| p.url.clone()))
+            .chain(self.hold_check.as_ref().map(|h| h.url.clone()))
+            .chain(self.payload_check.as_ref().map(|p| p.url.clone()))
+            .chain(self.anonymity_check.as_ref().map(|a| a.echo_url.clone()))
+            .chain(self.canary_check.as_ref().map(|c| c.url.clone()))
+            .chain(self.capacity_probe.as_ref().map(|c| c.url.clone()))
+            .chain(self.country.then(|| self.country_url.clone()))
+            .chain(
+                self.country_checks
+                    .iter()
+                    .map(|(_, target)| target_url(target)),
+            );
+
+        let mut hosts = urls
+            .filter_map(|url| Url::parse(&url).ok()?.host_str().map(str::to_owned))
+            .chain(self.http3_target.as_ref().map(|(host, _)| host.clone()))
+            .collect::<Vec<_>>();
+        hosts.sort_unstable();
+        hosts.dedup();
+        hosts
+    }
+
+    #[must_use]
+    pub fn timeout_for(&self, proxy: &ProxyConfig) -> Duration {
+        match (self.timeout_ping_factor, proxy.icmp_rtt) {
+            (Some((factor, min)), Some(rtt)) => (rtt * factor).max(min),
+            _ => self.request_timeout,
+        }
+    }
+
+    // Returns response with start time and number of the successful attempt
+    async fn fetch_with_retries(
+        &self,
+        inbound: &LocalInbound,
+        url: &str,
+        headers: &HeaderMap,
+        timeout: Duration,
+    ) -> Option<(Fetched, Instant, u32)> {
+        for attempt in 1..=self.retries + 1 {
+            let start = Instant::now();
+            match tokio::time::timeout(timeout, socks_client::get(inbound, url, headers)).await {
+                Ok(Ok(fetched)) => return Some((fetched, start, attempt)),
+                Ok(Err(e)) if is_resource_exhaustion(&e) => {
+                    self.exhausted.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(Err(_)) | Err(_) => {}
+            }
+            if attempt <= self.retries {
+                tokio::time::sleep(self.retry_delay).await;
+            }
+        }
+        None
+    }
+
+    // Headers of check request, user agent of checklist entry wins over
+    // `user_agent`
+    fn headers_with(&self, user_agent: &str) -> HeaderMap {
+        let mut headers = self.headers.clone();
+        let user_agent = Some(user_agent)
+            .filter(|user_agent| !user_agent.is_empty())
+            .or(self.user_agent.as_deref());
+        if let Some(user_agent) = user_agent.and_then(|ua| HeaderValue::from_str(ua).ok()) {
+            headers.insert(USER_AGENT, user_agent);
+        }
+        headers
+    }
+
+    #[must_use]
+    pub fn accepts_status(&self, status: StatusCode) -> bool {
+        self.expect_status
+            .map_or_else(|| status.is_success(), |expected| status == expected)
+    }
+
+    #[must_use]
+    pub fn accepts_body(&self, body: &[u8]) -> bool {
+        self.expect_body
+            .as_ref()
+            .is_none_or(|re| re.is_match(&String::from_utf8_lossy(body)))
+    }
+}
+
+/// Local socks inbound of one proxy in chunk
+pub struct LocalInbound {
+    pub port: usize,
+    // Routes to the proxy when inbound is shared
+    pub user: String,
+    pub password: String,
+}
+
+impl LocalInbound {
+    #[must_use]
+    pub fn url(&self) -> String {
+        format!(
+            "socks5://{}:{}@127.0.0.1:{}",
+            self.user, self.password, self.port
+        )
+    }
+}
+
+/// Clients bound to every local inbound. Every chunk listens on the same
+/// ports, so clients are built once and reused for all chunks.
+pub struct PortClients {
+    inbounds: Vec<LocalInbound>,
+    clients: Vec<Client>,
+}
+
+impl PortClients {
+    /// # Errors
+    /// Return error if failed to build client
+    pub fn new(engine: &impl TestEngine, count: usize, options: &CheckOptions) -> Result<Self> {
+        let inbounds = (0..count).map(|i| engine.endpoint(i)).collect::<Vec<_>>();
+        let clients = inbounds
+            .iter()
+            .map(|inbound| {
+                Client::builder()
+                    .timeout(options.request_timeout)
+                    .proxy(reqwest::Proxy::all(inbound.url())?)
+                    // Connections must not outlive xray process of the chunk
+                    .pool_max_idle_per_host(0)
+                    .build()
+            })
+            .collect::<reqwest::Result<_>>()?;
+
+        Ok(Self { inbounds, clients })
+    }
+}
+
+pub async fn test_proxy_chunk(
+    chunk: &[ProxyConfig],
+    clients: &PortClients,
+    options: &CheckOptions,
+) -> Vec<ProxyConfig> {
+    let candidates = chunk.iter().zip(&clients.clients).enumerate();
+    let candidates = if let Some(prefilter) = &options.prefilter {
+        let survivors = stream::iter(candidates)
+            .map(|candidate| async move {
+                let alive = prefilter.alive(candidate.1.1).await;
+                if !alive {
+                    options.events.on_proxy_result(candidate.1.0, false);
+                }
+                alive.then_some(candidate)
+            })
+            .buffer_unordered(options.max_concurrent_checks)
+            .filter_map(|x| async move { x })
+            .collect::<Vec<_>>()
+            .await;
+        log::debug!(
+            "{}/{} proxies passed prefilter",
+            survivors.len(),
+            chunk.len()
+        );
+        survivors
+    } else {
+        candidates.collect()
+    };
+
+    let mut working = stream::iter(candidates)
+        .map(|(i, (proxy, client))| async move {
+            let result = test_proxy(proxy, &clients.inbounds[i], client, options).await;
+            options.events.on_proxy_result(proxy, result.is_some());
+            result.map(|proxy| (i, proxy))
+        })
+        .buffer_unordered(options.max_concurrent_checks)
+        .filter_map(|x| async { x })
+        .collect::<Vec<_>>()
+        .await;
+
+    if let Some(api_port) = options.stats_api_port {
+        match query_outbound_traffic(api_port).await {
+            Ok(traffic) => {
+                for (i, proxy) in &mut working {
+                    proxy.traffic = traffic.get(i).copied();
+                }
+            }
+            Err(e) => log::warn!("Failed to query xray stats: {e}"),
+        }
+    }
+
+    working.into_iter().map(|(_, proxy)| proxy).collect()
+}
+
+async fn run_optional_checks(
+    proxy: &mut ProxyConfig,
+    client: &Client,
+    inbound: &LocalInbound,
+    options: &CheckOptions,
+) {
+    if let Some(check) = &options.hold_check
+        && !check.holds(client, options.request_timeout).await
+    {
+        log::debug!("Proxy {} dropped long-lived connection", proxy.address);
+        proxy.drops_long_connections = true;
+    }
+
+    if let Some(check) = &options.payload_check
+        && !check.intact(client).await
+    {
+        log::debug!("Proxy {} corrupted or stalled payload", proxy.address);
+        proxy.corrupts_payloads = true;
+    }
+
+    if let Some(probe) = &options.capacity_probe {
+        proxy.capacity = probe.probe(inbound, options.request_timeout).await;
+    }
+
+    if let Some((host, target_port)) = &options.http3_target {
+        let result = probe_quic(inbound, host, *target_port, options.request_timeout).await;
+        if let Err(e) = &result {
+            log::debug!("Proxy {} failed QUIC probe: {e}", proxy.address);
+        }
+        proxy.http3 = Some(result.is_ok());
+    }
+
+    if let Some(check) = &options.anonymity_check {
+        proxy.anonymity = check.classify(client, proxy).await;
+    }
+
+    if let Some(check) = &options.canary_check {
+        proxy.canary = check.plant(client).await;
+    }
+}
+
+/// URL of checklist target, a domain requested over HTTPS or a full URL
+#[must_use]
+pub fn target_url(target: &str) -> String {
+    if target.contains("://") {
+        target.to_owned()
+    } else {
+        format!("https://{target}")
+    }
+}
+
+fn trace_check(proxy: &ProxyConfig, domain: &str, sent: Option<&(Fetched, Instant, u32)>) {
+    match sent {
+        Some((fetched, start, attempts)) => trace::record(
+            proxy,
+            "check",
+            format_args!(
+                "{}: {} after {}ms, attempt {attempts}",
+                target_url(domain),
+                fetched.status,
+                start.elapsed().as_millis()
+            ),
+        ),
+        None => trace::record(
+            proxy,
+            "check",
+            format_args!("{}: no response", target_url(domain)),
+        ),
+    }
+}
+
+// Average latency of single sample, median and p95 of multiple, since one
+// request through a congested node says little
+fn latency_stats(latencies: &mut [Duration], samples: u32) -> (Duration, Option<Duration>) {
+    if samples <= 1 {
+        let total = latencies.iter().sum::<Duration>();
+        return (total / latencies.len() as u32, None);
+    }
+    latencies.sort_unstable();
+    // Nearest rank
+    let percentile = |p: usize| latencies[(latencies.len() * p).div_ceil(100).saturating_sub(1)];
+    (percentile(50), Some(percentile(95)))
+}
+
+fn average_timings(timings: &[Timings]) -> Option<Timings> {
+    let average = |phases: Vec<Duration>| {
+        let count = u32::try_from(phases.len()).ok()?;
+        phases.into_iter().sum::<Duration>().checked_div(count)
+    };
+    Some(Timings {
+        socks: average(timings.iter().map(|t| t.socks).collect())?,
+        tls: average(timings.iter().filter_map(|t| t.tls).collect()),
+        first_byte: average(timings.iter().map(|t| t.first_byte).collect())?,
+    })
+}
+
+// Country code and address of proxy exit, by `country_url`
+async fn exit_country(
+    client: &Client,
+    options: &CheckOptions,
+) -> Option<([char; 2], Option<IpAddr>)> {
+    if !options.country {
+        return None;
+    }
+    let info = client.get(&options.country_url).send().await.ok()?;
+    let info = serde_json::from_str::<serde_json::Value>(&info.text().await.ok()?).ok()?;
+    let &[first, second] = &info["country"].as_str()?.chars().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    Some((
+        [first, second],
+        info["ip"].as_str().and_then(|ip| ip.parse().ok()),
+    ))
+}
+
+// Geo IP databases lag behind, so exit country is trusted only when
+// endpoints geofenced to it answer through the proxy
+async fn confirms_country(
+    proxy: &ProxyConfig,
+    country: [char; 2],
+    inbound: &LocalInbound,
+    options: &CheckOptions,
+    timeout: Duration,
+) -> bool {
+    for (_, target) in options
+        .country_checks
+        .iter()
+        .filter(|(code, _)| *code == country)
+    {
+        let sent = options
+            .fetch_with_retries(inbound, &target_url(target), &options.headers, timeout)
+            .await;
+        trace_check(proxy, target, sent.as_ref());
+        if !sent.is_some_and(|(fetched, ..)| options.accepts_status(fetched.status)) {
+            return false;
+        }
+    }
+    true
+}
+
+async fn test_proxy(
+    proxy: &ProxyConfig,
+    inbound: &LocalInbound,
+    client: &Client,
+    options: &CheckOptions,
+) -> Option<ProxyConfig> {
+    let mut latencies = Vec::new();
+    let mut timings = Vec::new();
+    let mut total_bytes = 0u64;
+    let mut tls_mismatch = false;
+    let mut max_attempts = 1;
+    let timeout = options.timeout_for(proxy);
+
+    // Identical requests in identical order through hundreds of servers
+    // are easy to spot from the network
+    let mut rng = seed::rng(&proxy.to_string());
+    // Order of concurrent checks varies between runs, so deterministic
+    // runs pick pool targets by proxy
+    let turn = if seed::is_set() {
+        rng.random::<u32>() as usize
+    } else {
+        options.rotation.fetch_add(1, Ordering::Relaxed)
+    };
+    let mut checklist = options
+        .latency_checklist
+        .iter()
+        .filter_map(|pool| pool.get(turn % pool.len()))
+        .collect::<Vec<_>>();
+    if options.shuffle_checklist {
+        checklist.shuffle(&mut rng);
+    }
+
+    let requests = checklist.len().max(options.samples as usize);
+    for (domain, user_agent) in checklist.iter().cycle().take(requests) {
+        if !options.jitter.is_zero() {
+            let delay = rng.random_range(Duration::ZERO..options.jitter);
+            tokio::time::sleep(delay).await;
+        }
+        let headers = options.headers_with(user_agent);
+        let sent = options
+            .fetch_with_retries(inbound, &target_url(domain), &headers, timeout)
+            .await;
+        trace_check(proxy, domain, sent.as_ref());
+        let (fetched, start, attempts) = sent?;
+        max_attempts = max_attempts.max(attempts);
+        if !options.accepts_status(fetched.status) {
+            return None;
+        }
+
+        if let Some(pins) = options.spki_pins.get(domain)
+            && (fetched.peer_certificate.as_deref())
+                .and_then(spki_hash)
+                .is_none_or(|hash| !pins.contains(&hash))
+        {
+            log::warn!(
+                "Proxy {} presented unexpected certificate for {domain}",
+                proxy.address
+            );
+            if options.drop_tls_mismatch {
+                return None;
+            }
+            tls_mismatch = true;
+        }
+
+        let body = fetched.body;
+
+        if !options.accepts_body(&body) {
+            log::debug!(
+                "Proxy {} returned unexpected body for {domain}",
+                proxy.address
+            );
+            return None;
+        }
+
+        latencies.push(start.elapsed());
+        timings.push(fetched.timings);
+        total_bytes += body.len() as u64;
+    }
+
+    let total_duration = latencies.iter().sum::<Duration>();
+    let (latency, latency_p95) = latency_stats(&mut latencies, options.samples);
+    let avg_bandwidth = if total_duration.as_secs_f64() > 0.0 {
+        (total_bytes as f64 / total_duration.as_secs_f64()) as u64
+    } else {
+        0
+    };
+
+    let mut working_proxy = proxy.clone();
+    working_proxy.ping = latency;
+    working_proxy.ping_p95 = latency_p95;
+    working_proxy.timings = average_timings(&timings);
+    working_proxy.bandwidth = avg_bandwidth;
+    working_proxy.tls_mismatch = tls_mismatch;
+    working_proxy.check_attempts = max_attempts;
+
+    run_optional_checks(&mut working_proxy, client, inbound, options).await;
+
+    let (country, exit_ip) = exit_country(client, options).await?;
+    working_proxy.exit_ip = exit_ip;
+    if confirms_country(&working_proxy, country, inbound, options, timeout).await {
+        working_proxy.country = Some(country);
+    } else {
+        log::debug!(
+            "Proxy {} failed checks of {}{}, exit country unknown",
+            working_proxy.address,
+            country[0],
+            country[1]
+        );
+    }
+
+    log::debug!(
+        "Proxy {} latency: {}ms, avg bandwidth: {} B/s",
+        working_proxy.address,
+        latency.as_millis(),
+        avg_bandwidth
+    );
+    Some(working_proxy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings(socks: u64, tls: Option<u64>, first_byte: u64) -> Timings {
+        Timings {
+            socks: Duration::from_millis(socks),
+            tls: tls.map(Duration::from_millis),
+            first_byte: Duration::from_millis(first_byte),
+        }
+    }
+
+    #[test]
+    fn averages_each_phase() {
+        assert_eq!(
+            average_timings(&[timings(2, Some(100), 50), timings(4, Some(200), 150)]),
+            Some(timings(3, Some(150), 100))
+        );
+    }
+
+    #[test]
+    fn averages_tls_of_https_requests_only() {
+        assert_eq!(
+            average_timings(&[timings(2, None, 50), timings(2, Some(200), 50)]),
+            Some(timings(2, Some(200), 50))
+        );
+        assert_eq!(
+            average_timings(&[timings(2, None, 50)]),
+            Some(timings(2, None, 50))
+        );
+    }
+
+    #[test]
+    fn no_timings_without_requests() {
+        assert_eq!(average_timings(&[]), None);
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context as _, Result};
+use reqwest::Client;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Returns local clock offset from the `Date` header of `url`, positive
+/// when local clock is ahead
+///
+/// # Errors
+/// Return error if request failed or server returned no valid date
+pub async fn clock_skew(url: &str) -> Result<i64> {
+    let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
+    let resp = client
+        .head(url)
+        .send()
+        .await
+        .context("Time source request failed")?;
+    let date = resp
+        .headers()
+        .get("date")
+        .and_then(|date| date.to_str().ok())
+        .context("Time source returned no Date header")?;
+    let remote = parse_http_date(date).context("Invalid Date header")?;
+    let local = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+    Ok(local - remote)
+}
+
+// Parses IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`, into unix seconds
+fn parse_http_date(date: &str) -> Option<i64> {
+    let mut parts = date.split_whitespace().skip(1);
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month_name = parts.next()?;
+    let month = MONTHS.iter().position(|&m| m == month_name)? as i64 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|v| v.parse::<i64>().ok());
+    let (hour, min, sec) = (time.next()??, time.next()??, time.next()??);
+
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + min * 60 + sec)
+}
+
+// Howard Hinnant's days_from_civil
+const fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
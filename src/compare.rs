@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use ahash::{HashMap, HashSet};
+use anyhow::Result;
+
+use crate::{
+    Args, check_options, monitor::load_list, proxy_config::ProxyConfig, test_proxies_in_chunks,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct CompareArgs {
+    old_file: String,
+
+    new_file: String,
+}
+
+struct ListReport {
+    total: usize,
+    working: usize,
+    // Latencies of working proxies, sorted
+    latencies: Vec<Duration>,
+}
+
+impl ListReport {
+    fn new(list: &[ProxyConfig], results: &HashMap<String, ProxyConfig>) -> Self {
+        let mut latencies = list
+            .iter()
+            .filter_map(|proxy| results.get(&proxy.to_string()))
+            .map(|proxy| proxy.ping)
+            .collect::<Vec<_>>();
+        latencies.sort_unstable();
+
+        Self {
+            total: list.len(),
+            working: latencies.len(),
+            latencies,
+        }
+    }
+
+    fn availability(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.working as f64 * 100.0 / self.total as f64
+        }
+    }
+
+    fn median(&self) -> Option<Duration> {
+        self.latencies.get(self.latencies.len() / 2).copied()
+    }
+
+    fn average(&self) -> Option<Duration> {
+        self.latencies
+            .iter()
+            .sum::<Duration>()
+            .checked_div(self.latencies.len() as u32)
+    }
+}
+
+fn format_ms(latency: Option<Duration>) -> String {
+    latency.map_or_else(|| "-".to_owned(), |l| format!("{}ms", l.as_millis()))
+}
+
+/// Tests proxies of both lists in one pass, so they share network
+/// conditions, and prints availability and latency of each list
+///
+/// # Errors
+/// Return error if lists can't be read or xray failed to start
+pub(crate) async fn run_compare(args: &Args, compare: &CompareArgs) -> Result<()> {
+    let old = load_list(&compare.old_file, args).await?;
+    let new = load_list(&compare.new_file, args).await?;
+
+    // Proxies present in both lists are tested once
+    let mut keys = HashSet::default();
+    let combined = old
+        .iter()
+        .chain(&new)
+        .filter(|proxy| keys.insert(proxy.to_string()))
+        .cloned()
+        .collect::<Vec<_>>();
+    log::info!("Testing {} unique proxies", combined.len());
+
+    let working = test_proxies_in_chunks(&combined, &check_options(args).await?, None).await?;
+    let results = working
+        .into_iter()
+        .map(|proxy| (proxy.to_string(), proxy))
+        .collect::<HashMap<_, _>>();
+
+    let (old_report, new_report) = (
+        ListReport::new(&old, &results),
+        ListReport::new(&new, &results),
+    );
+    println!("{:<12}{:>12}{:>12}", "", "old", "new");
+    println!(
+        "{:<12}{:>12}{:>12}",
+        "proxies", old_report.total, new_report.total
+    );
+    println!(
+        "{:<12}{:>12}{:>12}",
+        "working", old_report.working, new_report.working
+    );
+    println!(
+        "{:<12}{:>11.1}%{:>11.1}%",
+        "available",
+        old_report.availability(),
+        new_report.availability()
+    );
+    println!(
+        "{:<12}{:>12}{:>12}",
+        "median",
+        format_ms(old_report.median()),
+        format_ms(new_report.median())
+    );
+    println!(
+        "{:<12}{:>12}{:>12}",
+        "average",
+        format_ms(old_report.average()),
+        format_ms(new_report.average())
+    );
+
+    Ok(())
+}
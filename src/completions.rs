@@ -0,0 +1,30 @@
+//! Shell completions and manpage of the command line, for packagers.
+
+use std::io;
+
+use anyhow::Result;
+use clap::CommandFactory as _;
+use clap_complete::Shell;
+
+use crate::Args;
+
+#[derive(clap::Args, Debug)]
+pub struct CompletionsArgs {
+    shell: Shell,
+}
+
+/// Prints completion script of `shell` to stdout
+pub fn print_completions(completions: &CompletionsArgs) {
+    let mut command = Args::command();
+    let name = command.get_name().to_owned();
+    clap_complete::generate(completions.shell, &mut command, name, &mut io::stdout());
+}
+
+/// Prints roff manpage to stdout
+///
+/// # Errors
+/// Return error if failed to write stdout
+pub fn print_man() -> Result<()> {
+    clap_mangen::Man::new(Args::command()).render(&mut io::stdout())?;
+    Ok(())
+}
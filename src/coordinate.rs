@@ -0,0 +1,307 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context as _, Result, bail};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader, Lines},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{Mutex, Notify},
+};
+
+use crate::{
+    Args, fetch_stage, proxy_config::ProxyConfig, results_header, rpc::proxy_from_json,
+    sort_proxies, summary::RunSummary, write_results,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct CoordinateArgs {
+    // Address of `serve` mode worker, repeatable
+    #[arg(long = "worker", required = true)]
+    workers: Vec<SocketAddr>,
+
+    // Links sent to a worker in one request
+    #[arg(long, default_value_t = 500)]
+    shard_size: usize,
+}
+
+// JSON-RPC connection to a worker
+struct Worker {
+    addr: SocketAddr,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+}
+
+impl Worker {
+    async fn connect(addr: SocketAddr) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr)
+            .await
+            .context("Failed to connect")?
+            .into_split();
+        Ok(Self {
+            addr,
+            lines: BufReader::new(reader).lines(),
+            writer,
+            next_id: 0,
+        })
+    }
+
+    async fn check(&mut self, links: &[String]) -> Result<Vec<ProxyConfig>> {
+        self.next_id += 1;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": self.next_id,
+            "method": "check",
+            "params": { "links": links, "raw": true },
+        });
+        self.writer
+            .write_all(format!("{request}\n").as_bytes())
+            .await?;
+
+        // Progress notifications come before the response
+        let response = loop {
+            let line = self
+                .lines
+                .next_line()
+                .await?
+                .context("Worker closed connection")?;
+            let message = serde_json::from_str::<Value>(&line)?;
+            if message["id"] == self.next_id {
+                break message;
+            }
+            if message["method"] == "progress" {
+                log::debug!("Worker {} progress: {}", self.addr, message["params"]);
+            }
+        };
+        if let Some(error) = response.get("error") {
+            bail!("Worker error: {}", error["message"]);
+        }
+
+        Ok(response["result"]
+            .as_array()
+            .context("Worker result isn't an array")?
+            .iter()
+            .filter_map(proxy_from_json)
+            .collect())
+    }
+}
+
+/// Fetches sources and shards the links across `serve` mode workers,
+/// merging their working proxies. Shards of failed workers go back to the
+/// queue for the rest.
+///
+/// # Errors
+/// Return error if failed to fetch sources or write results
+pub(crate) async fn run_coordinator(args: &Args, coordinate: &CoordinateArgs) -> Result<()> {
+    let mut summary = RunSummary::new();
+    let links = fetch_stage(args, &mut summary)
+        .await?
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let queue = ShardQueue::new(
+        links
+            .chunks(coordinate.shard_size.max(1))
+            .map(<[_]>::to_vec)
+            .collect(),
+    );
+    log::info!(
+        "Dispatching {} links in {} shards to {} workers",
+        links.len(),
+        queue.state.lock().await.shards.len(),
+        coordinate.workers.len()
+    );
+
+    // A worker that hangs without closing the connection would keep its
+    // shard forever, so give up on it after every link timed out in turn
+    let shard_timeout = args
+        .pipeline
+        .request_timeout()
+        .saturating_mul(u32::try_from(coordinate.shard_size.max(1)).unwrap_or(u32::MAX));
+    let mut working = futures::future::join_all(
+        coordinate
+            .workers
+            .iter()
+            .map(|addr| drain_shards(*addr, &queue, shard_timeout)),
+    )
+    .await
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    // Live workers only stop once the queue is empty
+    let untested = queue
+        .state
+        .lock()
+        .await
+        .shards
+        .iter()
+        .map(Vec::len)
+        .sum::<usize>();
+    if untested > 0 {
+        log::warn!(
+            "{untested} links left untested, all {} workers failed",
+            coordinate.workers.len()
+        );
+    }
+    log::info!("Found {} working proxies", working.len());
+
+    sort_proxies(&mut working, &args.sort);
+    let results = args.format.render(&working, &results_header(args))?;
+    write_results(args, &results).await
+}
+
+// Shards not checked yet and shards being checked, which come back when
+// their worker fails
+struct ShardQueue {
+    state: Mutex<QueueState>,
+    // Signals finished shards to workers waiting for requeued ones
+    settled: Notify,
+}
+
+struct QueueState {
+    shards: Vec<Vec<String>>,
+    in_flight: usize,
+}
+
+impl ShardQueue {
+    fn new(shards: Vec<Vec<String>>) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                shards,
+                in_flight: 0,
+            }),
+            settled: Notify::new(),
+        }
+    }
+
+    // Next shard, waiting while others are checked in case they come back.
+    // `None` once every shard is checked
+    async fn take(&self) -> Option<Vec<String>> {
+        loop {
+            let settled = self.settled.notified();
+            tokio::pin!(settled);
+            settled.as_mut().enable();
+            {
+                let mut state = self.state.lock().await;
+                if let Some(shard) = state.shards.pop() {
+                    state.in_flight += 1;
+                    return Some(shard);
+                }
+                if state.in_flight == 0 {
+                    return None;
+                }
+            }
+            settled.await;
+        }
+    }
+
+    // Marks shard taken before as checked, or puts it back when `failed`
+    async fn settle(&self, shard: Vec<String>, failed: bool) {
+        let mut state = self.state.lock().await;
+        state.in_flight -= 1;
+        if failed {
+            state.shards.push(shard);
+        }
+        drop(state);
+        self.settled.notify_waiters();
+    }
+}
+
+// Checks shards on one worker until every shard is checked or worker fails,
+// which includes not answering within `shard_timeout`
+async fn drain_shards(
+    addr: SocketAddr,
+    queue: &ShardQueue,
+    shard_timeout: Duration,
+) -> Vec<ProxyConfig> {
+    let mut worker = match Worker::connect(addr).await {
+        Ok(worker) => worker,
+        Err(e) => {
+            log::warn!("Worker {addr} unavailable: {e:#}");
+            return Vec::new();
+        }
+    };
+
+    let mut working = Vec::new();
+    while let Some(shard) = queue.take().await {
+        let checked = tokio::time::timeout(shard_timeout, worker.check(&shard))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("No response in {shard_timeout:?}")));
+        match checked {
+            Ok(proxies) => {
+                log::info!(
+                    "Worker {addr} found {}/{} working",
+                    proxies.len(),
+                    shard.len()
+                );
+                working.extend(proxies);
+                queue.settle(shard, false).await;
+            }
+            Err(e) => {
+                log::warn!("Worker {addr} failed, requeueing its shard: {e:#}");
+                queue.settle(shard, true).await;
+                break;
+            }
+        }
+    }
+    working
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shard(link: &str) -> Vec<String> {
+        vec![link.to_owned()]
+    }
+
+    #[tokio::test]
+    async fn requeued_shard_goes_to_waiting_worker() {
+        let queue = std::sync::Arc::new(ShardQueue::new(vec![shard("a")]));
+        let failing = queue.take().await.unwrap();
+
+        let waiting = tokio::spawn({
+            let queue = std::sync::Arc::clone(&queue);
+            async move {
+                let shard = queue.take().await;
+                if let Some(shard) = &shard {
+                    queue.settle(shard.clone(), false).await;
+                }
+                (shard, queue.take().await)
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished(), "worker left while shard in flight");
+
+        queue.settle(failing, true).await;
+        assert_eq!(waiting.await.unwrap(), (Some(shard("a")), None));
+    }
+
+    #[tokio::test]
+    async fn empty_queue_ends_workers() {
+        let queue = ShardQueue::new(vec![shard("a")]);
+        let taken = queue.take().await.unwrap();
+        queue.settle(taken, false).await;
+        assert_eq!(queue.take().await, None);
+    }
+
+    #[tokio::test]
+    async fn silent_worker_times_out_and_requeues_shard() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Accepts the request and never answers
+        let _silent = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+            drop(stream);
+        });
+
+        let queue = ShardQueue::new(vec![shard("a")]);
+        let working = drain_shards(addr, &queue, Duration::from_millis(100)).await;
+        assert!(working.is_empty());
+        assert_eq!(queue.take().await, Some(shard("a")));
+    }
+}
@@ -1,11 +1,68 @@
-use std::{fs, net::IpAddr, path::Path};
+use std::{fs, net::IpAddr, path::Path, time::Duration};
 
 use ahash::{HashMap, HashMapExt as _};
 use anyhow::{Context as _, Result};
+use reqwest::Client;
+
+use crate::cache_format::{CacheFile, Writer};
 
 pub struct DnsCache {
     cache: HashMap<String, (IpAddr, bool)>,
     cache_file: String,
+    // Second layer asked when domain isn't cached locally
+    pub shared: Option<SharedDnsCache>,
+}
+
+/// Key-value HTTP store shared by a fleet of runners, behind the local cache
+///
+/// Addresses are read with `GET <url>/<domain>` and stored with
+/// `PUT <url>/<domain>` as plain text, which fits HTTP gateways of Redis
+/// and most KV services.
+#[derive(Clone)]
+pub struct SharedDnsCache {
+    base_url: String,
+    client: Client,
+}
+
+impl SharedDnsCache {
+    /// # Errors
+    /// Return error if failed to build client
+    pub fn new(base_url: &str) -> Result<Self> {
+        Ok(Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            // Shared layer must never be slower than resolving ourselves
+            client: Client::builder()
+                .timeout(Duration::from_secs(2))
+                .build()
+                .context("Failed to build shared DNS cache client")?,
+        })
+    }
+
+    pub async fn get(&self, domain: &str) -> Option<IpAddr> {
+        let resp = self
+            .client
+            .get(format!("{}/{domain}", self.base_url))
+            .send()
+            .await
+            .ok()?;
+        if !resp.status().is_success() {
+            return None;
+        }
+        resp.text().await.ok()?.trim().parse().ok()
+    }
+
+    pub async fn put(&self, domain: &str, ip: IpAddr) {
+        let result = self
+            .client
+            .put(format!("{}/{domain}", self.base_url))
+            .body(ip.to_string())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if let Err(e) = result {
+            log::debug!("Failed to store {domain} in shared DNS cache: {e}");
+        }
+    }
 }
 
 impl Default for DnsCache {
@@ -20,29 +77,34 @@ impl DnsCache {
         Self {
             cache: HashMap::new(),
             cache_file: cache_file.to_owned(),
+            shared: None,
         }
     }
 
     /// # Errors
     /// Return error if failed to read file
-    pub fn load_cache(&mut self) -> Result<HashMap<String, (IpAddr, bool)>> {
+    pub fn load_cache(&mut self) -> Result<()> {
         if !Path::new(&self.cache_file).exists() {
-            return Ok(HashMap::new());
+            return Ok(());
         }
 
-        fs::read_to_string(&self.cache_file)
-            .context("Failed to read DNS cache")?
-            .lines()
-            .filter_map(|line| {
-                let mut parts = line.split_whitespace();
-                let domain = parts.next()?;
-                let ip = parts.next()?.parse().ok()?;
-                Some((domain.to_owned(), (ip, false)))
-            })
-            .try_fold(HashMap::new(), |mut map, (domain, entry)| {
-                map.insert(domain, entry);
-                Ok(map)
-            })
+        let data = fs::read(&self.cache_file).context("Failed to read DNS cache")?;
+        self.cache = match CacheFile::detect(&data).context("Failed to read DNS cache")? {
+            CacheFile::Binary(reader) => reader
+                .records(|r| Some((r.str()?, (r.ip()?, false))))
+                .collect(),
+            CacheFile::Text(text) => text
+                .lines()
+                .filter_map(|line| {
+                    let mut parts = line.split_whitespace();
+                    let domain = parts.next()?;
+                    let ip = parts.next()?.parse().ok()?;
+                    Some((domain.to_owned(), (ip, false)))
+                })
+                .collect(),
+        };
+
+        Ok(())
     }
 
     pub fn get(&mut self, domain: &str) -> Option<IpAddr> {
@@ -58,15 +120,39 @@ impl DnsCache {
 
     /// # Errors
     /// Return error if failed to save file
-    pub fn save(&self) -> Result<()> {
-        let content = self
-            .cache
-            .iter()
-            .filter(|(_, (_, used))| *used)
-            .map(|(domain, (ip, _))| format!("{domain} {ip}"))
-            .collect::<Vec<_>>()
-            .join("\n");
-
-        fs::write(&self.cache_file, content).context("Failed to save DNS cache")
+    pub async fn save(&self) -> Result<()> {
+        let mut content = Writer::new();
+        for (domain, (ip, _)) in self.cache.iter().filter(|(_, (_, used))| *used) {
+            content.str(domain);
+            content.ip(*ip);
+        }
+
+        tokio::fs::write(&self.cache_file, content.finish())
+            .await
+            .context("Failed to save DNS cache")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn text_cache_is_rewritten_as_binary() {
+        let path = std::env::temp_dir().join(format!("novaprox-{}-dns-text", std::process::id()));
+        fs::write(&path, "example.com 192.0.2.1\nbroken line\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut cache = DnsCache::new(path);
+        cache.load_cache().unwrap();
+        assert_eq!(cache.get("example.com"), Some("192.0.2.1".parse().unwrap()));
+        cache.save().await.unwrap();
+
+        let data = fs::read(path).unwrap();
+        assert!(matches!(CacheFile::detect(&data), Ok(CacheFile::Binary(_))));
+        let mut cache = DnsCache::new(path);
+        cache.load_cache().unwrap();
+        assert_eq!(cache.get("example.com"), Some("192.0.2.1".parse().unwrap()));
+        fs::remove_file(path).unwrap();
     }
 }
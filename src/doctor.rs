@@ -0,0 +1,242 @@
+use std::{
+    net::{Ipv4Addr, TcpListener},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use reqwest::Client;
+
+use crate::{
+    Args,
+    checker::{parse_checklist, target_url},
+    ping::icmp_available,
+    xray_config::Engine,
+    xray_process,
+};
+
+// Open files needed per chunk port: xray inbound, client socket and the
+// outbound connection of the proxy
+const FILES_PER_PORT: u64 = 3;
+
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    warnings: usize,
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&mut self, check: &str, detail: &str) {
+        self.passed += 1;
+        println!("ok    {check:<12}{detail}");
+    }
+
+    fn warn(&mut self, check: &str, detail: &str) {
+        self.warnings += 1;
+        println!("warn  {check:<12}{detail}");
+    }
+
+    fn fail(&mut self, check: &str, detail: &str) {
+        self.failures += 1;
+        println!("FAIL  {check:<12}{detail}");
+    }
+}
+
+/// Checks the environment a run depends on and prints a line per check
+/// with a fix for every problem found
+///
+/// # Errors
+/// Return error if any check failed
+pub(crate) async fn run_doctor(args: &Args) -> Result<()> {
+    let mut report = Report::default();
+
+    check_xray(&mut report).await;
+    check_icmp(args, &mut report);
+    check_dns(args, &mut report).await;
+    check_outbound(args, &mut report).await;
+    check_ports(args, &mut report);
+    check_open_files(args, &mut report);
+
+    println!(
+        "{} passed, {} warnings, {} failed",
+        report.passed, report.warnings, report.failures
+    );
+    if report.failures > 0 {
+        bail!("{} checks failed", report.failures);
+    }
+    Ok(())
+}
+
+async fn check_xray(report: &mut Report) {
+    let binary = xray_process::engine().binary();
+    match xray_process::probe_xray().await {
+        Ok(features) => {
+            let (major, minor, patch) = features.version;
+            report.ok("core", &format!("{binary} {major}.{minor}.{patch}"));
+            if features.engine == Engine::Xray && features.version < (1, 8, 0) {
+                report.warn(
+                    "core",
+                    "Reality and vision proxies need xray 1.8.0 or newer, update xray",
+                );
+            }
+        }
+        Err(e) => report.fail(
+            "core",
+            &format!("{e}, or run it in a container with --xray-container"),
+        ),
+    }
+}
+
+fn check_icmp(args: &Args, report: &mut Report) {
+    if args.ping_count == 0 {
+        report.ok("icmp", "ping disabled");
+    } else if icmp_available() {
+        report.ok("icmp", "ICMP sockets available");
+    } else {
+        report.fail(
+            "icmp",
+            "Can't open ICMP sockets. Allow them with `sysctl net.ipv4.ping_group_range=\"0 2147483647\"`, \
+             grant CAP_NET_RAW or use --ping-target tcp",
+        );
+    }
+}
+
+async fn check_dns(args: &Args, report: &mut Report) {
+    let Ok(checklist) = parse_checklist(&args.latency_checklist) else {
+        report.fail("dns", "Invalid --latency-checklist");
+        return;
+    };
+    let Some((domain, _)) = checklist.first().and_then(|pool| pool.first()) else {
+        report.warn("dns", "Empty --latency-checklist, nothing to resolve");
+        return;
+    };
+    let host = domain.split('/').next().unwrap_or(domain);
+
+    let start = Instant::now();
+    match tokio::time::timeout(Duration::from_secs(5), async {
+        Ok::<_, std::io::Error>(tokio::net::lookup_host((host, 443)).await?.next())
+    })
+    .await
+    {
+        Ok(Ok(Some(_))) => {
+            report.ok(
+                "dns",
+                &format!("{host} resolved in {}ms", start.elapsed().as_millis()),
+            );
+        }
+        Ok(Ok(None)) => report.fail("dns", &format!("{host} has no addresses")),
+        Ok(Err(e)) => report.fail(
+            "dns",
+            &format!("Failed to resolve {host}: {e}. Check /etc/resolv.conf"),
+        ),
+        Err(_) => report.fail(
+            "dns",
+            &format!(
+                "Resolving {host} timed out. Check /etc/resolv.conf or lower --max-concurrent-dns"
+            ),
+        ),
+    }
+}
+
+// Check targets must be reachable directly, otherwise every proxy fails
+// the same way and the cause is the local network
+async fn check_outbound(args: &Args, report: &mut Report) {
+    let Ok(checklist) = parse_checklist(&args.latency_checklist) else {
+        return;
+    };
+    let Ok(client) = Client::builder()
+        .timeout(args.pipeline.request_timeout())
+        .build()
+    else {
+        report.fail("outbound", "Failed to build HTTP client");
+        return;
+    };
+
+    let mut seen = Vec::new();
+    for (domain, _) in checklist.into_iter().flatten() {
+        if seen.contains(&domain) {
+            continue;
+        }
+        match client.get(target_url(&domain)).send().await {
+            Ok(resp) => report.ok("outbound", &format!("{domain}: {}", resp.status())),
+            Err(e) => report.warn(
+                "outbound",
+                &format!("{domain} unreachable directly: {e}. Proxies may still reach it"),
+            ),
+        }
+        seen.push(domain);
+    }
+}
+
+fn check_ports(args: &Args, report: &mut Report) {
+    let range =
+        args.pipeline.base_start_port..args.pipeline.base_start_port + args.pipeline.chunk_size;
+    let Ok(ports) = range
+        .clone()
+        .map(u16::try_from)
+        .collect::<Result<Vec<_>, _>>()
+    else {
+        report.fail(
+            "ports",
+            &format!(
+                "Port range {}-{} exceeds 65535, lower --base-start-port or --chunk-size",
+                range.start,
+                range.end - 1
+            ),
+        );
+        return;
+    };
+
+    let busy = ports
+        .into_iter()
+        .chain(args.stats_api_port)
+        .filter(|&port| TcpListener::bind((Ipv4Addr::LOCALHOST, port)).is_err())
+        .collect::<Vec<_>>();
+    if busy.is_empty() {
+        report.ok("ports", &format!("{}-{} free", range.start, range.end - 1));
+    } else {
+        report.fail(
+            "ports",
+            &format!(
+                "{} ports in use (first {}), stop the process holding them or change --base-start-port",
+                busy.len(),
+                busy[0]
+            ),
+        );
+    }
+}
+
+fn check_open_files(args: &Args, report: &mut Report) {
+    let Some(limit) = open_files_limit() else {
+        report.warn("ulimit", "Failed to read open files limit");
+        return;
+    };
+    let needed = args.pipeline.chunk_size as u64 * FILES_PER_PORT;
+    if limit < needed {
+        report.fail(
+            "ulimit",
+            &format!(
+                "Open files limit {limit} is below {needed} needed for --chunk-size {}. Raise it with `ulimit -n {needed}` or lower --chunk-size",
+                args.pipeline.chunk_size
+            ),
+        );
+    } else {
+        report.ok("ulimit", &format!("open files limit {limit}"));
+    }
+}
+
+// Soft limit from `Max open files` of /proc/self/limits
+fn open_files_limit() -> Option<u64> {
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    let soft = line
+        .trim_start_matches("Max open files")
+        .split_whitespace()
+        .next()?;
+    if soft == "unlimited" {
+        return Some(u64::MAX);
+    }
+    soft.parse().ok()
+}
@@ -0,0 +1,65 @@
+//! Happy eyeballs (RFC 8305) race of IPv4 and IPv6 addresses of dual-stack
+//! proxy servers, enabled with `--dual-stack`.
+//!
+//! Unlike a client, the race waits for the losing family too, so whether
+//! the server works over IPv6 is known even when IPv4 won.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use tokio::net::TcpStream;
+
+use crate::proxy_config::{DualStack, Family};
+
+static CONNECT_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+// Head start of IPv6 attempt, as RFC 8305 recommends
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Makes every later resolved domain race its address families, with
+/// `timeout` for connect attempts
+pub fn use_dual_stack(timeout: Duration) {
+    CONNECT_TIMEOUT.set(timeout).ok();
+}
+
+/// Address of `domain` that accepted TCP connection first. `None` when
+/// racing is off, domain doesn't have both A and AAAA records or neither
+/// family connected
+pub async fn race(domain: &str, port: u16) -> Option<(IpAddr, DualStack)> {
+    let timeout = *CONNECT_TIMEOUT.get()?;
+    let addrs = tokio::net::lookup_host((domain, port))
+        .await
+        .ok()?
+        .collect::<Vec<_>>();
+    let v6 = addrs.iter().copied().find(SocketAddr::is_ipv6)?;
+    let v4 = addrs.iter().copied().find(SocketAddr::is_ipv4)?;
+
+    let attempt = |addr: SocketAddr, delay: Duration| async move {
+        tokio::time::sleep(delay).await;
+        tokio::time::timeout(timeout, TcpStream::connect(addr))
+            .await
+            .is_ok_and(|connected| connected.is_ok())
+            .then(Instant::now)
+    };
+    let (v6_connected, v4_connected) = tokio::join!(
+        attempt(v6, Duration::ZERO),
+        attempt(v4, CONNECTION_ATTEMPT_DELAY)
+    );
+
+    let (addr, winner) = match (v6_connected, v4_connected) {
+        (None, None) => return None,
+        (Some(v6_at), Some(v4_at)) if v4_at < v6_at => (v4, Family::V4),
+        (Some(_), _) => (v6, Family::V6),
+        (None, Some(_)) => (v4, Family::V4),
+    };
+    Some((
+        addr.ip(),
+        DualStack {
+            winner,
+            ipv6: v6_connected.is_some(),
+        },
+    ))
+}
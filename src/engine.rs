@@ -0,0 +1,221 @@
+//! Proxy cores chunks of proxies are checked through.
+//!
+//! Chunk orchestration only talks to [`TestEngine`], so cores other than
+//! xray (sing-box, native clients) plug in by implementing it.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use regex::Regex;
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncReadExt as _},
+    process::{Child, ChildStdout},
+};
+
+use crate::{
+    checker::{CheckOptions, LocalInbound},
+    proxy_config::ProxyConfig,
+    start_xray_with_config, trace,
+    xray_config::{INBOUND_USER, generate_xray_config, mux_user},
+    xray_process::stop_xray,
+};
+
+/// Core running a chunk of proxies behind local inbounds
+pub trait TestEngine: Sync {
+    type Process: Send;
+
+    /// Feature of proxy the core doesn't support, such proxies aren't
+    /// put into chunks
+    fn missing_feature(&self, proxy: &ProxyConfig) -> Option<&'static str>;
+
+    /// Config running every proxy of `chunk` behind its endpoint
+    ///
+    /// # Errors
+    /// Return error if config can't be generated
+    fn generate_config(&self, chunk: &[ProxyConfig]) -> Result<String>;
+
+    /// Starts core with `config`
+    fn start(&self, config: &str) -> impl Future<Output = Result<Self::Process>> + Send;
+
+    /// Waits until core serves endpoints, returns its output when it
+    /// exited instead
+    fn ready(
+        &self,
+        process: &mut Self::Process,
+    ) -> impl Future<Output = Result<Result<(), String>>> + Send;
+
+    /// Local inbound of `index` proxy of chunk
+    fn endpoint(&self, index: usize) -> LocalInbound;
+
+    /// Passes log lines of running core to `on_line`
+    fn collect_logs(&self, process: &mut Self::Process, on_line: impl FnMut(&str) + Send + 'static);
+
+    /// Puts parts of `chunk` worth retrying back into `pending` after core
+    /// rejected it with `output`
+    fn split_rejected(
+        &self,
+        pending: &mut Vec<Vec<ProxyConfig>>,
+        chunk: Vec<ProxyConfig>,
+        output: &str,
+    );
+
+    fn stop(&self, process: &mut Self::Process) -> impl Future<Output = ()> + Send;
+}
+
+/// Xray or v2fly core, as selected with `--engine`
+pub struct XrayEngine<'a> {
+    pub base_port: usize,
+    pub options: &'a CheckOptions,
+}
+
+impl TestEngine for XrayEngine<'_> {
+    type Process = Child;
+
+    fn missing_feature(&self, proxy: &ProxyConfig) -> Option<&'static str> {
+        self.options.xray_features.missing_feature(proxy)
+    }
+
+    fn generate_config(&self, chunk: &[ProxyConfig]) -> Result<String> {
+        let config = generate_xray_config(
+            chunk,
+            self.base_port,
+            self.options.stats_api_port,
+            self.options.mux_inbound,
+            self.options.xray_features.engine,
+            &self.options.inbound_password,
+            self.options.sandbox.as_ref(),
+        )?;
+        trace_config(config, chunk)
+    }
+
+    async fn start(&self, config: &str) -> Result<Child> {
+        start_xray_with_config(config).await
+    }
+
+    async fn ready(&self, process: &mut Child) -> Result<Result<(), String>> {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let Some(exit) = process.try_wait()? else {
+            return Ok(Ok(()));
+        };
+        log::warn!("Xray exited: {exit}");
+        let mut out = String::new();
+        if let Some(stdout) = &mut process.stdout {
+            stdout.read_to_string(&mut out).await?;
+            log::debug!("Stdout: {out}");
+        }
+        Ok(Err(format!("exited: {exit}, {out}")))
+    }
+
+    fn endpoint(&self, index: usize) -> LocalInbound {
+        let password = self.options.inbound_password.clone();
+        if self.options.mux_inbound {
+            LocalInbound {
+                port: self.base_port,
+                user: mux_user(index),
+                password,
+            }
+        } else {
+            LocalInbound {
+                port: self.base_port + index,
+                user: INBOUND_USER.to_owned(),
+                password,
+            }
+        }
+    }
+
+    fn collect_logs(&self, process: &mut Child, on_line: impl FnMut(&str) + Send + 'static) {
+        if let Some(stdout) = process.stdout.take() {
+            tokio::spawn(read_log(stdout, on_line));
+        }
+    }
+
+    fn split_rejected(
+        &self,
+        pending: &mut Vec<Vec<ProxyConfig>>,
+        chunk: Vec<ProxyConfig>,
+        output: &str,
+    ) {
+        split_rejected_chunk(pending, chunk, output);
+    }
+
+    async fn stop(&self, process: &mut Child) {
+        stop_xray(process).await;
+    }
+}
+
+// Logs outbounds of traced proxies of chunk and raises xray log level, so
+// their connection errors reach the trace
+fn trace_config(config: String, chunk: &[ProxyConfig]) -> Result<String> {
+    let traced = chunk
+        .iter()
+        .enumerate()
+        .filter(|(_, proxy)| trace::traced(proxy))
+        .collect::<Vec<_>>();
+    if traced.is_empty() {
+        return Ok(config);
+    }
+
+    let mut config = serde_json::from_str::<serde_json::Value>(&config)?;
+    for (i, proxy) in &traced {
+        let suffix = format!("-out-{i}");
+        let outbound = config["outbounds"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|outbound| {
+                outbound["tag"]
+                    .as_str()
+                    .is_some_and(|tag| tag.ends_with(&suffix))
+            });
+        match outbound {
+            Some(outbound) => {
+                trace::record(proxy, "config", serde_json::to_string_pretty(outbound)?);
+            }
+            None => trace::record(proxy, "config", "no outbound generated"),
+        }
+    }
+    config["log"]["loglevel"] = "info".into();
+    Ok(serde_json::to_string(&config)?)
+}
+
+// Xray blocks once its stdout pipe is full, so the log is read to the end
+async fn read_log(stdout: ChildStdout, mut on_line: impl FnMut(&str)) {
+    let mut lines = tokio::io::BufReader::new(stdout).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        on_line(&line);
+    }
+}
+
+// Xray refused chunk config: drops the outbound its error names and retries
+// the rest, or bisects chunk when config error names no outbound. Other
+// errors (e.g. busy ports) drop the chunk
+fn split_rejected_chunk(
+    pending: &mut Vec<Vec<ProxyConfig>>,
+    mut chunk: Vec<ProxyConfig>,
+    output: &str,
+) {
+    let bad_outbound = Regex::new(r"[a-z0-9]+-out-(\d+)")
+        .ok()
+        .and_then(|re| re.captures(output)?[1].parse::<usize>().ok())
+        .filter(|index| *index < chunk.len());
+
+    if let Some(index) = bad_outbound {
+        log::warn!(
+            "Xray rejected {}, retrying chunk without it",
+            chunk.remove(index)
+        );
+        if !chunk.is_empty() {
+            pending.push(chunk);
+        }
+    } else if !output.contains("infra/conf") {
+        log::warn!("Xray output: {output}");
+    } else if chunk.len() > 1 {
+        log::warn!("Xray rejected chunk of {}, bisecting", chunk.len());
+        let second = chunk.split_off(chunk.len() / 2);
+        pending.push(second);
+        pending.push(chunk);
+    } else if let Some(proxy) = chunk.first() {
+        log::warn!("Xray rejected {proxy}");
+    }
+}
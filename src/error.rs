@@ -0,0 +1,108 @@
+//! Errors of the library API, typed so consumers can tell a missing core
+//! from dead proxies without matching on messages.
+
+use thiserror::Error;
+
+/// Source list or profile export couldn't be read
+#[derive(Debug, Error)]
+pub enum SourceFetchError {
+    #[error("Invalid profile export JSON")]
+    InvalidJson(#[source] serde_json::Error),
+    #[error("Unknown profile export format")]
+    UnknownFormat,
+}
+
+/// Link or its param is malformed
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("Reality link without pbk")]
+    MissingPbk,
+    #[error("Reality pbk isn't base64")]
+    PbkNotBase64(#[source] base64::DecodeError),
+    #[error("Reality pbk isn't X25519 key")]
+    InvalidPbk,
+    #[error("Reality sid isn't hex of up to 16 chars")]
+    InvalidSid,
+    #[error("Invalid {param}={value} of {transport} transport")]
+    InvalidTransport {
+        transport: &'static str,
+        param: &'static str,
+        value: String,
+    },
+}
+
+/// Proxy server address couldn't be resolved
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    #[error("URL has no host")]
+    NoHost,
+    #[error("Port required for DNS lookup of {0}")]
+    NoPort(String),
+    #[error("DNS lookup of {0} failed")]
+    Lookup(String, #[source] std::io::Error),
+    #[error("No addresses found for {0}")]
+    NoAddresses(String),
+}
+
+/// Proxy core is missing, unsuitable or given config it can't run
+#[derive(Debug, Error)]
+pub enum EngineError {
+    #[error("{0} not found, install it or put it in PATH")]
+    NotFound(&'static str, #[source] std::io::Error),
+    #[error("Unknown core version output: {0}")]
+    UnknownVersion(String),
+    #[error("Installed core is too old for {0}, update it")]
+    TooOld(&'static str),
+    #[error("Unsupported protocol: {0}")]
+    UnsupportedProtocol(String),
+    #[error("Invalid client template")]
+    InvalidTemplate(#[source] serde_json::Error),
+    #[error("Client template has no \"{0}\" array item")]
+    MissingMarker(&'static str),
+    #[error("Failed to serialize core config")]
+    Serialize(#[source] serde_json::Error),
+}
+
+/// Check couldn't run or found nothing
+#[derive(Debug, Error)]
+pub enum CheckError {
+    #[error("None of {tested} proxies works")]
+    NoneWorking { tested: usize },
+    #[error("Run cancelled")]
+    Cancelled,
+    #[error("Failed to run core api")]
+    Api(#[source] std::io::Error),
+    #[error("Invalid core stats output")]
+    InvalidStats(#[source] serde_json::Error),
+    #[error(transparent)]
+    Engine(#[from] EngineError),
+}
+
+/// Run configuration has values the run can't work with
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Invalid pipeline config JSON")]
+    InvalidJson(#[source] serde_json::Error),
+    #[error("Invalid CDN range {0}")]
+    InvalidRange(String),
+    #[error("{} must be above 0", flag(.0))]
+    Zero(&'static str),
+    #[error(
+        "{} ports from {} don't fit below 65536, lower {} or {}",
+        .chunk_size, .base_start_port, flag("chunk_size"), flag("base_start_port")
+    )]
+    PortsExhausted {
+        base_start_port: usize,
+        chunk_size: usize,
+    },
+    #[error("{} can't be above {}", flag(.option), flag(.limit))]
+    Exceeds {
+        option: &'static str,
+        limit: &'static str,
+    },
+}
+
+// Option as it's given on command line
+fn flag(option: &str) -> String {
+    format!("--{}", option.replace('_', "-"))
+}
@@ -0,0 +1,47 @@
+//! Progress callbacks of the check pipeline.
+//!
+//! Embedders implement [`EventHandler`] to drive their own progress UI or
+//! metrics, the novaprox binary only logs them.
+
+use std::{fmt, time::Duration};
+
+use crate::proxy_config::ProxyConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Fetch,
+    Resolve,
+    Ping,
+    Check,
+    Filter,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fetch => "fetch",
+            Self::Resolve => "resolve",
+            Self::Ping => "ping",
+            Self::Check => "check",
+            Self::Filter => "filter",
+        })
+    }
+}
+
+/// Receives pipeline progress, every method does nothing by default
+pub trait EventHandler: Send + Sync {
+    /// Stage started with `total` proxies (sources for fetch stage)
+    fn on_stage_start(&self, _stage: Stage, _total: usize) {}
+
+    /// Proxy finished its check
+    fn on_proxy_result(&self, _proxy: &ProxyConfig, _working: bool) {}
+
+    /// Chunk of proxies sharing one Xray instance finished, `done` of
+    /// `total` known chunks with `working` proxies found in it
+    fn on_chunk_done(&self, _done: usize, _total: usize, _working: usize, _elapsed: Duration) {}
+}
+
+/// Handler ignoring every event
+pub struct NoEvents;
+
+impl EventHandler for NoEvents {}
@@ -0,0 +1,140 @@
+//! C ABI over link parsing and conversion, enabled with `ffi` feature.
+//!
+//! Every returned string is owned by the caller and must be released with
+//! [`novaprox_string_free`]. Null is returned on any error.
+
+use std::{
+    ffi::{CStr, CString, c_char},
+    net::{IpAddr, ToSocketAddrs as _},
+};
+
+use url::Url;
+
+use crate::{
+    parse_url::parse_proxy_url,
+    proxy_config::{ProxyConfig, default_port},
+    xray_config::{Engine, create_outbound},
+};
+
+/// # Safety
+/// `ptr` must be null or point to valid nul-terminated string
+unsafe fn read_str<'a>(ptr: *const c_char) -> Option<&'a str> {
+    if ptr.is_null() {
+        return None;
+    }
+    // SAFETY: checked for null above, validity guaranteed by caller
+    unsafe { CStr::from_ptr(ptr) }.to_str().ok()
+}
+
+fn into_raw(value: Option<String>) -> *mut c_char {
+    value
+        .and_then(|s| CString::new(s).ok())
+        .map_or(std::ptr::null_mut(), CString::into_raw)
+}
+
+// Only domain hosts go to the system resolver, literal addresses are taken
+// as they are without blocking
+fn to_proxy_config(link: &str) -> Option<ProxyConfig> {
+    let url = Url::parse(link).ok()?;
+    let host = url.host_str()?.trim_matches(['[', ']']);
+    let address = if let Ok(address) = host.parse::<IpAddr>() {
+        address
+    } else {
+        let port = url
+            .port()
+            .or_else(|| default_port(url.scheme()))
+            .unwrap_or_default();
+        (host, port).to_socket_addrs().ok()?.next()?.ip()
+    };
+    Some(ProxyConfig::from_url(url, address))
+}
+
+/// Normalizes proxy link of `scheme` (e.g. `vless`), removing junk params.
+///
+/// # Safety
+/// Arguments must be null or valid nul-terminated strings
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn novaprox_parse_url(
+    link: *const c_char,
+    scheme: *const c_char,
+) -> *mut c_char {
+    // SAFETY: guaranteed by caller
+    let (link, scheme) = unsafe { (read_str(link), read_str(scheme)) };
+    into_raw(
+        link.zip(scheme)
+            .and_then(|(link, scheme)| parse_proxy_url(link, scheme, &[], &[]))
+            .map(String::from),
+    )
+}
+
+/// Converts proxy link into Xray outbound json. Domain hosts are resolved,
+/// so the call blocks for them, links with IP addresses never block.
+///
+/// # Safety
+/// `link` must be null or valid nul-terminated string
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn novaprox_to_xray_outbound(link: *const c_char) -> *mut c_char {
+    // SAFETY: guaranteed by caller
+    let link = unsafe { read_str(link) };
+    into_raw(
+        link.and_then(to_proxy_config)
+            .and_then(|proxy| create_outbound(&proxy, 0, Engine::Xray).ok().flatten())
+            .map(|outbound| outbound.to_string()),
+    )
+}
+
+/// Releases string returned by this library.
+///
+/// # Safety
+/// `ptr` must be null or returned by this library and not freed before
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn novaprox_string_free(ptr: *mut c_char) {
+    if !ptr.is_null() {
+        // SAFETY: pointer was created by `CString::into_raw` in this library
+        drop(unsafe { CString::from_raw(ptr) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn outbound(link: &str) -> Option<Value> {
+        let link = CString::new(link).unwrap();
+        // SAFETY: valid string, result is freed below
+        let raw = unsafe { novaprox_to_xray_outbound(link.as_ptr()) };
+        if raw.is_null() {
+            return None;
+        }
+        // SAFETY: non-null result of this library
+        let json = unsafe { CStr::from_ptr(raw) }.to_str().unwrap().to_owned();
+        // SAFETY: returned above and not freed before
+        unsafe { novaprox_string_free(raw) };
+        Some(serde_json::from_str(&json).unwrap())
+    }
+
+    #[test]
+    fn literal_addresses_skip_resolver() {
+        let proxy = to_proxy_config("vless://id@[2001:db8::1]:8443").unwrap();
+        assert_eq!(proxy.address, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(proxy.port, 8443);
+    }
+
+    #[test]
+    fn missing_port_is_default_of_scheme() {
+        let proxy = to_proxy_config("http://192.0.2.1").unwrap();
+        assert_eq!(Some(proxy.port), default_port("http"));
+        let proxy = to_proxy_config("trojan://secret@192.0.2.1").unwrap();
+        assert_eq!(Some(proxy.port), default_port("trojan"));
+    }
+
+    #[test]
+    fn converts_link_to_outbound() {
+        let outbound = outbound("trojan://secret@192.0.2.1:443?sni=a.example").unwrap();
+        assert_eq!(outbound["protocol"], "trojan");
+        assert_eq!(outbound["settings"]["address"], "192.0.2.1");
+        assert!(self::outbound("not a link").is_none());
+    }
+}
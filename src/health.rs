@@ -0,0 +1,265 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use ahash::HashMap;
+use anyhow::{Context as _, Result};
+use base64::Engine as _;
+use ring::digest::{SHA256, digest};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::abuse::now_secs;
+
+/// State of a daemon mode reported on `/healthz`
+#[derive(Default)]
+pub struct Health {
+    // Unix time of last successful check round
+    last_success: Option<u64>,
+    working: usize,
+    // Error of last round, cleared by next successful one
+    error: Option<String>,
+}
+
+impl Health {
+    pub fn success(&mut self, working: usize) {
+        self.last_success = Some(now_secs());
+        self.working = working;
+        self.error = None;
+    }
+
+    pub fn failure(&mut self, error: &anyhow::Error) {
+        self.error = Some(format!("{error:#}"));
+    }
+
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        json!({
+            "status": if self.error.is_some() { "error" } else { "ok" },
+            "last_success": self.last_success,
+            "working": self.working,
+            "error": self.error,
+        })
+    }
+}
+
+/// Result list served as subscription on `/sub`
+pub struct Subscription {
+    pub list_file: String,
+    // Value of `subscription-userinfo` header, e.g. `upload=0; download=0;
+    // total=0; expire=0`
+    pub userinfo: String,
+    // Hours clients should wait between updates
+    pub update_interval: u32,
+    // Recent revisions of the list, oldest first, as revision id and lines
+    // by link without name
+    pub history: Mutex<Vec<(String, HashMap<String, String>)>>,
+}
+
+// Revisions a client can be behind and still get a delta
+const DELTA_HISTORY: usize = 16;
+
+impl Subscription {
+    // List lines without comments
+    async fn lines(&self) -> Result<Vec<String>> {
+        let list = tokio::fs::read_to_string(&self.list_file).await?;
+        Ok(list
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect())
+    }
+
+    // Base64 list without comment lines, as subscription clients expect
+    async fn body(&self) -> Result<String> {
+        Ok(base64::engine::general_purpose::STANDARD.encode(self.lines().await?.join("\n")))
+    }
+
+    // Lines added and links removed since revision `since`, or the whole
+    // list when `since` is unknown. Names change with every measurement, so
+    // proxies are compared by link.
+    async fn delta(&self, since: Option<&str>) -> Result<Value> {
+        let lines = self
+            .lines()
+            .await?
+            .into_iter()
+            .map(|line| {
+                let link = line.split_once('#').map_or(line.as_str(), |(link, _)| link);
+                (link.to_owned(), line.clone())
+            })
+            .collect::<HashMap<_, _>>();
+        let mut links = lines.keys().map(String::as_str).collect::<Vec<_>>();
+        links.sort_unstable();
+        let revision = digest(&SHA256, links.join("\n").as_bytes())
+            .as_ref()
+            .iter()
+            .take(8)
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        let old = self.record_revision(&revision, &lines, since)?;
+        let (added, removed) = old.as_ref().map_or_else(
+            || (lines.values().cloned().collect::<Vec<_>>(), Vec::new()),
+            |old| {
+                (
+                    lines
+                        .iter()
+                        .filter(|(link, _)| !old.contains_key(*link))
+                        .map(|(_, line)| line.clone())
+                        .collect(),
+                    old.keys()
+                        .filter(|link| !lines.contains_key(*link))
+                        .cloned()
+                        .collect(),
+                )
+            },
+        );
+        Ok(json!({
+            "revision": revision,
+            "full": old.is_none(),
+            "added": added,
+            "removed": removed,
+        }))
+    }
+
+    // Adds revision to history, returns lines of revision `since` if known
+    fn record_revision(
+        &self,
+        revision: &str,
+        lines: &HashMap<String, String>,
+        since: Option<&str>,
+    ) -> Result<Option<HashMap<String, String>>> {
+        let mut history = self
+            .history
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Subscription history poisoned: {e}"))?;
+        if history.last().is_none_or(|(last, _)| last != revision) {
+            history.push((revision.to_owned(), lines.clone()));
+            if history.len() > DELTA_HISTORY {
+                history.remove(0);
+            }
+        }
+        let old = since.and_then(|since| {
+            history
+                .iter()
+                .find(|(revision, _)| revision == since)
+                .map(|(_, old)| old.clone())
+        });
+        drop(history);
+        Ok(old)
+    }
+
+    fn headers(&self) -> String {
+        format!(
+            "subscription-userinfo: {}\r\nprofile-update-interval: {}\r\n",
+            self.userinfo, self.update_interval
+        )
+    }
+}
+
+/// Serves health state as JSON on `GET /healthz` with status 503 while last
+/// round failed, for container orchestrators and uptime monitors, and
+/// results on `GET /sub` when `subscription` is set.
+///
+/// `GET /delta?since=rev` returns only proxies added and removed since
+/// revision `rev` of an earlier response, for clients polling often.
+///
+/// # Errors
+/// Return error if failed to bind listener
+pub async fn serve_health(
+    listen: SocketAddr,
+    health: Arc<Mutex<Health>>,
+    subscription: Option<Arc<Subscription>>,
+) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .context("Failed to bind health listener")?;
+    log::info!("Health endpoint on http://{listen}/healthz");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (health, subscription) = (Arc::clone(&health), subscription.clone());
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &health, subscription.as_deref()).await {
+                log::debug!("Health request failed: {e}");
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    health: &Mutex<Health>,
+    subscription: Option<&Subscription>,
+) -> Result<()> {
+    let mut request = [0u8; 1024];
+    let len = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let (status, content_type, headers, body) = match (path, subscription) {
+        ("/healthz", _) => {
+            let health = health
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Health state poisoned: {e}"))?;
+            let status = if health.error.is_some() {
+                "503 Service Unavailable"
+            } else {
+                "200 OK"
+            };
+            (
+                status,
+                "application/json",
+                String::new(),
+                health.to_json().to_string(),
+            )
+        }
+        ("/sub", Some(subscription)) => match subscription.body().await {
+            Ok(body) => ("200 OK", "text/plain", subscription.headers(), body),
+            Err(e) => {
+                log::warn!("Failed to read subscription list: {e}");
+                (
+                    "503 Service Unavailable",
+                    "text/plain",
+                    String::new(),
+                    String::new(),
+                )
+            }
+        },
+        ("/delta", Some(subscription)) => {
+            let since = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("since="));
+            match subscription.delta(since).await {
+                Ok(delta) => (
+                    "200 OK",
+                    "application/json",
+                    String::new(),
+                    delta.to_string(),
+                ),
+                Err(e) => {
+                    log::warn!("Failed to read subscription list: {e}");
+                    (
+                        "503 Service Unavailable",
+                        "text/plain",
+                        String::new(),
+                        String::new(),
+                    )
+                }
+            }
+        }
+        _ => ("404 Not Found", "text/plain", String::new(), String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use clap::Subcommand;
+use serde_json::{Value, json};
+
+use crate::{Args, abuse::now_secs, monitor::UptimeStats, seen::SeenDb};
+
+#[derive(clap::Args, Debug)]
+pub struct HistoryArgs {
+    #[command(subcommand)]
+    command: HistoryCommand,
+
+    // Uptime stats of monitor mode, moved along with verdicts
+    #[arg(long, global = true, default_value = "uptime.txt")]
+    uptime_file: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum HistoryCommand {
+    // Write verdicts of `--seen-db-file` and monitor uptime as JSON
+    Export {
+        // Only verdicts of tests done within this time
+        #[arg(long, value_parser = humantime::parse_duration)]
+        since: Option<Duration>,
+
+        #[arg(short, long, default_value = "history.json")]
+        output: String,
+    },
+    // Merge exported verdicts into `--seen-db-file`, newer verdict of a
+    // proxy wins, so exports of several runners can be combined. Uptime
+    // counts are added to those of `--uptime-file`
+    Import {
+        #[arg(required = true)]
+        inputs: Vec<String>,
+    },
+}
+
+impl HistoryArgs {
+    // Files kept between runs, moved into `--artifacts-dir`
+    pub(crate) fn artifact_paths(&mut self) -> Vec<&mut String> {
+        let mut paths = vec![&mut self.uptime_file];
+        if let HistoryCommand::Export { output, .. } = &mut self.command {
+            paths.push(output);
+        }
+        paths
+    }
+}
+
+/// Moves verdicts of tested proxies and monitor uptime between their
+/// files and JSON
+///
+/// # Errors
+/// Return error if database or JSON files can't be read or written
+pub(crate) async fn run_history(args: &Args, history: &HistoryArgs) -> Result<()> {
+    let mut seen = SeenDb::new(&args.seen_db_file);
+    seen.load()?;
+    let mut uptime = UptimeStats::new(&history.uptime_file);
+    uptime.load()?;
+
+    match &history.command {
+        HistoryCommand::Export { since, output } => {
+            let since = since.map_or(0, |since| now_secs().saturating_sub(since.as_secs()));
+            let verdicts = seen
+                .tested_since(since)
+                .map(|(proxy, working, tested_at)| {
+                    json!({
+                        "proxy": proxy,
+                        "working": working,
+                        "tested_at": tested_at,
+                    })
+                })
+                .collect::<Vec<_>>();
+            let uptime = uptime
+                .counts()
+                .map(|(proxy, checks, successes)| {
+                    json!({
+                        "proxy": proxy,
+                        "checks": checks,
+                        "successes": successes,
+                    })
+                })
+                .collect::<Vec<_>>();
+            log::info!(
+                "Exported {} verdicts and uptime of {} proxies to {output}",
+                verdicts.len(),
+                uptime.len()
+            );
+            let export = json!({ "verdicts": verdicts, "uptime": uptime });
+            tokio::fs::write(output, format!("{export:#}\n"))
+                .await
+                .context("Failed to write history export")
+        }
+        HistoryCommand::Import { inputs } => {
+            for input in inputs {
+                let export = serde_json::from_str::<Value>(
+                    &tokio::fs::read_to_string(input)
+                        .await
+                        .with_context(|| format!("Failed to read {input}"))?,
+                )
+                .with_context(|| format!("{input} isn't a history export"))?;
+                import_verdicts(&mut seen, &export, input)?;
+                import_uptime(&mut uptime, &export, input);
+            }
+            seen.save().await?;
+            uptime.save().await
+        }
+    }
+}
+
+// Exports before uptime was included are a bare array of verdicts
+fn import_verdicts(seen: &mut SeenDb, export: &Value, input: &str) -> Result<()> {
+    let verdicts = export
+        .as_array()
+        .or_else(|| export["verdicts"].as_array())
+        .with_context(|| format!("{input} isn't a history export"))?;
+
+    let mut taken = 0;
+    for verdict in verdicts {
+        let (Some(proxy), Some(working), Some(tested_at)) = (
+            verdict["proxy"].as_str(),
+            verdict["working"].as_bool(),
+            verdict["tested_at"].as_u64(),
+        ) else {
+            log::warn!("Skipping malformed verdict in {input}: {verdict}");
+            continue;
+        };
+        taken += usize::from(seen.merge(proxy.to_owned(), working, tested_at));
+    }
+    log::info!(
+        "Imported {taken}/{} verdicts from {input}, others were older",
+        verdicts.len()
+    );
+    Ok(())
+}
+
+fn import_uptime(uptime: &mut UptimeStats, export: &Value, input: &str) {
+    let Some(counts) = export["uptime"].as_array() else {
+        return;
+    };
+    let mut taken = 0;
+    for count in counts {
+        let (Some(proxy), Some(checks), Some(successes)) = (
+            count["proxy"].as_str(),
+            count["checks"].as_u64().and_then(|n| u32::try_from(n).ok()),
+            count["successes"]
+                .as_u64()
+                .and_then(|n| u32::try_from(n).ok()),
+        ) else {
+            log::warn!("Skipping malformed uptime in {input}: {count}");
+            continue;
+        };
+        uptime.merge(proxy.to_owned(), checks, successes);
+        taken += 1;
+    }
+    log::info!("Added uptime of {taken} proxies from {input}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imported_uptime_adds_to_known_counts() {
+        let mut uptime = UptimeStats::new("uptime.txt");
+        uptime.record("a", true);
+        uptime.record("a", false);
+        let export = json!({
+            "verdicts": [],
+            "uptime": [
+                { "proxy": "a", "checks": 2, "successes": 2 },
+                { "proxy": "b", "checks": 4, "successes": 1 },
+                { "proxy": "c", "checks": "many" },
+            ],
+        });
+        import_uptime(&mut uptime, &export, "export.json");
+
+        assert_eq!(uptime.uptime("a"), Some(75));
+        assert_eq!(uptime.uptime("b"), Some(25));
+        assert_eq!(uptime.uptime("c"), None);
+    }
+
+    #[test]
+    fn old_exports_without_uptime_still_import() {
+        let mut seen = SeenDb::new("seen.db");
+        let export = json!([{ "proxy": "a", "working": true, "tested_at": 1 }]);
+        import_verdicts(&mut seen, &export, "export.json").unwrap();
+        assert_eq!(seen.tested_since(0).count(), 1);
+
+        let mut uptime = UptimeStats::new("uptime.txt");
+        import_uptime(&mut uptime, &export, "export.json");
+        assert_eq!(uptime.counts().count(), 0);
+    }
+}
@@ -0,0 +1,221 @@
+//! Conversion of client profile exports to share links.
+//!
+//! Supports v2rayN `guiNConfig.json` (`vmess` array of profiles of every
+//! protocol) and NekoBox/NekoRay profiles (`{"type": ..., "bean": {...}}`),
+//! alone, in array or under `profiles`.
+
+use base64::Engine as _;
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use serde_json::{Value, json};
+
+use crate::{error::SourceFetchError, parse_url::url_host};
+
+/// Share links of every supported profile in export, unsupported
+/// protocols are skipped
+///
+/// # Errors
+/// Return error if export isn't JSON of known format
+pub fn profile_links(export: &str) -> Result<Vec<String>, SourceFetchError> {
+    let export: Value = serde_json::from_str(export).map_err(SourceFetchError::InvalidJson)?;
+
+    if let Some(profiles) = export["vmess"].as_array() {
+        return Ok(profiles.iter().filter_map(v2rayn_link).collect());
+    }
+
+    let profiles = match &export {
+        Value::Array(profiles) => profiles.as_slice(),
+        Value::Object(_) if export["bean"].is_object() => std::slice::from_ref(&export),
+        _ => match export["profiles"].as_array() {
+            Some(profiles) => profiles.as_slice(),
+            None => return Err(SourceFetchError::UnknownFormat),
+        },
+    };
+    Ok(profiles.iter().filter_map(nekobox_link).collect())
+}
+
+// Profile fields of every export format, empty when not set
+struct Profile<'a> {
+    // Link scheme: vmess, ss, vless or trojan
+    scheme: &'a str,
+    name: &'a str,
+    address: &'a str,
+    port: u64,
+    // UUID for vmess and vless, password for trojan and shadowsocks
+    id: &'a str,
+    alter_id: u64,
+    // Shadowsocks method or vmess cipher
+    method: &'a str,
+    flow: &'a str,
+    network: &'a str,
+    security: &'a str,
+    header_type: &'a str,
+    host: &'a str,
+    path: &'a str,
+    sni: &'a str,
+    alpn: &'a str,
+    fingerprint: &'a str,
+    public_key: &'a str,
+    short_id: &'a str,
+    spider_x: &'a str,
+    insecure: bool,
+}
+
+impl Profile<'_> {
+    fn link(&self) -> String {
+        if self.scheme == "vmess" {
+            let config = json!({
+                "v": "2",
+                "ps": self.name,
+                "add": self.address,
+                "port": self.port,
+                "id": self.id,
+                "aid": self.alter_id,
+                "scy": self.method,
+                "net": self.network,
+                "type": self.header_type,
+                "host": self.host,
+                "path": self.path,
+                "tls": self.security,
+                "sni": self.sni,
+                "alpn": self.alpn,
+                "fp": self.fingerprint,
+            });
+            return format!(
+                "vmess://{}",
+                base64::engine::general_purpose::STANDARD.encode(config.to_string())
+            );
+        }
+
+        let user = if self.scheme == "ss" {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD
+                .encode(format!("{}:{}", self.method, self.id))
+        } else {
+            utf8_percent_encode(self.id, NON_ALPHANUMERIC).to_string()
+        };
+        let mut link = format!(
+            "{}://{user}@{}:{}",
+            self.scheme,
+            url_host(self.address),
+            self.port
+        );
+
+        let query = [
+            ("type", self.network),
+            ("security", self.security),
+            (
+                "encryption",
+                if self.scheme == "vless" { "none" } else { "" },
+            ),
+            ("flow", self.flow),
+            ("headerType", self.header_type),
+            ("host", self.host),
+            ("path", self.path),
+            ("sni", self.sni),
+            ("alpn", self.alpn),
+            ("fp", self.fingerprint),
+            ("pbk", self.public_key),
+            ("sid", self.short_id),
+            ("spx", self.spider_x),
+            ("allowInsecure", if self.insecure { "1" } else { "" }),
+        ]
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| format!("{key}={}", utf8_percent_encode(value, NON_ALPHANUMERIC)))
+        .collect::<Vec<_>>();
+        if !query.is_empty() {
+            link += "?";
+            link += &query.join("&");
+        }
+        if !self.name.is_empty() {
+            link += "#";
+            link += &utf8_percent_encode(self.name, NON_ALPHANUMERIC).to_string();
+        }
+        link
+    }
+}
+
+fn str_field<'a>(value: &'a Value, key: &str) -> &'a str {
+    value[key].as_str().unwrap_or_default()
+}
+
+// Numbers are strings in some exports
+fn num_field(value: &Value, key: &str) -> Option<u64> {
+    value[key]
+        .as_u64()
+        .or_else(|| value[key].as_str()?.parse().ok())
+}
+
+fn v2rayn_link(profile: &Value) -> Option<String> {
+    let field = |key| str_field(profile, key);
+    let scheme = match profile["configType"].as_u64()? {
+        1 => "vmess",
+        3 => "ss",
+        5 => "vless",
+        6 => "trojan",
+        _ => return None,
+    };
+
+    Some(
+        Profile {
+            scheme,
+            name: field("remarks"),
+            address: field("address"),
+            port: num_field(profile, "port")?,
+            id: field("id"),
+            alter_id: num_field(profile, "alterId").unwrap_or_default(),
+            method: field("security"),
+            flow: field("flow"),
+            network: field("network"),
+            security: field("streamSecurity"),
+            header_type: field("headerType"),
+            host: field("requestHost"),
+            path: field("path"),
+            sni: field("sni"),
+            alpn: field("alpn"),
+            fingerprint: field("fingerprint"),
+            public_key: field("publicKey"),
+            short_id: field("shortId"),
+            spider_x: field("spiderX"),
+            insecure: field("allowInsecure") == "true",
+        }
+        .link(),
+    )
+}
+
+fn nekobox_link(profile: &Value) -> Option<String> {
+    let (bean, stream) = (&profile["bean"], &profile["bean"]["stream"]);
+    let field = |key| str_field(bean, key);
+    let stream_field = |key| str_field(stream, key);
+    let (scheme, id, method) = match str_field(profile, "type") {
+        "vmess" => ("vmess", field("id"), field("sec")),
+        "shadowsocks" => ("ss", field("pass"), field("method")),
+        scheme @ ("vless" | "trojan") => (scheme, field("pass"), ""),
+        _ => return None,
+    };
+
+    Some(
+        Profile {
+            scheme,
+            name: field("name"),
+            address: field("addr"),
+            port: num_field(bean, "port")?,
+            id,
+            alter_id: num_field(bean, "aid").unwrap_or_default(),
+            method,
+            flow: field("flow"),
+            network: stream_field("net"),
+            security: stream_field("sec"),
+            header_type: stream_field("headerType"),
+            host: stream_field("host"),
+            path: stream_field("path"),
+            sni: stream_field("sni"),
+            alpn: stream_field("alpn"),
+            fingerprint: stream_field("fp"),
+            public_key: stream_field("pbk"),
+            short_id: stream_field("sid"),
+            spider_x: stream_field("spx"),
+            insecure: stream["insecure"].as_bool() == Some(true),
+        }
+        .link(),
+    )
+}
@@ -0,0 +1,126 @@
+//! Append-only journal of run decisions for `--journal-file`, so a crashed
+//! or hung run leaves a record of how far it got.
+//!
+//! Every entry is a JSON line written straight to the file without
+//! buffering, a killed process loses nothing but the entry being written.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write as _,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as _, Result};
+use serde_json::{Value, json};
+
+static JOURNAL: Mutex<Option<File>> = Mutex::new(None);
+
+// Flags taking credentials, their values never reach files users share
+const SECRET_FLAGS: &[&str] = &[
+    "--abuseipdb-key",
+    "--sign-key",
+    "--telegram-token",
+    "--alert-webhook",
+];
+const REDACTED: &str = "<redacted>";
+
+/// Appends every later entry to `path`, starting with one of this process
+///
+/// # Errors
+/// Return error if file can't be opened
+pub fn open(path: &str) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open journal {path}"))?;
+    if let Ok(mut journal) = JOURNAL.lock() {
+        *journal = Some(file);
+    }
+    record(
+        "start",
+        json!({
+            "pid": std::process::id(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "args": redacted_args(),
+        }),
+    );
+    Ok(())
+}
+
+/// Command line of this process with values of secret flags hidden, for
+/// journal and run summary
+#[must_use]
+pub fn redacted_args() -> Vec<String> {
+    redact(std::env::args().skip(1))
+}
+
+fn redact(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut secret_next = false;
+    args.into_iter()
+        .map(|arg| {
+            if std::mem::take(&mut secret_next) {
+                return REDACTED.to_owned();
+            }
+            if let Some((flag, _)) = arg.split_once('=')
+                && SECRET_FLAGS.contains(&flag)
+            {
+                return format!("{flag}={REDACTED}");
+            }
+            secret_next = SECRET_FLAGS.contains(&arg.as_str());
+            arg
+        })
+        .collect()
+}
+
+/// Appends `event` with fields of `fields` object, if journal is open
+pub fn record(event: &str, fields: Value) {
+    let Ok(mut journal) = JOURNAL.lock() else {
+        return;
+    };
+    let Some(file) = journal.as_mut() else {
+        return;
+    };
+
+    let mut entry = json!({
+        "ts_ms": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64),
+        "event": event,
+    });
+    if let (Some(entry), Value::Object(fields)) = (entry.as_object_mut(), fields) {
+        entry.extend(fields);
+    }
+    if let Err(e) = writeln!(file, "{entry}") {
+        log::warn!("Failed to write journal: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn redacts_secret_flag_values() {
+        assert_eq!(
+            redact(args(
+                "--abuseipdb-key k1 -o out.txt --sign-key=k2 monitor --telegram-token k3"
+            )),
+            args(
+                "--abuseipdb-key <redacted> -o out.txt --sign-key=<redacted> monitor \
+                 --telegram-token <redacted>"
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_other_args() {
+        let line = args("--sort ping --sources=sources.txt --abuseipdb-max-score 5");
+        assert_eq!(redact(line.clone()), line);
+    }
+}
@@ -0,0 +1,19 @@
+//! Proxy link parsing and Xray config generation used by the novaprox checker.
+
+pub mod cdn;
+pub mod error;
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod import;
+pub mod parse_url;
+pub mod pipeline;
+pub mod protocol;
+pub mod proxy_config;
+#[cfg(feature = "pyo3")]
+pub mod python;
+pub mod transport;
+pub mod xray_config;
+#[cfg(feature = "cli")]
+pub mod xray_process;
+pub mod xray_stats;
@@ -0,0 +1,125 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write as _,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use log::{LevelFilter, Log, Metadata, Record};
+
+pub struct Rotation {
+    // Rotate when file grows past this size
+    pub max_size: u64,
+    // Rotate when file is older than this, regardless of size
+    pub max_age: Option<Duration>,
+    // Rotated files to keep as `<path>.1` (newest) .. `<path>.<keep>`
+    pub keep: usize,
+}
+
+struct State {
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+/// Logger writing to file, rotating it by size and age, for long-running
+/// modes
+struct FileLogger {
+    level: LevelFilter,
+    path: String,
+    rotation: Rotation,
+    state: Mutex<State>,
+}
+
+impl FileLogger {
+    fn open(path: &str) -> Result<State> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("Failed to open log file")?;
+        Ok(State {
+            size: file.metadata()?.len(),
+            file,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn rotate(&self, state: &mut State) -> Result<()> {
+        for index in (1..self.rotation.keep).rev() {
+            let from = format!("{}.{index}", self.path);
+            if fs::exists(&from)? {
+                fs::rename(from, format!("{}.{}", self.path, index + 1))?;
+            }
+        }
+        if self.rotation.keep > 0 {
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        *state = Self::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format!(
+            "{} {:<5} [{}] {}\n",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max_age| state.opened_at.elapsed() >= max_age);
+        if (state.size + line.len() as u64 > self.rotation.max_size || too_old)
+            && state.size > 0
+            && let Err(e) = self.rotate(&mut state)
+        {
+            eprintln!("Failed to rotate log file: {e}");
+        }
+
+        if state.file.write_all(line.as_bytes()).is_ok() {
+            state.size += line.len() as u64;
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.file.flush().ok();
+        }
+    }
+}
+
+/// Sets global logger writing to `path` with rotation
+///
+/// # Errors
+/// Return error if failed to open file or logger is already set
+pub fn init(path: &str, level: LevelFilter, rotation: Rotation) -> Result<()> {
+    let logger = FileLogger {
+        level,
+        path: path.to_owned(),
+        rotation,
+        state: Mutex::new(FileLogger::open(path)?),
+    };
+    log::set_logger(Box::leak(Box::new(logger)))
+        .map_err(|e| anyhow::anyhow!("Failed to set logger: {e}"))?;
+    log::set_max_level(level);
+    Ok(())
+}
@@ -1,42 +1,229 @@
 use anyhow::{Context as _, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use futures::{
-    StreamExt as _, TryFutureExt as _,
+    StreamExt as _,
     stream::{self},
 };
 use log::LevelFilter;
-use reqwest::{Client, ClientBuilder};
+use rand::Rng as _;
+use regex::Regex;
+use reqwest::{
+    ClientBuilder, StatusCode,
+    header::{HeaderMap, HeaderName, HeaderValue},
+};
+use serde_json::json;
 use std::{
-    collections::HashSet, fs, net::IpAddr, process::Stdio, str::FromStr as _, sync::Arc,
-    time::Duration,
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr as _,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+    },
+    time::{Duration, Instant},
 };
 use tokio::{
-    io::{AsyncReadExt as _, AsyncWriteExt as _},
-    process::Command,
+    io::AsyncWriteExt as _,
     sync::{Mutex, Semaphore},
 };
+use tokio_util::sync::CancellationToken;
 use url::{Host, Url};
 
 use crate::{
-    dns_cache::DnsCache,
-    parse_url::parse_proxy_url,
-    proxy_config::{ProxyConfig, country_code_to_emoji},
-    xray_config::generate_xray_config,
+    abuse::{AbuseCache, enrich_abuse_scores},
+    balance::{BalanceRule, balance},
+    canary::CanaryArgs,
+    checker::{
+        AnonymityCheck, CanaryCheck, CapacityProbe, CheckOptions, HoldCheck, PayloadCheck,
+        PortClients, Prefilter, TargetWorking, parse_checklist, parse_country_check,
+        parse_country_code, parse_spki_pins, test_proxy_chunk,
+    },
+    compare::CompareArgs,
+    completions::CompletionsArgs,
+    coordinate::CoordinateArgs,
+    dns_cache::{DnsCache, SharedDnsCache},
+    engine::{TestEngine, XrayEngine},
+    error::{CheckError, ConfigError, EngineError, ResolveError},
+    events::{EventHandler, Stage},
+    health::{Health, Subscription, serve_health},
+    history::HistoryArgs,
+    merge::MergeArgs,
+    monitor::MonitorArgs,
+    normalize::NormalizeArgs,
+    output::{OutputWriter, format_results, output_writer},
+    parse_url::{
+        RemoveRule, check_reality_params, normalize_link, parse_proxy_url, repair_link, split_links,
+    },
+    ping::{PingCache, PingOptions, PingPolicy, PingTarget, icmp_available, ping_proxies},
+    pipeline::PipelineConfig,
+    proxy_config::ProxyConfig,
+    rewrite::RewriteRule,
+    rotate::RotateArgs,
+    rpc::ServeArgs,
+    seen::SeenDb,
+    signing::Signer,
+    soak::SoakArgs,
+    source_wizard::SourcesArgs,
+    sources::{FetchOptions, SourceState},
+    spill::{Candidates, SeenHashes, Urls, memory_share},
+    summary::{RunSummary, SourceStats},
+    xray_config::{Engine, Sandbox},
+    xray_process::{XrayFeatures, probe_xray, spawn_xray},
+};
+
+pub use novaprox::{
+    cdn, error, events, import, parse_url, pipeline, protocol, proxy_config, xray_config,
+    xray_process, xray_stats,
 };
 
+pub mod abuse;
+pub mod alerts;
+pub mod balance;
+pub mod cache_format;
+pub mod canary;
+pub mod checker;
+pub mod clock;
+pub mod compare;
+pub mod completions;
+pub mod coordinate;
 pub mod dns_cache;
-pub mod parse_url;
-pub mod proxy_config;
-pub mod xray_config;
+pub mod doctor;
+pub mod dual_stack;
+pub mod engine;
+pub mod health;
+pub mod history;
+pub mod journal;
+pub mod log_file;
+pub mod merge;
+#[cfg(feature = "mock-engine")]
+pub mod mock_engine;
+pub mod monitor;
+pub mod native_engine;
+pub mod normalize;
+pub mod output;
+pub mod ping;
+pub mod profile;
+pub mod quic_probe;
+pub mod rewrite;
+pub mod rotate;
+pub mod rpc;
+pub mod seed;
+pub mod seen;
+pub mod signing;
+pub mod soak;
+pub mod socks_client;
+pub mod source_wizard;
+pub mod sources;
+pub mod spill;
+pub mod summary;
+pub mod trace;
+
+// Starts results header line naming location of the run
+const VANTAGE_PREFIX: &str = "# vantage: ";
 
 #[cfg(debug_assertions)]
 const CONFIG_FILE: &str = "xconf.json";
 
+#[derive(Subcommand, Debug)]
+enum Mode {
+    // Repeatedly check proxies of existing result list and rewrite it
+    // ordered by uptime, without fetching sources
+    Monitor(MonitorArgs),
+    // Expose single local socks5 inbound balanced across proxies of result
+    // list, following its changes
+    Rotate(RotateArgs),
+    // Serve parse/resolve/check as JSON-RPC over TCP for other programs
+    Serve(ServeArgs),
+    // Test two result lists under identical conditions and compare their
+    // availability and latency
+    Compare(CompareArgs),
+    // Shard fetched links across remote `serve` workers and merge their
+    // results
+    Coordinate(CoordinateArgs),
+    // Merge result lists of several `--vantage` locations into a CSV with
+    // availability per location
+    Merge(MergeArgs),
+    // Print share links of files in canonical form without checking, for
+    // deduplicating them elsewhere and diffing published lists
+    Normalize(NormalizeArgs),
+    // Check proxies of a list every interval for a duration, writing
+    // latency and availability time series per proxy
+    Soak(SoakArgs),
+    // Flag proxies whose `--canary-url` credentials were replayed, by
+    // access log of the canary server
+    Canary(CanaryArgs),
+    // Check xray, ICMP, DNS, outbound access, ports and open files limit
+    // a run depends on
+    Doctor,
+    // Add, list and test sources of `--sources-files`
+    Sources(SourcesArgs),
+    // Export or import verdicts of `--seen-db-file`, e.g. to move them to
+    // another machine
+    History(HistoryArgs),
+    // Print completion script of a shell
+    Completions(CompletionsArgs),
+    // Print roff manpage
+    Man,
+}
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_override_self = true)]
 struct Args {
-    #[arg(long, default_value = "info")]
-    log_level: String,
+    #[command(subcommand)]
+    mode: Option<Mode>,
+
+    // Default arguments, see src/profile.rs for the format
+    #[arg(long, default_value = profile::DEFAULT_CONFIG)]
+    config: String,
+
+    // Section of `--config` overriding its base settings
+    #[arg(long)]
+    profile: Option<String>,
+
+    // Overrides -q/-v and RUST_LOG, which are used when it's not set
+    #[arg(long)]
+    log_level: Option<String>,
+
+    // Seed of shuffling, jitter and checklist rotation, and stable chunk
+    // order, so runs over the same inputs are reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+
+    // Log timeline of proxies whose link contains this (host, address or
+    // uuid): parsing, resolving, ping, outbound, xray log and checks
+    #[arg(long)]
+    trace_proxy: Option<String>,
+
+    // Only warnings (-q) or only errors (-qq), for cron
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+
+    // Debug (-v) or trace (-vv) output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    // Write log to this file instead of stderr, rotating it
+    #[arg(long)]
+    log_file: Option<String>,
+
+    // Append JSON lines of run decisions (stages, chunks, core pids,
+    // timings) to this file, to attach to reports of hangs and crashes
+    #[arg(long)]
+    journal_file: Option<String>,
+
+    #[arg(long, default_value_t = 10)]
+    log_max_size_mb: u64,
+
+    // Also rotate log file older than this
+    #[arg(long, value_parser = humantime::parse_duration)]
+    log_max_age: Option<Duration>,
+
+    // Rotated log files to keep
+    #[arg(long, default_value_t = 5)]
+    log_keep: usize,
 
     #[arg(short, long, default_value = "vless")]
     scheme: String,
@@ -44,18 +231,67 @@ struct Args {
     #[arg(short, long, default_value = "")]
     whitelist_params: String,
 
+    // Repair corrupted links of scraped sources (stray whitespace, doubled
+    // scheme, truncated vmess base64) instead of dropping them
+    #[arg(long)]
+    lenient: bool,
+
+    // Spill candidate links to a temporary file once they take half of
+    // this and stop remembering seen links past a quarter, for huge
+    // aggregated sources on small machines. Resolved proxies and results
+    // aren't capped, they're what survived filtering
+    #[arg(long)]
+    max_memory_mb: Option<u64>,
+
     // Clear ads and other useless trash
-    // (sadly what in xhttp path often place ad)
+    // (sadly what in xhttp path often place ad). Rules are
+    // `key[=value][@param=value&...]`, conditions keep e.g. ws path
     #[arg(
         short,
         long,
-        default_value = "note,host,spx,authority,path,fp,*=none,*="
+        default_value = "note,spx,authority,host@type=tcp,path@type=tcp,fp@security=none,*=none,*="
     )]
     remove_params: String,
 
+    // Rewrite params of parsed links: `sni=old.com=>new.com` (exact),
+    // `sni~^(.*)\.old$=>$1.new` (regex), `security=>tls@scheme=trojan`
+    // (set when missing, under conditions), repeatable
+    #[arg(long, value_parser = RewriteRule::parse)]
+    rewrite_param: Vec<RewriteRule>,
+
     #[arg(short, long, default_value = "out.txt")]
     out_file: String,
 
+    // Format of `--out-file`: urls, base64, clash, sing-box, json or csv.
+    // Partial results of a running check are always urls
+    #[arg(long, default_value = "urls", value_parser = output_writer)]
+    format: &'static dyn OutputWriter,
+
+    // Directory relative output, cache and log files are written to, e.g.
+    // mounted volume of a container job
+    #[arg(long)]
+    artifacts_dir: Option<String>,
+
+    // Proxy core used for checking, v2fly can't check reality, vision and
+    // xhttp proxies
+    #[arg(long, value_enum, default_value_t = Engine::Xray)]
+    engine: Engine,
+
+    // Check through in-process SOCKS relays instead of xray, proxies work
+    // when their server accepts TCP. For tests without xray or internet
+    #[cfg(feature = "mock-engine")]
+    #[arg(long)]
+    mock_engine: bool,
+
+    // Run xray with `<runtime> exec` in this container, which must use
+    // host network
+    #[arg(long)]
+    xray_container: Option<String>,
+
+    // Docker compatible CLI for `--xray-container`, e.g. podman
+    #[arg(long, default_value = "docker")]
+    container_runtime: String,
+
     #[cfg(not(debug_assertions))]
     #[arg(long, default_value = "sources.txt")]
     sources_files: String,
@@ -64,11 +300,65 @@ struct Args {
     #[arg(long, default_value = "sources.txt")]
     sources_files: String,
 
+    // Concurrent source requests to the same host, to not get 429
+    #[arg(long, default_value_t = 4)]
+    source_host_concurrency: usize,
+
+    // Random delay up to this before every source request
+    #[arg(long, default_value_t = 300)]
+    source_jitter_ms: u64,
+
+    // Fetch sources directly even when HTTP(S)_PROXY or ALL_PROXY is set
+    #[arg(long, default_value_t = false)]
+    no_env_proxy: bool,
+
+    // v2rayN (guiNConfig.json) or NekoBox profile export to test along with
+    // sources, repeatable
+    #[arg(long)]
+    import: Vec<String>,
+
+    // Content hash of every source and time of its last change
+    #[arg(long, default_value = "sources.db")]
+    source_state_file: String,
+
+    // Sources with content unchanged this long are reported as stale
+    #[arg(long, default_value_t = 14)]
+    stale_source_days: u64,
+
+    // Don't fetch sources which were stale on previous run
+    #[arg(long)]
+    skip_stale_sources: bool,
+
     #[arg(long, default_value = "resolved.txt")]
     dns_cache_file: String,
 
+    // Base URL of HTTP key-value store shared with other runners, asked
+    // for domains missing from local DNS cache
+    #[arg(long)]
+    dns_shared_cache: Option<String>,
+
+    // Race TCP connections to IPv6 and IPv4 addresses of domains having
+    // both, keeping the address that connected first and tagging proxies
+    // working over IPv6. Connects time out after `--ping-timeout-ms`
+    #[arg(long, default_value_t = false)]
+    dual_stack: bool,
+
+    // Comma separated domains, proxies using them (or their subdomains) as
+    // TLS server name or host header are flagged
+    #[arg(long, default_value = "")]
+    banned_sni: String,
+
+    // Replace IP address or missing TLS server name with host header domain
+    #[arg(long, default_value_t = false)]
+    fix_sni: bool,
+
+    // Extra CDN edge ranges as `provider cidr` lines, for providers or
+    // edges missing from built-in list
+    #[arg(long)]
+    cdn_ranges: Option<String>,
+
     #[arg(long, default_value_t = 700)]
-    ping_timeout_ms: u128,
+    ping_timeout_ms: u64,
 
     #[arg(long, default_value_t = 100)]
     ping_delay: u64,
@@ -76,9 +366,117 @@ struct Args {
     #[arg(long, default_value_t = 3)]
     ping_count: usize,
 
+    // What to ping: proxy server, its SNI host, or TCP connect to proxy port
+    #[arg(long, value_enum, default_value_t = PingTarget::Server)]
+    ping_target: PingTarget,
+
+    // Many good hosts drop ICMP, `annotate` keeps them for the real check
+    #[arg(long, value_enum, default_value_t = PingPolicy::Filter)]
+    ping_policy: PingPolicy,
+
+    // Reuse ping results younger than this from previous runs (0s disables)
+    #[arg(long, default_value = "10m", value_parser = humantime::parse_duration)]
+    ping_cache_ttl: Duration,
+
+    #[arg(long, default_value = "ping.txt")]
+    ping_cache_file: String,
+
+    // Retry failed proxies with common mistakes fixed (missing fingerprint,
+    // certificate verification, ws path), reporting the fix that worked
+    #[arg(long)]
+    retry_variants: bool,
+
+    // Xray client config to put working outbounds into, in place of
+    // "{{novaprox_outbounds}}" item of its outbounds array. Their tags are
+    // put in place of "{{novaprox_tags}}" items, e.g. of balancer selector
+    #[arg(long)]
+    client_template: Option<String>,
+
+    #[arg(long, default_value = "client.json")]
+    client_config_out: String,
+
+    // Serve `/healthz` on this address in serve and monitor modes
+    #[arg(long)]
+    health_listen: Option<std::net::SocketAddr>,
+
+    // Result list served as base64 subscription on `/sub` of
+    // `--health-listen`
+    #[arg(long)]
+    subscription_file: Option<String>,
+
+    #[arg(long, default_value = "upload=0; download=0; total=0; expire=0")]
+    subscription_userinfo: String,
+
+    // Hours clients wait between subscription updates
+    #[arg(long, default_value_t = 1)]
+    profile_update_interval: u32,
+
+    // Comma separated result order, later keys break ties of earlier ones,
+    // e.g. `country,ping`
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "score")]
+    sort: Vec<SortKey>,
+
+    // Comma separated composition limits applied after sorting, e.g.
+    // `country:10,protocol:equal`
+    #[arg(long, value_delimiter = ',', value_parser = BalanceRule::parse)]
+    balance: Vec<BalanceRule>,
+
+    // Start results with comment lines holding generator version and
+    // generation time
+    #[arg(long, default_value_t = false)]
+    metadata_header: bool,
+
+    // Ed25519 key (hex or base64 seed, or PKCS#8) signing results into
+    // `<out-file>.minisig`, verifiable with minisign
+    #[arg(long)]
+    sign_key: Option<String>,
+
+    // Location label of this run, written to results and run summary so
+    // results of several locations can be merged
+    #[arg(long)]
+    vantage: Option<String>,
+
+    // Stage counts, durations and drop reasons of the run as JSON, `none`
+    // disables it
+    #[arg(long, default_value = "run-summary.json")]
+    summary_file: String,
+
+    // Verdicts of every tested proxy, kept between runs
+    #[arg(long, default_value = "seen.db")]
+    seen_db_file: String,
+
+    // Don't test again proxies which failed their last test within this time
+    #[arg(long, value_parser = humantime::parse_duration)]
+    skip_seen_within: Option<Duration>,
+
     #[arg(long, default_value_t = 5000)]
     request_timeout_ms: u64,
 
+    // Retry check requests failing on transport level, so a single
+    // dropped packet doesn't discard good proxy
+    #[arg(long, default_value_t = 0)]
+    check_retries: u32,
+
+    #[arg(long, default_value = "500ms", value_parser = humantime::parse_duration)]
+    check_retry_delay: Duration,
+
+    // Scale check timeout of every pinged proxy as
+    // `max(min_request_timeout_ms, factor * ping)` instead of using
+    // fixed request timeout (0 disables)
+    #[arg(long, default_value_t = 0)]
+    timeout_ping_factor: u32,
+
+    #[arg(long, default_value_t = 1000)]
+    min_request_timeout_ms: u64,
+
+    // Quick HEAD request pass with this timeout before the full check,
+    // so slow checks run only on proxies that are alive (0 disables)
+    #[arg(long, default_value_t = 0)]
+    prefilter_timeout_ms: u64,
+
+    #[arg(long, default_value = "https://www.gstatic.com/generate_204")]
+    prefilter_url: String,
+
     #[arg(long, default_value_t = 300)]
     chunk_size: usize,
 
@@ -94,32 +492,612 @@ struct Args {
     #[arg(long, default_value_t = 50)]
     max_concurrent_dns: usize,
 
+    // Comma separated `target[@user-agent]` every proxy must reach, a domain
+    // or full URL. Targets like `geosite:google` expand to representative
+    // URLs of the category.
+    // `a.com|b.com` entry is a pool, proxies are checked with its targets
+    // in turn so none of them rate limits a large run
     #[arg(
         long,
         default_value = "2ip.ru@curl/8.4.0,2ip.ru@curl/8.4.0,www.roblox.com,discord.com,www.youtube.com,telegram.org"
     )]
     latency_checklist: String,
 
+    // Request checklist in random order for every proxy
+    #[arg(long, default_value_t = false)]
+    shuffle_checklist: bool,
+
+    // Random delay up to this before every check request, so checks
+    // don't leave a burst pattern on the network (0 disables)
+    #[arg(long, default_value_t = 0)]
+    check_jitter_ms: u64,
+
+    // Check requests per proxy, the checklist is repeated until this many
+    // were made. Above 1 reports median and p95 latency instead of average
+    #[arg(long, default_value_t = 1)]
+    check_samples: u32,
+
     #[arg(long, short, default_value_t = true)]
     country: bool,
+
+    // Geo IP API answering with JSON of `country` code and `ip`
+    #[arg(long, default_value = "https://ipinfo.io/json")]
+    country_url: String,
+
+    // Endpoint geofenced to a country as `CC=target`, repeatable. Proxies
+    // exiting in CC keep their country only when all its targets respond,
+    // so `--target-countries` and `--balance` count them as CC-working
+    #[arg(long, value_parser = parse_country_check)]
+    country_check: Vec<([char; 2], String)>,
+
+    // User agent for checklist entries without `@user-agent`
+    #[arg(long)]
+    check_user_agent: Option<String>,
+
+    // Extra `Name: value` header sent with check requests, repeatable
+    #[arg(long)]
+    check_header: Vec<String>,
+
+    // Status code the check pages must return (any 2xx when unset)
+    #[arg(long)]
+    check_expect_status: Option<u16>,
+
+    // Regex every check page body must match, catches captive portals
+    // and MITM proxies that answer 200 with their own page
+    #[arg(long)]
+    check_expect_body: Option<String>,
+
+    // Expected leaf certificate SPKI hashes per check domain, as
+    // `domain=base64sha256|base64sha256,...`. Proxies presenting a
+    // different certificate are flagged in output
+    #[arg(long, default_value = "")]
+    check_pin_spki: String,
+
+    // Drop proxies failing SPKI pinning instead of flagging them
+    #[arg(long, default_value_t = false)]
+    check_pin_drop: bool,
+
+    // Serve whole chunk on a single socks inbound at `--base-start-port`,
+    // picking the proxy by socks username, instead of one port per proxy
+    #[arg(long, default_value_t = false)]
+    mux_inbound: bool,
+
+    // Let proxies carry only requests to hosts of check URLs, so a
+    // malicious proxy can't use the tester for other traffic
+    #[arg(long, default_value_t = false)]
+    sandbox_checks: bool,
+
+    // Extra host allowed with `--sandbox-checks`, repeatable
+    #[arg(long)]
+    sandbox_allow: Vec<String>,
+
+    // Stop checking once this many proxies passed, 0 checks every proxy
+    #[arg(long, default_value_t = 0)]
+    target_working: usize,
+
+    // Comma separated country codes, `--target-working` proxies are needed
+    // in each of them instead of in total
+    #[arg(long, default_value = "")]
+    target_countries: String,
+
+    // Classify http/socks proxies as transparent, anonymous or elite
+    // using a header echo endpoint
+    #[arg(long, default_value_t = false)]
+    check_anonymity: bool,
+
+    #[arg(long, default_value = "http://httpbin.org/headers")]
+    anonymity_echo_url: String,
+
+    // Used to find our own address when checking anonymity
+    #[arg(long, default_value = "https://api.ipify.org")]
+    real_ip_url: String,
+
+    // Plain http URL of own server, `{token}` replaced with unique token
+    // sent through every working proxy and recorded in `--canary-ledger`
+    #[arg(long)]
+    canary_url: Option<String>,
+
+    #[arg(long, default_value = "canary.tsv")]
+    canary_ledger: String,
+
+    // AbuseIPDB API key, enables abuse score lookup of working proxies
+    #[arg(long)]
+    abuseipdb_key: Option<String>,
+
+    #[arg(long, default_value = "abuse.txt")]
+    abuse_cache_file: String,
+
+    #[arg(long, default_value_t = 24)]
+    abuse_cache_ttl_hours: u64,
+
+    // Drop proxies whose server or exit address scored higher
+    #[arg(long)]
+    max_abuse_score: Option<u8>,
+
+    // Hold a streaming connection through every working proxy for this
+    // many seconds and flag ones dropping it (0 disables)
+    #[arg(long, default_value_t = 0)]
+    hold_seconds: u64,
+
+    #[arg(
+        long,
+        default_value = "https://httpbin.org/drip?duration={secs}&numbytes={secs}&delay=0"
+    )]
+    hold_url: String,
+
+    // Download this many bytes through every working proxy and flag ones
+    // returning other content or stalling, which small checks miss
+    // (0 disables)
+    #[arg(long, default_value_t = 0)]
+    payload_bytes: usize,
+
+    // `{bytes}` is replaced with `--payload-bytes`
+    #[arg(
+        long,
+        default_value = "https://speed.cloudflare.com/__down?bytes={bytes}"
+    )]
+    payload_url: String,
+
+    // Hex SHA-256 of payload, downloaded without proxy to hash it when
+    // unset
+    #[arg(long)]
+    payload_sha256: Option<String>,
+
+    // Time the whole payload download may take, through proxies and for
+    // the reference without proxy
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    payload_timeout: Duration,
+
+    // Open this many simultaneous requests through every working proxy
+    // and record how many succeed and how latency degrades (0 disables)
+    #[arg(long, default_value_t = 0)]
+    capacity_connections: usize,
+
+    #[arg(long, default_value = "https://www.gstatic.com/generate_204")]
+    capacity_url: String,
+
+    // Enable xray stats api on this port and report bytes transferred
+    // through every proxy
+    #[arg(long)]
+    stats_api_port: Option<u16>,
+
+    // Check that QUIC (HTTP/3) traffic passes through every working proxy
+    #[arg(long, default_value_t = false)]
+    check_http3: bool,
+
+    #[arg(long, default_value = "www.youtube.com:443")]
+    http3_target: String,
+
+    // VMess auth fails when clocks differ more than 120 seconds, so local
+    // clock is compared with this server before testing vmess
+    #[arg(long, default_value = "https://www.google.com")]
+    time_source_url: String,
+
+    #[arg(long, default_value_t = 90)]
+    max_clock_skew_secs: u64,
+
+    // Validated timeouts, concurrency and ports of the options above
+    #[arg(skip)]
+    pipeline: PipelineConfig,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let elapsed = std::time::Instant::now();
-    let args = Args::parse();
-    simple_logger::SimpleLogger::new()
-        .env()
-        .with_level(LevelFilter::from_str(&args.log_level.to_uppercase())?)
-        .without_timestamps()
-        .init()?;
+    let elapsed = Instant::now();
+    let args = Args::load()?;
+    init_logger(&args)?;
+    use_globals(&args)?;
+    use_core(&args);
+
+    if args.scheme == "vmess" {
+        check_clock_skew(&args).await;
+    }
 
-    let param_filters = parse_param_filters(if args.whitelist_params == "none" {
-        ""
-    } else {
-        &args.whitelist_params
+    match &args.mode {
+        Some(Mode::Monitor(monitor_args)) => {
+            return monitor::run_monitor(&args, monitor_args).await;
+        }
+        Some(Mode::Rotate(rotate_args)) => return rotate::run_rotate(&args, rotate_args).await,
+        Some(Mode::Serve(serve_args)) => return rpc::run_server(&args, serve_args).await,
+        Some(Mode::Compare(compare_args)) => {
+            return compare::run_compare(&args, compare_args).await;
+        }
+        Some(Mode::Coordinate(coordinate_args)) => {
+            return coordinate::run_coordinator(&args, coordinate_args).await;
+        }
+        Some(Mode::Merge(merge_args)) => return merge::run_merge(merge_args).await,
+        Some(Mode::Normalize(normalize_args)) => {
+            return normalize::run_normalize(&args, normalize_args).await;
+        }
+        Some(Mode::Soak(soak_args)) => return soak::run_soak(&args, soak_args).await,
+        Some(Mode::Canary(canary_args)) => return canary::run_canary(canary_args).await,
+        Some(Mode::Doctor) => return doctor::run_doctor(&args).await,
+        Some(Mode::Sources(sources_args)) => {
+            return source_wizard::run_sources(&args, sources_args).await;
+        }
+        Some(Mode::History(history_args)) => {
+            return history::run_history(&args, history_args).await;
+        }
+        Some(Mode::Completions(completions_args)) => {
+            completions::print_completions(completions_args);
+            return Ok(());
+        }
+        Some(Mode::Man) => return completions::print_man(),
+        None => {}
+    }
+
+    let cancel = cancel_on_ctrl_c();
+    let mut summary = RunSummary::new();
+    summary.vantage.clone_from(&args.vantage);
+    let valid_urls = until_cancelled(&cancel, fetch_stage(&args, &mut summary)).await?;
+
+    let dns_cache = open_dns_cache(&args)?;
+
+    let stage_start = Instant::now();
+    let selected = valid_urls.len();
+    LogEvents.on_stage_start(Stage::Resolve, selected);
+    let resolved_proxies = until_cancelled(
+        &cancel,
+        resolve_proxies(
+            valid_urls,
+            Arc::clone(&dns_cache),
+            args.pipeline.max_concurrent_dns,
+        ),
+    )
+    .await?;
+    summary.stage("resolve", resolved_proxies.len(), stage_start);
+    summary.dropped("unresolved", selected - resolved_proxies.len());
+
+    log::info!("Resolved {} proxies", resolved_proxies.len());
+    let resolved_proxies = validate_sni(&args, resolved_proxies);
+
+    let working_proxies =
+        check_stage(&args, resolved_proxies, dns_cache, &cancel, &mut summary).await?;
+
+    log::info!("Found {} working proxies", working_proxies.len());
+    if args.canary_url.is_some() {
+        canary::record(&args.canary_ledger, &working_proxies).await?;
+    }
+
+    let (stage_start, working) = (Instant::now(), working_proxies.len());
+    LogEvents.on_stage_start(Stage::Filter, working);
+    let mut sorted_proxies = filter_by_abuse_score(&args, working_proxies).await?;
+    summary.stage("abuse_filter", sorted_proxies.len(), stage_start);
+    summary.dropped("abuse_score", working - sorted_proxies.len());
+    sort_proxies(&mut sorted_proxies, &args.sort);
+    let (before, sorted_proxies) = (sorted_proxies.len(), balance(sorted_proxies, &args.balance));
+    summary.dropped("balance", before - sorted_proxies.len());
+
+    let results = args
+        .format
+        .render(&sorted_proxies, &results_header(&args))?;
+
+    log::info!(
+        "Time required: {}",
+        humantime::format_duration(elapsed.elapsed())
+    );
+
+    write_results(&args, &results).await?;
+
+    if let Some(template) = &args.client_template {
+        let config =
+            xray_config::fill_client_template(&fs::read_to_string(template)?, &sorted_proxies)?;
+        tokio::fs::write(&args.client_config_out, config).await?;
+    }
+
+    if args.summary_file != "none" {
+        summary.write(&args.summary_file).await?;
+    }
+    journal::record("finish", json!({ "working": sorted_proxies.len() }));
+
+    Ok(())
+}
+
+// Switches of tracing, randomness, CDN ranges and resolving, which every
+// mode shares
+fn use_globals(args: &Args) -> Result<()> {
+    if let Some(pattern) = &args.trace_proxy {
+        trace::trace_proxy(pattern);
+    }
+    if let Some(seed) = args.seed {
+        seed::use_seed(seed);
+    }
+    if let Some(path) = &args.cdn_ranges {
+        load_cdn_ranges(path)?;
+    }
+    if args.dual_stack {
+        dual_stack::use_dual_stack(args.pipeline.ping_timeout());
+    }
+    if let Some(path) = &args.journal_file {
+        journal::open(path)?;
+    }
+    Ok(())
+}
+
+// Selects core binary, container and engine every check runs through
+fn use_core(args: &Args) {
+    xray_process::use_engine(args.engine);
+    #[cfg(feature = "mock-engine")]
+    if args.mock_engine {
+        mock_engine::use_mock_engine();
+    }
+    if let Some(container) = &args.xray_container {
+        xray_process::use_container(&args.container_runtime, container);
+    }
+}
+
+// Ctrl-C cancels run instead of killing process, so xray children are
+// stopped and partial results kept
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            log::warn!("Cancelling run");
+            token.cancel();
+        }
     });
-    let request_timeout = Duration::from_millis(args.request_timeout_ms);
+    cancel
+}
+
+async fn until_cancelled<T>(
+    cancel: &CancellationToken,
+    stage: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    cancel
+        .run_until_cancelled(stage)
+        .await
+        .unwrap_or_else(|| Err(CheckError::Cancelled.into()))
+}
+
+// Comment lines describing the run, skipped by list readers
+fn results_header(args: &Args) -> String {
+    let mut header = String::new();
+    if args.metadata_header {
+        header += &format!(
+            "# generator: novaprox {}\n# generated: {}\n",
+            env!("CARGO_PKG_VERSION"),
+            humantime::format_rfc3339_seconds(std::time::SystemTime::now())
+        );
+    }
+    if let Some(vantage) = &args.vantage {
+        header += &format!("{VANTAGE_PREFIX}{vantage}\n");
+    }
+    header
+}
+
+async fn write_results(args: &Args, results: &str) -> Result<()> {
+    if args.out_file == "none" {
+        println!("{results}");
+        return Ok(());
+    }
+    // Replaces partial results with sorted ones
+    tokio::fs::write(&args.out_file, results).await?;
+
+    if let Some(key) = &args.sign_key {
+        let signer = Signer::load(key)?;
+        let file_name = Path::new(&args.out_file)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        tokio::fs::write(
+            format!("{}.minisig", args.out_file),
+            signer.sign(results.as_bytes(), &file_name),
+        )
+        .await
+        .context("Failed to write signature")?;
+        log::info!("Signed results, public key: {}", signer.public_key());
+    }
+    Ok(())
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    // Lowest latency first
+    Ping,
+    // Highest bandwidth first
+    Speed,
+    // Lowest latency per bandwidth first
+    Score,
+    // Alphabetically by country code, unknown last
+    Country,
+    // Highest uptime first, for monitored lists
+    Uptime,
+}
+
+impl SortKey {
+    fn compare(self, a: &ProxyConfig, b: &ProxyConfig) -> Ordering {
+        match self {
+            Self::Ping => a.ping.cmp(&b.ping),
+            Self::Speed => b.bandwidth.cmp(&a.bandwidth),
+            Self::Score => {
+                // Unmeasured bandwidth would divide by zero, it ranks last
+                let score = |proxy: &ProxyConfig| {
+                    if proxy.bandwidth == 0 {
+                        f64::INFINITY
+                    } else {
+                        proxy.ping.as_secs_f64() / (proxy.bandwidth as f64)
+                    }
+                };
+                score(a).total_cmp(&score(b))
+            }
+            Self::Country => match (a.country, b.country) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (a, b) => a.is_none().cmp(&b.is_none()),
+            },
+            Self::Uptime => b.uptime.cmp(&a.uptime),
+        }
+    }
+}
+
+// By every key in order, later keys break ties of earlier ones
+pub(crate) fn sort_proxies(proxies: &mut [ProxyConfig], keys: &[SortKey]) {
+    proxies.sort_by(|a, b| {
+        keys.iter()
+            .map(|key| key.compare(a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+}
+
+// Health state of daemon modes, served when `--health-listen` is set
+pub(crate) fn start_health(args: &Args) -> Arc<std::sync::Mutex<Health>> {
+    let health = Arc::new(std::sync::Mutex::new(Health::default()));
+    if let Some(listen) = args.health_listen {
+        let health = Arc::clone(&health);
+        let subscription = args.subscription_file.as_ref().map(|list_file| {
+            Arc::new(Subscription {
+                list_file: list_file.clone(),
+                userinfo: args.subscription_userinfo.clone(),
+                update_interval: args.profile_update_interval,
+                history: std::sync::Mutex::default(),
+            })
+        });
+        tokio::spawn(async move {
+            if let Err(e) = serve_health(listen, health, subscription).await {
+                log::error!("Health endpoint failed: {e:#}");
+            }
+        });
+    }
+    health
+}
+
+impl Args {
+    // Command line with `novaprox.conf` defaults, paths and pipeline
+    // config resolved
+    fn load() -> Result<Self> {
+        let mut args = Self::parse_from(profile::with_config_args(std::env::args().collect())?);
+        args.apply_artifacts_dir();
+        args.pipeline = args.pipeline_config()?;
+        Ok(args)
+    }
+
+    // Like `load`, but with edited config file and returning errors instead
+    // of exiting, for daemons reloading it
+    fn reload() -> Result<Self> {
+        let mut args =
+            Self::try_parse_from(profile::with_config_args(std::env::args().collect())?)?;
+        args.apply_artifacts_dir();
+        args.pipeline = args.pipeline_config()?;
+        Ok(args)
+    }
+
+    fn pipeline_config(&self) -> Result<PipelineConfig, ConfigError> {
+        PipelineConfig::builder()
+            .request_timeout(Duration::from_millis(self.request_timeout_ms))
+            .timeout_ping_factor(
+                self.timeout_ping_factor,
+                Duration::from_millis(self.min_request_timeout_ms),
+            )
+            .check_retries(self.check_retries, self.check_retry_delay)
+            .check_jitter(Duration::from_millis(self.check_jitter_ms))
+            .check_samples(self.check_samples)
+            .prefilter_timeout(Duration::from_millis(self.prefilter_timeout_ms))
+            .ping_timeout(Duration::from_millis(self.ping_timeout_ms))
+            .chunk_size(self.chunk_size)
+            .base_start_port(self.base_start_port)
+            .max_concurrent(
+                self.max_concurrent_pings,
+                self.max_concurrent_checks,
+                self.max_concurrent_dns,
+            )
+            .build()
+    }
+
+    // Moves relative artifact paths into `--artifacts-dir`
+    fn apply_artifacts_dir(&mut self) {
+        let Some(dir) = self.artifacts_dir.clone() else {
+            return;
+        };
+        let paths = [
+            &mut self.out_file,
+            &mut self.summary_file,
+            &mut self.client_config_out,
+            &mut self.dns_cache_file,
+            &mut self.ping_cache_file,
+            &mut self.seen_db_file,
+            &mut self.source_state_file,
+            &mut self.abuse_cache_file,
+        ];
+        let optional = self
+            .log_file
+            .as_mut()
+            .into_iter()
+            .chain(&mut self.journal_file)
+            .chain(&mut self.subscription_file);
+        let mode = match &mut self.mode {
+            Some(Mode::Monitor(monitor)) => monitor.artifact_paths(),
+            Some(Mode::History(history)) => history.artifact_paths(),
+            Some(Mode::Soak(soak)) => soak.artifact_paths(),
+            _ => Vec::new(),
+        };
+        for path in paths.into_iter().chain(optional).chain(mode) {
+            if path != "none" && Path::new(path.as_str()).is_relative() {
+                *path = Path::new(&dir).join(&*path).to_string_lossy().into_owned();
+            }
+        }
+    }
+}
+
+fn init_logger(args: &Args) -> Result<()> {
+    let level = log_level(args)?;
+
+    if let Some(path) = &args.log_file {
+        let level = level
+            .or_else(|| LevelFilter::from_str(&std::env::var("RUST_LOG").ok()?).ok())
+            .unwrap_or(LevelFilter::Info);
+        let rotation = log_file::Rotation {
+            max_size: args.log_max_size_mb.saturating_mul(1024 * 1024),
+            max_age: args.log_max_age,
+            keep: args.log_keep,
+        };
+        return log_file::init(path, level, rotation);
+    }
+
+    let logger = simple_logger::SimpleLogger::new()
+        .with_level(LevelFilter::Info)
+        .env()
+        .without_timestamps();
+    match level {
+        Some(level) => logger.with_level(level),
+        None => logger,
+    }
+    .init()?;
+    Ok(())
+}
+
+fn log_level(args: &Args) -> Result<Option<LevelFilter>> {
+    if let Some(level) = &args.log_level {
+        return Ok(Some(LevelFilter::from_str(level)?));
+    }
+    Ok(match (args.quiet, args.verbose) {
+        (0, 0) => None,
+        (1, _) => Some(LevelFilter::Warn),
+        (_, 0) => Some(LevelFilter::Error),
+        (_, 1) => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    })
+}
+
+fn load_cdn_ranges(path: &str) -> Result<()> {
+    let content = fs::read_to_string(path).context("Failed to read CDN ranges")?;
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (provider, cidr) = line
+            .split_once(char::is_whitespace)
+            .with_context(|| format!("CDN range line {line} isn't `provider cidr`"))?;
+        cdn::register_range(provider, cidr.trim())?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn fetch_stage(args: &Args, summary: &mut RunSummary) -> Result<Urls> {
+    let stage_start = Instant::now();
+    let stale_after = Duration::from_secs(args.stale_source_days * 24 * 3600);
+    let mut source_state = SourceState::new(&args.source_state_file);
+    source_state.load()?;
 
     let sources_content = args
         .sources_files
@@ -130,108 +1108,641 @@ async fn main() -> Result<()> {
                 .ok()
         })
         .collect::<Vec<_>>()
-        .join("\n");
-
-    let proxies = get_proxies_from_sources(&sources_content).await?;
-
-    log::info!("Loaded {} proxies", proxies.lines().count());
-
-    let valid_urls = proxies
+        .join("\n")
         .lines()
-        .filter_map(|line| {
-            parse_proxy_url(
-                line,
-                &args.scheme,
-                &param_filters,
-                &args
-                    .remove_params
-                    .split(',')
-                    .map(|v| v.split_once('=').unwrap_or((v, "*")))
-                    .collect::<Vec<_>>(),
-            )
+        .filter(|source| {
+            let skip = args.skip_stale_sources && source_state.is_stale(source, stale_after);
+            if skip {
+                log::info!("Skipping stale source: {source}");
+            }
+            !skip
         })
-        .collect::<Vec<_>>();
+        .collect::<Vec<_>>()
+        .join("\n");
 
-    log::info!("Selected {} proxies", valid_urls.len());
+    LogEvents.on_stage_start(Stage::Fetch, sources_content.lines().count());
+    let mut parser = LinkParser::new(args);
+    let mut valid_urls = Candidates::new(args.max_memory_mb);
+    let mut spill_error = None;
+    let fetch_options = FetchOptions {
+        per_host: args.source_host_concurrency,
+        jitter: Duration::from_millis(args.source_jitter_ms),
+        env_proxy: !args.no_env_proxy,
+    };
+    summary.sources = sources::fetch_sources(&sources_content, &fetch_options, |line| {
+        let urls = parser.parse(line);
+        let taken = urls.len();
+        for url in urls {
+            if let Err(e) = valid_urls.push(url) {
+                spill_error.get_or_insert(e);
+            }
+        }
+        taken
+    })
+    .await?;
+    if let Some(e) = spill_error {
+        return Err(e).context("Failed to spill candidates");
+    }
 
-    let dns_cache = Arc::new(Mutex::new(DnsCache::new(&args.dns_cache_file)));
-    dns_cache.lock().await.load_cache()?;
+    for path in &args.import {
+        let links = import::profile_links(&fs::read_to_string(path)?)
+            .with_context(|| format!("Failed to import {path}"))?;
+        let before = valid_urls.len();
+        for url in links.iter().flat_map(|link| parser.parse(link)) {
+            valid_urls.push(url).context("Failed to spill candidates")?;
+        }
+        let selected = valid_urls.len() - before;
+        log::info!("Imported {selected}/{} profiles from {path}", links.len());
+        summary.sources.push(SourceStats {
+            url: path.clone(),
+            error: None,
+            hash: None,
+            lines: links.len(),
+            links: selected,
+            stale: false,
+        });
+    }
 
-    let resolved_proxies = resolve_proxies(valid_urls, dns_cache, args.max_concurrent_dns).await?;
+    source_state.update(&mut summary.sources, stale_after);
+    source_state.save().await?;
+    for source in summary.sources.iter().filter(|source| source.stale) {
+        log::warn!(
+            "Source didn't change for {} days: {}",
+            args.stale_source_days,
+            source.url
+        );
+    }
 
-    log::info!("Resolved {} proxies", resolved_proxies.len());
+    let loaded = summary
+        .sources
+        .iter()
+        .map(|source| source.lines)
+        .sum::<usize>();
+    log::info!("Loaded {loaded} proxies");
+    log::info!("Selected {} proxies", valid_urls.len());
+    if args.lenient {
+        log::info!("Repaired {} corrupted links", parser.repaired);
+    }
 
-    let alive_proxies = if args.ping_count > 0 {
-        let alive = ping_proxies(
-            resolved_proxies,
-            args.ping_timeout_ms,
-            args.ping_delay,
-            args.max_concurrent_pings,
-            args.ping_count,
-        )
-        .await;
+    summary.stage("fetch", valid_urls.len(), stage_start);
+    summary.dropped("duplicate", parser.duplicates);
+    summary.dropped("malformed_reality", parser.malformed_reality);
+    summary.repaired = parser.repaired;
+    summary.dropped("rejected", parser.rejected);
+    Ok(valid_urls.into_urls()?)
+}
 
-        log::info!("Found {} alive proxies after ping", alive.len());
-        alive
+async fn check_stage(
+    args: &Args,
+    mut proxies: HashSet<ProxyConfig>,
+    dns_cache: Arc<Mutex<DnsCache>>,
+    cancel: &CancellationToken,
+    summary: &mut RunSummary,
+) -> Result<Vec<ProxyConfig>> {
+    let mut seen = SeenDb::new(&args.seen_db_file);
+    seen.load()?;
+    if let Some(within) = args.skip_seen_within {
+        let before = proxies.len();
+        proxies.retain(|proxy| !seen.is_recently_dead(&proxy.to_string(), within));
+        log::info!("Skipped {} recently dead proxies", before - proxies.len());
+        summary.dropped("recently_dead", before - proxies.len());
+    }
+    let tested = proxies.iter().map(ToString::to_string).collect::<Vec<_>>();
+    let mut check_options = check_options(args).await?;
+    check_options.target = target_working(args)?;
+    check_options.cancel = cancel.clone();
+
+    let stage_start = Instant::now();
+    let mut alive_proxies = if args.ping_count > 0 {
+        check_options
+            .events
+            .on_stage_start(Stage::Ping, proxies.len());
+        until_cancelled(cancel, ping_stage(args, proxies, dns_cache)).await?
     } else {
-        resolved_proxies.into_iter().collect::<Vec<_>>()
+        proxies.into_iter().collect::<Vec<_>>()
     };
-
-    let working_proxies = test_proxies_in_chunks(
+    if seed::is_set() {
+        // Resolving and pinging finish in varying order
+        alive_proxies.sort_by_cached_key(ToString::to_string);
+    }
+    summary.stage("ping", alive_proxies.len(), stage_start);
+    summary.dropped("no_ping", tested.len() - alive_proxies.len());
+
+    let stage_start = Instant::now();
+    check_options
+        .events
+        .on_stage_start(Stage::Check, alive_proxies.len());
+    let mut working_proxies = test_proxies_in_chunks(
         &alive_proxies,
-        args.chunk_size,
-        args.base_start_port,
-        request_timeout,
-        args.max_concurrent_checks,
-        args.latency_checklist
-            .split(',')
-            .map(|addr| addr.split_once('@').unwrap_or((addr, "")))
-            .collect(),
-        args.country,
+        &check_options,
+        (args.out_file != "none").then_some(args.out_file.as_str()),
     )
     .await?;
+    let target_reached = check_options
+        .target
+        .as_ref()
+        .is_some_and(|target| target.reached(&working_proxies));
+    if args.retry_variants && !target_reached {
+        let fixed = retry_variants(&alive_proxies, &working_proxies, &check_options).await?;
+        working_proxies.extend(fixed);
+    }
+    summary.stage("check", working_proxies.len(), stage_start);
+    summary.dropped("check_failed", alive_proxies.len() - working_proxies.len());
 
-    log::info!("Found {} working proxies", working_proxies.len());
+    if target_reached {
+        // Rest of proxies wasn't checked, only verdicts of passed ones are known
+        let passed = working_proxies.iter().map(ToString::to_string);
+        seen.record(passed, &working_proxies);
+    } else {
+        seen.record(tested, &working_proxies);
+    }
+    seen.save().await?;
+    Ok(working_proxies)
+}
 
-    let mut sorted_proxies = working_proxies;
-    sorted_proxies.sort_by(|a, b| {
-        let score_a = a.ping.as_secs_f64() / (a.bandwidth as f64);
-        let score_b = b.ping.as_secs_f64() / (b.bandwidth as f64);
-        score_a
-            .partial_cmp(&score_b)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
+fn target_working(args: &Args) -> Result<Option<TargetWorking>> {
+    if args.target_working == 0 {
+        return Ok(None);
+    }
+    let countries = args
+        .target_countries
+        .split(',')
+        .filter(|code| !code.is_empty())
+        .map(|code| {
+            parse_country_code(code)
+                .with_context(|| format!("Invalid country code {code} in --target-countries"))
+        })
+        .collect::<Result<_>>()?;
+    Ok(Some(TargetWorking {
+        count: args.target_working,
+        countries,
+    }))
+}
+
+// Flags implausible TLS server names, fixing obvious mistakes when asked
+fn validate_sni(args: &Args, proxies: HashSet<ProxyConfig>) -> HashSet<ProxyConfig> {
+    let banned = args
+        .banned_sni
+        .split(',')
+        .filter(|domain| !domain.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    let (mut fixed, mut flagged) = (0, 0);
+    let proxies = proxies
+        .into_iter()
+        .map(|mut proxy| {
+            if args.fix_sni && proxy.fix_sni() {
+                fixed += 1;
+            }
+            proxy.sni_issue = proxy.sni_issue(&banned);
+            flagged += usize::from(proxy.sni_issue.is_some());
+            proxy
+        })
+        .collect();
+    log::info!("Fixed SNI of {fixed} proxies, {flagged} still look wrong");
+    proxies
+}
 
-    let results = sorted_proxies
+// Tests variants of failed proxies with common mistakes fixed, returns first
+// working variant of every proxy
+async fn retry_variants(
+    tested: &[ProxyConfig],
+    working: &[ProxyConfig],
+    check_options: &CheckOptions,
+) -> Result<Vec<ProxyConfig>> {
+    let working = working
+        .iter()
+        .map(ToString::to_string)
+        .collect::<HashSet<_>>();
+    // Variant -> (failed proxy index, variant order)
+    let mut origins = ahash::HashMap::default();
+    let variants = tested
         .iter()
+        .filter(|proxy| !working.contains(&proxy.to_string()))
         .enumerate()
-        .map(|(id, proxy)| {
-            let bandwidth_kbps = proxy.bandwidth / 1024;
-            format!(
-                "{proxy}#{} - {} [{}ms] ({} KB/s)",
-                proxy
-                    .country
-                    .map_or_else(|| "Novaprox".to_string(), country_code_to_emoji),
-                id + 1,
-                proxy.ping.as_millis(),
-                bandwidth_kbps
-            )
+        .flat_map(|(i, proxy)| {
+            proxy
+                .variants()
+                .into_iter()
+                .enumerate()
+                .map(move |v| (i, v))
         })
-        .collect::<Vec<_>>()
-        .join("\n");
+        .filter(|(i, (order, variant))| origins.insert(variant.to_string(), (*i, *order)).is_none())
+        .map(|(_, (_, variant))| variant)
+        .collect::<Vec<_>>();
+    log::info!("Retrying {} variants of failed proxies", variants.len());
+
+    let mut best = ahash::HashMap::default();
+    for variant in test_proxies_in_chunks(&variants, check_options, None).await? {
+        let Some(&(origin, order)) = origins.get(&variant.to_string()) else {
+            continue;
+        };
+        best.entry(origin)
+            .and_modify(|(best_order, best_variant)| {
+                if order < *best_order {
+                    (*best_order, *best_variant) = (order, variant.clone());
+                }
+            })
+            .or_insert((order, variant));
+    }
+
+    log::info!("{} failed proxies work with fixes", best.len());
+    Ok(best.into_values().map(|(_, variant)| variant).collect())
+}
+
+async fn ping_stage(
+    args: &Args,
+    proxies: HashSet<ProxyConfig>,
+    dns_cache: Arc<Mutex<DnsCache>>,
+) -> Result<Vec<ProxyConfig>> {
+    let mut ping_options = PingOptions {
+        timeout: args.pipeline.ping_timeout(),
+        delay: Duration::from_millis(args.ping_delay),
+        max_concurrent: args.pipeline.max_concurrent_pings,
+        attempts: args.ping_count,
+        target: args.ping_target,
+        policy: args.ping_policy,
+    };
+    // Containers rarely get raw sockets, TCP connect works everywhere
+    if ping_options.target != PingTarget::Tcp
+        && xray_process::is_containerized()
+        && !icmp_available()
+    {
+        log::warn!("No ICMP in container, pinging proxy ports over TCP instead");
+        ping_options.target = PingTarget::Tcp;
+    }
+    let mut ping_cache = PingCache::new(&args.ping_cache_file, args.ping_cache_ttl);
+    ping_cache.load_cache()?;
+
+    let alive = ping_proxies(proxies, &ping_options, dns_cache, &Mutex::new(ping_cache)).await?;
 
     log::info!(
-        "Time required: {}",
-        humantime::format_duration(elapsed.elapsed())
+        "Found {} alive proxies after ping",
+        alive.iter().filter(|p| !p.no_icmp).count()
     );
+    Ok(alive)
+}
 
-    if args.out_file == "none" {
-        println!("{results}");
+async fn check_clock_skew(args: &Args) {
+    match clock::clock_skew(&args.time_source_url).await {
+        Ok(skew) if skew.unsigned_abs() > args.max_clock_skew_secs => log::warn!(
+            "Local clock is off by {skew}s, every vmess proxy will fail. Sync your clock (e.g. with NTP)"
+        ),
+        Ok(skew) => log::debug!("Local clock skew: {skew}s"),
+        Err(e) => log::warn!("Failed to check clock skew: {e}"),
+    }
+}
+
+async fn check_options(args: &Args) -> Result<CheckOptions> {
+    let anonymity_check = if args.check_anonymity {
+        Some(AnonymityCheck {
+            echo_url: args.anonymity_echo_url.clone(),
+            real_ip: get_real_ip(&args.real_ip_url).await?,
+        })
     } else {
-        fs::write(args.out_file, results)?;
+        None
+    };
+
+    let xray_features = core_features().await?;
+    log::debug!("Xray core {:?}", xray_features.version);
+    if args.stats_api_port.is_some() && !xray_features.stats_query() {
+        return Err(EngineError::TooOld("--stats-api-port").into());
     }
 
-    Ok(())
+    let pipeline = &args.pipeline;
+    let mut options = CheckOptions {
+        request_timeout: pipeline.request_timeout(),
+        retries: pipeline.check_retries,
+        retry_delay: pipeline.check_retry_delay(),
+        timeout_ping_factor: (pipeline.timeout_ping_factor > 0)
+            .then(|| (pipeline.timeout_ping_factor, pipeline.min_request_timeout())),
+        max_concurrent_checks: pipeline.max_concurrent_checks,
+        latency_checklist: parse_checklist(&args.latency_checklist)?,
+        rotation: AtomicUsize::new(0),
+        shuffle_checklist: args.shuffle_checklist,
+        jitter: pipeline.check_jitter(),
+        samples: pipeline.check_samples,
+        user_agent: args.check_user_agent.clone(),
+        headers: parse_headers(&args.check_header)?,
+        country: args.country,
+        country_url: args.country_url.clone(),
+        country_checks: args.country_check.clone(),
+        expect_status: args
+            .check_expect_status
+            .map(StatusCode::from_u16)
+            .transpose()
+            .context("Invalid --check-expect-status")?,
+        expect_body: args
+            .check_expect_body
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("Invalid --check-expect-body regex")?,
+        spki_pins: parse_spki_pins(&args.check_pin_spki)?,
+        drop_tls_mismatch: args.check_pin_drop,
+        anonymity_check,
+        canary_check: args.canary_url.clone().map(|url| CanaryCheck { url }),
+        hold_check: (args.hold_seconds > 0).then(|| HoldCheck {
+            url: args.hold_url.clone(),
+            duration: Duration::from_secs(args.hold_seconds),
+        }),
+        payload_check: payload_check(args).await?,
+        capacity_probe: (args.capacity_connections > 0).then(|| CapacityProbe {
+            url: args.capacity_url.clone(),
+            connections: args.capacity_connections,
+        }),
+        stats_api_port: args.stats_api_port,
+        mux_inbound: args.mux_inbound,
+        inbound_password: rand::rng()
+            .sample_iter(rand::distr::Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect(),
+        xray_features,
+        prefilter: (pipeline.prefilter_timeout_ms > 0).then(|| Prefilter {
+            url: args.prefilter_url.clone(),
+            timeout: pipeline.prefilter_timeout(),
+        }),
+        pipeline: pipeline.clone(),
+        events: Arc::new(LogEvents),
+        target: None,
+        cancel: CancellationToken::new(),
+        exhausted: AtomicUsize::new(0),
+        http3_target: if args.check_http3 {
+            let (host, port) = args
+                .http3_target
+                .rsplit_once(':')
+                .context("--http3-target must be host:port")?;
+            Some((
+                host.to_owned(),
+                port.parse().context("Invalid --http3-target port")?,
+            ))
+        } else {
+            None
+        },
+        sandbox: None,
+    };
+    if args.sandbox_checks {
+        let mut domains = options.check_hosts();
+        domains.extend(args.sandbox_allow.iter().cloned());
+        log::debug!("Proxies may only reach {}", domains.join(", "));
+        options.sandbox = Some(Sandbox {
+            domains,
+            quic_target: options.http3_target.clone(),
+        });
+    }
+    Ok(options)
+}
+
+async fn core_features() -> Result<XrayFeatures> {
+    #[cfg(feature = "mock-engine")]
+    if mock_engine::enabled() {
+        return Ok(mock_engine::FEATURES);
+    }
+    match probe_xray().await {
+        Ok(features) => Ok(features),
+        Err(e @ EngineError::NotFound(..)) => {
+            log::warn!("{e}. Checking only socks and http proxies, natively");
+            native_engine::use_native_engine();
+            Ok(native_engine::FEATURES)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse_headers(headers: &[String]) -> Result<HeaderMap> {
+    headers
+        .iter()
+        .map(|header| {
+            let (name, value) = header
+                .split_once(':')
+                .with_context(|| format!("Header must be `Name: value`: {header}"))?;
+            Ok((
+                HeaderName::from_str(name.trim())?,
+                HeaderValue::from_str(value.trim())?,
+            ))
+        })
+        .collect()
+}
+
+async fn payload_check(args: &Args) -> Result<Option<PayloadCheck>> {
+    if args.payload_bytes == 0 {
+        return Ok(None);
+    }
+    let url = args
+        .payload_url
+        .replace("{bytes}", &args.payload_bytes.to_string());
+    let check = match &args.payload_sha256 {
+        Some(sha256) => PayloadCheck::with_hash(url, sha256, args.payload_timeout)?,
+        None => PayloadCheck::fetch_reference(url, args.payload_timeout).await?,
+    };
+    Ok(Some(check))
+}
+
+async fn get_real_ip(url: &str) -> Result<IpAddr> {
+    let client = ClientBuilder::new()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let body = client
+        .get(url)
+        .send()
+        .await
+        .context("Failed to fetch real IP")?
+        .text()
+        .await?;
+    IpAddr::from_str(body.trim()).context("Real IP endpoint returned invalid address")
+}
+
+async fn filter_by_abuse_score(
+    args: &Args,
+    mut proxies: Vec<ProxyConfig>,
+) -> Result<Vec<ProxyConfig>> {
+    let Some(api_key) = &args.abuseipdb_key else {
+        return Ok(proxies);
+    };
+
+    let mut cache = AbuseCache::new(
+        &args.abuse_cache_file,
+        Duration::from_secs(args.abuse_cache_ttl_hours * 3600),
+    );
+    cache.load_cache()?;
+
+    enrich_abuse_scores(
+        &mut proxies,
+        api_key,
+        &Mutex::new(cache),
+        args.max_concurrent_checks,
+    )
+    .await?;
+
+    if let Some(max_score) = args.max_abuse_score {
+        proxies.retain(|proxy| proxy.abuse_score.is_none_or(|score| score <= max_score));
+        log::info!("{} proxies left after abuse score filter", proxies.len());
+    }
+
+    Ok(proxies)
+}
+
+// Pipeline progress as log lines
+struct LogEvents;
+
+impl EventHandler for LogEvents {
+    fn on_stage_start(&self, stage: Stage, total: usize) {
+        log::debug!("Starting {stage} stage with {total} entries");
+        journal::record(
+            "stage",
+            json!({ "stage": stage.to_string(), "total": total }),
+        );
+    }
+
+    fn on_proxy_result(&self, proxy: &ProxyConfig, working: bool) {
+        trace::record(proxy, "result", if working { "working" } else { "dead" });
+        log::debug!(
+            "Proxy {} is {}",
+            proxy.address,
+            if working { "working" } else { "dead" }
+        );
+    }
+
+    fn on_chunk_done(&self, done: usize, total: usize, working: usize, elapsed: Duration) {
+        journal::record(
+            "chunk_done",
+            json!({
+                "chunk": done,
+                "total": total,
+                "working": working,
+                "elapsed_ms": elapsed.as_millis() as u64,
+            }),
+        );
+        log::info!(
+            "Processed chunk {done}/{total} in {}, {working} working",
+            humantime::format_duration(Duration::from_millis(elapsed.as_millis() as u64))
+        );
+    }
+}
+
+fn parse_links<'a>(args: &Args, lines: impl Iterator<Item = &'a str>) -> Vec<Url> {
+    let mut parser = LinkParser::new(args);
+    lines.flat_map(|line| parser.parse(line)).collect()
+}
+
+/// Parses share links one by one, skipping ones seen before
+struct LinkParser<'a> {
+    scheme: &'a str,
+    param_filters: Vec<(&'a str, &'a str)>,
+    params_remove: Vec<RemoveRule<'a>>,
+    rewrite_rules: &'a [RewriteRule],
+    // Sources overlap a lot, so duplicates are dropped before resolving.
+    // Raw lines are kept as hashes to not hold every line in memory, both
+    // sets take up to an eighth of `--max-memory-mb`
+    seen_lines: SeenHashes,
+    line_hasher: ahash::RandomState,
+    // Hashes of normalized links, so links differing only by name, param
+    // order or case of host are the same proxy
+    seen_urls: SeenHashes,
+    // Lines and links skipped as duplicates of earlier ones
+    duplicates: usize,
+    malformed_reality: usize,
+    // Links not of `--scheme` or filtered out. Counted here, not from lines,
+    // since `--lenient` may find several links on one line
+    rejected: usize,
+    lenient: bool,
+    // Links parsed only after `--lenient` repairs
+    repaired: usize,
+}
+
+impl<'a> LinkParser<'a> {
+    fn new(args: &'a Args) -> Self {
+        Self {
+            scheme: &args.scheme,
+            param_filters: parse_param_filters(if args.whitelist_params == "none" {
+                ""
+            } else {
+                &args.whitelist_params
+            }),
+            params_remove: args
+                .remove_params
+                .split(',')
+                .map(RemoveRule::parse)
+                .collect(),
+            rewrite_rules: &args.rewrite_param,
+            seen_lines: SeenHashes::new(memory_share(args.max_memory_mb, 8)),
+            line_hasher: ahash::RandomState::new(),
+            seen_urls: SeenHashes::new(memory_share(args.max_memory_mb, 8)),
+            duplicates: 0,
+            malformed_reality: 0,
+            rejected: 0,
+            lenient: args.lenient,
+            repaired: 0,
+        }
+    }
+
+    // Links of `line`, more than one when `--lenient` finds several
+    // pasted on it
+    fn parse(&mut self, line: &str) -> Vec<Url> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Vec::new();
+        }
+        if !self.seen_lines.insert(self.line_hasher.hash_one(line)) {
+            self.duplicates += 1;
+            return Vec::new();
+        }
+        if self.lenient {
+            split_links(line)
+                .into_iter()
+                .filter_map(|piece| self.parse_link(piece))
+                .collect()
+        } else {
+            self.parse_link(line).into_iter().collect()
+        }
+    }
+
+    fn parse_link(&mut self, line: &str) -> Option<Url> {
+        let (link, repairs) = if self.lenient {
+            repair_link(line)
+        } else {
+            (line.into(), Vec::new())
+        };
+        let Some(mut url) =
+            parse_proxy_url(&link, self.scheme, &self.param_filters, &self.params_remove)
+        else {
+            trace::record(&line, "parse", "not a link of --scheme or filtered out");
+            self.rejected += 1;
+            return None;
+        };
+        if !repairs.is_empty() {
+            let repairs = repairs.iter().map(ToString::to_string).collect::<Vec<_>>();
+            log::debug!("Repaired {} of {line}", repairs.join(", "));
+            trace::record(
+                &line,
+                "parse",
+                format_args!("repaired {}", repairs.join(", ")),
+            );
+            self.repaired += 1;
+        }
+        for rule in self.rewrite_rules {
+            rule.apply(&mut url);
+        }
+        trace::follow(&line, &url);
+        if let Err(e) = check_reality_params(&url) {
+            log::debug!("Skipping {url}: {e}");
+            trace::record(&url, "parse", format_args!("skipped: {e}"));
+            self.malformed_reality += 1;
+            return None;
+        }
+        if self
+            .seen_urls
+            .insert(self.line_hasher.hash_one(normalize_link(&url).as_str()))
+        {
+            trace::record(&url, "parse", format_args!("parsed as {url}"));
+            Some(url)
+        } else {
+            trace::record(&url, "parse", "skipped as duplicate");
+            self.duplicates += 1;
+            None
+        }
+    }
 }
 
 fn parse_param_filters(params: &str) -> Vec<(&str, &str)> {
@@ -241,8 +1752,19 @@ fn parse_param_filters(params: &str) -> Vec<(&str, &str)> {
         .collect()
 }
 
+pub(crate) fn open_dns_cache(args: &Args) -> Result<Arc<Mutex<DnsCache>>> {
+    let mut dns_cache = DnsCache::new(&args.dns_cache_file);
+    dns_cache.load_cache()?;
+    dns_cache.shared = args
+        .dns_shared_cache
+        .as_deref()
+        .map(SharedDnsCache::new)
+        .transpose()?;
+    Ok(Arc::new(Mutex::new(dns_cache)))
+}
+
 async fn resolve_proxies(
-    urls: Vec<Url>,
+    urls: impl IntoIterator<Item = Url>,
     dns_cache: Arc<Mutex<DnsCache>>,
     max_concurrent_dns: usize,
 ) -> Result<HashSet<ProxyConfig>> {
@@ -262,7 +1784,7 @@ async fn resolve_proxies(
         .collect::<Vec<_>>()
         .await;
 
-    dns_cache.lock().await.save()?;
+    dns_cache.lock().await.save().await?;
 
     Ok(HashSet::from_iter(resolved))
 }
@@ -271,16 +1793,37 @@ async fn resolve_and_create_config(
     url: Url,
     dns_cache: Arc<Mutex<DnsCache>>,
 ) -> Result<Option<ProxyConfig>> {
-    let host = url.host().context("URL has no host")?;
-    let resolved_addr = resolve_host(host, url.port(), dns_cache).await?;
-    Ok(Some(ProxyConfig::from_url(url, resolved_addr)))
+    let host = url.host().ok_or(ResolveError::NoHost)?;
+    let resolved_addr = match resolve_host(host, url.port(), Arc::clone(&dns_cache)).await {
+        Ok(addr) => addr,
+        Err(e) => {
+            trace::record(&url, "resolve", &e);
+            return Err(e.into());
+        }
+    };
+    trace::record(&url, "resolve", format_args!("resolved to {resolved_addr}"));
+    let mut proxy = ProxyConfig::from_url(url.clone(), resolved_addr);
+    if let Some(Host::Domain(domain)) = url.host()
+        && let Some((addr, dual_stack)) = dual_stack::race(domain, proxy.port).await
+    {
+        trace::record(
+            &url,
+            "resolve",
+            format_args!("{} won dual-stack race with {addr}", dual_stack.winner),
+        );
+        dns_cache.lock().await.insert(domain.to_lowercase(), addr);
+        proxy = ProxyConfig::from_url(url.clone(), addr);
+        proxy.dual_stack = Some(dual_stack);
+    }
+    trace::follow(&url, &proxy);
+    Ok(Some(proxy))
 }
 
 async fn resolve_host(
     host: Host<&str>,
     port: Option<u16>,
     dns_cache: Arc<Mutex<DnsCache>>,
-) -> Result<IpAddr> {
+) -> Result<IpAddr, ResolveError> {
     match host {
         Host::Domain(domain) => {
             let domain_lower = domain.to_lowercase();
@@ -289,21 +1832,32 @@ async fn resolve_host(
                 return Ok(addr);
             }
 
-            let cached_addr = dns_cache.lock().await.get(&domain_lower);
+            let (cached_addr, shared) = {
+                let mut dns_cache = dns_cache.lock().await;
+                (dns_cache.get(&domain_lower), dns_cache.shared.clone())
+            };
             if let Some(addr) = cached_addr {
                 return Ok(addr);
             }
+            if let Some(addr) = match &shared {
+                Some(shared) => shared.get(&domain_lower).await,
+                None => None,
+            } {
+                dns_cache.lock().await.insert(domain_lower, addr);
+                return Ok(addr);
+            }
 
-            let resolved_addr = tokio::net::lookup_host((
-                domain_lower.as_str(),
-                port.context("Port required for DNS lookup")?,
-            ))
-            .await
-            .context("DNS lookup failed")?
-            .next()
-            .context("No addresses found")?
-            .ip();
+            let port = port.ok_or_else(|| ResolveError::NoPort(domain_lower.clone()))?;
+            let resolved_addr = tokio::net::lookup_host((domain_lower.as_str(), port))
+                .await
+                .map_err(|e| ResolveError::Lookup(domain_lower.clone(), e))?
+                .next()
+                .ok_or_else(|| ResolveError::NoAddresses(domain_lower.clone()))?
+                .ip();
 
+            if let Some(shared) = &shared {
+                shared.put(&domain_lower, resolved_addr).await;
+            }
             dns_cache.lock().await.insert(domain_lower, resolved_addr);
             Ok(resolved_addr)
         }
@@ -312,92 +1866,245 @@ async fn resolve_host(
     }
 }
 
-async fn ping_proxies(
-    proxies: impl IntoIterator<Item = ProxyConfig>,
-    ping_timeout_ms: u128,
-    ping_delay: u64,
-    max_concurrent_pings: usize,
-    max_attempts: usize,
-) -> Vec<ProxyConfig> {
-    stream::iter(proxies)
-        .map(|mut proxy| async move {
-            for attempt in 0..max_attempts {
-                if let Ok((_, ping)) = surge_ping::ping(proxy.address, &[]).await
-                    && ping.as_millis() < ping_timeout_ms
-                {
-                    proxy.ping = Duration::ZERO; // alive, latency will be measured later
-                    return Some(proxy);
-                }
-                if attempt < max_attempts - 1 {
-                    tokio::time::sleep(Duration::from_millis(ping_delay)).await;
-                }
-            }
-            None
-        })
-        .buffer_unordered(max_concurrent_pings)
-        .filter_map(|x| async { x })
-        .collect()
-        .await
-}
+// Chunks aren't made smaller than this when ports or open files run out
+const MIN_CHUNK_SIZE: usize = 25;
 
+// Working proxies are appended to `partial_out` after every chunk, so they
+// survive a crashed run and can be followed while it goes
 async fn test_proxies_in_chunks(
     alive_proxies: &[ProxyConfig],
-    chunk_size: usize,
-    base_start_port: usize,
-    request_timeout: Duration,
-    max_concurrent_checks: usize,
-    latency_checklist: Vec<(&str, &str)>,
-    country: bool,
+    check_options: &CheckOptions,
+    partial_out: Option<&str>,
+) -> Result<Vec<ProxyConfig>> {
+    #[cfg(feature = "mock-engine")]
+    if mock_engine::enabled() {
+        let engine = mock_engine::MockEngine {
+            base_port: check_options.pipeline.base_start_port,
+            options: check_options,
+        };
+        return test_chunks_with(
+            &engine,
+            alive_proxies,
+            check_options.pipeline.chunk_size,
+            check_options,
+            partial_out,
+        )
+        .await;
+    }
+    if native_engine::enabled() {
+        let engine = native_engine::NativeEngine {
+            base_port: check_options.pipeline.base_start_port,
+            options: check_options,
+        };
+        return test_chunks_with(
+            &engine,
+            alive_proxies,
+            check_options.pipeline.chunk_size,
+            check_options,
+            partial_out,
+        )
+        .await;
+    }
+    let engine = XrayEngine {
+        base_port: check_options.pipeline.base_start_port,
+        options: check_options,
+    };
+    test_chunks_with(
+        &engine,
+        alive_proxies,
+        check_options.pipeline.chunk_size,
+        check_options,
+        partial_out,
+    )
+    .await
+}
+
+async fn test_chunks_with(
+    engine: &impl TestEngine,
+    alive_proxies: &[ProxyConfig],
+    mut chunk_size: usize,
+    check_options: &CheckOptions,
+    partial_out: Option<&str>,
 ) -> Result<Vec<ProxyConfig>> {
     let mut all_working = Vec::new();
-    let total_chunks = alive_proxies.len().div_ceil(chunk_size);
+    let clients = PortClients::new(engine, chunk_size, check_options)?;
+    let mut partial_out = match partial_out {
+        Some(path) => Some(
+            tokio::fs::File::create(path)
+                .await
+                .context("Failed to create out file")?,
+        ),
+        None => None,
+    };
 
-    for (chunk_index, chunk) in alive_proxies.chunks(chunk_size).enumerate() {
-        let base_port = base_start_port + chunk_index * chunk_size;
-        let config = generate_xray_config(chunk, base_port)?;
+    // Feature and protocol of skipped proxies -> their count
+    let mut skipped = BTreeMap::<_, usize>::new();
+    let supported = alive_proxies
+        .iter()
+        .filter(|proxy| {
+            let missing = engine.missing_feature(proxy);
+            if let Some(feature) = missing {
+                log::debug!("Skipping {proxy}, core lacks {feature}");
+                *skipped
+                    .entry((feature, proxy.protocol.as_str()))
+                    .or_default() += 1;
+            }
+            missing.is_none()
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    for ((feature, protocol), count) in skipped {
+        log::warn!(
+            "Skipped {count} {protocol} proxies, core lacks {feature}, install or update xray to check them"
+        );
+    }
 
-        let mut xray_process = start_xray_with_config(&config).await?;
-        tokio::time::sleep(Duration::from_millis(500)).await;
+    // Stack of chunks left, rejected chunks are split back into it
+    let mut pending = supported
+        .chunks(chunk_size)
+        .rev()
+        .map(<[_]>::to_vec)
+        .collect::<Vec<_>>();
+    let mut processed = 0;
 
-        if let Some(exit) = xray_process.try_wait()? {
-            log::warn!("Xray exited: {exit}");
-            if let Some(stdout) = &mut xray_process.stdout {
-                let mut out = String::new();
-                stdout.read_to_string(&mut out).await?;
-                log::warn!("Stdout: {out}");
-            }
-            continue;
+    while let Some(chunk) = pending.pop() {
+        if check_options.cancel.is_cancelled() {
+            return Err(CheckError::Cancelled.into());
         }
+        let chunk_start = Instant::now();
+        journal::record(
+            "chunk_start",
+            json!({
+                "chunk": processed + 1,
+                "pending": pending.len(),
+                "proxies": chunk
+                    .iter()
+                    .map(|proxy| SocketAddr::new(proxy.address, proxy.port).to_string())
+                    .collect::<Vec<_>>(),
+            }),
+        );
+        let Some(mut process) = start_chunk(engine, &chunk, &mut pending).await? else {
+            continue;
+        };
 
-        let working_chunk = test_proxy_chunk(
-            chunk,
-            base_port,
-            request_timeout,
-            max_concurrent_checks,
-            &latency_checklist,
-            country,
-        )
-        .await;
+        let Some(working_chunk) = check_options
+            .cancel
+            .run_until_cancelled(test_proxy_chunk(&chunk, &clients, check_options))
+            .await
+        else {
+            engine.stop(&mut process).await;
+            return Err(CheckError::Cancelled.into());
+        };
+        if let Some(file) = &mut partial_out
+            && !working_chunk.is_empty()
+        {
+            file.write_all(format!("{}\n", format_results(&working_chunk)).as_bytes())
+                .await?;
+            file.flush().await?;
+        }
+        if check_options.exhausted.swap(0, AtomicOrdering::Relaxed) > 0
+            && chunk_size > MIN_CHUNK_SIZE
+        {
+            shrink_chunks(&mut pending, chunk, &working_chunk, &mut chunk_size);
+        }
+        processed += 1;
+        check_options.events.on_chunk_done(
+            processed,
+            processed + pending.len(),
+            working_chunk.len(),
+            chunk_start.elapsed(),
+        );
         all_working.extend(working_chunk);
 
-        log::info!("Processed chunk {}/{}", chunk_index + 1, total_chunks);
-
-        xray_process.kill().await.ok();
+        engine.stop(&mut process).await;
+        journal::record("chunk_stopped", json!({ "chunk": processed }));
+
+        if let Some(target) = &check_options.target
+            && target.reached(&all_working)
+        {
+            log::info!(
+                "Found enough working proxies, skipping {} chunks",
+                pending.len()
+            );
+            break;
+        }
     }
 
     Ok(all_working)
 }
 
+// Starts core of chunk and follows its log for traced proxies, or splits
+// chunk back into `pending` when core rejected it
+async fn start_chunk<E: TestEngine>(
+    engine: &E,
+    chunk: &[ProxyConfig],
+    pending: &mut Vec<Vec<ProxyConfig>>,
+) -> Result<Option<E::Process>> {
+    let config = engine.generate_config(chunk)?;
+    let mut process = engine.start(&config).await?;
+    let traced = chunk
+        .iter()
+        .filter(trace::traced)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if let Err(out) = engine.ready(&mut process).await? {
+        journal::record(
+            "chunk_rejected",
+            json!({ "size": chunk.len(), "output": out }),
+        );
+        for proxy in &traced {
+            trace::record(proxy, "xray", &out);
+        }
+        engine.split_rejected(pending, chunk.to_vec(), &out);
+        return Ok(None);
+    }
+    if !traced.is_empty() {
+        engine.collect_logs(&mut process, move |line| {
+            for proxy in &traced {
+                if line.contains(&proxy.address.to_string()) {
+                    trace::record(proxy, "xray", line);
+                }
+            }
+        });
+    }
+    Ok(Some(process))
+}
+
+// Splits chunks left into halved ones, with failed proxies of `chunk`
+// checked again last
+fn shrink_chunks(
+    pending: &mut Vec<Vec<ProxyConfig>>,
+    chunk: Vec<ProxyConfig>,
+    working: &[ProxyConfig],
+    chunk_size: &mut usize,
+) {
+    *chunk_size = (*chunk_size / 2).max(MIN_CHUNK_SIZE);
+    log::warn!("Ran out of local ports or open files, checking again in chunks of {chunk_size}");
+    let working = working
+        .iter()
+        .map(ToString::to_string)
+        .collect::<HashSet<_>>();
+    let left = std::mem::take(pending)
+        .into_iter()
+        .rev()
+        .flatten()
+        .chain(
+            chunk
+                .into_iter()
+                .filter(|proxy| !working.contains(&proxy.to_string())),
+        )
+        .collect::<Vec<_>>();
+    *pending = left.chunks(*chunk_size).rev().map(<[_]>::to_vec).collect();
+}
+
 async fn start_xray_with_config(config: &str) -> Result<tokio::process::Child> {
     #[cfg(debug_assertions)]
     fs::write(CONFIG_FILE, config).context("Failed to write Xray config")?;
 
-    let mut command = Command::new("xray")
-        .args(["run", "-config", "stdin:"])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .context("Failed to start Xray")?;
+    let mut command =
+        spawn_xray(xray_process::engine().run_args()).context("Failed to start Xray")?;
+    journal::record("spawn", json!({ "pid": command.id() }));
 
     if let Some(mut stdin) = command.stdin.take() {
         stdin
@@ -410,124 +2117,45 @@ async fn start_xray_with_config(config: &str) -> Result<tokio::process::Child> {
     Ok(command)
 }
 
-async fn test_proxy_chunk(
-    chunk: &[ProxyConfig],
-    base_port: usize,
-    request_timeout: Duration,
-    max_concurrent_checks: usize,
-    latency_checklist: &[(&str, &str)],
-    country: bool,
-) -> Vec<ProxyConfig> {
-    stream::iter(chunk.iter().enumerate())
-        .map(|(i, proxy)| {
-            let port = base_port + i;
-            let checklist: Vec<(String, String)> = latency_checklist
-                .iter()
-                .map(|(d, ua)| (d.to_string(), ua.to_string()))
-                .collect();
-
-            async move {
-                let proxy_url = format!("socks5://127.0.0.1:{port}");
-                let proxy_client = reqwest::Proxy::all(proxy_url).ok()?;
-                let client = Client::builder()
-                    .timeout(request_timeout)
-                    .proxy(proxy_client)
-                    .build()
-                    .ok()?;
-
-                let mut total_duration = Duration::ZERO;
-                let mut total_bytes = 0u64;
-                let mut success_count = 0;
-
-                for (domain, user_agent) in checklist {
-                    let mut req = client.get(format!("https://{domain}"));
-                    if !user_agent.is_empty() {
-                        req = req.header("User-Agent", user_agent);
-                    }
-
-                    let start = std::time::Instant::now();
-                    let Ok(resp) = req.send().await else {
-                        return None;
-                    };
-
-                    if !resp.status().is_success() {
-                        return None;
-                    }
-
-                    let Ok(body) = resp.bytes().await else {
-                        return None;
-                    };
-
-                    let elapsed = start.elapsed();
-                    total_duration += elapsed;
-                    total_bytes += body.len() as u64;
-                    success_count += 1;
-                }
-
-                let avg_latency = total_duration / success_count as u32;
-                let avg_bandwidth = if total_duration.as_secs_f64() > 0.0 {
-                    (total_bytes as f64 / total_duration.as_secs_f64()) as u64
-                } else {
-                    0
-                };
-
-                let mut working_proxy = proxy.clone();
-                working_proxy.ping = avg_latency;
-                working_proxy.bandwidth = avg_bandwidth;
-
-                if country
-                    && let Ok(r) = client.get("https://ipinfo.io/json").send().await
-                    && let Ok(c) = r.text().await
-                    && let Some(mut start) = c.find("\"country\": \"")
-                {
-                    start += 12;
-                    let chars = c[start..start + 2].chars().collect::<Vec<_>>();
-                    working_proxy.country = Some([chars[0], chars[1]]);
-                } else {
-                    return None;
-                }
+#[cfg(test)]
+mod tests {
+    use url::Url;
 
-                log::debug!(
-                    "Proxy {} avg latency: {}ms, avg bandwidth: {} B/s",
-                    working_proxy.address,
-                    avg_latency.as_millis(),
-                    avg_bandwidth
-                );
-                Some(working_proxy)
-            }
-        })
-        .buffer_unordered(max_concurrent_checks)
-        .filter_map(|x| async { x })
-        .collect()
-        .await
-}
+    use super::*;
 
-async fn get_proxies_from_sources(sources: &str) -> Result<String> {
-    let client = ClientBuilder::new()
-        .timeout(Duration::from_secs(10))
-        .build()?;
-    let fetch_tasks: Vec<_> = sources
-        .lines()
-        .filter(|line| line.starts_with("https://"))
-        .map(|url| {
-            let value = client.clone();
-            async move {
-                let data = value
-                    .get(url)
-                    .send()
-                    .and_then(|r| async { r.text().await })
-                    .await;
-                log::info!("Loaded source: {url}");
-                data
-            }
-        })
-        .collect();
+    fn proxy(ping_ms: u64, bandwidth: u64) -> ProxyConfig {
+        let url = Url::parse("trojan://secret@192.0.2.1:443").unwrap();
+        let mut proxy = ProxyConfig::from_url(url, "192.0.2.1".parse().unwrap());
+        proxy.ping = Duration::from_millis(ping_ms);
+        proxy.bandwidth = bandwidth;
+        proxy
+    }
 
-    let responses = futures::future::join_all(fetch_tasks)
-        .await
-        .into_iter()
-        .filter_map(|x| x.ok())
-        .collect::<Vec<_>>();
+    #[test]
+    fn score_ranks_unmeasured_bandwidth_last() {
+        let mut proxies = vec![
+            proxy(0, 0),
+            proxy(100, 0),
+            proxy(200, 1000),
+            proxy(100, 1000),
+        ];
+        sort_proxies(&mut proxies, &[SortKey::Score, SortKey::Ping]);
+        let order = proxies
+            .iter()
+            .map(|proxy| (proxy.ping.as_millis(), proxy.bandwidth))
+            .collect::<Vec<_>>();
+        assert_eq!(order, [(100, 1000), (200, 1000), (0, 0), (100, 0)]);
+    }
 
-    Ok(responses.join("\n"))
+    #[test]
+    fn lenient_line_counts_each_pasted_link() {
+        let args = Args::parse_from(["novaprox", "--scheme", "vless", "--lenient"]);
+        let mut parser = LinkParser::new(&args);
+        let line = "vless://a@192.0.2.1:443 vless://a@192.0.2.1:443 trojan://b@192.0.2.2:443";
+        assert_eq!(parser.parse(line).len(), 1);
+        assert_eq!(parser.parse("").len(), 0);
+        assert_eq!(parser.parse("  ").len(), 0);
+        assert_eq!(parser.parse(line).len(), 0);
+        assert_eq!((parser.duplicates, parser.rejected), (2, 1));
+    }
 }
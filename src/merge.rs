@@ -0,0 +1,127 @@
+use std::path::Path;
+
+use ahash::HashMap;
+use anyhow::{Context as _, Result};
+use regex::Regex;
+
+use crate::VANTAGE_PREFIX;
+
+#[derive(clap::Args, Debug)]
+pub struct MergeArgs {
+    // Result lists as `[location=]file`, location defaults to `--vantage`
+    // header of the file, then to file name
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    // CSV with latency of every proxy from every location
+    #[arg(long, default_value = "merged.csv")]
+    merged_file: String,
+}
+
+// Latency in ms of every proxy from every location, by input order
+struct Merged {
+    locations: Vec<String>,
+    proxies: Vec<(String, Vec<Option<u64>>)>,
+}
+
+impl Merged {
+    fn add(&mut self, location: String, list: &str) -> Result<()> {
+        let ping = Regex::new(r"\[(\d+)ms\]")?;
+        let column = self.locations.len();
+        self.locations.push(location);
+
+        let mut index = self
+            .proxies
+            .iter()
+            .enumerate()
+            .map(|(i, (link, _))| (link.clone(), i))
+            .collect::<HashMap<_, _>>();
+        for line in list.lines().filter(|line| !line.starts_with('#')) {
+            let link = line.split_once('#').map_or(line, |(link, _)| link);
+            if link.is_empty() {
+                continue;
+            }
+            let latency = ping
+                .captures(line)
+                .and_then(|captures| captures[1].parse().ok())
+                .unwrap_or_default();
+            let i = *index.entry(link.to_owned()).or_insert_with(|| {
+                self.proxies.push((link.to_owned(), Vec::new()));
+                self.proxies.len() - 1
+            });
+            let row = &mut self.proxies[i].1;
+            row.resize(column + 1, None);
+            row[column] = Some(latency);
+        }
+        Ok(())
+    }
+
+    fn into_csv(mut self) -> String {
+        let columns = self.locations.len();
+        for (_, row) in &mut self.proxies {
+            row.resize(columns, None);
+        }
+        // Available from most locations first, then by average latency
+        self.proxies.sort_by_key(|(_, row)| {
+            let latencies = row.iter().flatten().collect::<Vec<_>>();
+            (
+                std::cmp::Reverse(latencies.len()),
+                latencies.iter().copied().sum::<u64>() / latencies.len().max(1) as u64,
+            )
+        });
+
+        let mut csv = format!("link,{},available\n", self.locations.join(","));
+        for (link, row) in &self.proxies {
+            let cells = row
+                .iter()
+                .map(|latency| latency.map(|ms| ms.to_string()).unwrap_or_default())
+                .collect::<Vec<_>>();
+            let available = row.iter().flatten().count();
+            csv += &format!("\"{link}\",{},{available}/{columns}\n", cells.join(","));
+        }
+        csv
+    }
+}
+
+/// Merges result lists of several locations into a CSV with latency
+/// column per location, empty where proxy didn't work
+///
+/// # Errors
+/// Return error if failed to read inputs or write CSV
+pub async fn run_merge(merge: &MergeArgs) -> Result<()> {
+    let mut merged = Merged {
+        locations: Vec::new(),
+        proxies: Vec::new(),
+    };
+    for input in &merge.inputs {
+        let (location, path) = match input.split_once('=') {
+            Some((location, path)) => (Some(location.to_owned()), path),
+            None => (None, input.as_str()),
+        };
+        let list = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {path}"))?;
+        let location = location
+            .or_else(|| {
+                list.lines()
+                    .find_map(|line| line.strip_prefix(VANTAGE_PREFIX))
+                    .map(str::to_owned)
+            })
+            .unwrap_or_else(|| {
+                Path::new(path).file_stem().map_or_else(
+                    || path.to_owned(),
+                    |stem| stem.to_string_lossy().into_owned(),
+                )
+            });
+        merged.add(location, &list)?;
+    }
+
+    log::info!(
+        "Merged {} proxies from {} locations",
+        merged.proxies.len(),
+        merged.locations.len()
+    );
+    tokio::fs::write(&merge.merged_file, merged.into_csv())
+        .await
+        .context("Failed to write merged results")
+}
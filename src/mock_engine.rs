@@ -0,0 +1,132 @@
+//! In-process stand-in for xray, so the whole pipeline runs in tests without
+//! a core or internet access.
+//!
+//! Every chunk proxy gets a SOCKS5 relay on its endpoint. A proxy works when
+//! its server accepts TCP connections, then requests go straight to their
+//! destination, so checklist and country URLs should point at local
+//! fixtures.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Result;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::{
+    checker::{CheckOptions, LocalInbound},
+    engine::TestEngine,
+    native_engine::{CONNECTION_REFUSED, HOST_UNREACHABLE, SUCCEEDED, accept_request, reply},
+    proxy_config::ProxyConfig,
+    xray_config::{Engine, INBOUND_USER},
+    xray_process::XrayFeatures,
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Checks every following chunk through mock engine instead of xray
+pub fn use_mock_engine() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[must_use]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Mock engine runs every proxy, whatever its protocol
+pub const FEATURES: XrayFeatures = XrayFeatures {
+    engine: Engine::Xray,
+    version: (u32::MAX, 0, 0),
+};
+
+pub struct MockEngine<'a> {
+    pub base_port: usize,
+    pub options: &'a CheckOptions,
+}
+
+impl TestEngine for MockEngine<'_> {
+    type Process = Vec<JoinHandle<()>>;
+
+    fn missing_feature(&self, _: &ProxyConfig) -> Option<&'static str> {
+        None
+    }
+
+    // Config is the list of proxy servers, in chunk order
+    fn generate_config(&self, chunk: &[ProxyConfig]) -> Result<String> {
+        let servers = chunk
+            .iter()
+            .map(|proxy| SocketAddr::new(proxy.address, proxy.port).to_string())
+            .collect::<Vec<_>>();
+        Ok(serde_json::to_string(&servers)?)
+    }
+
+    async fn start(&self, config: &str) -> Result<Self::Process> {
+        let servers = serde_json::from_str::<Vec<SocketAddr>>(config)?;
+        let mut relays = Vec::with_capacity(servers.len());
+        for (i, server) in servers.into_iter().enumerate() {
+            let listener =
+                TcpListener::bind(("127.0.0.1", u16::try_from(self.base_port + i)?)).await?;
+            let password = self.options.inbound_password.clone();
+            relays.push(tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let password = password.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = relay(stream, server, &password).await {
+                            log::debug!("Mock relay of {server} failed: {e}");
+                        }
+                    });
+                }
+            }));
+        }
+        Ok(relays)
+    }
+
+    async fn ready(&self, _: &mut Self::Process) -> Result<Result<(), String>> {
+        Ok(Ok(()))
+    }
+
+    fn endpoint(&self, index: usize) -> LocalInbound {
+        LocalInbound {
+            port: self.base_port + index,
+            user: INBOUND_USER.to_owned(),
+            password: self.options.inbound_password.clone(),
+        }
+    }
+
+    fn collect_logs(&self, _: &mut Self::Process, _: impl FnMut(&str) + Send + 'static) {}
+
+    fn split_rejected(&self, _: &mut Vec<Vec<ProxyConfig>>, chunk: Vec<ProxyConfig>, _: &str) {
+        log::warn!("Mock engine rejected chunk of {}", chunk.len());
+    }
+
+    async fn stop(&self, relays: &mut Self::Process) {
+        for relay in relays.drain(..) {
+            relay.abort();
+            relay.await.ok();
+        }
+    }
+}
+
+// Serves one SOCKS5 connection, CONNECT goes straight to destination
+async fn relay(mut client: TcpStream, server: SocketAddr, password: &str) -> io::Result<()> {
+    let Some((host, port)) = accept_request(&mut client, password).await? else {
+        return Ok(());
+    };
+
+    // Dead proxy server fails every request, like a real one would
+    if TcpStream::connect(server).await.is_err() {
+        return reply(&mut client, CONNECTION_REFUSED).await;
+    }
+    let Ok(mut target) = TcpStream::connect((host.as_str(), port)).await else {
+        return reply(&mut client, HOST_UNREACHABLE).await;
+    };
+    reply(&mut client, SUCCEEDED).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut target).await?;
+    Ok(())
+}
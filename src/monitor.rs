@@ -0,0 +1,562 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use ahash::{HashMap, HashMapExt as _};
+use anyhow::{Context as _, Result};
+use futures::{StreamExt as _, stream};
+use url::Url;
+
+use crate::{
+    Args,
+    alerts::Alerter,
+    cache_format::{CacheFile, Writer},
+    check_options,
+    checker::CheckOptions,
+    error::CheckError,
+    format_results, open_dns_cache,
+    proxy_config::ProxyConfig,
+    resolve_and_create_config, results_header, start_health, test_proxies_in_chunks, write_results,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct MonitorArgs {
+    list_file: String,
+
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    interval: Duration,
+
+    #[arg(long, default_value = "uptime.txt")]
+    uptime_file: String,
+
+    // Alerts are sent as `{"text": "..."}` POST to this url
+    #[arg(long)]
+    alert_webhook: Option<String>,
+
+    #[arg(long, requires = "telegram_chat_id")]
+    telegram_token: Option<String>,
+
+    #[arg(long)]
+    telegram_chat_id: Option<String>,
+
+    // Proxy (as written in list, without `#name`) to alert about when it
+    // goes down, repeatable
+    #[arg(long)]
+    pin: Vec<String>,
+
+    // Alert when less than this percent of proxies is up
+    #[arg(long)]
+    min_availability: Option<u8>,
+
+    // Alert when average latency grows more than this percent between checks
+    #[arg(long)]
+    max_latency_regression: Option<u32>,
+}
+
+#[derive(Default)]
+struct RoundSummary {
+    up: ahash::HashSet<String>,
+    availability: u8,
+    avg_latency: Option<Duration>,
+}
+
+impl MonitorArgs {
+    // Files kept between runs, moved into `--artifacts-dir`
+    pub(crate) fn artifact_paths(&mut self) -> Vec<&mut String> {
+        vec![&mut self.uptime_file]
+    }
+
+    async fn alert_changes(&self, alerter: &Alerter, prev: &RoundSummary, cur: &RoundSummary) {
+        for pin in &self.pin {
+            if prev.up.contains(pin) && !cur.up.contains(pin) {
+                alerter
+                    .send(&format!("Pinned proxy went down: {pin}"))
+                    .await;
+            }
+        }
+
+        if let Some(min) = self.min_availability
+            && cur.availability < min
+            && prev.availability >= min
+        {
+            alerter
+                .send(&format!(
+                    "Availability dropped to {}% (threshold {min}%)",
+                    cur.availability
+                ))
+                .await;
+        }
+
+        if let Some(max_regression) = self.max_latency_regression
+            && let (Some(prev_latency), Some(cur_latency)) = (prev.avg_latency, cur.avg_latency)
+            && cur_latency.as_millis() * 100
+                > prev_latency.as_millis() * u128::from(100 + max_regression)
+        {
+            alerter
+                .send(&format!(
+                    "Average latency regressed from {}ms to {}ms",
+                    prev_latency.as_millis(),
+                    cur_latency.as_millis()
+                ))
+                .await;
+        }
+    }
+}
+
+/// Checks and successful checks of every monitored proxy, kept between runs
+pub struct UptimeStats {
+    stats: HashMap<String, (u32, u32)>,
+    stats_file: String,
+}
+
+impl UptimeStats {
+    #[must_use]
+    pub fn new(stats_file: &str) -> Self {
+        Self {
+            stats: HashMap::new(),
+            stats_file: stats_file.to_owned(),
+        }
+    }
+
+    /// # Errors
+    /// Return error if failed to read file
+    pub fn load(&mut self) -> Result<()> {
+        if !Path::new(&self.stats_file).exists() {
+            return Ok(());
+        }
+
+        let data = fs::read(&self.stats_file).context("Failed to read uptime stats")?;
+        self.stats = match CacheFile::detect(&data).context("Failed to read uptime stats")? {
+            CacheFile::Binary(reader) => reader
+                .records(|r| Some((r.str()?, (r.u32()?, r.u32()?))))
+                .collect(),
+            CacheFile::Text(text) => text
+                .lines()
+                .filter_map(|line| {
+                    let mut parts = line.split_whitespace();
+                    let proxy = parts.next()?;
+                    let checks = parts.next()?.parse().ok()?;
+                    let successes = parts.next()?.parse().ok()?;
+                    Some((proxy.to_owned(), (checks, successes)))
+                })
+                .collect(),
+        };
+
+        Ok(())
+    }
+
+    pub fn record(&mut self, proxy: &str, success: bool) {
+        let (checks, successes) = self.stats.entry(proxy.to_owned()).or_default();
+        *checks += 1;
+        *successes += u32::from(success);
+    }
+
+    /// Checks and successful checks of every proxy
+    pub fn counts(&self) -> impl Iterator<Item = (&str, u32, u32)> {
+        self.stats
+            .iter()
+            .map(|(proxy, (checks, successes))| (proxy.as_str(), *checks, *successes))
+    }
+
+    /// Adds counts of checks made elsewhere, e.g. on another machine
+    pub fn merge(&mut self, proxy: String, checks: u32, successes: u32) {
+        let (known_checks, known_successes) = self.stats.entry(proxy).or_default();
+        *known_checks = known_checks.saturating_add(checks);
+        *known_successes = known_successes.saturating_add(successes.min(checks));
+    }
+
+    /// Uptime in percents, if proxy was ever checked
+    #[must_use]
+    pub fn uptime(&self, proxy: &str) -> Option<u8> {
+        self.stats
+            .get(proxy)
+            .filter(|(checks, _)| *checks > 0)
+            .map(|(checks, successes)| (successes * 100 / checks) as u8)
+    }
+
+    /// # Errors
+    /// Return error if failed to save file
+    pub async fn save(&self) -> Result<()> {
+        let mut content = Writer::new();
+        for (proxy, (checks, successes)) in &self.stats {
+            content.str(proxy);
+            content.u32(*checks);
+            content.u32(*successes);
+        }
+
+        tokio::fs::write(&self.stats_file, content.finish())
+            .await
+            .context("Failed to save uptime stats")
+    }
+}
+
+// Files applied again before the next round when they change: the list,
+// which also gets edited by hand, and `--config` with check settings
+struct Watch {
+    files: Vec<(String, Option<SystemTime>)>,
+}
+
+impl Watch {
+    fn new(files: &[&str]) -> Self {
+        Self {
+            files: files
+                .iter()
+                .map(|path| ((*path).to_owned(), modified(path)))
+                .collect(),
+        }
+    }
+
+    // Files changed since last call
+    fn changed(&mut self) -> Vec<String> {
+        let mut changed = Vec::new();
+        for (path, seen) in &mut self.files {
+            let current = modified(path);
+            if current != *seen {
+                log::info!("{path} changed");
+                *seen = current;
+                changed.push(path.clone());
+            }
+        }
+        changed
+    }
+
+    // Whether `path` changed since last seen, without taking it as seen
+    fn edited(&self, path: &str) -> bool {
+        self.files
+            .iter()
+            .any(|(watched, seen)| watched == path && modified(path) != *seen)
+    }
+
+    // Writes `content` to `path` unless it was edited during the round,
+    // those edits are applied before the next one instead of overwritten
+    async fn write(&mut self, path: &str, content: String) -> Result<()> {
+        if self.edited(path) {
+            log::info!("{path} edited during check, not rewriting it");
+            return Ok(());
+        }
+        tokio::fs::write(path, content)
+            .await
+            .context("Failed to write monitored list")?;
+        for (watched, seen) in &mut self.files {
+            if watched == path {
+                *seen = modified(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+// SIGHUP asks for reload before the next round, even of unchanged files
+fn reload_on_hangup() -> Arc<AtomicBool> {
+    let requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let requested = Arc::clone(&requested);
+        match signal(SignalKind::hangup()) {
+            Ok(mut hangups) => {
+                tokio::spawn(async move {
+                    while hangups.recv().await.is_some() {
+                        log::info!("Got SIGHUP, reloading before next check");
+                        requested.store(true, Ordering::Relaxed);
+                    }
+                });
+            }
+            Err(e) => log::warn!("Failed to listen for SIGHUP: {e}"),
+        }
+    }
+    requested
+}
+
+pub(crate) async fn load_list(list_file: &str, args: &Args) -> Result<Vec<ProxyConfig>> {
+    let dns_cache = open_dns_cache(args)?;
+
+    let list = fs::read_to_string(list_file).context("Failed to read monitored list")?;
+    let proxies = stream::iter(list.lines().filter_map(|line| Url::parse(line).ok()))
+        .map(|url| resolve_and_create_config(url, Arc::clone(&dns_cache)))
+        .buffer_unordered(args.pipeline.max_concurrent_dns)
+        .filter_map(|result| async { result.ok().flatten() })
+        .collect::<Vec<_>>()
+        .await;
+
+    dns_cache.lock().await.save().await?;
+    Ok(proxies)
+}
+
+// Applies edited list and, when `options` are passed, config file. Monitor
+// options stay as started. Last results of proxies still listed are kept
+async fn reload(
+    args: &Args,
+    list_file: &str,
+    proxies: &mut Vec<ProxyConfig>,
+    options: Option<&mut CheckOptions>,
+    latest: &mut HashMap<String, ProxyConfig>,
+) {
+    let reloaded = async {
+        let Some(options) = options else {
+            return anyhow::Ok((load_list(list_file, args).await?, None));
+        };
+        let args = Args::reload()?;
+        let proxies = load_list(list_file, &args).await?;
+        Ok((proxies, Some((options, check_options(&args).await?))))
+    };
+    match reloaded.await {
+        Ok((reloaded, options)) => {
+            *proxies = reloaded;
+            if let Some((options, reloaded)) = options {
+                *options = reloaded;
+            }
+            keep_listed(latest, proxies);
+            log::info!("Reloaded, monitoring {} proxies", proxies.len());
+        }
+        Err(e) => log::warn!("Reload failed, keeping previous settings: {e:#}"),
+    }
+}
+
+// Drops results of proxies no longer listed and adds new ones, which have
+// none yet
+fn keep_listed(latest: &mut HashMap<String, ProxyConfig>, proxies: &[ProxyConfig]) {
+    let listed = proxies
+        .iter()
+        .map(ToString::to_string)
+        .collect::<ahash::HashSet<_>>();
+    latest.retain(|key, _| listed.contains(key));
+    for proxy in proxies {
+        latest
+            .entry(proxy.to_string())
+            .or_insert_with(|| proxy.clone());
+    }
+}
+
+// Rewrites list ordered by uptime. List is read back, so it stays links and
+// other formats go to `--out-file`
+async fn write_round(
+    args: &Args,
+    list_file: &str,
+    watch: &mut Watch,
+    latest: &HashMap<String, ProxyConfig>,
+    uptime: &UptimeStats,
+) -> Result<()> {
+    let mut ordered = latest
+        .iter()
+        .map(|(key, proxy)| {
+            let mut proxy = proxy.clone();
+            proxy.uptime = uptime.uptime(key);
+            proxy
+        })
+        .collect::<Vec<_>>();
+    ordered.sort_by_key(|proxy| (std::cmp::Reverse(proxy.uptime), proxy.ping));
+
+    watch.write(list_file, format_results(&ordered)).await?;
+    if args.format.name() != "urls" && args.out_file != "none" {
+        let results = args.format.render(&ordered, &results_header(args))?;
+        write_results(args, &results).await?;
+    }
+    Ok(())
+}
+
+/// Repeatedly checks proxies of existing result list and rewrites it
+/// ordered by uptime. Changes of the list and config file (or SIGHUP) are
+/// applied before the next check, keeping uptime and last results
+///
+/// # Errors
+/// Return error if list can't be read or written
+pub(crate) async fn run_monitor(args: &Args, monitor: &MonitorArgs) -> Result<()> {
+    let list_file = &monitor.list_file;
+    if args.format.name() != "urls" && args.out_file == *list_file {
+        anyhow::bail!(
+            "--format {:?} would overwrite monitored list, pass another --out-file",
+            args.format
+        );
+    }
+    let mut proxies = load_list(list_file, args).await?;
+    log::info!("Monitoring {} proxies from {list_file}", proxies.len());
+
+    let mut check_options = check_options(args).await?;
+    let mut watch = Watch::new(&[list_file, &args.config]);
+    let hangup = reload_on_hangup();
+    let mut uptime = UptimeStats::new(&monitor.uptime_file);
+    uptime.load()?;
+    let alerter = Alerter::new(
+        monitor.alert_webhook.clone(),
+        monitor
+            .telegram_token
+            .clone()
+            .zip(monitor.telegram_chat_id.clone()),
+    )?;
+    let mut prev_round = None;
+    // Last successful result of every proxy, so failing ones keep metrics
+    let mut latest = proxies
+        .iter()
+        .map(|proxy| (proxy.to_string(), proxy.clone()))
+        .collect::<HashMap<_, _>>();
+
+    let health = start_health(args);
+
+    loop {
+        let changed = watch.changed();
+        let hung_up = hangup.swap(false, Ordering::Relaxed);
+        if hung_up || !changed.is_empty() {
+            // Options take a core probe and DNS and geo setup, so they're
+            // only rebuilt when their settings may have changed
+            let options = (hung_up || changed.contains(&args.config)).then_some(&mut check_options);
+            reload(args, list_file, &mut proxies, options, &mut latest).await;
+        }
+
+        let working = match test_proxies_in_chunks(&proxies, &check_options, None).await {
+            Ok(working) => working,
+            Err(e) => {
+                log::error!("Check round failed: {e:#}");
+                if let Ok(mut health) = health.lock() {
+                    health.failure(&e);
+                }
+                tokio::time::sleep(monitor.interval).await;
+                continue;
+            }
+        };
+        if let Ok(mut health) = health.lock() {
+            if working.is_empty() && !proxies.is_empty() {
+                // Every proxy dying at once is more likely a local problem
+                health.failure(
+                    &CheckError::NoneWorking {
+                        tested: proxies.len(),
+                    }
+                    .into(),
+                );
+            } else {
+                health.success(working.len());
+            }
+        }
+        let working = working
+            .into_iter()
+            .map(|proxy| (proxy.to_string(), proxy))
+            .collect::<HashMap<_, _>>();
+
+        for proxy in &proxies {
+            let key = proxy.to_string();
+            uptime.record(&key, working.contains_key(&key));
+        }
+        latest.extend(working.iter().map(|(k, v)| (k.clone(), v.clone())));
+        uptime.save().await?;
+
+        let round = RoundSummary {
+            up: working.keys().cloned().collect(),
+            availability: (working.len() * 100)
+                .checked_div(proxies.len())
+                .unwrap_or(0) as u8,
+            avg_latency: working
+                .values()
+                .map(|proxy| proxy.ping)
+                .sum::<Duration>()
+                .checked_div(working.len() as u32),
+        };
+        if alerter.is_enabled()
+            && let Some(prev) = &prev_round
+        {
+            monitor.alert_changes(&alerter, prev, &round).await;
+        }
+        prev_round = Some(round);
+
+        write_round(args, list_file, &mut watch, &latest, &uptime).await?;
+        log::info!(
+            "{}/{} proxies up, next check in {}",
+            working.len(),
+            proxies.len(),
+            humantime::format_duration(monitor.interval)
+        );
+
+        tokio::time::sleep(monitor.interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, path::PathBuf};
+
+    use super::*;
+
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("novaprox-{}-{name}", std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    // Edits within one timestamp tick would look unchanged
+    fn touch(path: &PathBuf, content: &str) {
+        let before = modified(path.to_str().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(before + Duration::from_secs(1))
+            .unwrap();
+    }
+
+    fn proxy(link: &str) -> ProxyConfig {
+        let url = Url::parse(link).unwrap();
+        let address = url.host_str().unwrap().parse().unwrap();
+        ProxyConfig::from_url(url, address)
+    }
+
+    #[tokio::test]
+    async fn edit_during_round_is_not_overwritten() {
+        let path = temp_file("edited.txt", "old\n");
+        let path_str = path.to_str().unwrap();
+        let mut watch = Watch::new(&[path_str]);
+
+        touch(&path, "edited\n");
+        watch.write(path_str, "results\n".to_owned()).await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "edited\n");
+        assert_eq!(watch.changed(), [path_str]);
+
+        watch.write(path_str, "results\n".to_owned()).await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "results\n");
+        fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn unchanged_file_does_not_reload() {
+        let path = temp_file("unchanged.txt", "list\n");
+        let path_str = path.to_str().unwrap();
+        let mut watch = Watch::new(&[path_str, "missing-config.conf"]);
+        assert!(watch.changed().is_empty());
+
+        // Own writes aren't changes to apply
+        watch.write(path_str, "results\n".to_owned()).await.unwrap();
+        assert!(watch.changed().is_empty());
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn removed_proxies_drop_out_of_latest() {
+        let kept = proxy("socks://192.0.2.1:1080");
+        let removed = proxy("socks://192.0.2.2:1080");
+        let added = proxy("socks://192.0.2.3:1080");
+        let mut measured = kept.clone();
+        measured.ping = Duration::from_millis(50);
+        let mut latest = [
+            (kept.to_string(), measured),
+            (removed.to_string(), removed.clone()),
+        ]
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+        keep_listed(&mut latest, &[kept.clone(), added.clone()]);
+        assert_eq!(latest.len(), 2);
+        assert!(!latest.contains_key(&removed.to_string()));
+        assert_eq!(latest[&kept.to_string()].ping, Duration::from_millis(50));
+        assert!(latest.contains_key(&added.to_string()));
+    }
+}
@@ -0,0 +1,310 @@
+//! Checks socks and http proxies without a core, so runs on systems
+//! without xray still check what they can instead of aborting.
+//!
+//! Every chunk proxy gets a local SOCKS5 relay on its endpoint, which
+//! reaches destinations through the proxy with SOCKS5 or HTTP CONNECT.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Result;
+use base64::Engine as _;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::{
+    checker::{CheckOptions, LocalInbound},
+    engine::TestEngine,
+    proxy_config::ProxyConfig,
+    xray_config::{Engine, INBOUND_USER},
+    xray_process::XrayFeatures,
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Checks every following chunk natively instead of through xray
+pub fn use_native_engine() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[must_use]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// No xray feature is available, version checks fail
+pub const FEATURES: XrayFeatures = XrayFeatures {
+    engine: Engine::Xray,
+    version: (0, 0, 0),
+};
+
+// SOCKS5 reply codes
+pub(crate) const SUCCEEDED: u8 = 0;
+pub(crate) const HOST_UNREACHABLE: u8 = 4;
+pub(crate) const CONNECTION_REFUSED: u8 = 5;
+pub(crate) const COMMAND_NOT_SUPPORTED: u8 = 7;
+
+// Upstream proxy of relay
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Upstream {
+    server: SocketAddr,
+    socks: bool,
+    auth: Option<(String, String)>,
+}
+
+pub struct NativeEngine<'a> {
+    pub base_port: usize,
+    pub options: &'a CheckOptions,
+}
+
+impl TestEngine for NativeEngine<'_> {
+    type Process = Vec<JoinHandle<()>>;
+
+    fn missing_feature(&self, proxy: &ProxyConfig) -> Option<&'static str> {
+        match proxy.protocol.as_str() {
+            "socks" | "socks5" | "http" => None,
+            _ => Some("protocol support"),
+        }
+    }
+
+    // Config is the list of upstreams, in chunk order
+    fn generate_config(&self, chunk: &[ProxyConfig]) -> Result<String> {
+        let upstreams = chunk
+            .iter()
+            .map(|proxy| {
+                let param = |key: &str| proxy.query_params.get(key).cloned();
+                Upstream {
+                    server: SocketAddr::new(proxy.address, proxy.port),
+                    socks: proxy.protocol.starts_with("socks"),
+                    auth: param("user").map(|user| (user, param("pass").unwrap_or_default())),
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok(serde_json::to_string(&upstreams)?)
+    }
+
+    async fn start(&self, config: &str) -> Result<Self::Process> {
+        let upstreams = serde_json::from_str::<Vec<Upstream>>(config)?;
+        let mut relays = Vec::with_capacity(upstreams.len());
+        for (i, upstream) in upstreams.into_iter().enumerate() {
+            let listener =
+                TcpListener::bind(("127.0.0.1", u16::try_from(self.base_port + i)?)).await?;
+            let password = self.options.inbound_password.clone();
+            let upstream = std::sync::Arc::new(upstream);
+            relays.push(tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let (password, upstream) = (password.clone(), upstream.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = relay(stream, &upstream, &password).await {
+                            log::debug!("Relay through {} failed: {e}", upstream.server);
+                        }
+                    });
+                }
+            }));
+        }
+        Ok(relays)
+    }
+
+    async fn ready(&self, _: &mut Self::Process) -> Result<Result<(), String>> {
+        Ok(Ok(()))
+    }
+
+    fn endpoint(&self, index: usize) -> LocalInbound {
+        LocalInbound {
+            port: self.base_port + index,
+            user: INBOUND_USER.to_owned(),
+            password: self.options.inbound_password.clone(),
+        }
+    }
+
+    fn collect_logs(&self, _: &mut Self::Process, _: impl FnMut(&str) + Send + 'static) {}
+
+    fn split_rejected(&self, _: &mut Vec<Vec<ProxyConfig>>, chunk: Vec<ProxyConfig>, _: &str) {
+        log::warn!("Native engine rejected chunk of {}", chunk.len());
+    }
+
+    async fn stop(&self, relays: &mut Self::Process) {
+        for relay in relays.drain(..) {
+            relay.abort();
+            relay.await.ok();
+        }
+    }
+}
+
+async fn relay(mut client: TcpStream, upstream: &Upstream, password: &str) -> io::Result<()> {
+    let Some((host, port)) = accept_request(&mut client, password).await? else {
+        return Ok(());
+    };
+    let Ok(mut server) = TcpStream::connect(upstream.server).await else {
+        return reply(&mut client, CONNECTION_REFUSED).await;
+    };
+    let connected = if upstream.socks {
+        socks_connect(&mut server, &host, port, upstream.auth.as_ref()).await
+    } else {
+        http_connect(&mut server, &host, port, upstream.auth.as_ref()).await
+    };
+    if let Err(e) = connected {
+        log::debug!("{} refused {host}:{port}: {e}", upstream.server);
+        return reply(&mut client, HOST_UNREACHABLE).await;
+    }
+    reply(&mut client, SUCCEEDED).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+    Ok(())
+}
+
+/// Serves SOCKS5 handshake with username/password auth (RFC 1928,
+/// RFC 1929) up to the request, returns destination of CONNECT. Failed
+/// auth and other commands are answered here and give `None`
+pub(crate) async fn accept_request(
+    client: &mut TcpStream,
+    password: &str,
+) -> io::Result<Option<(String, u16)>> {
+    let mut head = [0; 2];
+    client.read_exact(&mut head).await?;
+    let mut methods = vec![0; usize::from(head[1])];
+    client.read_exact(&mut methods).await?;
+    client.write_all(&[5, 2]).await?;
+
+    let user = read_field(client, 1).await?;
+    let pass = read_field(client, 0).await?;
+    if user != INBOUND_USER.as_bytes() || pass != password.as_bytes() {
+        client.write_all(&[1, 1]).await?;
+        return Ok(None);
+    }
+    client.write_all(&[1, 0]).await?;
+
+    let mut request = [0; 4];
+    client.read_exact(&mut request).await?;
+    let host = read_address(client, request[3]).await?;
+    let port = client.read_u16().await?;
+    if request[1] != 1 {
+        reply(client, COMMAND_NOT_SUPPORTED).await?;
+        return Ok(None);
+    }
+    Ok(Some((host, port)))
+}
+
+pub(crate) async fn reply(stream: &mut TcpStream, code: u8) -> io::Result<()> {
+    stream.write_all(&[5, code, 0, 1, 0, 0, 0, 0, 0, 0]).await
+}
+
+/// SOCKS5 CONNECT through upstream proxy, by domain name so it resolves
+/// the destination itself
+pub(crate) async fn socks_connect(
+    server: &mut TcpStream,
+    host: &str,
+    port: u16,
+    auth: Option<&(String, String)>,
+) -> io::Result<()> {
+    server
+        .write_all(if auth.is_some() {
+            &[5, 1, 2]
+        } else {
+            &[5, 1, 0]
+        })
+        .await?;
+    let mut method = [0; 2];
+    server.read_exact(&mut method).await?;
+    match (method[1], auth) {
+        (0, _) => {}
+        (2, Some((user, pass))) => {
+            let mut request = vec![1, field_len(user)?];
+            request.extend_from_slice(user.as_bytes());
+            request.push(field_len(pass)?);
+            request.extend_from_slice(pass.as_bytes());
+            server.write_all(&request).await?;
+            let mut status = [0; 2];
+            server.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(io::Error::other("authentication failed"));
+            }
+        }
+        _ => return Err(io::Error::other("no acceptable auth method")),
+    }
+
+    let mut request = vec![5, 1, 0, 3, field_len(host)?];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    server.write_all(&request).await?;
+    let mut response = [0; 4];
+    server.read_exact(&mut response).await?;
+    if response[1] != SUCCEEDED {
+        return Err(io::Error::other(format!("reply {}", response[1])));
+    }
+    read_address(server, response[3]).await?;
+    server.read_u16().await?;
+    Ok(())
+}
+
+async fn http_connect(
+    server: &mut TcpStream,
+    host: &str,
+    port: u16,
+    auth: Option<&(String, String)>,
+) -> io::Result<()> {
+    let target = if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    };
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((user, pass)) = auth {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{user}:{pass}"));
+        request += &format!("Proxy-Authorization: Basic {credentials}\r\n");
+    }
+    request += "\r\n";
+    server.write_all(request.as_bytes()).await?;
+
+    // Byte by byte, so nothing after the response head is consumed
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 8192 {
+            return Err(io::Error::other("response head too long"));
+        }
+        head.push(server.read_u8().await?);
+    }
+    let status = String::from_utf8_lossy(&head);
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(
+            status.lines().next().unwrap_or_default().to_owned(),
+        )),
+    }
+}
+
+// Address of SOCKS5 request or reply of `kind` type
+async fn read_address(stream: &mut TcpStream, kind: u8) -> io::Result<String> {
+    Ok(match kind {
+        1 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        4 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            std::net::Ipv6Addr::from(ip).to_string()
+        }
+        _ => String::from_utf8_lossy(&read_field(stream, 0).await?).into_owned(),
+    })
+}
+
+// Length prefixed field, after `skip` bytes of version
+async fn read_field(stream: &mut TcpStream, skip: usize) -> io::Result<Vec<u8>> {
+    let mut version = vec![0; skip];
+    stream.read_exact(&mut version).await?;
+    let mut field = vec![0; usize::from(stream.read_u8().await?)];
+    stream.read_exact(&mut field).await?;
+    Ok(field)
+}
+
+fn field_len(field: &str) -> io::Result<u8> {
+    u8::try_from(field.len()).map_err(io::Error::other)
+}
@@ -0,0 +1,100 @@
+use std::borrow::Cow;
+
+use ahash::HashSet;
+use anyhow::{Context as _, Result};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+use crate::{
+    Args,
+    parse_url::{canonical_link, repair_link, split_links},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct NormalizeArgs {
+    // Files of share links, `-` reads stdin
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    // Write links to this file instead of stdout
+    #[arg(long)]
+    output: Option<String>,
+
+    // Keep only first of links that are the same proxy, whatever their
+    // names
+    #[arg(long)]
+    dedup: bool,
+}
+
+/// Prints links of inputs in canonical form without checking them, in
+/// input order. Comment lines are kept, lines that aren't links dropped
+///
+/// # Errors
+/// Return error if failed to read inputs or write output
+pub(crate) async fn run_normalize(args: &Args, normalize: &NormalizeArgs) -> Result<()> {
+    let mut out = String::new();
+    let mut seen = HashSet::default();
+    let (mut links, mut duplicates, mut invalid) = (0, 0, 0);
+
+    for input in &normalize.inputs {
+        let list = read_input(input).await?;
+        for (number, line) in list.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('#') {
+                out += line;
+                out.push('\n');
+                continue;
+            }
+
+            let pieces: Vec<Cow<'_, str>> = if args.lenient {
+                split_links(line)
+                    .into_iter()
+                    .map(|piece| repair_link(piece).0)
+                    .collect()
+            } else {
+                vec![line.into()]
+            };
+            for link in pieces {
+                let Some(link) = canonical_link(&link) else {
+                    log::warn!("{input}:{}: not a link: {line}", number + 1);
+                    invalid += 1;
+                    continue;
+                };
+                let proxy = link
+                    .split_once('#')
+                    .map_or(link.as_str(), |(proxy, _)| proxy);
+                if normalize.dedup && !seen.insert(proxy.to_owned()) {
+                    duplicates += 1;
+                    continue;
+                }
+                out += &link;
+                out.push('\n');
+                links += 1;
+            }
+        }
+    }
+
+    log::info!(
+        "Normalized {links} links, dropped {duplicates} duplicates and {invalid} invalid lines"
+    );
+    match &normalize.output {
+        Some(path) => tokio::fs::write(path, out)
+            .await
+            .with_context(|| format!("Failed to write {path}")),
+        None => Ok(tokio::io::stdout().write_all(out.as_bytes()).await?),
+    }
+}
+
+async fn read_input(input: &str) -> Result<String> {
+    if input == "-" {
+        let mut list = String::new();
+        tokio::io::stdin().read_to_string(&mut list).await?;
+        Ok(list)
+    } else {
+        tokio::fs::read_to_string(input)
+            .await
+            .with_context(|| format!("Failed to read {input}"))
+    }
+}
@@ -0,0 +1,698 @@
+use std::{fmt, time::Duration};
+
+use anyhow::Result;
+use base64::Engine as _;
+use serde_json::{Value, json};
+
+use crate::{
+    protocol::protocol_handler,
+    proxy_config::{ProxyConfig, country_code_to_emoji},
+};
+
+/// Format results are written in, selected with `--format`
+pub trait OutputWriter: Send + Sync {
+    /// Name of the format on command line
+    fn name(&self) -> &'static str;
+
+    /// Renders working proxies, best first. `header` holds `#` comment
+    /// lines describing the run, formats without comments drop it
+    ///
+    /// # Errors
+    /// Return error if proxies can't be serialized
+    fn render(&self, proxies: &[ProxyConfig], header: &str) -> Result<String>;
+}
+
+impl fmt::Debug for dyn OutputWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// New formats only need to be listed here
+static WRITERS: &[&dyn OutputWriter] = &[&Urls, &Base64, &Clash, &SingBox, &Json, &Csv];
+
+/// Writer of format `name`, parser of `--format`
+///
+/// # Errors
+/// Return error naming known formats if there is no such format
+pub fn output_writer(name: &str) -> Result<&'static dyn OutputWriter, String> {
+    WRITERS
+        .iter()
+        .copied()
+        .find(|writer| writer.name() == name)
+        .ok_or_else(|| {
+            let names = WRITERS.iter().map(|writer| writer.name());
+            format!(
+                "unknown format, expected one of: {}",
+                names.collect::<Vec<_>>().join(", ")
+            )
+        })
+}
+
+// Name shown by clients, `id` counts from 1
+fn display_name(proxy: &ProxyConfig, id: usize) -> String {
+    format!(
+        "{} - {id}",
+        proxy
+            .country
+            .map_or_else(|| "Novaprox".to_string(), country_code_to_emoji)
+    )
+}
+
+// Group clients pick proxies in, country groups first
+const SELECTOR: &str = "Novaprox";
+const GROUP_TEST_URL: &str = "https://www.gstatic.com/generate_204";
+const GROUP_TEST_INTERVAL: Duration = Duration::from_secs(300);
+// HTTP and SOCKS port of generated client configs
+const LOCAL_PORT: u16 = 7890;
+
+// Names of proxies by country for url-test groups, countries ordered by
+// their best proxy, proxies without country grouped last
+fn country_groups(named: &[(Option<[char; 2]>, String)]) -> Vec<(String, Vec<String>)> {
+    let mut groups: Vec<(Option<[char; 2]>, Vec<String>)> = Vec::new();
+    for (country, name) in named {
+        match groups.iter_mut().find(|(known, _)| known == country) {
+            Some((_, names)) => names.push(name.clone()),
+            None => groups.push((*country, vec![name.clone()])),
+        }
+    }
+    groups.sort_by_key(|(country, _)| country.is_none());
+    groups
+        .into_iter()
+        .map(|(country, names)| {
+            let group = country.map_or_else(
+                || "Other - auto".to_owned(),
+                |code| {
+                    format!(
+                        "{} {} - auto",
+                        country_code_to_emoji(code),
+                        String::from_iter(code)
+                    )
+                },
+            );
+            (group, names)
+        })
+        .collect()
+}
+
+fn param<'a>(proxy: &'a ProxyConfig, key: &str) -> Option<&'a str> {
+    proxy
+        .query_params
+        .get(key)
+        .map(String::as_str)
+        .filter(|value| !value.is_empty())
+}
+
+/// Share links with name and check results in fragment, one per line
+#[must_use]
+pub fn format_results(proxies: &[ProxyConfig]) -> String {
+    proxies
+        .iter()
+        .enumerate()
+        .map(|(id, proxy)| {
+            let bandwidth_kbps = proxy.bandwidth / 1024;
+            let mut line = format!(
+                "{proxy}#{} [{}ms] ({} KB/s)",
+                display_name(proxy, id + 1),
+                proxy.ping.as_millis(),
+                bandwidth_kbps
+            );
+            if let Some(p95) = proxy.ping_p95 {
+                line += &format!(" [p95 {}ms]", p95.as_millis());
+            }
+            if let Some(uptime) = proxy.uptime {
+                line += &format!(" [uptime {uptime}%]");
+            }
+            if proxy.dual_stack.is_some_and(|dual_stack| dual_stack.ipv6) {
+                line += " [v6]";
+            }
+            if proxy.no_icmp {
+                line += " [no icmp]";
+            }
+            if proxy.check_attempts > 1 {
+                line += &format!(" [attempts {}]", proxy.check_attempts);
+            }
+            if let Some(anonymity) = proxy.anonymity {
+                line += &format!(" [{anonymity}]");
+            }
+            if let Some(score) = proxy.abuse_score {
+                line += &format!(" [abuse {score}%]");
+            }
+            if let Some(capacity) = proxy.capacity {
+                line += &format!(
+                    " [load {}/{} {}ms]",
+                    capacity.succeeded,
+                    capacity.attempted,
+                    capacity.latency.as_millis()
+                );
+            }
+            if let Some((up, down)) = proxy.traffic {
+                line += &format!(" [{} KB up / {} KB down]", up / 1024, down / 1024);
+            }
+            if proxy.http3 == Some(false) {
+                line += " [no h3]";
+            }
+            if proxy.drops_long_connections {
+                line += " [unstable]";
+            }
+            if proxy.corrupts_payloads {
+                line += " [bad payload]";
+            }
+            if proxy.tls_mismatch {
+                line += " [MITM]";
+            }
+            if let Some(issue) = proxy.sni_issue {
+                line += &format!(" [{issue}]");
+            }
+            if let Some(cdn) = &proxy.cdn {
+                line += &format!(" [cdn={cdn}]");
+            }
+            if let Some(variant) = proxy.variant {
+                line += &format!(" [fixed {variant}]");
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+struct Urls;
+
+impl OutputWriter for Urls {
+    fn name(&self) -> &'static str {
+        "urls"
+    }
+
+    fn render(&self, proxies: &[ProxyConfig], header: &str) -> Result<String> {
+        Ok(header.to_owned() + &format_results(proxies))
+    }
+}
+
+// Subscription most clients import as is
+struct Base64;
+
+impl OutputWriter for Base64 {
+    fn name(&self) -> &'static str {
+        "base64"
+    }
+
+    fn render(&self, proxies: &[ProxyConfig], _: &str) -> Result<String> {
+        Ok(base64::engine::general_purpose::STANDARD.encode(format_results(proxies)))
+    }
+}
+
+// Config of Clash Meta (mihomo) sending everything through the selector
+// group. JSON is valid YAML, so every entry is written as a flow mapping
+struct Clash;
+
+impl OutputWriter for Clash {
+    fn name(&self) -> &'static str {
+        "clash"
+    }
+
+    fn render(&self, proxies: &[ProxyConfig], header: &str) -> Result<String> {
+        let mut entries = String::new();
+        let mut named = Vec::new();
+        for (id, proxy) in proxies.iter().enumerate() {
+            let name = display_name(proxy, id + 1);
+            match clash_proxy(proxy, name.clone()) {
+                Some(entry) => {
+                    entries += &format!("  - {}\n", serde_json::to_string(&entry)?);
+                    named.push((proxy.country, name));
+                }
+                None => log::debug!("Clash can't run {proxy}, leaving it out"),
+            }
+        }
+        let mut out = header.to_owned();
+        if entries.is_empty() {
+            out += "proxies: []\n";
+        } else {
+            out += "proxies:\n";
+            out += &entries;
+        }
+
+        let countries = country_groups(&named);
+        let mut selectable = countries
+            .iter()
+            .map(|(group, _)| group.clone())
+            .collect::<Vec<_>>();
+        selectable.extend(named.into_iter().map(|(_, name)| name));
+        // Clash rejects empty groups, without proxies traffic goes direct
+        if selectable.is_empty() {
+            selectable.push("DIRECT".to_owned());
+        }
+        out += "proxy-groups:\n";
+        let select = json!({ "name": SELECTOR, "type": "select", "proxies": selectable });
+        out += &format!("  - {}\n", serde_json::to_string(&select)?);
+        for (group, names) in countries {
+            let url_test = json!({
+                "name": group,
+                "type": "url-test",
+                "url": GROUP_TEST_URL,
+                "interval": GROUP_TEST_INTERVAL.as_secs(),
+                "proxies": names,
+            });
+            out += &format!("  - {}\n", serde_json::to_string(&url_test)?);
+        }
+        out += &format!("rules:\n  - MATCH,{SELECTOR}\n");
+        Ok(format!("mixed-port: {LOCAL_PORT}\nmode: rule\n{out}"))
+    }
+}
+
+fn clash_proxy(proxy: &ProxyConfig, name: String) -> Option<Value> {
+    let mut entry = json!({
+        "name": name,
+        "server": proxy.address,
+        "port": proxy.port,
+    });
+    let fields = protocol_handler(&proxy.protocol)?.clash(proxy)?;
+    entry.as_object_mut()?.extend(fields);
+
+    match param(proxy, "security") {
+        Some("tls" | "reality") => {
+            entry["tls"] = json!(true);
+            if let Some(sni) = param(proxy, "sni") {
+                let key = if entry["type"] == "trojan" {
+                    "sni"
+                } else {
+                    "servername"
+                };
+                entry[key] = json!(sni);
+            }
+            if let Some(fp) = param(proxy, "fp") {
+                entry["client-fingerprint"] = json!(fp);
+            }
+            if let Some(alpn) = param(proxy, "alpn") {
+                entry["alpn"] = json!(alpn.split(',').collect::<Vec<_>>());
+            }
+            if matches!(param(proxy, "allowInsecure"), Some("1" | "true")) {
+                entry["skip-cert-verify"] = json!(true);
+            }
+            if param(proxy, "security") == Some("reality") {
+                entry["reality-opts"] = json!({
+                    "public-key": param(proxy, "pbk")?,
+                    "short-id": param(proxy, "sid").unwrap_or_default(),
+                });
+            }
+        }
+        _ if entry["type"] == "trojan" => entry["tls"] = json!(true),
+        _ => {}
+    }
+
+    match param(proxy, "type").unwrap_or("tcp") {
+        "tcp" => {}
+        "ws" => {
+            entry["network"] = json!("ws");
+            let mut opts = json!({ "path": param(proxy, "path").unwrap_or("/") });
+            if let Some(host) = param(proxy, "host") {
+                opts["headers"] = json!({ "Host": host });
+            }
+            entry["ws-opts"] = opts;
+        }
+        "grpc" => {
+            entry["network"] = json!("grpc");
+            entry["grpc-opts"] =
+                json!({ "grpc-service-name": param(proxy, "serviceName").unwrap_or_default() });
+        }
+        _ => return None,
+    }
+    Some(entry)
+}
+
+// Config of sing-box with a local mixed inbound routed to the selector
+struct SingBox;
+
+impl OutputWriter for SingBox {
+    fn name(&self) -> &'static str {
+        "sing-box"
+    }
+
+    fn render(&self, proxies: &[ProxyConfig], _: &str) -> Result<String> {
+        let mut outbounds = Vec::new();
+        let mut named = Vec::new();
+        for (id, proxy) in proxies.iter().enumerate() {
+            let tag = display_name(proxy, id + 1);
+            match sing_box_outbound(proxy, tag.clone()) {
+                Some(outbound) => {
+                    outbounds.push(outbound);
+                    named.push((proxy.country, tag));
+                }
+                None => log::debug!("sing-box can't run {proxy}, leaving it out"),
+            }
+        }
+        // Selector can't be empty, without proxies traffic goes direct
+        if named.is_empty() {
+            outbounds.push(json!({ "type": "direct", "tag": "direct" }));
+        }
+
+        let countries = country_groups(&named);
+        let mut selectable = countries
+            .iter()
+            .map(|(group, _)| group.clone())
+            .collect::<Vec<_>>();
+        selectable.extend(named.into_iter().map(|(_, tag)| tag));
+        if selectable.is_empty() {
+            selectable.push("direct".to_owned());
+        }
+        let mut groups = vec![json!({
+            "type": "selector",
+            "tag": SELECTOR,
+            "outbounds": selectable,
+            "default": selectable[0],
+        })];
+        groups.extend(countries.into_iter().map(|(group, tags)| {
+            json!({
+                "type": "urltest",
+                "tag": group,
+                "outbounds": tags,
+                "url": GROUP_TEST_URL,
+                "interval": format!("{}s", GROUP_TEST_INTERVAL.as_secs()),
+            })
+        }));
+        groups.append(&mut outbounds);
+        Ok(serde_json::to_string_pretty(&json!({
+            "inbounds": [{
+                "type": "mixed",
+                "tag": "mixed-in",
+                "listen": "127.0.0.1",
+                "listen_port": LOCAL_PORT,
+            }],
+            "outbounds": groups,
+            "route": { "final": SELECTOR },
+        }))?)
+    }
+}
+
+fn sing_box_outbound(proxy: &ProxyConfig, tag: String) -> Option<Value> {
+    let mut outbound = json!({
+        "tag": tag,
+        "server": proxy.address,
+        "server_port": proxy.port,
+    });
+    let fields = protocol_handler(&proxy.protocol)?.sing_box(proxy)?;
+    outbound.as_object_mut()?.extend(fields);
+
+    let security = param(proxy, "security");
+    if matches!(security, Some("tls" | "reality")) || outbound["type"] == "trojan" {
+        let mut tls = json!({ "enabled": true });
+        if let Some(sni) = param(proxy, "sni") {
+            tls["server_name"] = json!(sni);
+        }
+        if let Some(alpn) = param(proxy, "alpn") {
+            tls["alpn"] = json!(alpn.split(',').collect::<Vec<_>>());
+        }
+        if matches!(param(proxy, "allowInsecure"), Some("1" | "true")) {
+            tls["insecure"] = json!(true);
+        }
+        if let Some(fp) = param(proxy, "fp") {
+            tls["utls"] = json!({ "enabled": true, "fingerprint": fp });
+        }
+        if security == Some("reality") {
+            tls["reality"] = json!({
+                "enabled": true,
+                "public_key": param(proxy, "pbk")?,
+                "short_id": param(proxy, "sid").unwrap_or_default(),
+            });
+        }
+        outbound["tls"] = tls;
+    }
+
+    let transport = match param(proxy, "type").unwrap_or("tcp") {
+        "tcp" => None,
+        "ws" => {
+            let mut ws = json!({ "type": "ws", "path": param(proxy, "path").unwrap_or("/") });
+            if let Some(host) = param(proxy, "host") {
+                ws["headers"] = json!({ "Host": host });
+            }
+            Some(ws)
+        }
+        "grpc" => Some(json!({
+            "type": "grpc",
+            "service_name": param(proxy, "serviceName").unwrap_or_default(),
+        })),
+        "httpupgrade" => Some(json!({
+            "type": "httpupgrade",
+            "path": param(proxy, "path").unwrap_or("/"),
+            "host": param(proxy, "host").unwrap_or_default(),
+        })),
+        _ => return None,
+    };
+    if let Some(transport) = transport {
+        outbound["transport"] = transport;
+    }
+    Some(outbound)
+}
+
+// Check results for scripts, fields of unmeasured results are null
+struct Json;
+
+impl OutputWriter for Json {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn render(&self, proxies: &[ProxyConfig], _: &str) -> Result<String> {
+        let results = proxies
+            .iter()
+            .enumerate()
+            .map(|(id, proxy)| {
+                json!({
+                    "link": proxy.to_string(),
+                    "name": display_name(proxy, id + 1),
+                    "country": proxy.country.map(String::from_iter),
+                    "ping_ms": proxy.ping.as_millis() as u64,
+                    "ping_p95_ms": proxy.ping_p95.map(|p95| p95.as_millis() as u64),
+                    "timings": proxy.timings.map(|timings| json!({
+                        "socks_ms": timings.socks.as_millis() as u64,
+                        "tls_ms": timings.tls.map(|tls| tls.as_millis() as u64),
+                        "ttfb_ms": timings.first_byte.as_millis() as u64,
+                    })),
+                    "bandwidth": proxy.bandwidth,
+                    "uptime": proxy.uptime,
+                    "anonymity": proxy.anonymity.map(|anonymity| anonymity.to_string()),
+                    "abuse_score": proxy.abuse_score,
+                    "exit_ip": proxy.exit_ip,
+                    "cdn": proxy.cdn,
+                    "variant": proxy.variant,
+                    "dual_stack": proxy.dual_stack.map(|dual_stack| json!({
+                        "winner": dual_stack.winner.to_string(),
+                        "ipv6": dual_stack.ipv6,
+                    })),
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(format!("{:#}\n", Value::from(results)))
+    }
+}
+
+struct Csv;
+
+impl OutputWriter for Csv {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    fn render(&self, proxies: &[ProxyConfig], _: &str) -> Result<String> {
+        let mut csv = "link,country,ping_ms,bandwidth_kbps,uptime,abuse_score,ping_p95_ms,\
+                       socks_ms,tls_ms,ttfb_ms\n"
+            .to_owned();
+        for proxy in proxies {
+            let optional = |value: Option<u8>| value.map(|value| value.to_string());
+            let millis = |value: Option<Duration>| {
+                value
+                    .map(|value| value.as_millis().to_string())
+                    .unwrap_or_default()
+            };
+            let timings = proxy.timings;
+            csv += &format!(
+                "\"{}\",{},{},{},{},{},{},{},{},{}\n",
+                proxy.to_string().replace('"', "\"\""),
+                proxy.country.map(String::from_iter).unwrap_or_default(),
+                proxy.ping.as_millis(),
+                proxy.bandwidth / 1024,
+                optional(proxy.uptime).unwrap_or_default(),
+                optional(proxy.abuse_score).unwrap_or_default(),
+                millis(proxy.ping_p95),
+                millis(timings.map(|timings| timings.socks)),
+                millis(timings.and_then(|timings| timings.tls)),
+                millis(timings.map(|timings| timings.first_byte)),
+            );
+        }
+        Ok(csv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+    use crate::{protocol, proxy_config::Timings};
+
+    fn proxy(link: &str, country: Option<[char; 2]>) -> ProxyConfig {
+        let url = Url::parse(link).unwrap();
+        let address = url.host_str().unwrap().parse().unwrap();
+        let mut proxy = ProxyConfig::from_url(url, address);
+        proxy.country = country;
+        proxy
+    }
+
+    fn proxies() -> Vec<ProxyConfig> {
+        vec![
+            proxy(
+                "trojan://secret@192.0.2.1:443?sni=a.example",
+                Some(['D', 'E']),
+            ),
+            proxy("trojan://secret@192.0.2.2:443?sni=b.example", None),
+            proxy(
+                "trojan://secret@192.0.2.3:443?sni=c.example",
+                Some(['D', 'E']),
+            ),
+            proxy(
+                "trojan://secret@192.0.2.4:443?sni=d.example",
+                Some(['N', 'L']),
+            ),
+        ]
+    }
+
+    #[test]
+    fn clash_groups_proxies_by_country() {
+        let out = Clash.render(&proxies(), "").unwrap();
+        let (_, groups) = out.split_once("proxy-groups:\n").unwrap();
+        let (groups, _) = groups.split_once("rules:\n").unwrap();
+        let groups = groups
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line.trim_start_matches("  - ")).unwrap())
+            .collect::<Vec<_>>();
+        let de = format!("{} DE - auto", country_code_to_emoji(['D', 'E']));
+        let nl = format!("{} NL - auto", country_code_to_emoji(['N', 'L']));
+
+        assert_eq!(groups[0]["type"], "select");
+        assert_eq!(groups[0]["proxies"].as_array().unwrap().len(), 3 + 4);
+        assert_eq!(groups[0]["proxies"][0], de.as_str());
+        assert_eq!(groups[1]["name"], de.as_str());
+        assert_eq!(groups[1]["type"], "url-test");
+        assert_eq!(groups[1]["proxies"].as_array().unwrap().len(), 2);
+        assert_eq!(groups[2]["name"], nl.as_str());
+        assert_eq!(groups[3]["name"], "Other - auto");
+        assert!(out.starts_with("mixed-port: 7890\n"));
+        assert!(out.ends_with("rules:\n  - MATCH,Novaprox\n"));
+    }
+
+    #[test]
+    fn sing_box_groups_reference_outbounds() {
+        let config =
+            serde_json::from_str::<Value>(&SingBox.render(&proxies(), "").unwrap()).unwrap();
+        assert_eq!(config["route"]["final"], "Novaprox");
+        let outbounds = config["outbounds"].as_array().unwrap().clone();
+        let tags = outbounds
+            .iter()
+            .map(|outbound| outbound["tag"].as_str().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(outbounds[0]["type"], "selector");
+        assert_eq!(outbounds[1]["type"], "urltest");
+        for group in outbounds
+            .iter()
+            .filter(|outbound| outbound["outbounds"].is_array())
+        {
+            for tag in group["outbounds"].as_array().unwrap() {
+                assert!(tags.contains(&tag.as_str().unwrap()), "{tag} isn't defined");
+            }
+        }
+    }
+
+    fn timed() -> Vec<ProxyConfig> {
+        let mut proxies = proxies();
+        proxies[0].timings = Some(Timings {
+            socks: Duration::from_millis(2),
+            tls: Some(Duration::from_millis(150)),
+            first_byte: Duration::from_millis(80),
+        });
+        proxies.truncate(2);
+        proxies
+    }
+
+    #[test]
+    fn json_has_timings_or_null() {
+        let results = serde_json::from_str::<Value>(&Json.render(&timed(), "").unwrap()).unwrap();
+        assert_eq!(
+            results[0]["timings"],
+            json!({ "socks_ms": 2, "tls_ms": 150, "ttfb_ms": 80 })
+        );
+        assert!(results[1]["timings"].is_null());
+    }
+
+    #[test]
+    fn csv_has_timing_columns() {
+        let csv = Csv.render(&timed(), "").unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert!(lines[0].ends_with(",socks_ms,tls_ms,ttfb_ms"));
+        assert!(lines[1].ends_with(",2,150,80"), "{}", lines[1]);
+        assert!(lines[2].ends_with(",,,"), "{}", lines[2]);
+    }
+
+    #[test]
+    fn empty_configs_keep_their_keys_and_go_direct() {
+        let clash = Clash.render(&[], "").unwrap();
+        assert_eq!(
+            clash,
+            format!(
+                "mixed-port: {LOCAL_PORT}\nmode: rule\nproxies: []\nproxy-groups:\n  - \
+                 {{\"name\":\"{SELECTOR}\",\"proxies\":[\"DIRECT\"],\"type\":\"select\"}}\n\
+                 rules:\n  - MATCH,{SELECTOR}\n"
+            )
+        );
+
+        let sing_box = serde_json::from_str::<Value>(&SingBox.render(&[], "").unwrap()).unwrap();
+        assert_eq!(sing_box["route"]["final"], SELECTOR);
+        assert_eq!(
+            sing_box["outbounds"],
+            json!([
+                {
+                    "type": "selector",
+                    "tag": SELECTOR,
+                    "outbounds": ["direct"],
+                    "default": "direct",
+                },
+                { "type": "direct", "tag": "direct" },
+            ])
+        );
+    }
+
+    // Protocol Clash runs but sing-box doesn't
+    struct ClashOnly;
+
+    impl protocol::ProtocolHandler for ClashOnly {
+        fn schemes(&self) -> &'static [&'static str] {
+            &["clashonly"]
+        }
+
+        fn tag(&self) -> &'static str {
+            "clashonly"
+        }
+
+        fn outbound(&self, _: &ProxyConfig, tag: String, _: crate::xray_config::Engine) -> Value {
+            json!({ "protocol": "clashonly", "tag": tag })
+        }
+
+        fn clash(&self, proxy: &ProxyConfig) -> Option<serde_json::Map<String, Value>> {
+            let mut fields = serde_json::Map::new();
+            fields.insert("type".to_owned(), json!("clashonly"));
+            fields.insert("password".to_owned(), json!(proxy.username));
+            Some(fields)
+        }
+    }
+
+    #[test]
+    fn registered_protocol_is_exported() {
+        protocol::register_protocol(std::sync::Arc::new(ClashOnly));
+        let proxy = proxy("clashonly://secret@192.0.2.1:1000", None);
+
+        let entry = clash_proxy(&proxy, "name".to_owned()).unwrap();
+        assert_eq!(entry["type"], "clashonly");
+        assert_eq!(entry["password"], "secret");
+        assert_eq!(entry["server"], "192.0.2.1");
+        assert!(sing_box_outbound(&proxy, "tag".to_owned()).is_none());
+    }
+}
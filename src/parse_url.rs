@@ -1,21 +1,331 @@
-use anyhow::{Context as _, Result};
+use std::{borrow::Cow, fmt};
+
+use anyhow::Result;
 use base64::Engine as _;
 use url::Url;
 
+use crate::{error::ParseError, protocol::protocol_handler, proxy_config::default_port};
+
+// Values of params missing from link, as xray treats them
+const PARAM_DEFAULTS: &[(&str, &str)] = &[("type", "tcp"), ("security", "none")];
+
+/// Rule removing query param, written as `key[=value][@cond[&cond...]]`
+///
+/// `*` key or value matches any, missing value is `*`. Conditions are
+/// [`conditions_match`] on the original link, e.g. `path@type=tcp` removes
+/// path only from tcp links.
+pub struct RemoveRule<'a> {
+    key: &'a str,
+    value: &'a str,
+    conditions: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> RemoveRule<'a> {
+    #[must_use]
+    pub fn parse(rule: &'a str) -> Self {
+        let (param, conditions) = rule.split_once('@').unwrap_or((rule, ""));
+        let (key, value) = param.split_once('=').unwrap_or((param, "*"));
+        Self {
+            key,
+            value,
+            conditions: conditions
+                .split('&')
+                .filter_map(|condition| condition.split_once('='))
+                .collect(),
+        }
+    }
+
+    fn applies_to(&self, url: &Url) -> bool {
+        conditions_match(url, &self.conditions)
+    }
+
+    fn matches(&self, key: &str, value: &str) -> bool {
+        (self.key == key || self.key == "*") && (self.value == value || self.value == "*")
+    }
+}
+
+/// Whether link matches all `param=value` conditions, `scheme` and `port`
+/// match link scheme and port
+#[must_use]
+pub fn conditions_match(url: &Url, conditions: &[(&str, &str)]) -> bool {
+    conditions.iter().all(|&(key, value)| match key {
+        "scheme" => url.scheme() == value,
+        "port" => url.port().is_some_and(|port| port.to_string() == value),
+        _ => url
+            .query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+            .or_else(|| {
+                PARAM_DEFAULTS
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| (*v).into())
+            })
+            .is_some_and(|v| v == value),
+    })
+}
+
+/// Form of link for finding duplicates: name removed, host lowercased,
+/// params sorted and default port dropped. Credentials are kept as is,
+/// since passwords are case sensitive
+#[must_use]
+pub fn normalize_link(url: &Url) -> Url {
+    let mut normalized = url.clone();
+    normalized.set_fragment(None);
+
+    if let Some(host) = url.host_str()
+        && host.chars().any(|c| c.is_ascii_uppercase())
+    {
+        normalized.set_host(Some(&host.to_ascii_lowercase())).ok();
+    }
+    if url.port().is_some() && url.port() == default_port(url.scheme()) {
+        normalized.set_port(None).ok();
+    }
+
+    let mut params = url.query_pairs().collect::<Vec<_>>();
+    if !params.is_empty() {
+        params.sort();
+        normalized.query_pairs_mut().clear().extend_pairs(params);
+    }
+
+    normalized
+}
+
+/// Share link in canonical form, for publishing lists and diffing them
+///
+/// Params are sorted, default port dropped, host lowercased and name
+/// percent-decoded. Base64 vmess config, standard or URL-safe, is
+/// re-encoded as standard with sorted keys and `#name` after it dropped, as
+/// name is in config. `None` if line isn't a link
+#[must_use]
+pub fn canonical_link(line: &str) -> Option<String> {
+    if let Some(encoded) = line.strip_prefix("vmess://")
+        && let encoded = encoded
+            .split_once('#')
+            .map_or(encoded, |(encoded, _)| encoded)
+        && !encoded.contains('@')
+    {
+        let decoded = decode_vmess_base64(encoded)?;
+        let config = serde_json::from_slice::<serde_json::Value>(&decoded).ok()?;
+        return Some(format!(
+            "vmess://{}",
+            base64::engine::general_purpose::STANDARD.encode(config.to_string())
+        ));
+    }
+
+    let url = Url::parse(&strip_zone_id(line))
+        .ok()
+        .filter(|url| url.host_str().is_some())?;
+    let link = normalize_link(&url);
+    let name = url
+        .fragment()
+        .map(|name| percent_encoding::percent_decode_str(name).decode_utf8_lossy())
+        .filter(|name| !name.is_empty());
+    Some(match name {
+        // Decoded line breaks would split the link
+        Some(name) => format!("{link}#{}", name.replace(char::is_control, " ")),
+        None => link.into(),
+    })
+}
+
+// Vmess base64 as sources write it: standard or URL-safe, padded or not
+fn decode_vmess_base64(encoded: &str) -> Option<Vec<u8>> {
+    const CONFIG: base64::engine::GeneralPurposeConfig =
+        base64::engine::GeneralPurposeConfig::new()
+            .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent);
+    const STANDARD: base64::engine::GeneralPurpose =
+        base64::engine::GeneralPurpose::new(&base64::alphabet::STANDARD, CONFIG);
+    const URL_SAFE: base64::engine::GeneralPurpose =
+        base64::engine::GeneralPurpose::new(&base64::alphabet::URL_SAFE, CONFIG);
+
+    let encoded = encoded.trim();
+    STANDARD
+        .decode(encoded)
+        .or_else(|_| URL_SAFE.decode(encoded))
+        .ok()
+}
+
+/// Host as written in URL authority: IPv6 literal bracketed and without
+/// zone id, which neither URLs nor xray addresses can carry
+#[must_use]
+pub fn url_host(address: &str) -> String {
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+    let address = address.split('%').next().unwrap_or(address);
+    if address.contains(':') {
+        format!("[{address}]")
+    } else {
+        address.to_owned()
+    }
+}
+
+// Url rejects IPv6 zone ids, e.g. `[fe80::1%25eth0]`
+pub(crate) fn strip_zone_id(line: &str) -> Cow<'_, str> {
+    if let Some(start) = line.find('[')
+        && let Some(len) = line[start..].find(']')
+        && let Some(zone) = line[start..start + len].find('%')
+    {
+        return format!("{}{}", &line[..start + zone], &line[start + len..]).into();
+    }
+    line.into()
+}
+
+/// Corruption of scraped link undone by [`repair_link`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    // Spaces or line breaks before the name, e.g. from wrapped text
+    Whitespace,
+    // Scheme written twice, e.g. `vless://vless://`
+    DuplicatedScheme,
+    // Vmess base64 cut off, unpadded or URL-safe
+    TruncatedBase64,
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Whitespace => "stray whitespace",
+            Self::DuplicatedScheme => "duplicated scheme",
+            Self::TruncatedBase64 => "truncated base64",
+        })
+    }
+}
+
+/// Pieces of `line` that each start with a link
+///
+/// Links pasted on one line with spaces between them are repaired one by
+/// one instead of glued together. Words not starting a link stay with the
+/// piece before them, e.g. a name with spaces or a link broken by wrapping
+#[must_use]
+pub fn split_links(line: &str) -> Vec<&str> {
+    let mut starts = vec![0];
+    let mut after_space = false;
+    for (i, c) in line.char_indices() {
+        if after_space && !c.is_whitespace() && starts_link(&line[i..]) {
+            starts.push(i);
+        }
+        after_space = c.is_whitespace();
+    }
+    let ends = starts.iter().skip(1).copied().chain([line.len()]);
+    starts
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| line[start..end].trim())
+        .filter(|piece| !piece.is_empty())
+        .collect()
+}
+
+fn starts_link(word: &str) -> bool {
+    word.split_once("://").is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    })
+}
+
+/// Link with common corruption of scraped sources undone, and repairs it
+/// took. Clean links come back unchanged with no repairs
+#[must_use]
+pub fn repair_link(line: &str) -> (Cow<'_, str>, Vec<Repair>) {
+    let mut repairs = Vec::new();
+    let mut link = Cow::Borrowed(line.trim());
+
+    // Name after `#` may have spaces, the rest of link can't
+    let (body, name) = link.split_once('#').unwrap_or((&link, ""));
+    if body.contains(char::is_whitespace) {
+        let body = body.split_whitespace().collect::<String>();
+        link = if name.is_empty() && !link.ends_with('#') {
+            body.into()
+        } else {
+            format!("{body}#{name}").into()
+        };
+        repairs.push(Repair::Whitespace);
+    }
+
+    if let Some((scheme, rest)) = link.split_once("://") {
+        let prefix = format!("{scheme}://");
+        let mut rest = rest;
+        while let Some(stripped) = rest.strip_prefix(&prefix) {
+            rest = stripped;
+        }
+        if rest.len() < link.len() - prefix.len() {
+            link = format!("{prefix}{rest}").into();
+            repairs.push(Repair::DuplicatedScheme);
+        }
+    }
+
+    if let Some(encoded) = link.strip_prefix("vmess://")
+        && base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .is_err()
+        && let Some(config) = recover_vmess_config(encoded)
+    {
+        link = format!(
+            "vmess://{}",
+            base64::engine::general_purpose::STANDARD.encode(config)
+        )
+        .into();
+        repairs.push(Repair::TruncatedBase64);
+    }
+
+    (link, repairs)
+}
+
+// Decodes what's left of vmess base64 and closes JSON after the last
+// complete field
+fn recover_vmess_config(encoded: &str) -> Option<String> {
+    const LENIENT: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
+        &base64::alphabet::STANDARD,
+        base64::engine::GeneralPurposeConfig::new()
+            .with_decode_allow_trailing_bits(true)
+            .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent),
+    );
+
+    let mut encoded = encoded
+        .chars()
+        .filter_map(|c| match c {
+            '-' => Some('+'),
+            '_' => Some('/'),
+            c if c.is_ascii_alphanumeric() || c == '+' || c == '/' => Some(c),
+            _ => None,
+        })
+        .collect::<String>();
+    // Single char left of last quantum carries no whole byte
+    if encoded.len() % 4 == 1 {
+        encoded.pop();
+    }
+    let decoded = LENIENT.decode(&encoded).ok()?;
+    let config = String::from_utf8_lossy(&decoded);
+    let config = config.trim_end();
+
+    if serde_json::from_str::<serde_json::Value>(config).is_ok() {
+        return Some(config.to_owned());
+    }
+    let (complete, _) = config.rsplit_once(',')?;
+    let closed = format!("{complete}}}");
+    serde_json::from_str::<serde_json::Value>(&closed)
+        .is_ok()
+        .then_some(closed)
+}
+
 #[must_use]
 pub fn parse_proxy_url(
     line: &str,
     target_scheme: &str,
     param_filters: &[(&str, &str)],
-    params_remove: &[(&str, &str)],
+    params_remove: &[RemoveRule<'_>],
 ) -> Option<Url> {
     let cleaned_line = line.replace("amp;", "");
 
+    // Vmess base64 config carries no link params to filter
     if target_scheme == "vmess" && cleaned_line.starts_with("vmess://") {
-        parse_vmess_url(&cleaned_line).ok().flatten()
+        protocol_handler("vmess")?.parse(&cleaned_line)
     } else {
-        Url::parse(&cleaned_line)
-            .ok()
+        protocol_handler(target_scheme)
+            .map_or_else(
+                || Url::parse(&strip_zone_id(&cleaned_line)).ok(),
+                |handler| handler.parse(&cleaned_line),
+            )
             .filter(|url| {
                 url.scheme() == target_scheme
                     && param_filters
@@ -23,16 +333,14 @@ pub fn parse_proxy_url(
                         .all(|&(pk, pv)| url.query_pairs().any(|(qk, qv)| qk == pk && qv == pv))
             })
             .map(|mut url| {
+                let rules = params_remove
+                    .iter()
+                    .filter(|rule| rule.applies_to(&url))
+                    .collect::<Vec<_>>();
                 url.set_query(Some(
                     &url.query_pairs()
                         .filter_map(|(k, v)| {
-                            let (k, v) = (k.as_ref(), v.as_ref());
-                            if params_remove.iter().any(|(rk, rv)| {
-                                let (rk, rv) = (*rk, *rv);
-                                (rk == k && rv == "*")
-                                    || (rk == "*" && rv == v)
-                                    || (rk == k && rv == v)
-                            }) {
+                            if rules.iter().any(|rule| rule.matches(&k, &v)) {
                                 None
                             } else {
                                 // Fix encryption=none=*some@trash\/eeee in urls
@@ -48,28 +356,222 @@ pub fn parse_proxy_url(
     }
 }
 
-fn parse_vmess_url(url: &str) -> Result<Option<Url>> {
-    let base64_part = url.strip_prefix("vmess://").context("Invalid VMESS URL")?;
-    let decoded = base64::engine::general_purpose::STANDARD
-        .decode(base64_part)
-        .context("Base64 decode failed")?;
-    let config_str = String::from_utf8(decoded).context("Invalid UTF-8 in VMESS config")?;
+/// Checks Reality params of link, since a single malformed Reality
+/// outbound stops Xray from loading whole config
+///
+/// # Errors
+/// Return error if `pbk` isn't base64 X25519 public key or `sid` isn't hex
+/// of up to 16 chars
+pub fn check_reality_params(url: &Url) -> Result<(), ParseError> {
+    let param = |key: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    };
+    if param("security").as_deref() != Some("reality") {
+        return Ok(());
+    }
+
+    let pbk = param("pbk").ok_or(ParseError::MissingPbk)?;
+    let key = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(&pbk)
+        .map_err(ParseError::PbkNotBase64)?;
+    if pbk.len() != 43 || key.len() != 32 {
+        return Err(ParseError::InvalidPbk);
+    }
+
+    let sid = param("sid").unwrap_or_default();
+    if sid.len() > 16 || !sid.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ParseError::InvalidSid);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
 
-    let config: serde_json::Value =
-        serde_json::from_str(&config_str).context("Invalid JSON in VMESS config")?;
+    use super::*;
+    use crate::proxy_config::ProxyConfig;
 
-    let address = config["add"]
-        .as_str()
-        .context("Missing address in VMESS config")?;
-    let port = config["port"]
-        .as_u64()
-        .context("Missing port in VMESS config")? as u16;
-    let username = config["id"]
-        .as_str()
-        .context("Missing ID in VMESS config")?;
+    #[test]
+    fn url_host_brackets_ipv6_only() {
+        assert_eq!(url_host("192.0.2.1"), "192.0.2.1");
+        assert_eq!(url_host("example.com"), "example.com");
+        assert_eq!(url_host("2001:db8::1"), "[2001:db8::1]");
+        assert_eq!(url_host("[2001:db8::1]"), "[2001:db8::1]");
+    }
+
+    #[test]
+    fn url_host_drops_zone_id() {
+        assert_eq!(url_host("fe80::1%eth0"), "[fe80::1]");
+        assert_eq!(url_host("[fe80::1%eth0]"), "[fe80::1]");
+    }
 
-    let url_str = format!("vmess://{username}@{address}:{port}");
-    let url = Url::parse(&url_str).context("Failed to parse VMESS URL")?;
+    #[test]
+    fn strip_zone_id_leaves_rest_of_link() {
+        assert_eq!(
+            strip_zone_id("vless://id@[fe80::1%25eth0]:443?type=ws#name"),
+            "vless://id@[fe80::1]:443?type=ws#name"
+        );
+        assert!(matches!(
+            strip_zone_id("vless://id@[2001:db8::1]:443"),
+            Cow::Borrowed(_)
+        ));
+        // Percent sign outside brackets is an escape, not a zone
+        assert_eq!(
+            strip_zone_id("vless://id@192.0.2.1:443#a%20b"),
+            "vless://id@192.0.2.1:443#a%20b"
+        );
+    }
+
+    #[test]
+    fn ipv6_link_round_trips() {
+        let url = parse_proxy_url(
+            "vless://id@[fe80::1%25eth0]:443?type=tcp",
+            "vless",
+            &[],
+            &[],
+        )
+        .unwrap();
+        let address = url
+            .host_str()
+            .unwrap()
+            .trim_matches(['[', ']'])
+            .parse::<IpAddr>()
+            .unwrap();
+        let link = ProxyConfig::from_url(url, address).to_string();
+        assert_eq!(link, "vless://id@[fe80::1]:443?type=tcp");
+
+        let reparsed = parse_proxy_url(&link, "vless", &[], &[]).unwrap();
+        assert_eq!(reparsed.host_str(), Some("[fe80::1]"));
+        assert_eq!(reparsed.port(), Some(443));
+    }
+
+    #[test]
+    fn vmess_ipv6_address_is_bracketed() {
+        let config = r#"{"add":"2001:db8::1","port":443,"id":"id"}"#;
+        let link = format!(
+            "vmess://{}",
+            base64::engine::general_purpose::STANDARD.encode(config)
+        );
+        let url = parse_proxy_url(&link, "vmess", &[], &[]).unwrap();
+        assert_eq!(url.as_str(), "vmess://id@[2001:db8::1]:443");
+    }
+
+    #[test]
+    fn links_pasted_on_one_line_are_split() {
+        let line = "vless://a@192.0.2.1:443#first name vless://b@192.0.2.2:443";
+        assert_eq!(
+            split_links(line),
+            [
+                "vless://a@192.0.2.1:443#first name",
+                "vless://b@192.0.2.2:443"
+            ]
+        );
+        let repaired = split_links(line)
+            .into_iter()
+            .map(|piece| repair_link(piece))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            repaired,
+            [
+                ("vless://a@192.0.2.1:443#first name".into(), Vec::new()),
+                ("vless://b@192.0.2.2:443".into(), Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn wrapped_link_stays_one_piece() {
+        let line = "trojan://pass@192.0.2.1:443?security=tls &sni=example.com#name";
+        assert_eq!(split_links(line), [line]);
+        assert_eq!(
+            repair_link(line),
+            (
+                "trojan://pass@192.0.2.1:443?security=tls&sni=example.com#name".into(),
+                vec![Repair::Whitespace]
+            )
+        );
+    }
 
-    Ok(Some(url))
+    #[test]
+    fn name_with_spaces_is_kept() {
+        let (link, repairs) = repair_link("ss://YWVzOnBhc3M@192.0.2.1:8388#my  fast proxy");
+        assert_eq!(link, "ss://YWVzOnBhc3M@192.0.2.1:8388#my  fast proxy");
+        assert!(repairs.is_empty());
+    }
+
+    #[test]
+    fn duplicated_scheme_is_dropped() {
+        assert_eq!(
+            repair_link("vless://vless://a@192.0.2.1:443"),
+            (
+                "vless://a@192.0.2.1:443".into(),
+                vec![Repair::DuplicatedScheme]
+            )
+        );
+    }
+
+    #[test]
+    fn truncated_vmess_keeps_complete_fields() {
+        let config = r#"{"add":"example.com","port":443,"id":"id","net":"tcp"}"#;
+        let encoded = base64::engine::general_purpose::STANDARD.encode(config);
+        let truncated = &encoded[..encoded.len() - 7];
+        assert_eq!(
+            recover_vmess_config(truncated).as_deref(),
+            Some(r#"{"add":"example.com","port":443,"id":"id"}"#)
+        );
+
+        let line = format!("vmess://{truncated}");
+        let (link, repairs) = repair_link(&line);
+        assert_eq!(repairs, [Repair::TruncatedBase64]);
+        let url = parse_proxy_url(&link, "vmess", &[], &[]).unwrap();
+        assert_eq!(url.as_str(), "vmess://id@example.com:443");
+    }
+
+    #[test]
+    fn url_safe_unpadded_vmess_is_recovered() {
+        let config = r#"{"add":"example.com","port":443,"id":"id?>"}"#;
+        let encoded = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(config);
+        assert_eq!(recover_vmess_config(&encoded).as_deref(), Some(config));
+    }
+
+    #[test]
+    fn garbage_is_not_recovered() {
+        assert_eq!(recover_vmess_config("!!!"), None);
+        assert_eq!(
+            recover_vmess_config(&base64::engine::general_purpose::STANDARD.encode("no json")),
+            None
+        );
+    }
+
+    #[test]
+    fn canonical_vmess_drops_name_and_accepts_both_alphabets() {
+        // `?` and `>` encode to `/` and `+` in standard alphabet
+        let config = r#"{"port":443,"id":"id?>","add":"example.com"}"#;
+        let canonical = format!(
+            "vmess://{}",
+            base64::engine::general_purpose::STANDARD
+                .encode(r#"{"add":"example.com","id":"id?>","port":443}"#)
+        );
+        for engine in [
+            base64::engine::general_purpose::STANDARD,
+            base64::engine::general_purpose::STANDARD_NO_PAD,
+            base64::engine::general_purpose::URL_SAFE,
+            base64::engine::general_purpose::URL_SAFE_NO_PAD,
+        ] {
+            let encoded = engine.encode(config);
+            assert_eq!(
+                canonical_link(&format!("vmess://{encoded}")).as_deref(),
+                Some(canonical.as_str())
+            );
+            assert_eq!(
+                canonical_link(&format!("vmess://{encoded}#my%20proxy")).as_deref(),
+                Some(canonical.as_str())
+            );
+        }
+        assert_eq!(canonical_link("vmess://not base64!"), None);
+    }
 }
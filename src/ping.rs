@@ -0,0 +1,280 @@
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU16, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use ahash::{HashMap, HashMapExt as _};
+use anyhow::{Context as _, Result};
+use futures::{StreamExt as _, stream};
+use surge_ping::{Client, Config, ICMP, PingIdentifier, PingSequence};
+use tokio::{net::TcpStream, sync::Mutex};
+use url::Host;
+
+use crate::{
+    abuse::now_secs,
+    cache_format::{CacheFile, Writer},
+    dns_cache::DnsCache,
+    proxy_config::ProxyConfig,
+    resolve_host, trace,
+};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingTarget {
+    // ICMP ping of proxy server address
+    Server,
+    // ICMP ping of SNI host, falls back to server when proxy has no SNI
+    Sni,
+    // TCP connect to proxy port, for hosts filtering ICMP
+    Tcp,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingPolicy {
+    // Drop proxies not answering ping
+    Filter,
+    // Keep them for the real check, marked as not answering ping
+    Annotate,
+}
+
+pub struct PingOptions {
+    pub timeout: Duration,
+    pub delay: Duration,
+    pub max_concurrent: usize,
+    pub attempts: usize,
+    pub target: PingTarget,
+    pub policy: PingPolicy,
+}
+
+/// Ping results of recent runs, so overlapping runs don't ping same hosts again
+pub struct PingCache {
+    // Pinged host -> (rtt in ms or none if dead, unix time of ping)
+    cache: HashMap<String, (Option<u64>, u64)>,
+    cache_file: String,
+    ttl: Duration,
+}
+
+impl PingCache {
+    #[must_use]
+    pub fn new(cache_file: &str, ttl: Duration) -> Self {
+        Self {
+            cache: HashMap::new(),
+            cache_file: cache_file.to_owned(),
+            ttl,
+        }
+    }
+
+    /// # Errors
+    /// Return error if failed to read file
+    pub fn load_cache(&mut self) -> Result<()> {
+        if self.ttl.is_zero() || !Path::new(&self.cache_file).exists() {
+            return Ok(());
+        }
+
+        let data = fs::read(&self.cache_file).context("Failed to read ping cache")?;
+        self.cache = match CacheFile::detect(&data).context("Failed to read ping cache")? {
+            CacheFile::Binary(reader) => reader
+                .records(|r| {
+                    let host = r.str()?;
+                    // Dead hosts are stored with u64::MAX rtt
+                    let rtt = Some(r.u64()?).filter(|rtt| *rtt != u64::MAX);
+                    Some((host, (rtt, r.u64()?)))
+                })
+                .collect(),
+            CacheFile::Text(text) => text
+                .lines()
+                .filter_map(|line| {
+                    let mut parts = line.split_whitespace();
+                    let host = parts.next()?;
+                    let rtt = parts.next()?.parse().ok();
+                    let checked_at = parts.next()?.parse().ok()?;
+                    Some((host.to_owned(), (rtt, checked_at)))
+                })
+                .collect(),
+        };
+
+        Ok(())
+    }
+
+    // Returns fresh entry, rtt in it is none if host was dead
+    fn get(&self, host: &str) -> Option<&(Option<u64>, u64)> {
+        self.cache
+            .get(host)
+            .filter(|(_, checked_at)| now_secs().saturating_sub(*checked_at) < self.ttl.as_secs())
+    }
+
+    fn insert(&mut self, host: String, rtt: Option<Duration>) {
+        let rtt = rtt.map(|rtt| rtt.as_millis() as u64);
+        self.cache.insert(host, (rtt, now_secs()));
+    }
+
+    /// # Errors
+    /// Return error if failed to save file
+    pub async fn save(&self) -> Result<()> {
+        if self.ttl.is_zero() {
+            return Ok(());
+        }
+
+        let mut content = Writer::new();
+        for (host, (rtt, checked_at)) in self.cache.iter().filter(|(_, (_, checked_at))| {
+            now_secs().saturating_sub(*checked_at) < self.ttl.as_secs()
+        }) {
+            content.str(host);
+            content.u64(rtt.unwrap_or(u64::MAX));
+            content.u64(*checked_at);
+        }
+
+        tokio::fs::write(&self.cache_file, content.finish())
+            .await
+            .context("Failed to save ping cache")
+    }
+}
+
+struct Pingers {
+    v4: Client,
+    // Missing when system has no IPv6
+    v6: Option<Client>,
+    // Every ping gets own identifier, so concurrent pings of the same
+    // host don't take each other replies
+    next_ident: AtomicU16,
+}
+
+impl Pingers {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            v4: Client::new(&Config::default())?,
+            v6: Client::new(&Config::builder().kind(ICMP::V6).build()).ok(),
+            next_ident: AtomicU16::new(0),
+        })
+    }
+
+    async fn ping(&self, host: IpAddr, timeout: Duration, seq: u16) -> Option<Duration> {
+        let client = if host.is_ipv4() {
+            &self.v4
+        } else {
+            self.v6.as_ref()?
+        };
+        let ident = self.next_ident.fetch_add(1, Ordering::Relaxed);
+        let mut pinger = client.pinger(host, PingIdentifier(ident)).await;
+        pinger.timeout(timeout);
+        pinger
+            .ping(PingSequence(seq), &[])
+            .await
+            .ok()
+            .map(|(_, rtt)| rtt)
+    }
+}
+
+/// Whether ICMP sockets can be opened, they need raw socket capability or
+/// unprivileged ping permission
+#[must_use]
+pub fn icmp_available() -> bool {
+    Client::new(&Config::default()).is_ok()
+}
+
+async fn tcp_ping(addr: SocketAddr, timeout: Duration) -> Option<Duration> {
+    let start = Instant::now();
+    tokio::time::timeout(timeout, TcpStream::connect(addr))
+        .await
+        .ok()?
+        .ok()?;
+    Some(start.elapsed())
+}
+
+async fn ping_host(
+    proxy: &ProxyConfig,
+    target: PingTarget,
+    dns_cache: Arc<Mutex<DnsCache>>,
+) -> IpAddr {
+    match (target, proxy.query_params.get("sni")) {
+        (PingTarget::Sni, Some(sni)) => resolve_host(Host::Domain(sni), Some(443), dns_cache)
+            .await
+            .unwrap_or(proxy.address),
+        _ => proxy.address,
+    }
+}
+
+async fn measure_rtt(
+    pingers: &Pingers,
+    proxy: &ProxyConfig,
+    host: IpAddr,
+    options: &PingOptions,
+) -> Option<Duration> {
+    for attempt in 0..options.attempts {
+        let rtt = if options.target == PingTarget::Tcp {
+            tcp_ping(SocketAddr::new(proxy.address, proxy.port), options.timeout).await
+        } else {
+            pingers.ping(host, options.timeout, attempt as u16).await
+        };
+        trace::record(
+            proxy,
+            "ping",
+            format_args!("attempt {} to {host}: {rtt:?}", attempt + 1),
+        );
+        if rtt.is_some() {
+            return rtt;
+        }
+        if attempt < options.attempts - 1 {
+            tokio::time::sleep(options.delay).await;
+        }
+    }
+    None
+}
+
+/// Returns proxies answering ping, with measured round trip time
+///
+/// # Errors
+/// Return error if failed to open ICMP sockets
+pub async fn ping_proxies(
+    proxies: impl IntoIterator<Item = ProxyConfig>,
+    options: &PingOptions,
+    dns_cache: Arc<Mutex<DnsCache>>,
+    ping_cache: &Mutex<PingCache>,
+) -> Result<Vec<ProxyConfig>> {
+    let pingers = Pingers::new()?;
+
+    let alive = stream::iter(proxies)
+        .map(|mut proxy| {
+            let (pingers, dns_cache) = (&pingers, Arc::clone(&dns_cache));
+            async move {
+                let host = ping_host(&proxy, options.target, dns_cache).await;
+                let cache_key = if options.target == PingTarget::Tcp {
+                    SocketAddr::new(proxy.address, proxy.port).to_string()
+                } else {
+                    host.to_string()
+                };
+
+                let cached = ping_cache.lock().await.get(&cache_key).copied();
+                let rtt = if let Some((rtt, _)) = cached {
+                    rtt.map(Duration::from_millis)
+                } else {
+                    let rtt = measure_rtt(pingers, &proxy, host, options).await;
+                    ping_cache.lock().await.insert(cache_key, rtt);
+                    rtt
+                };
+
+                proxy.ping = Duration::ZERO; // alive, latency will be measured later
+                proxy.icmp_rtt = rtt;
+                if rtt.is_none() {
+                    if options.policy == PingPolicy::Filter {
+                        return None;
+                    }
+                    proxy.no_icmp = true;
+                }
+                Some(proxy)
+            }
+        })
+        .buffer_unordered(options.max_concurrent)
+        .filter_map(|x| async { x })
+        .collect()
+        .await;
+
+    ping_cache.lock().await.save().await?;
+
+    Ok(alive)
+}
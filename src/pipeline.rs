@@ -0,0 +1,234 @@
+//! Timeouts, concurrency and ports of a checking run.
+//!
+//! [`PipelineConfig`] is filled from command line and `novaprox.conf` by the
+//! checker, embedders build it with [`PipelineConfig::builder`] or
+//! deserialize it, every way ends in [`PipelineConfig::validate`].
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::ConfigError;
+
+// Highest port a chunk inbound may take
+const MAX_PORT: usize = u16::MAX as usize;
+
+/// Knobs of every stage of a run, named like their command line options.
+/// Missing fields of deserialized config keep their defaults
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub request_timeout_ms: u64,
+    // Lower bound of ping scaled timeout, see `timeout_ping_factor`
+    pub min_request_timeout_ms: u64,
+    // Check timeout of pinged proxies is `factor * ping` when above 0
+    pub timeout_ping_factor: u32,
+    pub check_retries: u32,
+    pub check_retry_delay_ms: u64,
+    pub check_jitter_ms: u64,
+    // Check requests per proxy, latency is reported as percentiles when
+    // above 1
+    pub check_samples: u32,
+    // Quick pass before the full check when above 0
+    pub prefilter_timeout_ms: u64,
+    pub ping_timeout_ms: u64,
+    // Proxies run by a single core process
+    pub chunk_size: usize,
+    // Local inbounds of chunk take ports from this one up
+    pub base_start_port: usize,
+    pub max_concurrent_pings: usize,
+    pub max_concurrent_checks: usize,
+    pub max_concurrent_dns: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout_ms: 5000,
+            min_request_timeout_ms: 1000,
+            timeout_ping_factor: 0,
+            check_retries: 0,
+            check_retry_delay_ms: 500,
+            check_jitter_ms: 0,
+            check_samples: 1,
+            prefilter_timeout_ms: 0,
+            ping_timeout_ms: 700,
+            chunk_size: 300,
+            base_start_port: 15808,
+            max_concurrent_pings: 200,
+            max_concurrent_checks: 100,
+            max_concurrent_dns: 50,
+        }
+    }
+}
+
+impl PipelineConfig {
+    #[must_use]
+    pub fn builder() -> PipelineConfigBuilder {
+        PipelineConfigBuilder::default()
+    }
+
+    /// Config of JSON object, see [`PipelineConfig`] for field names
+    ///
+    /// # Errors
+    /// Return error if JSON has unknown or mistyped fields, or config is
+    /// invalid
+    pub fn from_json(json: &str) -> Result<Self, ConfigError> {
+        let config = serde_json::from_str::<Self>(json).map_err(ConfigError::InvalidJson)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks values the run can't work with
+    ///
+    /// # Errors
+    /// Return error naming the option to fix
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let zero = [
+            ("request_timeout_ms", self.request_timeout_ms == 0),
+            ("ping_timeout_ms", self.ping_timeout_ms == 0),
+            ("check_samples", self.check_samples == 0),
+            ("chunk_size", self.chunk_size == 0),
+            ("base_start_port", self.base_start_port == 0),
+            ("max_concurrent_pings", self.max_concurrent_pings == 0),
+            ("max_concurrent_checks", self.max_concurrent_checks == 0),
+            ("max_concurrent_dns", self.max_concurrent_dns == 0),
+        ];
+        if let Some((option, _)) = zero.into_iter().find(|(_, zero)| *zero) {
+            return Err(ConfigError::Zero(option));
+        }
+
+        if self.base_start_port + self.chunk_size > MAX_PORT {
+            return Err(ConfigError::PortsExhausted {
+                base_start_port: self.base_start_port,
+                chunk_size: self.chunk_size,
+            });
+        }
+        if self.timeout_ping_factor > 0 && self.min_request_timeout_ms > self.request_timeout_ms {
+            return Err(ConfigError::Exceeds {
+                option: "min_request_timeout_ms",
+                limit: "request_timeout_ms",
+            });
+        }
+        if self.prefilter_timeout_ms > self.request_timeout_ms {
+            return Err(ConfigError::Exceeds {
+                option: "prefilter_timeout_ms",
+                limit: "request_timeout_ms",
+            });
+        }
+        Ok(())
+    }
+
+    #[must_use]
+    pub const fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+
+    #[must_use]
+    pub const fn min_request_timeout(&self) -> Duration {
+        Duration::from_millis(self.min_request_timeout_ms)
+    }
+
+    #[must_use]
+    pub const fn check_retry_delay(&self) -> Duration {
+        Duration::from_millis(self.check_retry_delay_ms)
+    }
+
+    #[must_use]
+    pub const fn check_jitter(&self) -> Duration {
+        Duration::from_millis(self.check_jitter_ms)
+    }
+
+    #[must_use]
+    pub const fn prefilter_timeout(&self) -> Duration {
+        Duration::from_millis(self.prefilter_timeout_ms)
+    }
+
+    #[must_use]
+    pub const fn ping_timeout(&self) -> Duration {
+        Duration::from_millis(self.ping_timeout_ms)
+    }
+}
+
+/// Builds validated [`PipelineConfig`], unset options keep their defaults
+#[derive(Debug, Clone, Default)]
+pub struct PipelineConfigBuilder {
+    config: PipelineConfig,
+}
+
+impl PipelineConfigBuilder {
+    #[must_use]
+    pub const fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.request_timeout_ms = timeout.as_millis() as u64;
+        self
+    }
+
+    /// Scales check timeout of pinged proxies as `max(min, factor * ping)`
+    #[must_use]
+    pub const fn timeout_ping_factor(mut self, factor: u32, min: Duration) -> Self {
+        self.config.timeout_ping_factor = factor;
+        self.config.min_request_timeout_ms = min.as_millis() as u64;
+        self
+    }
+
+    #[must_use]
+    pub const fn check_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.config.check_retries = retries;
+        self.config.check_retry_delay_ms = delay.as_millis() as u64;
+        self
+    }
+
+    #[must_use]
+    pub const fn check_jitter(mut self, jitter: Duration) -> Self {
+        self.config.check_jitter_ms = jitter.as_millis() as u64;
+        self
+    }
+
+    /// Checklist requests are repeated until `samples` were made
+    #[must_use]
+    pub const fn check_samples(mut self, samples: u32) -> Self {
+        self.config.check_samples = samples;
+        self
+    }
+
+    #[must_use]
+    pub const fn prefilter_timeout(mut self, timeout: Duration) -> Self {
+        self.config.prefilter_timeout_ms = timeout.as_millis() as u64;
+        self
+    }
+
+    #[must_use]
+    pub const fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.config.ping_timeout_ms = timeout.as_millis() as u64;
+        self
+    }
+
+    #[must_use]
+    pub const fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.config.chunk_size = chunk_size;
+        self
+    }
+
+    #[must_use]
+    pub const fn base_start_port(mut self, port: usize) -> Self {
+        self.config.base_start_port = port;
+        self
+    }
+
+    /// Concurrency of ping, check and DNS stages
+    #[must_use]
+    pub const fn max_concurrent(mut self, pings: usize, checks: usize, dns: usize) -> Self {
+        self.config.max_concurrent_pings = pings;
+        self.config.max_concurrent_checks = checks;
+        self.config.max_concurrent_dns = dns;
+        self
+    }
+
+    /// # Errors
+    /// Return error naming the option to fix, see
+    /// [`PipelineConfig::validate`]
+    pub fn build(self) -> Result<PipelineConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
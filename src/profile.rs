@@ -0,0 +1,166 @@
+//! Config file of default arguments, with named profiles.
+//!
+//! Lines are `key = value` of top level options, e.g. `chunk-size = 200`,
+//! `true` turns flag on and `false` off. Lines before the first `[name]`
+//! section apply always, section `[name]` only with `--profile name` and
+//! overrides them.
+//! `inherits = other` in a section applies profile `other` first.
+
+use std::{fs, path::Path};
+
+use ahash::HashMap;
+use anyhow::{Context as _, Result, bail};
+
+pub const DEFAULT_CONFIG: &str = "novaprox.conf";
+
+// Deepest `inherits` chain, deeper ones are taken as cycle
+const MAX_INHERITANCE: usize = 16;
+
+/// Command line with arguments of config file and selected profile put
+/// before the given ones, which override them
+///
+/// # Errors
+/// Return error if config is malformed, or profile is selected and config
+/// or profile doesn't exist
+pub fn with_config_args(cli: Vec<String>) -> Result<Vec<String>> {
+    let config = option_value(&cli, "--config");
+    let profile = option_value(&cli, "--profile");
+    let path = config.unwrap_or(DEFAULT_CONFIG);
+
+    if config.is_none() && profile.is_none() && !Path::new(path).exists() {
+        return Ok(cli);
+    }
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read config {path}"))?;
+    let sections = parse_sections(&content)?;
+
+    let mut chain = Vec::new();
+    let mut next = profile.map(str::to_owned);
+    while let Some(name) = next {
+        if chain.len() == MAX_INHERITANCE {
+            bail!("Profile {name} inherits itself");
+        }
+        let section = sections
+            .get(&name)
+            .with_context(|| format!("No profile {name} in {path}"))?;
+        next = section
+            .iter()
+            .find(|(key, _)| key == "inherits")
+            .map(|(_, parent)| parent.clone());
+        chain.push(name);
+    }
+
+    let mut args = cli.iter().take(1).cloned().collect::<Vec<_>>();
+    let base = sections.get("").into_iter();
+    let profiles = chain.iter().rev().filter_map(|name| sections.get(name));
+    args.extend(merged_args(base.chain(profiles)));
+    args.extend(cli.into_iter().skip(1));
+    Ok(args)
+}
+
+// Arguments of sections applied in order. A key set again replaces all
+// its earlier values, so `false` turns off a flag set before and repeated
+// options of a profile replace those of the base
+fn merged_args<'a>(sections: impl Iterator<Item = &'a Vec<(String, String)>>) -> Vec<String> {
+    let mut merged = Vec::<(&str, Vec<&str>)>::new();
+    for section in sections {
+        let mut replaced = Vec::new();
+        for (key, value) in section {
+            if key == "inherits" {
+                continue;
+            }
+            if let Some((_, values)) = merged.iter_mut().find(|(known, _)| known == key) {
+                if !replaced.contains(&key) {
+                    values.clear();
+                    replaced.push(key);
+                }
+                values.push(value);
+            } else {
+                merged.push((key, vec![value]));
+                replaced.push(key);
+            }
+        }
+    }
+
+    merged
+        .into_iter()
+        .flat_map(|(key, values)| {
+            values.into_iter().filter_map(move |value| match value {
+                "true" => Some(format!("--{key}")),
+                "false" => None,
+                value => Some(format!("--{key}={value}")),
+            })
+        })
+        .collect()
+}
+
+// Value of `--name value` or `--name=value` in command line
+fn option_value<'a>(cli: &'a [String], name: &str) -> Option<&'a str> {
+    cli.iter().enumerate().find_map(|(i, arg)| {
+        if arg == name {
+            cli.get(i + 1).map(String::as_str)
+        } else {
+            arg.strip_prefix(name)?.strip_prefix('=')
+        }
+    })
+}
+
+// Settings by section name, base settings under empty name
+fn parse_sections(content: &str) -> Result<HashMap<String, Vec<(String, String)>>> {
+    let mut sections = HashMap::<String, Vec<_>>::default();
+    let mut section = String::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_owned();
+            sections.entry(section.clone()).or_default();
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("Config line {line} isn't `key = value`"))?;
+        sections.entry(section.clone()).or_default().push((
+            key.trim().replace('_', "-"),
+            value.trim().trim_matches('"').to_owned(),
+        ));
+    }
+    Ok(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(config: &str, profiles: &[&str]) -> Vec<String> {
+        let sections = parse_sections(config).unwrap();
+        let applied = std::iter::once("")
+            .chain(profiles.iter().copied())
+            .filter_map(|name| sections.get(name));
+        merged_args(applied)
+    }
+
+    #[test]
+    fn profile_turns_off_flag_of_base() {
+        let config = "check-http3 = true\nchunk-size = 100\n[quick]\ncheck-http3 = false\n";
+        assert_eq!(args(config, &[]), ["--check-http3", "--chunk-size=100"]);
+        assert_eq!(args(config, &["quick"]), ["--chunk-size=100"]);
+    }
+
+    #[test]
+    fn profile_replaces_repeated_options_of_base() {
+        let config = "sandbox-allow = a\nsandbox-allow = b\n[other]\nsandbox-allow = c\n";
+        assert_eq!(
+            args(config, &[]),
+            ["--sandbox-allow=a", "--sandbox-allow=b"]
+        );
+        assert_eq!(args(config, &["other"]), ["--sandbox-allow=c"]);
+    }
+
+    #[test]
+    fn inherited_profile_applies_first() {
+        let config = "[base]\nsort = ping\n[child]\ninherits = base\nsort = speed\n";
+        assert_eq!(args(config, &["base", "child"]), ["--sort=speed"]);
+    }
+}
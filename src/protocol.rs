@@ -0,0 +1,477 @@
+//! Parsing, outbound generation and export of every protocol, looked up by
+//! link scheme.
+//!
+//! Built-in protocols are handlers like any other, protocols added outside
+//! this crate are registered with [`register_protocol`] and take precedence
+//! over built-in handlers of the same scheme.
+
+use std::sync::{Arc, LazyLock, RwLock};
+
+use base64::Engine as _;
+use serde_json::{Map, Value, json};
+use url::Url;
+
+use crate::{
+    error::EngineError,
+    parse_url::{strip_zone_id, url_host},
+    proxy_config::ProxyConfig,
+    xray_config::{Engine, create_common_server_settings, create_stream_settings},
+};
+
+/// Parses links of one protocol, validates proxies and emits their
+/// outbounds and client config entries
+pub trait ProtocolHandler: Send + Sync {
+    /// Link schemes of the protocol, e.g. `ss` and `shadowsocks`
+    fn schemes(&self) -> &'static [&'static str];
+
+    /// Outbound tag prefix, lowercase letters and digits so rejected
+    /// outbounds can be found in core errors
+    fn tag(&self) -> &'static str;
+
+    /// Checks `engine` can run outbound of proxy
+    ///
+    /// # Errors
+    /// Return error if proxy can't be run by `engine`
+    fn validate(&self, _proxy: &ProxyConfig, _engine: Engine) -> Result<(), EngineError> {
+        Ok(())
+    }
+
+    /// Share link as URL, `None` if it isn't one. Links are URLs unless
+    /// the protocol encodes them otherwise, like vmess base64 config
+    fn parse(&self, link: &str) -> Option<Url> {
+        Url::parse(&strip_zone_id(link)).ok()
+    }
+
+    /// Outbound of proxy for `engine`, tagged with `tag`
+    fn outbound(&self, proxy: &ProxyConfig, tag: String, engine: Engine) -> Value;
+
+    /// Protocol fields of Clash proxy entry, `type` included. `None` if
+    /// Clash can't run the protocol
+    fn clash(&self, _proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        None
+    }
+
+    /// Protocol fields of sing-box outbound, `type` included. `None` if
+    /// sing-box can't run the protocol
+    fn sing_box(&self, _proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        None
+    }
+}
+
+// Registered handlers first, so they override built-in ones
+static PROTOCOLS: LazyLock<RwLock<Vec<Arc<dyn ProtocolHandler>>>> = LazyLock::new(|| {
+    RwLock::new(vec![
+        Arc::new(Http),
+        Arc::new(Socks),
+        Arc::new(Shadowsocks),
+        Arc::new(Trojan),
+        Arc::new(Vless),
+        Arc::new(Vmess),
+    ])
+});
+
+/// Adds handler of a protocol, replacing earlier handlers of its schemes
+pub fn register_protocol(handler: Arc<dyn ProtocolHandler>) {
+    if let Ok(mut handlers) = PROTOCOLS.write() {
+        handlers.insert(0, handler);
+    }
+}
+
+/// Handler of links of `scheme`
+#[must_use]
+pub fn protocol_handler(scheme: &str) -> Option<Arc<dyn ProtocolHandler>> {
+    PROTOCOLS
+        .read()
+        .ok()?
+        .iter()
+        .find(|handler| handler.schemes().contains(&scheme))
+        .cloned()
+}
+
+// Param of link, empty ones are as good as missing
+fn param<'a>(proxy: &'a ProxyConfig, key: &str) -> Option<&'a str> {
+    proxy
+        .query_params
+        .get(key)
+        .map(String::as_str)
+        .filter(|value| !value.is_empty())
+}
+
+// Fields of an object, for `json!` literals of export hooks
+fn fields(value: Value) -> Option<Map<String, Value>> {
+    match value {
+        Value::Object(fields) => Some(fields),
+        _ => None,
+    }
+}
+
+// Username and password of http and socks proxies, under `user` and
+// `pass` keys of both Clash and sing-box
+fn credentials(proxy: &ProxyConfig, kind: &str) -> Map<String, Value> {
+    let mut fields = Map::new();
+    fields.insert("type".to_owned(), json!(kind));
+    if let Some(user) = param(proxy, "user") {
+        fields.insert("username".to_owned(), json!(user));
+    }
+    if let Some(pass) = param(proxy, "pass") {
+        fields.insert("password".to_owned(), json!(pass));
+    }
+    fields
+}
+
+struct Http;
+
+impl ProtocolHandler for Http {
+    fn schemes(&self) -> &'static [&'static str] {
+        &["http", "https"]
+    }
+
+    fn tag(&self) -> &'static str {
+        "http"
+    }
+
+    fn outbound(&self, proxy: &ProxyConfig, tag: String, _: Engine) -> Value {
+        let settings = create_common_server_settings(proxy, &["user", "pass"]);
+        json!({
+            "protocol": "http",
+            "settings": settings,
+            "tag": tag
+        })
+    }
+
+    fn clash(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        Some(credentials(proxy, "http"))
+    }
+
+    fn sing_box(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        Some(credentials(proxy, "http"))
+    }
+}
+
+struct Socks;
+
+impl ProtocolHandler for Socks {
+    fn schemes(&self) -> &'static [&'static str] {
+        &["socks", "socks5"]
+    }
+
+    fn tag(&self) -> &'static str {
+        "socks"
+    }
+
+    fn outbound(&self, proxy: &ProxyConfig, tag: String, _: Engine) -> Value {
+        let settings = create_common_server_settings(proxy, &["user", "pass"]);
+        json!({
+            "protocol": "socks",
+            "settings": settings,
+            "tag": tag
+        })
+    }
+
+    fn clash(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        Some(credentials(proxy, "socks5"))
+    }
+
+    fn sing_box(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        Some(credentials(proxy, "socks"))
+    }
+}
+
+struct Shadowsocks;
+
+impl ProtocolHandler for Shadowsocks {
+    fn schemes(&self) -> &'static [&'static str] {
+        &["ss", "shadowsocks"]
+    }
+
+    fn tag(&self) -> &'static str {
+        "ss"
+    }
+
+    fn outbound(&self, proxy: &ProxyConfig, tag: String, engine: Engine) -> Value {
+        let mut settings = create_common_server_settings(proxy, &[]);
+
+        if let Some(method) = proxy.query_params.get("method") {
+            settings["method"] = json!(method);
+        } else {
+            settings["method"] = json!("aes-256-gcm");
+        }
+
+        settings["password"] = json!(proxy.username);
+
+        // UDP over TCP is xray only
+        if engine == Engine::Xray {
+            if let Some(uot) = proxy.query_params.get("uot") {
+                settings["uot"] = json!(uot == "true");
+            }
+            if let Some(uot_version) = proxy
+                .query_params
+                .get("UoTVersion")
+                .and_then(|v| v.parse::<u32>().ok())
+            {
+                settings["UoTVersion"] = json!(uot_version);
+            }
+        }
+
+        json!({
+            "protocol": "shadowsocks",
+            "settings": settings,
+            "tag": tag
+        })
+    }
+
+    fn clash(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        fields(json!({
+            "type": "ss",
+            "cipher": param(proxy, "method").unwrap_or("aes-256-gcm"),
+            "password": proxy.username,
+        }))
+    }
+
+    fn sing_box(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        fields(json!({
+            "type": "shadowsocks",
+            "method": param(proxy, "method").unwrap_or("aes-256-gcm"),
+            "password": proxy.username,
+        }))
+    }
+}
+
+struct Trojan;
+
+impl ProtocolHandler for Trojan {
+    fn schemes(&self) -> &'static [&'static str] {
+        &["trojan"]
+    }
+
+    fn tag(&self) -> &'static str {
+        "trojan"
+    }
+
+    fn outbound(&self, proxy: &ProxyConfig, tag: String, _: Engine) -> Value {
+        let mut settings = create_common_server_settings(proxy, &[]);
+        settings["password"] = json!(proxy.username);
+
+        let mut outbound = json!({
+            "protocol": "trojan",
+            "settings": settings,
+            "tag": tag
+        });
+
+        if let Some(stream_settings) = create_stream_settings(&proxy.query_params) {
+            outbound["streamSettings"] = stream_settings;
+        }
+
+        outbound
+    }
+
+    fn clash(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        fields(json!({ "type": "trojan", "password": proxy.username }))
+    }
+
+    fn sing_box(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        fields(json!({ "type": "trojan", "password": proxy.username }))
+    }
+}
+
+struct Vless;
+
+impl ProtocolHandler for Vless {
+    fn schemes(&self) -> &'static [&'static str] {
+        &["vless"]
+    }
+
+    fn tag(&self) -> &'static str {
+        "vless"
+    }
+
+    fn outbound(&self, proxy: &ProxyConfig, tag: String, engine: Engine) -> Value {
+        let mut settings = create_common_server_settings(proxy, &[]);
+        settings["id"] = json!(proxy.username);
+        settings["encryption"] = json!("none");
+
+        // v2fly has no xtls flows
+        if engine == Engine::Xray
+            && let Some(flow) = proxy.query_params.get("flow")
+        {
+            settings["flow"] = json!(flow);
+        }
+
+        let mut outbound = json!({
+            "protocol": "vless",
+            "settings": settings,
+            "tag": tag
+        });
+
+        if let Some(stream_settings) = create_stream_settings(&proxy.query_params) {
+            outbound["streamSettings"] = stream_settings;
+        }
+
+        outbound
+    }
+
+    fn clash(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        let mut fields = fields(json!({ "type": "vless", "uuid": proxy.username }))?;
+        if let Some(flow) = param(proxy, "flow") {
+            fields.insert("flow".to_owned(), json!(flow));
+        }
+        Some(fields)
+    }
+
+    fn sing_box(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        self.clash(proxy)
+    }
+}
+
+struct Vmess;
+
+impl ProtocolHandler for Vmess {
+    fn schemes(&self) -> &'static [&'static str] {
+        &["vmess"]
+    }
+
+    fn tag(&self) -> &'static str {
+        "vmess"
+    }
+
+    // Link is base64 of JSON config
+    fn parse(&self, link: &str) -> Option<Url> {
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(link.strip_prefix("vmess://")?)
+            .ok()?;
+        let config = serde_json::from_slice::<Value>(&decoded).ok()?;
+        let address = config["add"].as_str()?;
+        let port = u16::try_from(config["port"].as_u64()?).ok()?;
+        let id = config["id"].as_str()?;
+        Url::parse(&format!("vmess://{id}@{}:{port}", url_host(address))).ok()
+    }
+
+    fn outbound(&self, proxy: &ProxyConfig, tag: String, _: Engine) -> Value {
+        let security = proxy
+            .query_params
+            .get("security")
+            .map(|s| s.as_str())
+            .unwrap_or("auto");
+        let level = proxy
+            .query_params
+            .get("level")
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(0);
+
+        let settings = json!({
+            "vnext": [{
+                "address": proxy.address,
+                "port": proxy.port,
+                "users": [{
+                    "id": proxy.username,
+                    "security": security,
+                    "level": level
+                }]
+            }]
+        });
+
+        let mut outbound = json!({
+            "protocol": "vmess",
+            "settings": settings,
+            "tag": tag
+        });
+
+        if let Some(stream_settings) = create_stream_settings(&proxy.query_params) {
+            outbound["streamSettings"] = stream_settings;
+        }
+
+        outbound
+    }
+
+    fn clash(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        fields(json!({
+            "type": "vmess",
+            "uuid": proxy.username,
+            "alterId": 0,
+            "cipher": param(proxy, "security").unwrap_or("auto"),
+        }))
+    }
+
+    fn sing_box(&self, proxy: &ProxyConfig) -> Option<Map<String, Value>> {
+        fields(json!({
+            "type": "vmess",
+            "uuid": proxy.username,
+            "security": param(proxy, "security").unwrap_or("auto"),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_url::parse_proxy_url;
+
+    fn proxy(link: &str) -> ProxyConfig {
+        let url = Url::parse(link).unwrap();
+        let address = url.host_str().unwrap().parse().unwrap();
+        ProxyConfig::from_url(url, address)
+    }
+
+    // Links written as `compact:host:port:secret`
+    struct Compact;
+
+    impl ProtocolHandler for Compact {
+        fn schemes(&self) -> &'static [&'static str] {
+            &["compact"]
+        }
+
+        fn tag(&self) -> &'static str {
+            "compact"
+        }
+
+        fn parse(&self, link: &str) -> Option<Url> {
+            let mut parts = link.strip_prefix("compact:")?.split(':');
+            let (host, port, secret) = (parts.next()?, parts.next()?, parts.next()?);
+            Url::parse(&format!("compact://{secret}@{host}:{port}")).ok()
+        }
+
+        fn outbound(&self, proxy: &ProxyConfig, tag: String, _: Engine) -> Value {
+            json!({ "protocol": "compact", "settings": { "secret": proxy.username }, "tag": tag })
+        }
+    }
+
+    #[test]
+    fn registered_handler_parses_its_links() {
+        register_protocol(Arc::new(Compact));
+        let url = parse_proxy_url("compact:192.0.2.1:1000:secret", "compact", &[], &[]).unwrap();
+        assert_eq!(url.scheme(), "compact");
+        assert_eq!(url.username(), "secret");
+        assert_eq!(url.host_str(), Some("192.0.2.1"));
+        assert_eq!(url.port(), Some(1000));
+        assert!(parse_proxy_url("compact:broken", "compact", &[], &[]).is_none());
+    }
+
+    #[test]
+    fn v2fly_outbounds_leave_out_xray_fields() {
+        let vless = proxy("vless://id@192.0.2.1:443?flow=xtls-rprx-vision");
+        let handler = protocol_handler("vless").unwrap();
+        let xray = handler.outbound(&vless, "vless-out-0".to_owned(), Engine::Xray);
+        let v2fly = handler.outbound(&vless, "vless-out-0".to_owned(), Engine::V2fly);
+        assert_eq!(xray["settings"]["flow"], "xtls-rprx-vision");
+        assert!(v2fly["settings"].get("flow").is_none());
+
+        let ss = proxy("ss://pass@192.0.2.1:8388?uot=true&UoTVersion=2");
+        let handler = protocol_handler("ss").unwrap();
+        let xray = handler.outbound(&ss, "ss-out-0".to_owned(), Engine::Xray);
+        let v2fly = handler.outbound(&ss, "ss-out-0".to_owned(), Engine::V2fly);
+        assert_eq!(xray["settings"]["uot"], true);
+        assert_eq!(xray["settings"]["UoTVersion"], 2);
+        assert!(v2fly["settings"].get("uot").is_none());
+        assert!(v2fly["settings"].get("UoTVersion").is_none());
+    }
+
+    #[test]
+    fn builtins_export_to_clash_and_sing_box() {
+        for scheme in ["http", "socks", "ss", "trojan", "vless", "vmess"] {
+            let proxy = proxy(&format!("{scheme}://id@192.0.2.1:443"));
+            let handler = protocol_handler(scheme).unwrap();
+            assert!(handler.clash(&proxy).unwrap().contains_key("type"));
+            assert!(handler.sing_box(&proxy).unwrap().contains_key("type"));
+        }
+    }
+}
@@ -1,23 +1,156 @@
 use std::{
     fmt::{self},
     net::IpAddr,
+    sync::RwLock,
     time::Duration,
 };
 
 use litemap::LiteMap;
 use url::Url;
 
+use crate::cdn;
+
+// Ports assumed for links without one
 const DEFAULT_PORTS: &[(&str, u16)] = &[
     ("http", 80),
-    ("https", 80),
+    ("https", 443),
     ("socks", 1080),
     ("socks5", 1080),
+    ("ss", 8388),
     ("shadowsocks", 8388),
     ("trojan", 443),
     ("vless", 443),
     ("vmess", 443),
+    ("hysteria", 443),
+    ("hysteria2", 443),
+    ("hy2", 443),
+    ("tuic", 443),
+    ("wireguard", 51820),
 ];
 
+// Port when neither link nor registry has one
+const FALLBACK_PORT: u16 = 8080;
+
+// Default ports of schemes registered at runtime, checked before
+// DEFAULT_PORTS
+static REGISTERED_PORTS: RwLock<Vec<(String, u16)>> = RwLock::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anonymity {
+    // Client IP is visible to the destination
+    Transparent,
+    // Client IP hidden, but proxy headers reveal a proxy is used
+    Anonymous,
+    // No trace of the proxy in request headers
+    Elite,
+}
+
+impl fmt::Display for Anonymity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Transparent => "transparent",
+            Self::Anonymous => "anonymous",
+            Self::Elite => "elite",
+        })
+    }
+}
+
+impl Anonymity {
+    const PROXY_HEADERS: &[&str] = &[
+        "via",
+        "x-forwarded-for",
+        "forwarded",
+        "x-real-ip",
+        "proxy-connection",
+        "x-proxy-id",
+        "client-ip",
+    ];
+
+    /// Classifies proxy by the request headers echoed back by the destination
+    #[must_use]
+    pub fn classify(echoed_headers: &str, real_ip: IpAddr) -> Self {
+        let echoed = echoed_headers.to_lowercase();
+        if echoed.contains(&real_ip.to_string()) {
+            Self::Transparent
+        } else if Self::PROXY_HEADERS
+            .iter()
+            .any(|header| echoed.contains(header))
+        {
+            Self::Anonymous
+        } else {
+            Self::Elite
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::V4 => "v4",
+            Self::V6 => "v6",
+        })
+    }
+}
+
+/// Outcome of racing IPv4 and IPv6 addresses of a dual-stack server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DualStack {
+    // Family that connected first, proxy address is of it
+    pub winner: Family,
+    // Server accepted connection over IPv6
+    pub ipv6: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SniIssue {
+    // TLS server name is an IP address, which servers never expect
+    IpLiteral,
+    // Server name or host header is a domain known to be blocked
+    Banned,
+    // Server name and host header name different domains
+    HostMismatch,
+}
+
+impl fmt::Display for SniIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::IpLiteral => "sni ip",
+            Self::Banned => "sni banned",
+            Self::HostMismatch => "sni/host mismatch",
+        })
+    }
+}
+
+/// Phases of check requests, each from the end of the previous
+///
+/// xray acknowledges SOCKS CONNECT before it dials the proxy, so reaching the
+/// proxy falls into the first phase talking to the target: TLS handshake of
+/// HTTPS targets, first byte of plain HTTP ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    // SOCKS handshake with the local inbound. Only engine overhead with
+    // xray, the native engine answers it once the proxy reached the target
+    pub socks: Duration,
+    // Handshake with HTTPS targets
+    pub tls: Option<Duration>,
+    // From sending the request to the response head
+    pub first_byte: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    pub succeeded: usize,
+    pub attempted: usize,
+    // Average latency of the parallel requests
+    pub latency: Duration,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ProxyConfig {
     pub address: IpAddr,
@@ -26,8 +159,40 @@ pub struct ProxyConfig {
     pub query_params: LiteMap<String, String>,
     pub username: String,
     pub ping: Duration,
+    // 95th percentile of check latency, `ping` is then the median
+    pub ping_p95: Option<Duration>,
+    // Average phases of check requests, slow servers are slow to answer,
+    // long routes are slow in every phase
+    pub timings: Option<Timings>,
     pub bandwidth: u64,
     pub country: Option<[char; 2]>,
+    pub tls_mismatch: bool,
+    pub anonymity: Option<Anonymity>,
+    pub exit_ip: Option<IpAddr>,
+    pub abuse_score: Option<u8>,
+    pub drops_long_connections: bool,
+    // Large download came back different or didn't finish in time
+    pub corrupts_payloads: bool,
+    pub capacity: Option<Capacity>,
+    // Uplink and downlink bytes counted by xray during testing
+    pub traffic: Option<(u64, u64)>,
+    // Whether QUIC traffic passes through the proxy
+    pub http3: Option<bool>,
+    // Most attempts a single check request needed
+    pub check_attempts: u32,
+    pub icmp_rtt: Option<Duration>,
+    // Proxy didn't answer ping, but was kept for the real check
+    pub no_icmp: bool,
+    // Percent of successful checks in monitor mode
+    pub uptime: Option<u8>,
+    // Fix applied to make proxy work when it failed as published
+    pub variant: Option<&'static str>,
+    // CDN provider owning server address
+    pub cdn: Option<String>,
+    pub sni_issue: Option<SniIssue>,
+    // Token of canary credentials sent through the proxy
+    pub canary: Option<String>,
+    pub dual_stack: Option<DualStack>,
 }
 
 impl fmt::Display for ProxyConfig {
@@ -39,11 +204,35 @@ impl fmt::Display for ProxyConfig {
             query_params,
             username,
             ping: _,
+            ping_p95: _,
+            timings: _,
             bandwidth: _,
             country: _,
+            tls_mismatch: _,
+            anonymity: _,
+            exit_ip: _,
+            abuse_score: _,
+            drops_long_connections: _,
+            corrupts_payloads: _,
+            capacity: _,
+            traffic: _,
+            http3: _,
+            check_attempts: _,
+            icmp_rtt: _,
+            no_icmp: _,
+            uptime: _,
+            variant: _,
+            cdn: _,
+            sni_issue: _,
+            canary: _,
+            dual_stack: _,
         } = self;
 
-        write!(f, "{protocol}://{username}@{address}:{port}")?;
+        // IPv6 literal must be bracketed in link authority
+        match address {
+            IpAddr::V4(ip) => write!(f, "{protocol}://{username}@{ip}:{port}")?,
+            IpAddr::V6(ip) => write!(f, "{protocol}://{username}@[{ip}]:{port}")?,
+        }
 
         if !query_params.is_empty() {
             write!(f, "?")?;
@@ -79,22 +268,153 @@ impl ProxyConfig {
     pub fn from_url(url: Url, resolved_addr: IpAddr) -> Self {
         let query_params = url.query_pairs().into_owned().collect::<LiteMap<_, _>>();
 
-        let default_port = DEFAULT_PORTS
-            .iter()
-            .find(|(scheme, _)| *scheme == url.scheme())
-            .map(|(_, port)| *port)
-            .unwrap_or(8080);
+        let default_port = default_port(url.scheme()).unwrap_or(FALLBACK_PORT);
 
         Self {
             address: resolved_addr,
             port: url.port().unwrap_or(default_port),
             protocol: url.scheme().to_lowercase(),
             query_params,
-            username: url.username().to_lowercase(),
+            // Case matters for passwords of trojan and shadowsocks
+            username: url.username().to_owned(),
             ping: Duration::default(),
+            ping_p95: None,
+            timings: None,
             bandwidth: 0,
             country: None,
+            tls_mismatch: false,
+            anonymity: None,
+            exit_ip: None,
+            abuse_score: None,
+            drops_long_connections: false,
+            corrupts_payloads: false,
+            capacity: None,
+            traffic: None,
+            http3: None,
+            check_attempts: 0,
+            icmp_rtt: None,
+            no_icmp: false,
+            uptime: None,
+            variant: None,
+            cdn: cdn::provider(resolved_addr),
+            sni_issue: None,
+            canary: None,
+            dual_stack: None,
+        }
+    }
+
+    /// Copies of proxy with common publishing mistakes fixed, in order
+    /// they are worth trying when proxy fails as published
+    #[must_use]
+    pub fn variants(&self) -> Vec<Self> {
+        let param = |key: &str| self.query_params.get(key).map(String::as_str);
+        let mut variants = Vec::new();
+        let mut add = |name, key: &str, value: Option<&str>| {
+            let mut variant = self.clone();
+            match value {
+                Some(value) => variant
+                    .query_params
+                    .insert(key.to_owned(), value.to_owned()),
+                None => variant.query_params.remove(key),
+            };
+            variant.variant = Some(name);
+            variants.push(variant);
+        };
+
+        let security = param("security");
+        if matches!(security, Some("tls" | "reality")) && param("fp").is_none() {
+            add("fp", "fp", Some("chrome"));
+        }
+        if security == Some("tls") {
+            match param("allowInsecure") {
+                Some("1" | "true") => add("secure", "allowInsecure", None),
+                _ => add("insecure", "allowInsecure", Some("1")),
+            }
+        }
+        if param("type") == Some("ws") && param("path") != Some("/") {
+            add("ws path", "path", Some("/"));
+        }
+
+        variants
+    }
+
+    /// Implausible TLS server name or host header, `banned` domains match
+    /// their subdomains too
+    #[must_use]
+    pub fn sni_issue(&self, banned: &[String]) -> Option<SniIssue> {
+        let param = |key: &str| self.query_params.get(key).filter(|value| !value.is_empty());
+        let (sni, host) = (param("sni"), param("host"));
+
+        let is_banned = |name: &String| {
+            banned.iter().any(|domain| {
+                name == domain
+                    || name
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+        };
+        if sni.is_some_and(|sni| sni.parse::<IpAddr>().is_ok()) {
+            Some(SniIssue::IpLiteral)
+        } else if sni.into_iter().chain(host).any(is_banned) {
+            Some(SniIssue::Banned)
+        } else if let (Some(sni), Some(host)) = (sni, host)
+            && !sni.eq_ignore_ascii_case(host)
+            && param("security").map(String::as_str) == Some("tls")
+            && host.parse::<IpAddr>().is_err()
+        {
+            Some(SniIssue::HostMismatch)
+        } else {
+            None
+        }
+    }
+
+    /// Fixes obvious server name mistakes: IP address or missing TLS server
+    /// name is replaced with host header domain. Returns whether anything
+    /// changed
+    pub fn fix_sni(&mut self) -> bool {
+        let Some(host) = self
+            .query_params
+            .get("host")
+            .filter(|host| !host.is_empty() && host.parse::<IpAddr>().is_err())
+            .cloned()
+        else {
+            return false;
+        };
+        let fixable = match self.query_params.get("sni").map(String::as_str) {
+            None | Some("") => self.query_params.get("security").map(String::as_str) == Some("tls"),
+            Some(sni) => sni.parse::<IpAddr>().is_ok(),
+        };
+        if fixable {
+            self.query_params.insert("sni".to_owned(), host);
+            self.variant = Some("sni");
         }
+        fixable
+    }
+}
+
+/// Port assumed for links of `scheme` without one
+#[must_use]
+pub fn default_port(scheme: &str) -> Option<u16> {
+    let registered = REGISTERED_PORTS.read().ok().and_then(|ports| {
+        ports
+            .iter()
+            .find(|(known, _)| known == scheme)
+            .map(|(_, port)| *port)
+    });
+
+    registered.or_else(|| {
+        DEFAULT_PORTS
+            .iter()
+            .find(|(known, _)| *known == scheme)
+            .map(|(_, port)| *port)
+    })
+}
+
+/// Sets default port of `scheme`, for protocols added outside this crate
+pub fn register_default_port(scheme: &str, port: u16) {
+    if let Ok(mut ports) = REGISTERED_PORTS.write() {
+        ports.retain(|(known, _)| known != scheme);
+        ports.push((scheme.to_owned(), port));
     }
 }
 
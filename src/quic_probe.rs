@@ -0,0 +1,141 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as _, Result, bail};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::checker::LocalInbound;
+
+// Reserved version forcing any QUIC server to answer with Version Negotiation
+const GREASE_VERSION: [u8; 4] = [0x1a, 0x2a, 0x3a, 0x4a];
+// Servers ignore client Initials smaller than this
+const MIN_INITIAL_SIZE: usize = 1200;
+
+/// Sends QUIC packet to `host:port` through local socks5 UDP relay and
+/// waits for server answer, proving the proxy passes QUIC traffic.
+///
+/// reqwest can't speak HTTP/3 through a socks proxy, so the probe stops at
+/// version negotiation instead of doing a full h3 request.
+///
+/// # Errors
+/// Return error if socks handshake failed or no answer came in time
+pub async fn probe_quic(
+    inbound: &LocalInbound,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<()> {
+    tokio::time::timeout(timeout, probe(inbound, host, port))
+        .await
+        .context("QUIC probe timed out")?
+}
+
+async fn probe(inbound: &LocalInbound, host: &str, port: u16) -> Result<()> {
+    // Control connection must stay open while the relay is used
+    let mut control = TcpStream::connect(("127.0.0.1", inbound.port as u16)).await?;
+    authenticate(&mut control, inbound).await?;
+
+    control.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    let relay = read_associate_reply(&mut control).await?;
+
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    socket
+        .send_to(&socks_udp_packet(host, port)?, relay)
+        .await?;
+
+    let mut buf = [0u8; 2048];
+    let len = socket.recv(&mut buf).await?;
+    if len <= 10 {
+        bail!("Empty QUIC answer");
+    }
+
+    Ok(())
+}
+
+async fn authenticate(control: &mut TcpStream, inbound: &LocalInbound) -> Result<()> {
+    // Username/password auth of RFC 1929
+    control.write_all(&[5, 1, 2]).await?;
+    let mut method = [0u8; 2];
+    control.read_exact(&mut method).await?;
+    if method != [5, 2] {
+        bail!("Socks password auth rejected");
+    }
+    let mut request = vec![1, inbound.user.len() as u8];
+    request.extend_from_slice(inbound.user.as_bytes());
+    request.push(inbound.password.len() as u8);
+    request.extend_from_slice(inbound.password.as_bytes());
+    control.write_all(&request).await?;
+    let mut status = [0u8; 2];
+    control.read_exact(&mut status).await?;
+    if status[1] != 0 {
+        bail!("Socks credentials rejected");
+    }
+    Ok(())
+}
+
+async fn read_associate_reply(control: &mut TcpStream) -> Result<SocketAddr> {
+    let mut head = [0u8; 4];
+    control.read_exact(&mut head).await?;
+    if head[1] != 0 {
+        bail!("Socks UDP associate rejected: {}", head[1]);
+    }
+
+    let ip = match head[3] {
+        1 => {
+            let mut octets = [0u8; 4];
+            control.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        4 => {
+            let mut octets = [0u8; 16];
+            control.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        atyp => bail!("Unexpected relay address type: {atyp}"),
+    };
+    let port = control.read_u16().await?;
+
+    // Relay bound on any address is reachable on localhost
+    let ip = if ip.is_unspecified() {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    } else {
+        ip
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn socks_udp_packet(host: &str, port: u16) -> Result<Vec<u8>> {
+    // Literal addresses go as such, not as domain names proxies would try
+    // to resolve
+    let mut packet = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(IpAddr::V4(ip)) => [&[0, 0, 0, 1][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[0, 0, 0, 4][..], &ip.octets()].concat(),
+        Err(_) => {
+            let host_len = u8::try_from(host.len()).context("Host too long")?;
+            [&[0, 0, 0, 3, host_len][..], host.as_bytes()].concat()
+        }
+    };
+    packet.extend_from_slice(&port.to_be_bytes());
+    packet.extend_from_slice(&quic_initial());
+    Ok(packet)
+}
+
+fn quic_initial() -> Vec<u8> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+
+    let mut packet = vec![0xc0];
+    packet.extend_from_slice(&GREASE_VERSION);
+    // Destination connection id, source connection id is empty
+    packet.push(8);
+    packet.extend_from_slice(&nanos.to_be_bytes());
+    packet.push(0);
+    packet.resize(MIN_INITIAL_SIZE, 0);
+    packet
+}
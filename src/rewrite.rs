@@ -0,0 +1,95 @@
+use anyhow::{Context as _, Result, bail};
+use regex::Regex;
+use url::Url;
+
+use crate::parse_url::conditions_match;
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    // Param is missing, so the rule injects a default
+    Missing,
+    Exact(String),
+    Regex(Regex),
+}
+
+/// Param rewrite rule applied to every parsed link
+///
+/// Written as `key=old=>new` (exact value), `key~regex=>new` (regex with
+/// `$1` style groups in new value) or `key=>new` (set when param is
+/// missing), optionally followed by `@param=value&...` conditions, e.g.
+/// `security=>tls@scheme=trojan&port=443`.
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    key: String,
+    matcher: Matcher,
+    replacement: String,
+    conditions: Vec<(String, String)>,
+}
+
+impl RewriteRule {
+    /// # Errors
+    /// Return error if rule has no `=>` or invalid regex
+    pub fn parse(rule: &str) -> Result<Self> {
+        let (rule, conditions) = rule.rsplit_once('@').unwrap_or((rule, ""));
+        let Some((matcher, replacement)) = rule.split_once("=>") else {
+            bail!("Rewrite rule {rule} has no =>");
+        };
+
+        let (key, matcher) = match matcher.find(['=', '~']) {
+            Some(pos) if matcher[pos..].starts_with('~') => (
+                &matcher[..pos],
+                Matcher::Regex(Regex::new(&matcher[pos + 1..]).context("Invalid rewrite regex")?),
+            ),
+            Some(pos) => (
+                &matcher[..pos],
+                Matcher::Exact(matcher[pos + 1..].to_owned()),
+            ),
+            None => (matcher, Matcher::Missing),
+        };
+
+        Ok(Self {
+            key: key.to_owned(),
+            matcher,
+            replacement: replacement.to_owned(),
+            conditions: conditions
+                .split('&')
+                .filter_map(|condition| condition.split_once('='))
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+        })
+    }
+
+    fn new_value(&self, value: Option<&str>) -> Option<String> {
+        match (&self.matcher, value) {
+            (Matcher::Missing, None) => Some(self.replacement.clone()),
+            (Matcher::Exact(old), Some(value)) if old == value => Some(self.replacement.clone()),
+            (Matcher::Regex(regex), Some(value)) if regex.is_match(value) => {
+                Some(regex.replace_all(value, &self.replacement).into_owned())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, url: &mut Url) {
+        let conditions = self
+            .conditions
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<Vec<_>>();
+        if !conditions_match(url, &conditions) {
+            return;
+        }
+
+        let mut params = url.query_pairs().into_owned().collect::<Vec<_>>();
+        let current = params.iter().position(|(k, _)| *k == self.key);
+        let Some(value) = self.new_value(current.map(|i| params[i].1.as_str())) else {
+            return;
+        };
+        match current {
+            Some(i) => params[i].1 = value,
+            None => params.push((self.key.clone(), value)),
+        }
+
+        url.query_pairs_mut().clear().extend_pairs(params);
+    }
+}
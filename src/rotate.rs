@@ -0,0 +1,120 @@
+use std::{collections::BTreeSet, fs, time::Duration};
+
+use anyhow::{Context as _, Result};
+use url::Url;
+
+use crate::{
+    Args, monitor::load_list, parse_url::normalize_link, start_xray_with_config,
+    xray_config::generate_rotate_config, xray_process::stop_xray,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct RotateArgs {
+    // Result list to balance across, reloaded when it changes (e.g. by monitor)
+    list_file: String,
+
+    #[arg(long, default_value_t = 1080)]
+    listen: u16,
+
+    // Xray balancer strategy: leastPing, leastLoad, random or roundRobin
+    #[arg(long, default_value = "leastPing")]
+    strategy: String,
+
+    #[arg(long, default_value = "https://www.gstatic.com/generate_204")]
+    probe_url: String,
+
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    reload_interval: Duration,
+}
+
+/// Serves single local socks5 inbound balanced across proxies of the list
+///
+/// Xray is restarted only when links of the list change. Monitor rewrites
+/// it every round with new order and names, ranking is left to the balancer
+///
+/// # Errors
+/// Return error if list can't be read or xray failed to start
+pub(crate) async fn run_rotate(args: &Args, rotate: &RotateArgs) -> Result<()> {
+    loop {
+        let mut modified = modified_at(&rotate.list_file).context("Failed to read list file")?;
+        let links = listed_links(&rotate.list_file)?;
+
+        let proxies = load_list(&rotate.list_file, args).await?;
+        let config =
+            generate_rotate_config(&proxies, rotate.listen, &rotate.strategy, &rotate.probe_url)?;
+        let mut xray_process = start_xray_with_config(&config).await?;
+        log::info!(
+            "Serving socks5://127.0.0.1:{} over {} proxies",
+            rotate.listen,
+            proxies.len()
+        );
+
+        loop {
+            tokio::time::sleep(rotate.reload_interval).await;
+
+            if let Some(exit) = xray_process.try_wait()? {
+                log::warn!("Xray exited: {exit}, restarting");
+                break;
+            }
+            let Ok(current) = modified_at(&rotate.list_file) else {
+                continue;
+            };
+            if current == modified {
+                continue;
+            }
+            modified = current;
+            match listed_links(&rotate.list_file) {
+                Ok(current) if current != links => {
+                    log::info!("Links of list changed, reloading");
+                    break;
+                }
+                Ok(_) => log::debug!("List rewritten with the same links, keeping xray"),
+                Err(e) => log::warn!("{e:#}"),
+            }
+        }
+
+        stop_xray(&mut xray_process).await;
+    }
+}
+
+fn modified_at(path: &str) -> std::io::Result<std::time::SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
+// Links of list without names and order, which monitor changes every round
+fn listed_links(path: &str) -> Result<BTreeSet<String>> {
+    let list = fs::read_to_string(path).context("Failed to read list file")?;
+    Ok(list
+        .lines()
+        .filter_map(|line| Url::parse(line.trim()).ok())
+        .map(|url| normalize_link(&url).into())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reordered_and_renamed_list_has_same_links() {
+        let path = std::env::temp_dir().join(format!("novaprox-{}-rotate.txt", std::process::id()));
+        let path_str = path.to_str().unwrap();
+
+        fs::write(
+            &path,
+            "vless://a@192.0.2.1:443#up 90%\nsocks://192.0.2.2:1080#up 50%\n",
+        )
+        .unwrap();
+        let before = listed_links(path_str).unwrap();
+        fs::write(
+            &path,
+            "socks://192.0.2.2:1080#up 60%\nvless://a@192.0.2.1:443#up 80%\n",
+        )
+        .unwrap();
+        assert_eq!(listed_links(path_str).unwrap(), before);
+
+        fs::write(&path, "vless://a@192.0.2.1:443#up 80%\n").unwrap();
+        assert_ne!(listed_links(path_str).unwrap(), before);
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{TcpListener, TcpStream, tcp::OwnedWriteHalf},
+    sync::Mutex,
+};
+use tokio_util::sync::CancellationToken;
+use url::{Host, Url};
+
+use crate::{
+    Args, check_options, dns_cache::DnsCache, format_results, health::Health, open_dns_cache,
+    parse_links, proxy_config::ProxyConfig, resolve_proxies, start_health, test_proxies_in_chunks,
+};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const PARSE_ERROR: i64 = -32700;
+
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    #[arg(long, default_value = "127.0.0.1:9090")]
+    listen: SocketAddr,
+}
+
+struct RpcError(i64, String);
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(INTERNAL_ERROR, format!("{e:#}"))
+    }
+}
+
+struct Server<'a> {
+    args: &'a Args,
+    dns_cache: Arc<Mutex<DnsCache>>,
+    // Checks bind same local ports, so only one runs at a time
+    check_lock: Mutex<()>,
+    health: Arc<std::sync::Mutex<Health>>,
+    // Token of the running check, cancelled by `abort`
+    running: std::sync::Mutex<CancellationToken>,
+}
+
+/// Serves JSON-RPC 2.0 over TCP, one JSON message per line.
+///
+/// Methods take `{"links": [...]}`: `parse` returns normalized links,
+/// `resolve` links with resolved addresses and `check` formatted working
+/// proxies, sending `progress` notifications after every chunk. With
+/// `"raw": true` `check` returns objects with link and measurements.
+/// `abort` takes no params and cancels the running check.
+///
+/// # Errors
+/// Return error if failed to bind listener
+pub(crate) async fn run_server(args: &Args, serve: &ServeArgs) -> Result<()> {
+    let listener = TcpListener::bind(serve.listen)
+        .await
+        .context("Failed to bind RPC listener")?;
+    log::info!("RPC server listening on {}", serve.listen);
+
+    let dns_cache = open_dns_cache(args)?;
+    let server = Server {
+        args,
+        dns_cache,
+        check_lock: Mutex::new(()),
+        health: start_health(args),
+        running: std::sync::Mutex::new(CancellationToken::new()),
+    };
+
+    // Connections are served concurrently on this task, so they can borrow args
+    let mut connections = futures::stream::FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                log::debug!("RPC connection from {peer}");
+                connections.push(server.serve_connection(stream));
+            }
+            Some(result) = futures::StreamExt::next(&mut connections) => {
+                if let Err(e) = result {
+                    log::debug!("RPC connection closed: {e}");
+                }
+            }
+        }
+    }
+}
+
+impl Server<'_> {
+    async fn serve_connection(&self, stream: TcpStream) -> Result<()> {
+        let (reader, writer) = stream.into_split();
+        let writer = Mutex::new(writer);
+        let mut lines = BufReader::new(reader).lines();
+
+        // Requests are read while earlier ones run, so `abort` reaches the
+        // check it aborts on the same connection
+        let mut in_flight = futures::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                line = lines.next_line() => match line? {
+                    Some(line) => in_flight.push(self.respond(line, &writer)),
+                    None => break,
+                },
+                Some(result) = futures::StreamExt::next(&mut in_flight) => result?,
+            }
+        }
+        while let Some(result) = futures::StreamExt::next(&mut in_flight).await {
+            result?;
+        }
+
+        Ok(())
+    }
+
+    async fn respond(&self, line: String, writer: &Mutex<OwnedWriteHalf>) -> Result<()> {
+        let response = match serde_json::from_str::<Value>(&line) {
+            Ok(request) => {
+                let id = request["id"].clone();
+                match self.handle(&request, writer).await {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err(RpcError(code, message)) => error_response(&id, code, &message),
+                }
+            }
+            Err(e) => error_response(&Value::Null, PARSE_ERROR, &e.to_string()),
+        };
+        send(writer, &response).await
+    }
+
+    async fn handle(
+        &self,
+        request: &Value,
+        writer: &Mutex<OwnedWriteHalf>,
+    ) -> Result<Value, RpcError> {
+        if request["method"] == "abort" {
+            if let Ok(running) = self.running.lock() {
+                running.cancel();
+            }
+            return Ok(Value::Null);
+        }
+
+        match request["method"].as_str() {
+            Some("parse") => Ok(json!(
+                self.links(request)?
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+            )),
+            Some("resolve") => {
+                let proxies = resolve_proxies(
+                    self.links(request)?,
+                    Arc::clone(&self.dns_cache),
+                    self.args.pipeline.max_concurrent_dns,
+                )
+                .await?;
+                Ok(json!(
+                    proxies.iter().map(ToString::to_string).collect::<Vec<_>>()
+                ))
+            }
+            Some("check") => {
+                let result = self.check(self.links(request)?, writer).await;
+                if let Ok(mut health) = self.health.lock() {
+                    match &result {
+                        Ok(working) => health.success(working.len()),
+                        Err(e) => health.failure(e),
+                    }
+                }
+                let working = result?;
+                if request["params"]["raw"] == true {
+                    return Ok(json!(working.iter().map(proxy_to_json).collect::<Vec<_>>()));
+                }
+                Ok(json!(format_results(&working).lines().collect::<Vec<_>>()))
+            }
+            _ => Err(RpcError(METHOD_NOT_FOUND, "Unknown method".to_owned())),
+        }
+    }
+
+    // Parsed `params.links` of request
+    fn links(&self, request: &Value) -> Result<Vec<Url>, RpcError> {
+        let links = request["params"]["links"]
+            .as_array()
+            .ok_or_else(|| RpcError(INVALID_PARAMS, "Expected params.links array".to_owned()))?
+            .iter()
+            .filter_map(Value::as_str);
+        Ok(parse_links(self.args, links))
+    }
+}
+
+impl Server<'_> {
+    async fn check(
+        &self,
+        urls: Vec<Url>,
+        writer: &Mutex<OwnedWriteHalf>,
+    ) -> Result<Vec<ProxyConfig>> {
+        let proxies = resolve_proxies(
+            urls,
+            Arc::clone(&self.dns_cache),
+            self.args.pipeline.max_concurrent_dns,
+        )
+        .await?
+        .into_iter()
+        .collect::<Vec<_>>();
+        let mut options = check_options(self.args).await?;
+        let _guard = self.check_lock.lock().await;
+        if let Ok(mut running) = self.running.lock() {
+            *running = CancellationToken::new();
+            options.cancel = running.clone();
+        }
+
+        let chunks = proxies.chunks(self.args.chunk_size);
+        let total = chunks.len();
+        let mut working = Vec::new();
+        for (i, chunk) in chunks.enumerate() {
+            working.extend(test_proxies_in_chunks(chunk, &options, None).await?);
+            let progress = json!({
+                "jsonrpc": "2.0",
+                "method": "progress",
+                "params": { "chunk": i + 1, "total": total, "working": working.len() }
+            });
+            send(writer, &progress).await?;
+        }
+        Ok(working)
+    }
+}
+
+fn proxy_to_json(proxy: &ProxyConfig) -> Value {
+    json!({
+        "link": proxy.to_string(),
+        "ping_ms": proxy.ping.as_millis() as u64,
+        "bandwidth": proxy.bandwidth,
+        "country": proxy.country.map(String::from_iter),
+    })
+}
+
+/// Proxy of raw `check` result
+#[must_use]
+pub fn proxy_from_json(value: &Value) -> Option<ProxyConfig> {
+    let url = Url::parse(value["link"].as_str()?).ok()?;
+    let address = match url.host()? {
+        Host::Ipv4(ip) => IpAddr::V4(ip),
+        Host::Ipv6(ip) => IpAddr::V6(ip),
+        Host::Domain(domain) => domain.parse().ok()?,
+    };
+    let mut proxy = ProxyConfig::from_url(url, address);
+    proxy.ping = Duration::from_millis(value["ping_ms"].as_u64()?);
+    proxy.bandwidth = value["bandwidth"].as_u64()?;
+    proxy.country = match value["country"]
+        .as_str()
+        .map(|code| code.chars().collect::<Vec<_>>())
+        .as_deref()
+    {
+        Some(&[first, second]) => Some([first, second]),
+        _ => None,
+    };
+    Some(proxy)
+}
+
+fn error_response(id: &Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message }
+    })
+}
+
+async fn send(writer: &Mutex<OwnedWriteHalf>, message: &Value) -> Result<()> {
+    let mut line = message.to_string();
+    line.push('\n');
+    writer.lock().await.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn abort_is_answered_during_check_on_same_connection() {
+        let dns_cache_file =
+            std::env::temp_dir().join(format!("novaprox-{}-rpc-dns.txt", std::process::id()));
+        let args = Args::parse_from([
+            "novaprox",
+            "--no-env-proxy",
+            "--dns-cache-file",
+            dns_cache_file.to_str().unwrap(),
+        ]);
+        let server = Server {
+            args: &args,
+            dns_cache: open_dns_cache(&args).unwrap(),
+            check_lock: Mutex::new(()),
+            health: Arc::default(),
+            running: std::sync::Mutex::new(CancellationToken::new()),
+        };
+        // Another check holds the lock, so this connection's check stays
+        // running until the test ends
+        let _busy = server.check_lock.lock().await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = async {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream
+                .write_all(
+                    concat!(
+                        r#"{"jsonrpc":"2.0","id":1,"method":"check","params":{"links":[]}}"#,
+                        "\n",
+                        r#"{"jsonrpc":"2.0","id":2,"method":"abort"}"#,
+                        "\n",
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            let line = tokio::time::timeout(Duration::from_secs(10), lines.next_line())
+                .await
+                .expect("abort wasn't answered while check ran")
+                .unwrap()
+                .unwrap();
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+        let serve = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            server.serve_connection(stream).await
+        };
+
+        let response = tokio::select! {
+            response = client => response,
+            result = serve => panic!("connection ended: {result:?}"),
+        };
+        assert_eq!(
+            response,
+            json!({ "jsonrpc": "2.0", "id": 2, "result": null })
+        );
+        std::fs::remove_file(dns_cache_file).ok();
+    }
+}
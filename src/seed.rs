@@ -0,0 +1,31 @@
+//! Fixed seed of `--seed`, so random decisions of a run are reproducible.
+
+use std::{hash::Hash, sync::OnceLock};
+
+use rand::{SeedableRng as _, rngs::StdRng};
+
+static SEED: OnceLock<u64> = OnceLock::new();
+
+/// Makes every later [`rng`] deterministic
+pub fn use_seed(seed: u64) {
+    SEED.set(seed).ok();
+}
+
+/// Whether run is deterministic
+#[must_use]
+pub fn is_set() -> bool {
+    SEED.get().is_some()
+}
+
+/// Random generator of decisions about `key`, e.g. proxy link or source
+/// url. With `--seed` it depends only on seed and key, so decisions don't
+/// depend on order concurrent tasks run in
+#[must_use]
+pub fn rng(key: &impl Hash) -> StdRng {
+    match SEED.get() {
+        Some(&seed) => {
+            StdRng::seed_from_u64(ahash::RandomState::with_seeds(seed, 0, 0, 0).hash_one(key))
+        }
+        None => StdRng::from_rng(&mut rand::rng()),
+    }
+}
@@ -0,0 +1,104 @@
+use std::{fs, path::Path, time::Duration};
+
+use ahash::{HashMap, HashMapExt as _, HashSet};
+use anyhow::{Context as _, Result};
+
+use crate::{
+    abuse::now_secs,
+    cache_format::{CacheFile, Writer},
+    proxy_config::ProxyConfig,
+};
+
+/// Last verdict of every proxy ever tested, kept between runs
+pub struct SeenDb {
+    // Proxy -> (was working, unix time of test)
+    seen: HashMap<String, (bool, u64)>,
+    db_file: String,
+}
+
+impl SeenDb {
+    #[must_use]
+    pub fn new(db_file: &str) -> Self {
+        Self {
+            seen: HashMap::new(),
+            db_file: db_file.to_owned(),
+        }
+    }
+
+    /// # Errors
+    /// Return error if failed to read file
+    pub fn load(&mut self) -> Result<()> {
+        if !Path::new(&self.db_file).exists() {
+            return Ok(());
+        }
+
+        let data = fs::read(&self.db_file).context("Failed to read seen proxies")?;
+        self.seen = match CacheFile::detect(&data).context("Failed to read seen proxies")? {
+            CacheFile::Binary(reader) => reader
+                .records(|r| Some((r.str()?, (r.u8()? != 0, r.u64()?))))
+                .collect(),
+            // Never written as text
+            CacheFile::Text(_) => HashMap::new(),
+        };
+
+        Ok(())
+    }
+
+    /// Whether proxy failed its last test, done less than `within` ago
+    #[must_use]
+    pub fn is_recently_dead(&self, proxy: &str, within: Duration) -> bool {
+        self.seen.get(proxy).is_some_and(|(working, tested_at)| {
+            !working && now_secs().saturating_sub(*tested_at) < within.as_secs()
+        })
+    }
+
+    /// Records verdicts of `tested` proxies, `working` ones passed the test
+    pub fn record(&mut self, tested: impl IntoIterator<Item = String>, working: &[ProxyConfig]) {
+        let working = working
+            .iter()
+            .map(ToString::to_string)
+            .collect::<HashSet<_>>();
+        let now = now_secs();
+        for proxy in tested {
+            let verdict = (working.contains(&proxy), now);
+            self.seen.insert(proxy, verdict);
+        }
+    }
+
+    /// Verdicts of tests done at or after unix time `since`, as proxy, was
+    /// working and unix time of test
+    pub fn tested_since(&self, since: u64) -> impl Iterator<Item = (&str, bool, u64)> {
+        self.seen
+            .iter()
+            .filter(move |(_, (_, tested_at))| *tested_at >= since)
+            .map(|(proxy, (working, tested_at))| (proxy.as_str(), *working, *tested_at))
+    }
+
+    /// Takes verdict of another database unless a newer one is known,
+    /// returns whether it was taken
+    pub fn merge(&mut self, proxy: String, working: bool, tested_at: u64) -> bool {
+        let newer = self
+            .seen
+            .get(&proxy)
+            .is_none_or(|(_, known_at)| *known_at < tested_at);
+        if newer {
+            self.seen.insert(proxy, (working, tested_at));
+        }
+        newer
+    }
+
+    /// # Errors
+    /// Return error if failed to save file
+    pub async fn save(&self) -> Result<()> {
+        let mut content = Writer::new();
+        for (proxy, (working, tested_at)) in &self.seen {
+            content.str(proxy);
+            content.u8(u8::from(*working));
+            content.u64(*tested_at);
+        }
+
+        tokio::fs::write(&self.db_file, content.finish())
+            .await
+            .context("Failed to save seen proxies")
+    }
+}
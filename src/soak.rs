@@ -0,0 +1,191 @@
+use std::time::{Duration, Instant};
+
+use ahash::HashMap;
+use anyhow::{Context as _, Result};
+use serde_json::json;
+use tokio::io::AsyncWriteExt as _;
+
+use crate::{
+    Args, abuse::now_secs, check_options, monitor::load_list, proxy_config::ProxyConfig,
+    test_proxies_in_chunks,
+};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoakFormat {
+    // `time,link,working,latency_ms` row per proxy and round, appended
+    // after every round
+    Csv,
+    // Samples grouped by proxy, rewritten after every round
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SoakArgs {
+    list_file: String,
+
+    #[arg(long, default_value = "6h", value_parser = humantime::parse_duration)]
+    duration: Duration,
+
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    interval: Duration,
+
+    #[arg(long, value_enum, default_value_t = SoakFormat::Csv)]
+    format: SoakFormat,
+
+    #[arg(long, default_value = "soak.csv")]
+    soak_file: String,
+}
+
+// Time and latency of every round of one proxy, no latency when it failed
+struct Series {
+    link: String,
+    samples: Vec<(u64, Option<Duration>)>,
+}
+
+impl SoakArgs {
+    // Files kept between runs, moved into `--artifacts-dir`
+    pub(crate) fn artifact_paths(&mut self) -> Vec<&mut String> {
+        vec![&mut self.soak_file]
+    }
+}
+
+impl Series {
+    fn availability(&self) -> f64 {
+        let working = self
+            .samples
+            .iter()
+            .filter(|(_, ping)| ping.is_some())
+            .count();
+        working as f64 * 100.0 / self.samples.len().max(1) as f64
+    }
+
+    fn median(&self) -> Option<Duration> {
+        let mut latencies = self
+            .samples
+            .iter()
+            .filter_map(|(_, ping)| *ping)
+            .collect::<Vec<_>>();
+        latencies.sort_unstable();
+        latencies.get(latencies.len() / 2).copied()
+    }
+}
+
+/// Checks proxies of the list every interval for the whole duration and
+/// writes latency and availability of every round, then prints proxies
+/// ordered by availability
+///
+/// # Errors
+/// Return error if list can't be read or soak file written
+pub(crate) async fn run_soak(args: &Args, soak: &SoakArgs) -> Result<()> {
+    let proxies = load_list(&soak.list_file, args).await?;
+    let check_options = check_options(args).await?;
+    let mut series = proxies
+        .iter()
+        .map(|proxy| Series {
+            link: proxy.to_string(),
+            samples: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+    if soak.format == SoakFormat::Csv {
+        tokio::fs::write(&soak.soak_file, "time,link,working,latency_ms\n")
+            .await
+            .context("Failed to write soak file")?;
+    }
+    log::info!(
+        "Soaking {} proxies for {}",
+        proxies.len(),
+        humantime::format_duration(soak.duration)
+    );
+
+    let start = Instant::now();
+    loop {
+        let round_start = Instant::now();
+        let time = now_secs();
+        let working = match test_proxies_in_chunks(&proxies, &check_options, None).await {
+            Ok(working) => working,
+            Err(e) => {
+                log::error!("Soak round failed: {e:#}");
+                Vec::new()
+            }
+        };
+        let working = working
+            .into_iter()
+            .map(|proxy| (proxy.to_string(), proxy))
+            .collect::<HashMap<_, ProxyConfig>>();
+
+        for series in &mut series {
+            let ping = working.get(&series.link).map(|proxy| proxy.ping);
+            series.samples.push((time, ping));
+        }
+        write_round(soak, &series).await?;
+        log::info!("Soak round: {}/{} working", working.len(), proxies.len());
+
+        if start.elapsed() + soak.interval > soak.duration {
+            break;
+        }
+        tokio::time::sleep(soak.interval.saturating_sub(round_start.elapsed())).await;
+    }
+
+    series.sort_by(|a, b| b.availability().total_cmp(&a.availability()));
+    println!("{:>10}{:>10}  link", "available", "median");
+    for series in &series {
+        println!(
+            "{:>9.1}%{:>10}  {}",
+            series.availability(),
+            series
+                .median()
+                .map_or_else(|| "-".to_owned(), |ping| format!("{}ms", ping.as_millis())),
+            series.link
+        );
+    }
+    Ok(())
+}
+
+async fn write_round(soak: &SoakArgs, series: &[Series]) -> Result<()> {
+    match soak.format {
+        SoakFormat::Csv => {
+            let rows = series
+                .iter()
+                .filter_map(|series| {
+                    let (time, ping) = series.samples.last()?;
+                    Some(format!(
+                        "{time},\"{}\",{},{}\n",
+                        series.link,
+                        u8::from(ping.is_some()),
+                        ping.map(|ping| ping.as_millis().to_string())
+                            .unwrap_or_default()
+                    ))
+                })
+                .collect::<String>();
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&soak.soak_file)
+                .await
+                .context("Failed to open soak file")?
+                .write_all(rows.as_bytes())
+                .await
+                .context("Failed to write soak file")
+        }
+        SoakFormat::Json => {
+            let proxies = series
+                .iter()
+                .map(|series| {
+                    json!({
+                        "link": series.link,
+                        "availability": series.availability(),
+                        "samples": series.samples.iter().map(|(time, ping)| json!({
+                            "time": time,
+                            "latency_ms": ping.map(|ping| ping.as_millis() as u64),
+                        })).collect::<Vec<_>>(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            tokio::fs::write(
+                &soak.soak_file,
+                serde_json::to_string_pretty(&json!({ "proxies": proxies }))?,
+            )
+            .await
+            .context("Failed to write soak file")
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Check requests straight through local SOCKS5 inbounds, a connection per
+//! request without a pooling client in between, so SOCKS handshake, TLS
+//! handshake and first byte are timed separately.
+//!
+//! Only HTTP/1.1 GET is spoken, redirects are followed like reqwest does.
+
+use std::{
+    io,
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
+
+use http_body_util::{BodyExt as _, Empty};
+use hyper::{
+    Request, StatusCode,
+    body::Bytes,
+    header::{
+        ACCEPT, AUTHORIZATION, COOKIE, HOST, HeaderMap, HeaderValue, LOCATION, PROXY_AUTHORIZATION,
+        WWW_AUTHENTICATE,
+    },
+};
+use hyper_util::rt::TokioIo;
+use rustls_pki_types::ServerName;
+use rustls_platform_verifier::ConfigVerifierExt as _;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{TlsConnector, client::TlsStream, rustls::ClientConfig};
+use url::{Position, Url};
+
+use crate::{checker::LocalInbound, native_engine::socks_connect, proxy_config::Timings};
+
+const MAX_REDIRECTS: usize = 10;
+
+static TLS_CONFIG: LazyLock<Result<Arc<ClientConfig>, String>> = LazyLock::new(|| {
+    let mut config = ClientConfig::with_platform_verifier().map_err(|e| e.to_string())?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+});
+
+pub struct Fetched {
+    pub status: StatusCode,
+    pub body: Bytes,
+    // DER of the leaf certificate of HTTPS targets
+    pub peer_certificate: Option<Vec<u8>>,
+    // Of the last request, when redirected
+    pub timings: Timings,
+}
+
+/// GETs `url` through `inbound`, following redirects
+///
+/// # Errors
+/// Return error if any request fails or redirects don't end
+pub async fn get(inbound: &LocalInbound, url: &str, headers: &HeaderMap) -> io::Result<Fetched> {
+    let mut url = Url::parse(url).map_err(io::Error::other)?;
+    let mut headers = headers.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let (fetched, location) = get_once(inbound, &url, &headers).await?;
+        match location {
+            Some(location) if fetched.status.is_redirection() => {
+                let next = url.join(&location).map_err(io::Error::other)?;
+                if next.origin() != url.origin() {
+                    remove_sensitive_headers(&mut headers);
+                }
+                url = next;
+            }
+            _ => return Ok(fetched),
+        }
+    }
+    Err(io::Error::other(format!(
+        "more than {MAX_REDIRECTS} redirects"
+    )))
+}
+
+// Credentials of one origin aren't sent to another, as reqwest does
+fn remove_sensitive_headers(headers: &mut HeaderMap) {
+    for name in [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, WWW_AUTHENTICATE] {
+        headers.remove(name);
+    }
+    headers.remove("cookie2");
+}
+
+// Response and its `Location` header
+async fn get_once(
+    inbound: &LocalInbound,
+    url: &Url,
+    headers: &HeaderMap,
+) -> io::Result<(Fetched, Option<String>)> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| io::Error::other("URL without host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| io::Error::other("URL without port"))?;
+    let inbound_port = u16::try_from(inbound.port).map_err(io::Error::other)?;
+
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(("127.0.0.1", inbound_port)).await?;
+    let auth = (inbound.user.clone(), inbound.password.clone());
+    socks_connect(&mut stream, host, port, Some(&auth)).await?;
+    let socks = start.elapsed();
+
+    let mut request = Request::get(&url[Position::BeforePath..Position::AfterQuery])
+        .body(Empty::<Bytes>::new())
+        .map_err(io::Error::other)?;
+    *request.headers_mut() = headers.clone();
+    let authority = &url[Position::BeforeHost..Position::AfterPort];
+    request.headers_mut().insert(
+        HOST,
+        HeaderValue::from_str(authority).map_err(io::Error::other)?,
+    );
+    if !request.headers().contains_key(ACCEPT) {
+        request
+            .headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static("*/*"));
+    }
+
+    let (tls, peer_certificate, (status, body, location, first_byte)) = if url.scheme() == "https" {
+        let handshake = Instant::now();
+        let stream = tls_connect(stream, host).await?;
+        let tls = handshake.elapsed();
+        let peer_certificate = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .map(|der| der.to_vec());
+        (
+            Some(tls),
+            peer_certificate,
+            exchange(stream, request).await?,
+        )
+    } else {
+        (None, None, exchange(stream, request).await?)
+    };
+
+    let fetched = Fetched {
+        status,
+        body,
+        peer_certificate,
+        timings: Timings {
+            socks,
+            tls,
+            first_byte,
+        },
+    };
+    Ok((fetched, location))
+}
+
+async fn tls_connect(stream: TcpStream, host: &str) -> io::Result<TlsStream<TcpStream>> {
+    let config = TLS_CONFIG
+        .as_ref()
+        .map_err(|e| io::Error::other(e.clone()))?;
+    let name = ServerName::try_from(host.to_owned()).map_err(io::Error::other)?;
+    TlsConnector::from(Arc::clone(config))
+        .connect(name, stream)
+        .await
+}
+
+// Sends `request` over `stream`, returns status, body, `Location` and time
+// to the response head
+async fn exchange(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    request: Request<Empty<Bytes>>,
+) -> io::Result<(StatusCode, Bytes, Option<String>, Duration)> {
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(io::Error::other)?;
+    // Connection must not outlive the check, whatever the server keeps open
+    let connection = tokio::spawn(connection);
+
+    let sent = Instant::now();
+    let response = sender.send_request(request).await;
+    let first_byte = sent.elapsed();
+    let result = match response {
+        Ok(response) => {
+            let status = response.status();
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .map(str::to_owned);
+            response
+                .into_body()
+                .collect()
+                .await
+                .map(|body| (status, body.to_bytes(), location, first_byte))
+                .map_err(io::Error::other)
+        }
+        Err(e) => Err(io::Error::other(e)),
+    };
+    connection.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_only_sensitive_headers() {
+        let mut headers = HeaderMap::new();
+        for name in [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, ACCEPT] {
+            headers.insert(name, HeaderValue::from_static("value"));
+        }
+        remove_sensitive_headers(&mut headers);
+        assert_eq!(headers.keys().collect::<Vec<_>>(), [ACCEPT]);
+    }
+}
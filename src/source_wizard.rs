@@ -0,0 +1,177 @@
+//! `sources` subcommands editing sources file, so new feeds are validated
+//! before they get into it.
+//!
+//! Sources file has one `https://` or `git+https://` source per line,
+//! sources commented out with `#` are disabled.
+
+use std::{path::Path, time::Duration};
+
+use anyhow::{Context as _, Result, bail};
+use clap::Subcommand;
+use tokio::io::AsyncWriteExt as _;
+
+use crate::{
+    Args, LinkParser,
+    sources::{self, FetchOptions, is_source_url},
+    summary::SourceStats,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct SourcesArgs {
+    #[command(subcommand)]
+    command: SourcesCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum SourcesCommand {
+    // Fetch source and append it to first of `--sources-files` when it
+    // yields share links of `--scheme`
+    Add {
+        url: String,
+
+        // Append even when source yields no links
+        #[arg(long)]
+        force: bool,
+    },
+    // Print sources of first of `--sources-files`
+    List,
+    // Fetch given source, or every enabled one, and print how many links
+    // of `--scheme` it yields
+    Test {
+        url: Option<String>,
+    },
+}
+
+/// Runs `sources` subcommand on first of `--sources-files`
+///
+/// # Errors
+/// Return error if sources file can't be read or written, or added source
+/// is invalid
+pub(crate) async fn run_sources(args: &Args, sources_args: &SourcesArgs) -> Result<()> {
+    let path = sources_file(args);
+    match &sources_args.command {
+        SourcesCommand::Add { url, force } => add_source(args, &path, url, *force).await,
+        SourcesCommand::List => {
+            for (url, enabled) in read_sources(&path).await? {
+                println!("{} {url}", if enabled { "+" } else { "-" });
+            }
+            Ok(())
+        }
+        SourcesCommand::Test { url } => {
+            let urls = match url {
+                Some(url) => vec![url.clone()],
+                None => read_sources(&path)
+                    .await?
+                    .into_iter()
+                    .filter_map(|(url, enabled)| enabled.then_some(url))
+                    .collect(),
+            };
+            for stats in test_sources(args, &urls).await? {
+                match &stats.error {
+                    Some(e) => println!("failed {}: {e}", stats.url),
+                    None => println!("{}/{} links {}", stats.links, stats.lines, stats.url),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn add_source(args: &Args, path: &str, url: &str, force: bool) -> Result<()> {
+    if !is_source_url(url) {
+        bail!("Source must be an https:// or git+https:// URL: {url}");
+    }
+    let existing = read_sources(path).await?;
+    if let Some((_, enabled)) = existing.iter().find(|(known, _)| known == url) {
+        bail!(
+            "Source is already in {path}{}",
+            if *enabled { "" } else { " (disabled)" }
+        );
+    }
+
+    let stats = test_sources(args, &[url.to_owned()])
+        .await?
+        .pop()
+        .context("Source wasn't fetched")?;
+    if let Some(e) = stats.error {
+        bail!("Failed to fetch {url}: {e}");
+    }
+    log::info!(
+        "Source yields {} links in {} lines",
+        stats.links,
+        stats.lines
+    );
+    if stats.links == 0 && !force {
+        bail!("Source has no {} links, add it with --force", args.scheme);
+    }
+
+    let content = tokio::fs::read_to_string(path).await.unwrap_or_default();
+    let separator = if content.is_empty() || content.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .context("Failed to open sources file")?
+        .write_all(format!("{separator}{url}\n").as_bytes())
+        .await
+        .context("Failed to write sources file")?;
+    log::info!("Added {url} to {path}");
+    Ok(())
+}
+
+// Sources of file with whether they are enabled
+async fn read_sources(path: &str) -> Result<Vec<(String, bool)>> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read sources file {path}"))?;
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let (line, enabled) = line
+                .strip_prefix('#')
+                .map_or((line, true), |disabled| (disabled.trim(), false));
+            is_source_url(line).then(|| (line.to_owned(), enabled))
+        })
+        .collect())
+}
+
+// Every source is parsed on its own, so links it shares with other
+// sources are counted too
+async fn test_sources(args: &Args, urls: &[String]) -> Result<Vec<SourceStats>> {
+    let fetch_options = FetchOptions {
+        per_host: args.source_host_concurrency,
+        jitter: Duration::ZERO,
+        env_proxy: !args.no_env_proxy,
+    };
+    let results = futures::future::join_all(urls.iter().map(|url| {
+        let fetch_options = &fetch_options;
+        async move {
+            let mut parser = LinkParser::new(args);
+            sources::fetch_sources(url, fetch_options, |line| parser.parse(line).len()).await
+        }
+    }))
+    .await;
+    Ok(results
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+// First of `--sources-files`, looked up in `sources/` as fetch does
+fn sources_file(args: &Args) -> String {
+    let name = args.sources_files.split(',').next().unwrap_or_default();
+    let bundled = format!("sources/{name}");
+    if !Path::new(name).exists() && Path::new(&bundled).exists() {
+        bundled
+    } else {
+        name.to_owned()
+    }
+}
@@ -0,0 +1,370 @@
+use std::{fs, path::Path, process::Stdio, sync::Arc, time::Duration};
+
+use ahash::{HashMap, HashMapExt as _};
+use anyhow::{Context as _, Result, bail};
+use rand::Rng as _;
+use regex::Regex;
+use reqwest::{Client, ClientBuilder, StatusCode, header::RETRY_AFTER};
+use ring::digest;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt as _, BufReader},
+    process::Command,
+    sync::{Semaphore, mpsc},
+};
+use url::Url;
+
+use crate::{
+    abuse::now_secs,
+    cache_format::{CacheFile, Writer},
+    seed,
+    summary::SourceStats,
+};
+
+// Lines waiting for parser, fetches pause while it's full
+const LINE_BUFFER: usize = 4096;
+// Longer lines aren't share links, so they are skipped instead of buffered
+const MAX_LINE_LEN: usize = 64 * 1024;
+// Retries of source answering 429, waiting for its Retry-After
+const RATE_LIMIT_RETRIES: usize = 2;
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+pub struct FetchOptions {
+    // Concurrent requests to the same host, many sources share
+    // raw.githubusercontent.com which rate limits bursts
+    pub per_host: usize,
+    // Random delay up to this before every request
+    pub jitter: Duration,
+    // Go through proxy of HTTP(S)_PROXY and ALL_PROXY variables, as curl
+    // and git do
+    pub env_proxy: bool,
+}
+
+// Proxy variables reqwest and git read
+const PROXY_VARS: &[&str] = &[
+    "http_proxy",
+    "https_proxy",
+    "all_proxy",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "ALL_PROXY",
+];
+
+/// Whether `line` is a source URL: `https://`, `git+https://`, or plain
+/// `http://` of loopback host (local mirrors, test fixtures)
+#[must_use]
+pub fn is_source_url(line: &str) -> bool {
+    if line.starts_with("https://") || line.starts_with("git+https://") {
+        return true;
+    }
+    line.starts_with("http://")
+        && Url::parse(line).is_ok_and(|url| match url.host() {
+            Some(url::Host::Domain(domain)) => domain == "localhost",
+            Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+            Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+            None => false,
+        })
+}
+
+/// Fetches every `https://` source concurrently and passes lines of
+/// responses to `on_line` as they arrive, without holding whole responses
+/// in memory
+///
+/// `git+https://` sources are repositories, read file by file from shallow
+/// clone. Optional `#glob` suffix selects files by path, e.g.
+/// `git+https://github.com/user/repo#sub/*.txt`. `on_line` returns how many
+/// share links were taken from line.
+///
+/// # Errors
+/// Return error if failed to build http client
+pub async fn fetch_sources(
+    sources: &str,
+    options: &FetchOptions,
+    mut on_line: impl FnMut(&str) -> usize,
+) -> Result<Vec<SourceStats>> {
+    let mut client = ClientBuilder::new().timeout(Duration::from_secs(10));
+    if !options.env_proxy {
+        client = client.no_proxy();
+    } else if let Some(var) = PROXY_VARS
+        .iter()
+        .find(|var| std::env::var_os(var).is_some())
+    {
+        log::info!("Fetching sources through proxy of {var}");
+    }
+    let client = client.build()?;
+    let (tx, mut rx) = mpsc::channel(LINE_BUFFER);
+    let urls = sources
+        .lines()
+        .filter(|line| is_source_url(line))
+        .collect::<Vec<_>>();
+
+    let mut host_limits = HashMap::new();
+    let fetches = futures::future::join_all(urls.iter().enumerate().map(|(index, url)| {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_owned));
+        let host_limit = Arc::clone(
+            host_limits
+                .entry(host)
+                .or_insert_with(|| Arc::new(Semaphore::new(options.per_host.max(1)))),
+        );
+        let (client, tx) = (&client, tx.clone());
+        async move {
+            let _permit = host_limit.acquire().await;
+            let result = if let Some(repo) = url.strip_prefix("git+") {
+                fetch_git(repo, index, &tx, options.env_proxy).await
+            } else {
+                fetch_lines(client, url, index, &tx, options.jitter).await
+            };
+            match &result {
+                Ok(()) => log::debug!("Loaded source: {url}"),
+                Err(e) => log::warn!("Failed to load source {url}: {e}"),
+            }
+            result.err().map(|e| e.to_string())
+        }
+    }));
+    drop(tx);
+
+    let mut counts = vec![(0, 0, digest::Context::new(&digest::SHA256)); urls.len()];
+    let consume = async {
+        while let Some((index, line)) = rx.recv().await {
+            let (lines, links, hash) = &mut counts[index];
+            *lines += 1;
+            *links += on_line(&line);
+            hash.update(line.as_bytes());
+            hash.update(b"\n");
+        }
+    };
+    let (errors, ()) = tokio::join!(fetches, consume);
+
+    Ok(urls
+        .into_iter()
+        .zip(errors)
+        .zip(counts)
+        .map(|((url, error), (lines, links, hash))| SourceStats {
+            url: url.to_owned(),
+            hash: error.is_none().then(|| hex_digest(hash.finish().as_ref())),
+            error,
+            lines,
+            links,
+            stale: false,
+        })
+        .collect())
+}
+
+fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Content hash of every source and when it last changed, kept between
+/// runs to find feeds which aren't updated anymore
+pub struct SourceState {
+    // Source url -> (content hash, unix time of last change)
+    state: HashMap<String, (String, u64)>,
+    state_file: String,
+}
+
+impl SourceState {
+    #[must_use]
+    pub fn new(state_file: &str) -> Self {
+        Self {
+            state: HashMap::new(),
+            state_file: state_file.to_owned(),
+        }
+    }
+
+    /// # Errors
+    /// Return error if failed to read file
+    pub fn load(&mut self) -> Result<()> {
+        if !Path::new(&self.state_file).exists() {
+            return Ok(());
+        }
+
+        let data = fs::read(&self.state_file).context("Failed to read source state")?;
+        self.state = match CacheFile::detect(&data).context("Failed to read source state")? {
+            CacheFile::Binary(reader) => reader
+                .records(|r| Some((r.str()?, (r.str()?, r.u64()?))))
+                .collect(),
+            // Never written as text
+            CacheFile::Text(_) => HashMap::new(),
+        };
+
+        Ok(())
+    }
+
+    /// Whether source content didn't change for `stale_after`
+    #[must_use]
+    pub fn is_stale(&self, url: &str, stale_after: Duration) -> bool {
+        self.state.get(url).is_some_and(|(_, changed_at)| {
+            now_secs().saturating_sub(*changed_at) >= stale_after.as_secs()
+        })
+    }
+
+    /// Records hashes of fetched sources and marks stale ones
+    pub fn update(&mut self, sources: &mut [SourceStats], stale_after: Duration) {
+        for source in sources {
+            let Some(hash) = &source.hash else {
+                continue;
+            };
+            match self.state.get_mut(&source.url) {
+                Some((old_hash, _)) if old_hash == hash => {}
+                _ => {
+                    self.state
+                        .insert(source.url.clone(), (hash.clone(), now_secs()));
+                }
+            }
+            source.stale = self.is_stale(&source.url, stale_after);
+        }
+    }
+
+    /// # Errors
+    /// Return error if failed to save file
+    pub async fn save(&self) -> Result<()> {
+        let mut content = Writer::new();
+        for (url, (hash, changed_at)) in &self.state {
+            content.str(url);
+            content.str(hash);
+            content.u64(*changed_at);
+        }
+
+        tokio::fs::write(&self.state_file, content.finish())
+            .await
+            .context("Failed to save source state")
+    }
+}
+
+async fn fetch_lines(
+    client: &Client,
+    url: &str,
+    index: usize,
+    tx: &mpsc::Sender<(usize, String)>,
+    jitter: Duration,
+) -> Result<()> {
+    let mut retries = 0;
+    let mut response = loop {
+        if !jitter.is_zero() {
+            let delay = seed::rng(&(url, retries)).random_range(Duration::ZERO..jitter);
+            tokio::time::sleep(delay).await;
+        }
+
+        let response = client.get(url).send().await?;
+        if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            break response;
+        }
+        if retries == RATE_LIMIT_RETRIES {
+            bail!("Rate limited");
+        }
+        retries += 1;
+
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .map_or(Duration::from_secs(5), Duration::from_secs);
+        log::debug!("Source {url} rate limited, retrying in {retry_after:?}");
+        tokio::time::sleep(retry_after.min(MAX_RETRY_AFTER)).await;
+    };
+    let mut pending = Vec::new();
+    // Inside line longer than MAX_LINE_LEN, dropping it up to next newline
+    let mut skipping = false;
+
+    while let Some(chunk) = response.chunk().await? {
+        pending.extend_from_slice(&chunk);
+
+        if let Some(end) = pending.iter().rposition(|&b| b == b'\n') {
+            let rest = pending.split_off(end + 1);
+            let text = String::from_utf8_lossy(&pending);
+            let mut lines = text.lines().map(|line| (index, line.to_owned()));
+            if skipping {
+                lines.next();
+                skipping = false;
+            }
+            for line in lines {
+                tx.send(line).await?;
+            }
+            drop(text);
+            pending = rest;
+        }
+
+        if pending.len() > MAX_LINE_LEN {
+            pending.clear();
+            skipping = true;
+        }
+    }
+
+    if !skipping {
+        for line in String::from_utf8_lossy(&pending).lines() {
+            tx.send((index, line.to_owned())).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn fetch_git(
+    repo: &str,
+    index: usize,
+    tx: &mpsc::Sender<(usize, String)>,
+    env_proxy: bool,
+) -> Result<()> {
+    let (repo, pattern) = repo.split_once('#').unwrap_or((repo, "*"));
+    let pattern = Regex::new(&format!(
+        "^{}$",
+        regex::escape(pattern).replace(r"\*", ".*")
+    ))?;
+    let dir = std::env::temp_dir().join(format!("novaprox-{}-{index}", std::process::id()));
+
+    let mut git = Command::new("git");
+    if !env_proxy {
+        for var in PROXY_VARS {
+            git.env_remove(var);
+        }
+    }
+    let status = git
+        .args(["clone", "--quiet", "--depth", "1", repo])
+        .arg(&dir)
+        .stdin(Stdio::null())
+        .status()
+        .await
+        .context("Failed to run git")?;
+    if !status.success() {
+        bail!("git clone failed: {status}");
+    }
+
+    let result = send_repo_files(&dir, &pattern, index, tx).await;
+    tokio::fs::remove_dir_all(&dir).await.ok();
+    result
+}
+
+async fn send_repo_files(
+    dir: &Path,
+    pattern: &Regex,
+    index: usize,
+    tx: &mpsc::Sender<(usize, String)>,
+) -> Result<()> {
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_name() == ".git" {
+                continue;
+            }
+            if entry.file_type().await?.is_dir() {
+                pending.push(path);
+                continue;
+            }
+
+            let relative = path.strip_prefix(dir)?.to_string_lossy().replace('\\', "/");
+            if !pattern.is_match(&relative) {
+                continue;
+            }
+            let mut lines = BufReader::new(File::open(&path).await?).lines();
+            // Binary files stop at first invalid line
+            while let Ok(Some(line)) = lines.next_line().await {
+                tx.send((index, line)).await?;
+            }
+        }
+    }
+    Ok(())
+}
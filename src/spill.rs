@@ -0,0 +1,278 @@
+//! Candidate links of a run, moved to a temporary file once they outgrow
+//! their share of `--max-memory-mb`, so aggregated sources of millions of
+//! lines fit small machines.
+//!
+//! Links are read back one by one while resolving, the file is removed when
+//! they're all read or the run stops. Only candidates spill, resolved
+//! proxies and check results stay in memory. Hashes of seen lines and links
+//! don't spill, they stop growing at their share instead.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufRead as _, BufReader, BufWriter, Lines, Write as _},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use url::Url;
+
+// Spill files of this process, so every run of a daemon gets its own
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+// Bytes a hash takes in set, with its control byte
+const HASH_SIZE: usize = size_of::<u64>() + 1;
+
+/// Bytes of `1 / divisor` of `max_memory_mb`, unbounded for `None`
+#[must_use]
+pub fn memory_share(max_memory_mb: Option<u64>, divisor: u64) -> usize {
+    max_memory_mb.map_or(usize::MAX, |mb| {
+        usize::try_from(mb.saturating_mul(1024 * 1024) / divisor).unwrap_or(usize::MAX)
+    })
+}
+
+pub struct Candidates {
+    // Bytes links may take in memory before spilling
+    budget: usize,
+    memory: Vec<Url>,
+    size: usize,
+    spill: Option<(PathBuf, BufWriter<File>)>,
+    len: usize,
+}
+
+impl Candidates {
+    /// Candidates spilling past half of `max_memory_mb`, the rest is left
+    /// for resolved proxies and checks. `None` keeps all in memory
+    #[must_use]
+    pub fn new(max_memory_mb: Option<u64>) -> Self {
+        Self {
+            budget: memory_share(max_memory_mb, 2),
+            memory: Vec::new(),
+            size: 0,
+            spill: None,
+            len: 0,
+        }
+    }
+
+    /// Adds `url` to memory or, over budget, to the spill file
+    ///
+    /// # Errors
+    /// Return error if spill file can't be created or written
+    pub fn push(&mut self, url: Url) -> io::Result<()> {
+        self.len += 1;
+        if let Some((_, writer)) = &mut self.spill {
+            return writeln!(writer, "{url}");
+        }
+
+        self.size += size_of::<Url>() + url.as_str().len();
+        self.memory.push(url);
+        if self.size > self.budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "novaprox-candidates-{}-{}.txt",
+            std::process::id(),
+            SPILLS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for url in self.memory.drain(..) {
+            writeln!(writer, "{url}")?;
+        }
+        log::info!(
+            "Candidates exceed {} MB, spilling them to {}",
+            self.budget / 1024 / 1024,
+            path.display()
+        );
+        self.memory.shrink_to_fit();
+        self.spill = Some((path, writer));
+        Ok(())
+    }
+
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Links in the order they were added
+    ///
+    /// # Errors
+    /// Return error if spill file can't be flushed or reopened
+    pub fn into_urls(mut self) -> io::Result<Urls> {
+        let spilled = match self.spill.take() {
+            Some((path, writer)) => {
+                writer
+                    .into_inner()
+                    .map_err(io::IntoInnerError::into_error)?;
+                let lines = BufReader::new(File::open(&path)?).lines();
+                Some((lines, path))
+            }
+            None => None,
+        };
+        Ok(Urls {
+            memory: std::mem::take(&mut self.memory).into_iter(),
+            spilled,
+            remaining: self.len,
+        })
+    }
+}
+
+impl Drop for Candidates {
+    fn drop(&mut self) {
+        if let Some((path, _)) = &self.spill {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+pub struct Urls {
+    memory: std::vec::IntoIter<Url>,
+    spilled: Option<(Lines<BufReader<File>>, PathBuf)>,
+    remaining: usize,
+}
+
+impl Iterator for Urls {
+    type Item = Url;
+
+    fn next(&mut self) -> Option<Url> {
+        let url = self.memory.next().or_else(|| self.next_spilled())?;
+        self.remaining = self.remaining.saturating_sub(1);
+        Some(url)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for Urls {}
+
+impl Urls {
+    // Skips lines that don't parse back, so one can't end the rest
+    fn next_spilled(&mut self) -> Option<Url> {
+        let (lines, path) = self.spilled.as_mut()?;
+        loop {
+            match lines.next()? {
+                Ok(line) => match Url::parse(&line) {
+                    Ok(url) => return Some(url),
+                    Err(e) => {
+                        log::warn!("Dropping spilled candidate {line:?}: {e}");
+                        self.remaining = self.remaining.saturating_sub(1);
+                    }
+                },
+                Err(e) => {
+                    log::warn!("Failed to read spilled candidates {}: {e}", path.display());
+                    self.remaining = 0;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Urls {
+    fn drop(&mut self) {
+        if let Some((_, path)) = &self.spilled {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// Hashes of lines or links seen before, growing up to `budget` bytes.
+/// Once full, hashes kept so far are still found but new ones aren't kept,
+/// so their repeats get through
+pub struct SeenHashes {
+    hashes: HashSet<u64>,
+    budget: usize,
+    full: bool,
+}
+
+impl SeenHashes {
+    #[must_use]
+    pub fn new(budget: usize) -> Self {
+        Self {
+            hashes: HashSet::new(),
+            budget,
+            full: false,
+        }
+    }
+
+    /// Whether `hash` wasn't seen before, keeping it while there's room
+    pub fn insert(&mut self, hash: u64) -> bool {
+        if self.hashes.contains(&hash) {
+            return false;
+        }
+        // Set doubles when it runs out of capacity
+        let grows = self.hashes.len() == self.hashes.capacity();
+        if !self.full && grows && (self.hashes.capacity() * 2).max(4) * HASH_SIZE > self.budget {
+            log::info!(
+                "Seen {} distinct hashes, further duplicates aren't dropped to stay in --max-memory-mb",
+                self.hashes.len()
+            );
+            self.full = true;
+        }
+        if !self.full {
+            self.hashes.insert(hash);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(id: usize) -> Url {
+        Url::parse(&format!("vless://{id}@192.0.2.1:443")).unwrap()
+    }
+
+    #[test]
+    fn spilled_urls_come_back_in_order() {
+        let mut candidates = Candidates::new(Some(0));
+        for id in 0..100 {
+            candidates.push(url(id)).unwrap();
+        }
+        assert!(candidates.spill.is_some());
+
+        let urls = candidates.into_urls().unwrap();
+        assert_eq!(urls.len(), 100);
+        assert!(urls.eq((0..100).map(url)));
+    }
+
+    #[test]
+    fn unparsable_spilled_line_is_skipped() {
+        let mut candidates = Candidates::new(Some(0));
+        candidates.push(url(1)).unwrap();
+        if let Some((_, writer)) = &mut candidates.spill {
+            writeln!(writer, "not a link").unwrap();
+        }
+        candidates.len += 1;
+        candidates.push(url(2)).unwrap();
+
+        let urls = candidates.into_urls().unwrap();
+        assert_eq!(urls.collect::<Vec<_>>(), [url(1), url(2)]);
+    }
+
+    #[test]
+    fn full_seen_hashes_keep_finding_old_ones() {
+        let mut seen = SeenHashes::new(64 * HASH_SIZE);
+        assert!((0..1000).all(|hash| seen.insert(hash)));
+        assert!(seen.full);
+        assert!(seen.hashes.len() < 64);
+        assert!(!seen.insert(0));
+        assert!(seen.insert(999));
+    }
+
+    #[test]
+    fn huge_memory_cap_doesnt_overflow() {
+        assert!(memory_share(Some(u64::MAX), 2) >= usize::MAX / 2);
+        assert_eq!(memory_share(Some(2), 2), 1024 * 1024);
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+use serde_json::{Value, json};
+
+use crate::{abuse::now_secs, journal};
+
+pub struct SourceStats {
+    pub url: String,
+    pub error: Option<String>,
+    // Hex SHA-256 of content, missing when fetch failed
+    pub hash: Option<String>,
+    pub lines: usize,
+    // Lines selected as share links
+    pub links: usize,
+    // Content didn't change for a long time
+    pub stale: bool,
+}
+
+/// Counts and durations of a run, written as JSON for automation
+pub struct RunSummary {
+    started_at: u64,
+    started: Instant,
+    // Stage name, proxies left after it, duration
+    stages: Vec<(&'static str, usize, Duration)>,
+    dropped: Vec<(&'static str, usize)>,
+    // Links kept thanks to `--lenient` repairs
+    pub repaired: usize,
+    pub sources: Vec<SourceStats>,
+    // Location label of the run
+    pub vantage: Option<String>,
+}
+
+impl Default for RunSummary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunSummary {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            started_at: now_secs(),
+            started: Instant::now(),
+            stages: Vec::new(),
+            dropped: Vec::new(),
+            repaired: 0,
+            sources: Vec::new(),
+            vantage: None,
+        }
+    }
+
+    pub fn stage(&mut self, name: &'static str, count: usize, started: Instant) {
+        self.stages.push((name, count, started.elapsed()));
+    }
+
+    pub fn dropped(&mut self, reason: &'static str, count: usize) {
+        if count > 0 {
+            self.dropped.push((reason, count));
+        }
+    }
+
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "args": journal::redacted_args(),
+            "vantage": self.vantage,
+            "started_at": self.started_at,
+            "duration_ms": self.started.elapsed().as_millis() as u64,
+            "stages": self
+                .stages
+                .iter()
+                .map(|(name, count, duration)| json!({
+                    "name": name,
+                    "count": count,
+                    "duration_ms": duration.as_millis() as u64,
+                }))
+                .collect::<Vec<_>>(),
+            "dropped": self
+                .dropped
+                .iter()
+                .map(|(reason, count)| ((*reason).to_owned(), json!(count)))
+                .collect::<serde_json::Map<_, _>>(),
+            "repaired": self.repaired,
+            "sources": self
+                .sources
+                .iter()
+                .map(|source| json!({
+                    "url": source.url,
+                    "error": source.error,
+                    "lines": source.lines,
+                    "links": source.links,
+                    "hash": source.hash,
+                    "stale": source.stale,
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    /// # Errors
+    /// Return error if failed to write file
+    pub async fn write(&self, path: &str) -> Result<()> {
+        tokio::fs::write(path, format!("{:#}\n", self.to_json()))
+            .await
+            .context("Failed to write run summary")
+    }
+}
@@ -0,0 +1,64 @@
+//! Timeline of one proxy through every stage, for `--trace-proxy`.
+
+use std::{fmt::Display, sync::Mutex, time::Instant};
+
+use ahash::HashSet;
+
+struct Tracer {
+    pattern: String,
+    start: Instant,
+    // Links of stages after resolving, which may not contain the pattern
+    // anymore (e.g. host replaced by address)
+    links: HashSet<String>,
+}
+
+static TRACER: Mutex<Option<Tracer>> = Mutex::new(None);
+
+/// Traces every proxy whose link contains `pattern`, e.g. its host,
+/// address or uuid
+pub fn trace_proxy(pattern: &str) {
+    if let Ok(mut tracer) = TRACER.lock() {
+        *tracer = Some(Tracer {
+            pattern: pattern.to_owned(),
+            start: Instant::now(),
+            links: HashSet::default(),
+        });
+    }
+}
+
+/// Whether proxy with `link` is traced
+#[must_use]
+pub fn traced(link: &impl Display) -> bool {
+    let Ok(tracer) = TRACER.lock() else {
+        return false;
+    };
+    tracer.as_ref().is_some_and(|tracer| {
+        let link = link.to_string();
+        link.contains(&tracer.pattern) || tracer.links.contains(&link)
+    })
+}
+
+/// Keeps tracing proxy under its new link, if it was traced under `from`
+pub fn follow(from: &impl Display, to: &impl Display) {
+    if !traced(from) {
+        return;
+    }
+    if let Ok(mut tracer) = TRACER.lock()
+        && let Some(tracer) = tracer.as_mut()
+    {
+        tracer.links.insert(to.to_string());
+    }
+}
+
+/// Logs `event` of `stage` if proxy with `link` is traced
+pub fn record(link: &impl Display, stage: &str, event: impl Display) {
+    if !traced(link) {
+        return;
+    }
+    let elapsed = TRACER
+        .lock()
+        .ok()
+        .and_then(|tracer| Some(tracer.as_ref()?.start.elapsed()))
+        .unwrap_or_default();
+    log::info!("[trace +{}ms] {stage}: {event}", elapsed.as_millis());
+}
@@ -0,0 +1,407 @@
+//! Stream settings of every transport, looked up by `type` link param.
+//!
+//! Like protocols, transports added outside this crate are registered with
+//! [`register_transport`] and take precedence over built-in ones.
+
+use std::sync::{Arc, LazyLock, RwLock};
+
+use litemap::LiteMap;
+use serde_json::{Value, json};
+
+use crate::error::ParseError;
+
+/// Validates transport params of links and fills their stream settings
+pub trait TransportHandler: Send + Sync {
+    /// Value of `type` link param and of `network` stream setting
+    fn network(&self) -> &'static str;
+
+    /// Checks link params of the transport
+    ///
+    /// # Errors
+    /// Return error if a param has a value the core rejects
+    fn validate(&self, _params: &LiteMap<String, String>) -> Result<(), ParseError> {
+        Ok(())
+    }
+
+    /// Puts settings of the transport into `stream_settings`
+    fn apply(&self, stream_settings: &mut Value, params: &LiteMap<String, String>);
+}
+
+// Registered handlers first, so they override built-in ones
+static TRANSPORTS: LazyLock<RwLock<Vec<Arc<dyn TransportHandler>>>> = LazyLock::new(|| {
+    RwLock::new(vec![
+        Arc::new(Ws),
+        Arc::new(Grpc),
+        Arc::new(Xhttp),
+        Arc::new(Kcp),
+        Arc::new(Quic),
+        Arc::new(HttpUpgrade),
+    ])
+});
+
+/// Adds handler of a transport, replacing earlier handler of its network
+pub fn register_transport(handler: Arc<dyn TransportHandler>) {
+    if let Ok(mut handlers) = TRANSPORTS.write() {
+        handlers.insert(0, handler);
+    }
+}
+
+/// Handler of `network`, missing for transports without settings (tcp)
+#[must_use]
+pub fn transport_handler(network: &str) -> Option<Arc<dyn TransportHandler>> {
+    TRANSPORTS
+        .read()
+        .ok()?
+        .iter()
+        .find(|handler| handler.network() == network)
+        .cloned()
+}
+
+// Rejects `param` value outside `allowed`, missing param is fine
+fn check_value(
+    params: &LiteMap<String, String>,
+    transport: &'static str,
+    param: &'static str,
+    allowed: &[&str],
+) -> Result<(), ParseError> {
+    match params.get(param) {
+        Some(value) if !allowed.contains(&value.as_str()) => Err(ParseError::InvalidTransport {
+            transport,
+            param,
+            value: value.clone(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn decoded_path(params: &LiteMap<String, String>) -> Option<String> {
+    params.get("path").map(|path| {
+        percent_encoding::percent_decode_str(path)
+            .decode_utf8_lossy()
+            .into_owned()
+    })
+}
+
+// Header obfuscation types of kcp and quic
+const HEADER_TYPES: &[&str] = &["none", "srtp", "utp", "wechat-video", "dtls", "wireguard"];
+
+struct Ws;
+
+impl TransportHandler for Ws {
+    fn network(&self) -> &'static str {
+        "ws"
+    }
+
+    fn apply(&self, stream_settings: &mut Value, params: &LiteMap<String, String>) {
+        if let Some(path) = decoded_path(params) {
+            let mut ws_settings = json!({ "path": path });
+
+            if let Some(host) = params.get("host") {
+                ws_settings["headers"] = json!({ "Host": host });
+            }
+
+            stream_settings["wsSettings"] = ws_settings;
+        }
+    }
+}
+
+struct Grpc;
+
+impl TransportHandler for Grpc {
+    fn network(&self) -> &'static str {
+        "grpc"
+    }
+
+    fn validate(&self, params: &LiteMap<String, String>) -> Result<(), ParseError> {
+        check_value(params, "grpc", "mode", &["gun", "multi"])
+    }
+
+    fn apply(&self, stream_settings: &mut Value, params: &LiteMap<String, String>) {
+        let mut grpc_settings = serde_json::Map::new();
+        if let Some(service_name) = params.get("serviceName") {
+            grpc_settings.insert("serviceName".to_owned(), json!(service_name));
+        }
+        if let Some(mode) = params.get("mode") {
+            grpc_settings.insert("multiMode".to_owned(), json!(mode == "multi"));
+        }
+        if !grpc_settings.is_empty() {
+            stream_settings["grpcSettings"] = json!(grpc_settings);
+        }
+    }
+}
+
+struct Xhttp;
+
+impl TransportHandler for Xhttp {
+    fn network(&self) -> &'static str {
+        "xhttp"
+    }
+
+    fn validate(&self, params: &LiteMap<String, String>) -> Result<(), ParseError> {
+        check_value(
+            params,
+            "xhttp",
+            "mode",
+            &["auto", "packet-up", "stream-up", "stream-one"],
+        )
+    }
+
+    fn apply(&self, stream_settings: &mut Value, params: &LiteMap<String, String>) {
+        let mut xhttp_settings = serde_json::Map::new();
+
+        if let Some(path) = decoded_path(params) {
+            xhttp_settings.insert("path".to_owned(), json!(path));
+        }
+
+        if let Some(host) = params.get("host") {
+            xhttp_settings.insert("host".to_owned(), json!(host));
+        }
+
+        let mode = params.get("mode").map(|s| s.as_str()).unwrap_or("auto");
+        if mode != "auto" {
+            xhttp_settings.insert("mode".to_owned(), json!(mode));
+        }
+
+        let extra = create_xhttp_extra(params);
+        if !extra.is_empty() {
+            xhttp_settings.insert("extra".to_owned(), json!(extra));
+        }
+
+        if !xhttp_settings.is_empty() {
+            stream_settings["xhttpSettings"] = json!(xhttp_settings);
+        }
+    }
+}
+
+fn create_xhttp_extra(query_params: &LiteMap<String, String>) -> serde_json::Map<String, Value> {
+    let mut extra = serde_json::Map::new();
+
+    if let Some(headers) = query_params.get("headers")
+        && let Ok(headers_value) = serde_json::from_str::<Value>(headers)
+    {
+        extra.insert("headers".to_owned(), headers_value);
+    }
+
+    let numeric_fields = [
+        ("xPaddingBytes", "xPaddingBytes"),
+        ("scMaxEachPostBytes", "scMaxEachPostBytes"),
+        ("scMinPostsIntervalMs", "scMinPostsIntervalMs"),
+        ("scMaxBufferedPosts", "scMaxBufferedPosts"),
+    ];
+
+    for (param, field) in numeric_fields {
+        if let Some(value) = query_params.get(param).and_then(|v| v.parse::<u32>().ok()) {
+            extra.insert(field.to_owned(), json!(value));
+        }
+    }
+
+    let bool_fields = [
+        ("noGRPCHeader", "noGRPCHeader"),
+        ("noSSEHeader", "noSSEHeader"),
+    ];
+
+    for (param, field) in bool_fields {
+        if let Some(value) = query_params.get(param) {
+            extra.insert(field.to_owned(), json!(value == "true"));
+        }
+    }
+
+    if let Some(secs) = query_params.get("scStreamUpServerSecs") {
+        extra.insert("scStreamUpServerSecs".to_owned(), json!(secs));
+    }
+
+    extra
+}
+
+struct Kcp;
+
+impl TransportHandler for Kcp {
+    fn network(&self) -> &'static str {
+        "kcp"
+    }
+
+    fn validate(&self, params: &LiteMap<String, String>) -> Result<(), ParseError> {
+        check_value(params, "kcp", "headerType", HEADER_TYPES)
+    }
+
+    fn apply(&self, stream_settings: &mut Value, params: &LiteMap<String, String>) {
+        let mut kcp_settings = json!({});
+        if let Some(header) = params.get("headerType") {
+            kcp_settings["header"] = json!({ "type": header });
+        }
+        if let Some(seed) = params.get("seed") {
+            kcp_settings["seed"] = json!(seed);
+        }
+        stream_settings["kcpSettings"] = kcp_settings;
+    }
+}
+
+struct Quic;
+
+impl TransportHandler for Quic {
+    fn network(&self) -> &'static str {
+        "quic"
+    }
+
+    fn validate(&self, params: &LiteMap<String, String>) -> Result<(), ParseError> {
+        check_value(params, "quic", "headerType", HEADER_TYPES)?;
+        check_value(
+            params,
+            "quic",
+            "quicSecurity",
+            &["none", "aes-128-gcm", "chacha20-poly1305"],
+        )
+    }
+
+    fn apply(&self, stream_settings: &mut Value, params: &LiteMap<String, String>) {
+        let mut quic_settings = json!({});
+        if let Some(security) = params.get("quicSecurity") {
+            quic_settings["security"] = json!(security);
+        }
+        if let Some(key) = params.get("key") {
+            quic_settings["key"] = json!(key);
+        }
+        if let Some(header) = params.get("headerType") {
+            quic_settings["header"] = json!({ "type": header });
+        }
+        stream_settings["quicSettings"] = quic_settings;
+    }
+}
+
+struct HttpUpgrade;
+
+impl TransportHandler for HttpUpgrade {
+    fn network(&self) -> &'static str {
+        "httpupgrade"
+    }
+
+    fn apply(&self, stream_settings: &mut Value, params: &LiteMap<String, String>) {
+        let mut settings = json!({});
+        if let Some(path) = decoded_path(params) {
+            settings["path"] = json!(path);
+        }
+        if let Some(host) = params.get("host") {
+            settings["host"] = json!(host);
+        }
+        stream_settings["httpupgradeSettings"] = settings;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> LiteMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    fn settings(network: &str, pairs: &[(&str, &str)]) -> Value {
+        let mut stream_settings = json!({});
+        transport_handler(network)
+            .unwrap()
+            .apply(&mut stream_settings, &params(pairs));
+        stream_settings
+    }
+
+    fn invalid(network: &str, pairs: &[(&str, &str)]) -> bool {
+        matches!(
+            transport_handler(network).unwrap().validate(&params(pairs)),
+            Err(ParseError::InvalidTransport { .. })
+        )
+    }
+
+    #[test]
+    fn tcp_has_no_handler() {
+        assert!(transport_handler("tcp").is_none());
+    }
+
+    #[test]
+    fn ws_decodes_path_and_sets_host() {
+        assert_eq!(
+            settings(
+                "ws",
+                &[("path", "%2Fws%3Fed%3D2048"), ("host", "cdn.example")]
+            ),
+            json!({ "wsSettings": { "path": "/ws?ed=2048", "headers": { "Host": "cdn.example" } } })
+        );
+        assert_eq!(settings("ws", &[("host", "cdn.example")]), json!({}));
+    }
+
+    #[test]
+    fn grpc_sets_service_name_and_multi_mode() {
+        assert_eq!(
+            settings("grpc", &[("serviceName", "tun"), ("mode", "multi")]),
+            json!({ "grpcSettings": { "serviceName": "tun", "multiMode": true } })
+        );
+        assert_eq!(
+            settings("grpc", &[("serviceName", "tun"), ("mode", "gun")]),
+            json!({ "grpcSettings": { "serviceName": "tun", "multiMode": false } })
+        );
+        assert_eq!(settings("grpc", &[]), json!({}));
+        assert!(invalid("grpc", &[("mode", "stream")]));
+        assert!(!invalid("grpc", &[("mode", "gun")]));
+    }
+
+    #[test]
+    fn xhttp_sets_mode_and_extra() {
+        assert_eq!(
+            settings(
+                "xhttp",
+                &[
+                    ("path", "/x"),
+                    ("mode", "packet-up"),
+                    ("xPaddingBytes", "100"),
+                    ("noSSEHeader", "true"),
+                ]
+            ),
+            json!({ "xhttpSettings": {
+                "path": "/x",
+                "mode": "packet-up",
+                "extra": { "xPaddingBytes": 100, "noSSEHeader": true },
+            } })
+        );
+        assert_eq!(settings("xhttp", &[("mode", "auto")]), json!({}));
+        assert!(invalid("xhttp", &[("mode", "stream")]));
+    }
+
+    #[test]
+    fn kcp_sets_header_and_seed() {
+        assert_eq!(
+            settings("kcp", &[("headerType", "wechat-video"), ("seed", "s")]),
+            json!({ "kcpSettings": { "header": { "type": "wechat-video" }, "seed": "s" } })
+        );
+        assert!(invalid("kcp", &[("headerType", "http")]));
+    }
+
+    #[test]
+    fn quic_sets_security_key_and_header() {
+        assert_eq!(
+            settings(
+                "quic",
+                &[
+                    ("quicSecurity", "aes-128-gcm"),
+                    ("key", "k"),
+                    ("headerType", "srtp")
+                ]
+            ),
+            json!({ "quicSettings": {
+                "security": "aes-128-gcm",
+                "key": "k",
+                "header": { "type": "srtp" },
+            } })
+        );
+        assert!(invalid("quic", &[("quicSecurity", "aes-256-gcm")]));
+        assert!(invalid("quic", &[("headerType", "http")]));
+    }
+
+    #[test]
+    fn httpupgrade_sets_path_and_host() {
+        assert_eq!(
+            settings("httpupgrade", &[("path", "%2Fup"), ("host", "h.example")]),
+            json!({ "httpupgradeSettings": { "path": "/up", "host": "h.example" } })
+        );
+    }
+}
@@ -1,36 +1,123 @@
-use anyhow::{Context as _, Result};
+use crate::error::EngineError;
 use litemap::LiteMap;
 use serde_json::{Value, json};
 
-use crate::proxy_config::ProxyConfig;
+use crate::{
+    protocol::protocol_handler, proxy_config::ProxyConfig, transport::transport_handler,
+    xray_stats::enable_stats,
+};
+
+/// Proxy core configs are generated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Engine {
+    #[default]
+    Xray,
+    // v2ray-core v5 of v2fly, without reality, vision, xhttp and uTLS
+    V2fly,
+}
+
+impl Engine {
+    #[must_use]
+    pub const fn binary(self) -> &'static str {
+        match self {
+            Self::Xray => "xray",
+            Self::V2fly => "v2ray",
+        }
+    }
+
+    /// Arguments running core with JSON config from stdin
+    #[must_use]
+    pub const fn run_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Xray => &["run", "-config", "stdin:"],
+            Self::V2fly => &["run", "-config", "stdin:", "-format", "json"],
+        }
+    }
+}
 
+/// Socks username of per proxy inbounds
+pub const INBOUND_USER: &str = "novaprox";
+
+/// Socks username routed to `index` proxy of chunk on shared inbound
+#[must_use]
+pub fn mux_user(index: usize) -> String {
+    format!("proxy-{index}")
+}
+
+/// Destinations proxies may be asked for, traffic to any other is dropped
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    pub domains: Vec<String>,
+    // Host and port of the QUIC probe, whose packets carry no name to sniff
+    pub quic_target: Option<(String, u16)>,
+}
+
+/// Every inbound requires socks password auth with `password`, so other
+/// local users can't send traffic through proxies being checked. With
+/// `sandbox` only its destinations are reachable.
+///
 /// # Errors
 /// Will result error if proxy config is invalid
-pub fn generate_xray_config(proxies: &[ProxyConfig], base_port: usize) -> Result<String> {
+pub fn generate_xray_config(
+    proxies: &[ProxyConfig],
+    base_port: usize,
+    stats_api_port: Option<u16>,
+    mux_inbound: bool,
+    engine: Engine,
+    password: &str,
+    sandbox: Option<&Sandbox>,
+) -> Result<String, EngineError> {
     let mut inbounds = Vec::new();
     let mut outbounds = Vec::new();
     let mut rules = Vec::new();
+    let mut accounts = Vec::new();
 
     for (i, proxy) in proxies.iter().enumerate() {
-        let port = base_port + i;
-        let inbound_tag = format!("socks-in-{i}");
-
-        inbounds.push(json!({
-            "listen": "127.0.0.1",
-            "port": port,
-            "protocol": "socks",
-            "settings": {"auth": "noauth", "udp": true},
-            "tag": inbound_tag.clone()
-        }));
-
-        if let Some(outbound) = create_outbound(proxy, i)? {
-            outbounds.push(outbound);
+        let Some(outbound) = create_outbound(proxy, i, engine)? else {
+            continue;
+        };
+
+        // Tag is per protocol, not per scheme (ss and shadowsocks links
+        // both give ss-out)
+        if mux_inbound {
+            // Xray uses socks username as user email for routing
+            accounts.push(json!({"user": mux_user(i), "pass": password}));
+            rules.push(json!({
+                "type": "field",
+                "user": [mux_user(i)],
+                "outboundTag": outbound["tag"]
+            }));
+        } else {
+            let inbound_tag = format!("socks-in-{i}");
+            inbounds.push(json!({
+                "listen": "127.0.0.1",
+                "port": base_port + i,
+                "protocol": "socks",
+                "settings": {
+                    "auth": "password",
+                    "accounts": [{"user": INBOUND_USER, "pass": password}],
+                    "udp": true
+                },
+                "tag": inbound_tag.clone()
+            }));
             rules.push(json!({
                 "type": "field",
                 "inboundTag": [inbound_tag],
-                "outboundTag": format!("{}-out-{i}", proxy.protocol)
+                "outboundTag": outbound["tag"]
             }));
         }
+        outbounds.push(outbound);
+    }
+
+    if mux_inbound {
+        inbounds.push(json!({
+            "listen": "127.0.0.1",
+            "port": base_port,
+            "protocol": "socks",
+            "settings": {"auth": "password", "accounts": accounts, "udp": true},
+            "tag": "socks-in"
+        }));
     }
 
     outbounds.push(json!({
@@ -38,7 +125,7 @@ pub fn generate_xray_config(proxies: &[ProxyConfig], base_port: usize) -> Result
         "tag": "direct"
     }));
 
-    let config = json!({
+    let mut config = json!({
         "log": {"loglevel": "error"},
         "inbounds": inbounds,
         "outbounds": outbounds,
@@ -48,147 +135,233 @@ pub fn generate_xray_config(proxies: &[ProxyConfig], base_port: usize) -> Result
         }
     });
 
-    serde_json::to_string_pretty(&config).context("Failed to serialize Xray config")
-}
-
-fn create_outbound(proxy: &ProxyConfig, index: usize) -> Result<Option<Value>> {
-    let outbound = match proxy.protocol.as_str() {
-        "http" | "https" => create_http_outbound(proxy, index),
-        "socks" | "socks5" => create_socks_outbound(proxy, index),
-        "ss" | "shadowsocks" => create_shadowsocks_outbound(proxy, index),
-        "trojan" => create_trojan_outbound(proxy, index),
-        "vless" => create_vless_outbound(proxy, index),
-        "vmess" => create_vmess_outbound(proxy, index),
-        _ => return Err(anyhow::anyhow!("Unsupported protocol: {}", proxy.protocol)),
-    };
+    // Before stats, whose api inbound must stay reachable
+    if let Some(sandbox) = sandbox {
+        apply_sandbox(&mut config, sandbox);
+    }
 
-    Ok(Some(outbound))
-}
+    if let Some(api_port) = stats_api_port {
+        enable_stats(&mut config, api_port);
+    }
 
-fn create_http_outbound(proxy: &ProxyConfig, index: usize) -> Value {
-    let settings = create_common_server_settings(proxy, &["user", "pass"]);
-    json!({
-        "protocol": "http",
-        "settings": settings,
-        "tag": format!("http-out-{index}")
-    })
-}
+    if engine == Engine::V2fly {
+        make_v2fly_compatible(&mut config);
+    }
 
-fn create_socks_outbound(proxy: &ProxyConfig, index: usize) -> Value {
-    let settings = create_common_server_settings(proxy, &["user", "pass"]);
-    json!({
-        "protocol": "socks",
-        "settings": settings,
-        "tag": format!("socks-out-{index}")
-    })
+    serde_json::to_string_pretty(&config).map_err(EngineError::Serialize)
 }
 
-fn create_shadowsocks_outbound(proxy: &ProxyConfig, index: usize) -> Value {
-    let mut settings = create_common_server_settings(proxy, &[]);
-
-    if let Some(method) = proxy.query_params.get("method") {
-        settings["method"] = json!(method);
-    } else {
-        settings["method"] = json!("aes-256-gcm");
+// Routes only requests to allowed domains to proxies and the rest of
+// inbound traffic to blackhole. Checker resolves hosts locally, so the
+// domain is sniffed from TLS SNI, HTTP Host or QUIC for routing. QUIC probe
+// is matched by destination, its version negotiation packet has no SNI.
+fn apply_sandbox(config: &mut Value, sandbox: &Sandbox) {
+    let domains = sandbox
+        .domains
+        .iter()
+        .map(|domain| format!("domain:{domain}"))
+        .collect::<Vec<_>>();
+
+    let mut inbound_tags = Vec::new();
+    for inbound in config["inbounds"].as_array_mut().into_iter().flatten() {
+        inbound["sniffing"] = json!({
+            "enabled": true,
+            "destOverride": ["http", "tls", "quic"],
+            "routeOnly": true
+        });
+        inbound_tags.push(inbound["tag"].clone());
     }
 
-    settings["password"] = json!(proxy.username);
-
-    if let Some(uot) = proxy.query_params.get("uot") {
-        settings["uot"] = json!(uot == "true");
+    if let Some(rules) = config["routing"]["rules"].as_array_mut() {
+        let mut quic_rules = Vec::new();
+        for rule in rules.iter_mut() {
+            if let Some((host, port)) = &sandbox.quic_target {
+                let mut quic_rule = rule.clone();
+                quic_rule["network"] = json!("udp");
+                quic_rule["port"] = json!(port.to_string());
+                match host.trim_matches(['[', ']']).parse::<std::net::IpAddr>() {
+                    Ok(ip) => quic_rule["ip"] = json!([ip.to_string()]),
+                    Err(_) => quic_rule["domain"] = json!([format!("full:{host}")]),
+                }
+                quic_rules.push(quic_rule);
+            }
+            rule["domain"] = json!(domains);
+        }
+        rules.append(&mut quic_rules);
+        rules.push(json!({
+            "type": "field",
+            "inboundTag": inbound_tags,
+            "outboundTag": "blocked"
+        }));
     }
-    if let Some(uot_version) = proxy
-        .query_params
-        .get("UoTVersion")
-        .and_then(|v| v.parse::<u32>().ok())
-    {
-        settings["UoTVersion"] = json!(uot_version);
+    if let Some(outbounds) = config["outbounds"].as_array_mut() {
+        outbounds.push(json!({
+            "protocol": "blackhole",
+            "tag": "blocked"
+        }));
     }
-
-    json!({
-        "protocol": "shadowsocks",
-        "settings": settings,
-        "tag": format!("ss-out-{index}")
-    })
 }
 
-fn create_trojan_outbound(proxy: &ProxyConfig, index: usize) -> Value {
-    let mut settings = create_common_server_settings(proxy, &[]);
-    settings["password"] = json!(proxy.username);
+// Drops xray-only stream fields v2fly rejects, protocol handlers leave out
+// their own
+fn make_v2fly_compatible(config: &mut Value) {
+    for outbound in config["outbounds"].as_array_mut().into_iter().flatten() {
+        if let Some(tls) = outbound["streamSettings"]["tlsSettings"].as_object_mut() {
+            tls.remove("fingerprint");
+        }
+    }
+}
 
-    let mut outbound = json!({
-        "protocol": "trojan",
-        "settings": settings,
-        "tag": format!("trojan-out-{index}")
-    });
+/// Config with single socks inbound balanced across all proxies
+///
+/// # Errors
+/// Will result error if proxy config is invalid
+pub fn generate_rotate_config(
+    proxies: &[ProxyConfig],
+    listen_port: u16,
+    strategy: &str,
+    probe_url: &str,
+) -> Result<String, EngineError> {
+    let mut outbounds = Vec::new();
+    let mut tags = Vec::new();
 
-    if let Some(stream_settings) = create_stream_settings(&proxy.query_params) {
-        outbound["streamSettings"] = stream_settings;
+    for (i, proxy) in proxies.iter().enumerate() {
+        if let Some(outbound) = create_outbound(proxy, i, Engine::Xray)? {
+            if let Some(tag) = outbound["tag"].as_str() {
+                tags.push(tag.to_owned());
+            }
+            outbounds.push(outbound);
+        }
     }
 
-    outbound
+    outbounds.push(json!({
+        "protocol": "freedom",
+        "tag": "direct"
+    }));
+
+    let config = json!({
+        "log": {"loglevel": "error"},
+        "inbounds": [{
+            "listen": "127.0.0.1",
+            "port": listen_port,
+            "protocol": "socks",
+            "settings": {"auth": "noauth", "udp": true},
+            "tag": "rotate-in"
+        }],
+        "outbounds": outbounds,
+        "observatory": {
+            "subjectSelector": tags,
+            "probeUrl": probe_url,
+            "probeInterval": "1m"
+        },
+        "routing": {
+            "domainStrategy": "IPIfNonMatch",
+            "balancers": [{
+                "tag": "rotate",
+                "selector": tags,
+                "strategy": {"type": strategy}
+            }],
+            "rules": [{
+                "type": "field",
+                "inboundTag": ["rotate-in"],
+                "balancerTag": "rotate"
+            }]
+        }
+    });
+
+    serde_json::to_string_pretty(&config).map_err(EngineError::Serialize)
 }
 
-fn create_vless_outbound(proxy: &ProxyConfig, index: usize) -> Value {
-    let mut settings = create_common_server_settings(proxy, &[]);
-    settings["id"] = json!(proxy.username);
-    settings["encryption"] = json!("none");
+// Array items of client template replaced by outbounds and their tags
+const OUTBOUNDS_MARKER: &str = "{{novaprox_outbounds}}";
+const TAGS_MARKER: &str = "{{novaprox_tags}}";
 
-    if let Some(flow) = proxy.query_params.get("flow") {
-        settings["flow"] = json!(flow);
-    }
+/// Fills user's Xray client config with outbounds of proxies
+///
+/// Template array item `"{{novaprox_outbounds}}"` (e.g. in `outbounds`) is
+/// replaced by outbounds and `"{{novaprox_tags}}"` (e.g. in balancer or
+/// observatory selector) by their tags, anywhere in the template.
+///
+/// # Errors
+/// Will result error if template isn't JSON or has no outbounds marker
+pub fn fill_client_template(
+    template: &str,
+    proxies: &[ProxyConfig],
+) -> Result<String, EngineError> {
+    let mut config: Value = serde_json::from_str(template).map_err(EngineError::InvalidTemplate)?;
 
-    let mut outbound = json!({
-        "protocol": "vless",
-        "settings": settings,
-        "tag": format!("vless-out-{index}")
-    });
+    let mut outbounds = Vec::new();
+    for (i, proxy) in proxies.iter().enumerate() {
+        if let Some(outbound) = create_outbound(proxy, i, Engine::Xray)? {
+            outbounds.push(outbound);
+        }
+    }
+    let tags = outbounds
+        .iter()
+        .map(|outbound| outbound["tag"].clone())
+        .collect::<Vec<_>>();
 
-    if let Some(stream_settings) = create_stream_settings(&proxy.query_params) {
-        outbound["streamSettings"] = stream_settings;
+    if !replace_marker(&mut config, OUTBOUNDS_MARKER, &outbounds) {
+        return Err(EngineError::MissingMarker(OUTBOUNDS_MARKER));
     }
+    replace_marker(&mut config, TAGS_MARKER, &tags);
 
-    outbound
+    serde_json::to_string_pretty(&config).map_err(EngineError::Serialize)
 }
 
-fn create_vmess_outbound(proxy: &ProxyConfig, index: usize) -> Value {
-    let security = proxy
-        .query_params
-        .get("security")
-        .map(|s| s.as_str())
-        .unwrap_or("auto");
-    let level = proxy
-        .query_params
-        .get("level")
-        .and_then(|l| l.parse().ok())
-        .unwrap_or(0);
-
-    let settings = json!({
-        "vnext": [{
-            "address": proxy.address,
-            "port": proxy.port,
-            "users": [{
-                "id": proxy.username,
-                "security": security,
-                "level": level
-            }]
-        }]
-    });
-
-    let mut outbound = json!({
-        "protocol": "vmess",
-        "settings": settings,
-        "tag": format!("vmess-out-{index}")
-    });
+// Splices `items` in place of marker items of every array, returns whether
+// marker was found
+fn replace_marker(value: &mut Value, marker: &str, items: &[Value]) -> bool {
+    match value {
+        Value::Array(array) => {
+            let nested = array.iter_mut().fold(false, |found, item| {
+                replace_marker(item, marker, items) | found
+            });
+            let Some(pos) = array.iter().position(|item| item == marker) else {
+                return nested;
+            };
+            array.splice(pos..=pos, items.iter().cloned());
+            true
+        }
+        Value::Object(map) => map.values_mut().fold(false, |found, item| {
+            replace_marker(item, marker, items) | found
+        }),
+        _ => false,
+    }
+}
 
-    if let Some(stream_settings) = create_stream_settings(&proxy.query_params) {
-        outbound["streamSettings"] = stream_settings;
+/// Outbound of proxy tagged `<protocol>-out-<index>`, see
+/// [`ProtocolHandler`](crate::protocol::ProtocolHandler)
+///
+/// Proxies with invalid transport params get none and a warning, as their
+/// check would only fail
+///
+/// # Errors
+/// Will result error if protocol is unsupported or `engine` can't run it
+pub fn create_outbound(
+    proxy: &ProxyConfig,
+    index: usize,
+    engine: Engine,
+) -> Result<Option<Value>, EngineError> {
+    let handler = protocol_handler(&proxy.protocol)
+        .ok_or_else(|| EngineError::UnsupportedProtocol(proxy.protocol.clone()))?;
+    handler.validate(proxy, engine)?;
+    let network = proxy.query_params.get("type").map_or("tcp", String::as_str);
+    if let Some(transport) = transport_handler(network)
+        && let Err(e) = transport.validate(&proxy.query_params)
+    {
+        log::warn!("Leaving {proxy} out of core config: {e}");
+        return Ok(None);
     }
+    let tag = format!("{}-out-{index}", handler.tag());
 
-    outbound
+    Ok(Some(handler.outbound(proxy, tag, engine)))
 }
 
-fn create_common_server_settings(proxy: &ProxyConfig, additional_fields: &[&str]) -> Value {
+/// Server address and port of proxy with user params of link, plus
+/// `additional_fields` params
+#[must_use]
+pub fn create_common_server_settings(proxy: &ProxyConfig, additional_fields: &[&str]) -> Value {
     let mut settings = json!({
         "address": proxy.address,
         "port": proxy.port
@@ -218,7 +391,10 @@ fn create_common_server_settings(proxy: &ProxyConfig, additional_fields: &[&str]
     settings
 }
 
-fn create_stream_settings(query_params: &LiteMap<String, String>) -> Option<Value> {
+/// Stream settings of `security` and `type` link params, missing for plain
+/// TCP
+#[must_use]
+pub fn create_stream_settings(query_params: &LiteMap<String, String>) -> Option<Value> {
     let security = query_params
         .get("security")
         .map(|s| s.as_str())
@@ -255,7 +431,9 @@ fn create_stream_settings(query_params: &LiteMap<String, String>) -> Option<Valu
         _ => {}
     }
 
-    apply_network_settings(&mut stream_settings, query_params, network);
+    if let Some(transport) = transport_handler(network) {
+        transport.apply(&mut stream_settings, query_params);
+    }
 
     Some(stream_settings)
 }
@@ -335,103 +513,14 @@ fn create_tls_settings(query_params: &LiteMap<String, String>) -> Option<Value>
         settings["fingerprint"] = json!(fp);
     }
 
-    Some(settings)
-}
-
-fn apply_network_settings(
-    stream_settings: &mut Value,
-    query_params: &LiteMap<String, String>,
-    network: &str,
-) {
-    match network {
-        "ws" => {
-            if let Some(path) = query_params.get("path") {
-                let decoded_path = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
-                let mut ws_settings = json!({ "path": decoded_path });
-
-                if let Some(host) = query_params.get("host") {
-                    ws_settings["headers"] = json!({ "Host": host });
-                }
-
-                stream_settings["wsSettings"] = ws_settings;
-            }
-        }
-        "grpc" => {
-            if let Some(service_name) = query_params.get("serviceName") {
-                stream_settings["grpcSettings"] = json!({ "serviceName": service_name });
-            }
-        }
-        "xhttp" => {
-            let mut xhttp_settings = serde_json::Map::new();
-
-            if let Some(path) = query_params.get("path") {
-                let decoded_path = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
-                xhttp_settings.insert("path".to_owned(), json!(decoded_path));
-            }
-
-            if let Some(host) = query_params.get("host") {
-                xhttp_settings.insert("host".to_owned(), json!(host));
-            }
-
-            let mode = query_params
-                .get("mode")
-                .map(|s| s.as_str())
-                .unwrap_or("auto");
-            if mode != "auto" {
-                xhttp_settings.insert("mode".to_owned(), json!(mode));
-            }
-
-            let extra = create_xhttp_extra(query_params);
-            if !extra.is_empty() {
-                xhttp_settings.insert("extra".to_owned(), json!(extra));
-            }
-
-            if !xhttp_settings.is_empty() {
-                stream_settings["xhttpSettings"] = json!(xhttp_settings);
-            }
-        }
-        _ => {}
-    }
-}
-
-fn create_xhttp_extra(query_params: &LiteMap<String, String>) -> serde_json::Map<String, Value> {
-    let mut extra = serde_json::Map::new();
-
-    if let Some(headers) = query_params.get("headers")
-        && let Ok(headers_value) = serde_json::from_str::<Value>(headers)
-    {
-        extra.insert("headers".to_owned(), headers_value);
+    if matches!(
+        query_params.get("allowInsecure").map(String::as_str),
+        Some("1" | "true")
+    ) {
+        settings["allowInsecure"] = json!(true);
     }
 
-    let numeric_fields = [
-        ("xPaddingBytes", "xPaddingBytes"),
-        ("scMaxEachPostBytes", "scMaxEachPostBytes"),
-        ("scMinPostsIntervalMs", "scMinPostsIntervalMs"),
-        ("scMaxBufferedPosts", "scMaxBufferedPosts"),
-    ];
-
-    for (param, field) in numeric_fields {
-        if let Some(value) = query_params.get(param).and_then(|v| v.parse::<u32>().ok()) {
-            extra.insert(field.to_owned(), json!(value));
-        }
-    }
-
-    let bool_fields = [
-        ("noGRPCHeader", "noGRPCHeader"),
-        ("noSSEHeader", "noSSEHeader"),
-    ];
-
-    for (param, field) in bool_fields {
-        if let Some(value) = query_params.get(param) {
-            extra.insert(field.to_owned(), json!(value == "true"));
-        }
-    }
-
-    if let Some(secs) = query_params.get("scStreamUpServerSecs") {
-        extra.insert("scStreamUpServerSecs".to_owned(), json!(secs));
-    }
-
-    extra
+    Some(settings)
 }
 
 fn map_reality_field(param: &str) -> &str {
@@ -442,3 +531,54 @@ fn map_reality_field(param: &str) -> &str {
         _ => param,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    fn config(quic_target: Option<(&str, u16)>) -> Value {
+        let url = Url::parse("trojan://secret@192.0.2.1:443?sni=a.example").unwrap();
+        let proxy = ProxyConfig::from_url(url, "192.0.2.1".parse().unwrap());
+        let sandbox = Sandbox {
+            domains: vec!["check.example".to_owned()],
+            quic_target: quic_target.map(|(host, port)| (host.to_owned(), port)),
+        };
+        let config = generate_xray_config(
+            &[proxy],
+            10000,
+            None,
+            false,
+            Engine::Xray,
+            "password",
+            Some(&sandbox),
+        )
+        .unwrap();
+        serde_json::from_str(&config).unwrap()
+    }
+
+    #[test]
+    fn sandbox_routes_only_allowed_domains() {
+        let config = config(None);
+        let rules = config["routing"]["rules"].as_array().unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0]["domain"], json!(["domain:check.example"]));
+        assert_eq!(rules[1]["outboundTag"], "blocked");
+    }
+
+    #[test]
+    fn sandbox_lets_quic_probe_through() {
+        let config = config(Some(("www.youtube.com", 443)));
+        let rules = config["routing"]["rules"].as_array().unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[1]["network"], "udp");
+        assert_eq!(rules[1]["port"], "443");
+        assert_eq!(rules[1]["domain"], json!(["full:www.youtube.com"]));
+        assert_eq!(rules[1]["outboundTag"], rules[0]["outboundTag"]);
+        assert_eq!(rules[2]["outboundTag"], "blocked");
+
+        let config = self::config(Some(("[2001:db8::1]", 8443)));
+        assert_eq!(config["routing"]["rules"][1]["ip"], json!(["2001:db8::1"]));
+    }
+}
@@ -0,0 +1,247 @@
+//! Launching of the xray binary, locally or inside a container.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    process::Stdio,
+    sync::{
+        LazyLock, Mutex, RwLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use tokio::process::{Child, Command};
+
+use crate::{error::EngineError, proxy_config::ProxyConfig, xray_config::Engine};
+
+// Container runtime and container name xray runs in, set at startup
+static CONTAINER: RwLock<Option<(String, String)>> = RwLock::new(None);
+static ENGINE: RwLock<Engine> = RwLock::new(Engine::Xray);
+// Files in container holding pids of cores started there, by pid of their
+// local `exec` client
+static PID_FILES: LazyLock<Mutex<HashMap<u32, String>>> = LazyLock::new(Mutex::default);
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+// Last probed core and its features, so reloads don't run it again
+static PROBED: RwLock<Option<(Engine, XrayFeatures)>> = RwLock::new(None);
+
+/// Runs every following core command with binary of `engine`
+pub fn use_engine(engine: Engine) {
+    if let Ok(mut current) = ENGINE.write() {
+        *current = engine;
+    }
+}
+
+#[must_use]
+pub fn engine() -> Engine {
+    ENGINE.read().map_or(Engine::Xray, |engine| *engine)
+}
+
+/// Runs every following xray command with `runtime exec` in `container`,
+/// which must share host network so local inbounds are reachable
+pub fn use_container(runtime: &str, container: &str) {
+    if let Ok(mut current) = CONTAINER.write() {
+        *current = Some((runtime.to_owned(), container.to_owned()));
+    }
+}
+
+fn container() -> Option<(String, String)> {
+    CONTAINER.read().ok()?.clone()
+}
+
+/// Command running binary of the selected core with no arguments yet
+#[must_use]
+pub fn xray_command() -> Command {
+    let binary = engine().binary();
+    match container() {
+        Some((runtime, container)) => {
+            let mut command = Command::new(runtime);
+            command.args(["exec", "-i", &container, binary]);
+            command
+        }
+        None => Command::new(binary),
+    }
+}
+
+/// Starts core with `args`, stdin and stdout piped
+///
+/// In container core first writes its pid to a file there, so
+/// [`stop_xray`] kills it and not cores of other processes.
+///
+/// # Errors
+/// Return error if core can't be started
+pub fn spawn_xray(args: &[&str]) -> io::Result<Child> {
+    let Some((runtime, container)) = container() else {
+        return xray_command()
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn();
+    };
+    let pid_file = format!(
+        "/tmp/novaprox-{}-{}.pid",
+        std::process::id(),
+        STARTED.fetch_add(1, Ordering::Relaxed)
+    );
+    let script = format!(r#"echo $$ > {pid_file} && exec "$0" "$@""#);
+    let child = Command::new(runtime)
+        .args(["exec", "-i", &container, "sh", "-c", &script])
+        .arg(engine().binary())
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    if let Some(pid) = child.id()
+        && let Ok(mut pid_files) = PID_FILES.lock()
+    {
+        pid_files.insert(pid, pid_file);
+    }
+    Ok(child)
+}
+
+/// Kills xray process. Killing the `exec` client leaves xray running in
+/// the container, so there it's killed by pid it wrote at start
+pub async fn stop_xray(process: &mut Child) {
+    let pid_file = process
+        .id()
+        .and_then(|pid| PID_FILES.lock().ok()?.remove(&pid));
+    process.kill().await.ok();
+
+    if let Some((runtime, container)) = container()
+        && let Some(pid_file) = pid_file
+    {
+        let script = format!(r#"kill "$(cat {pid_file})"; rm -f {pid_file}"#);
+        let status = Command::new(runtime)
+            .args(["exec", &container, "sh", "-c", &script])
+            .status()
+            .await;
+        if let Err(e) = status {
+            log::warn!("Failed to stop xray in container {container}: {e}");
+        }
+    }
+}
+
+/// Whether this process runs inside a docker, podman or kubernetes container
+#[must_use]
+pub fn is_containerized() -> bool {
+    Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+        || std::fs::read_to_string("/proc/1/cgroup").is_ok_and(|cgroup| {
+            ["docker", "kubepods", "containerd", "libpod"]
+                .iter()
+                .any(|marker| cgroup.contains(marker))
+        })
+}
+
+/// Features of installed xray core, so configs it would reject are never
+/// generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XrayFeatures {
+    pub engine: Engine,
+    pub version: (u32, u32, u32),
+}
+
+impl XrayFeatures {
+    // First core versions supporting features novaprox generates configs for
+    const REALITY: (u32, u32, u32) = (1, 8, 0);
+    const VISION: (u32, u32, u32) = (1, 8, 0);
+    const XHTTP: (u32, u32, u32) = (24, 9, 30);
+    const STATS_QUERY: (u32, u32, u32) = (1, 8, 0);
+
+    /// Parses first line of `xray version` or `v2ray version`, e.g.
+    /// `Xray 25.3.6 (Xray, Penetrates Everything.) ...`
+    #[must_use]
+    pub fn from_version_output(output: &str) -> Option<Self> {
+        let mut words = output.split_whitespace();
+        let engine = match words.next()? {
+            "V2Ray" => Engine::V2fly,
+            _ => Engine::Xray,
+        };
+        let version = words.next()?.trim_start_matches('v');
+        let mut parts = version.split('.').map(|part| part.parse().ok());
+        Some(Self {
+            engine,
+            version: (
+                parts.next()??,
+                parts.next()??,
+                parts.next().flatten().unwrap_or(0),
+            ),
+        })
+    }
+
+    /// Feature of proxy the core doesn't support
+    #[must_use]
+    pub fn missing_feature(&self, proxy: &ProxyConfig) -> Option<&'static str> {
+        let param = |key: &str| proxy.query_params.get(key).map(String::as_str);
+        // Version checks are of xray, v2fly has none of these
+        let xray_since = |since| self.engine == Engine::Xray && self.version >= since;
+        if param("security") == Some("reality") && !xray_since(Self::REALITY) {
+            Some("reality")
+        } else if param("flow").is_some_and(|flow| flow.contains("vision"))
+            && !xray_since(Self::VISION)
+        {
+            Some("xtls vision")
+        } else if param("type") == Some("xhttp") && !xray_since(Self::XHTTP) {
+            Some("xhttp")
+        } else {
+            None
+        }
+    }
+
+    #[must_use]
+    pub fn stats_query(&self) -> bool {
+        self.engine == Engine::Xray && self.version >= Self::STATS_QUERY
+    }
+}
+
+/// Runs `xray version` to find supported features, once per engine
+///
+/// # Errors
+/// Return error if xray can't be started or its version is unknown
+pub async fn probe_xray() -> Result<XrayFeatures, EngineError> {
+    let engine = engine();
+    if let Some((probed, features)) = PROBED.read().ok().and_then(|cached| *cached)
+        && probed == engine
+    {
+        return Ok(features);
+    }
+    let output = xray_command()
+        .arg("version")
+        .output()
+        .await
+        .map_err(|e| EngineError::NotFound(engine.binary(), e))?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let features = XrayFeatures::from_version_output(&output)
+        .ok_or_else(|| EngineError::UnknownVersion(output.trim().to_owned()))?;
+    if let Ok(mut probed) = PROBED.write() {
+        *probed = Some((engine, features));
+    }
+    Ok(features)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Engine and container are process wide, so both branches are checked
+    // in one test
+    #[test]
+    fn command_runs_binary_of_selected_engine() {
+        use_engine(Engine::V2fly);
+        assert_eq!(xray_command().as_std().get_program(), "v2ray");
+
+        use_container("podman", "core");
+        let command = xray_command();
+        assert_eq!(command.as_std().get_program(), "podman");
+        assert_eq!(
+            command.as_std().get_args().collect::<Vec<_>>(),
+            ["exec", "-i", "core", "v2ray"]
+        );
+
+        use_engine(Engine::Xray);
+        assert_eq!(
+            xray_command().as_std().get_args().last(),
+            Some("xray".as_ref())
+        );
+    }
+}
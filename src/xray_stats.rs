@@ -0,0 +1,97 @@
+#[cfg(feature = "cli")]
+use crate::error::CheckError;
+#[cfg(feature = "cli")]
+use crate::xray_process::xray_command;
+#[cfg(feature = "cli")]
+use ahash::{HashMap, HashMapExt as _};
+use serde_json::{Value, json};
+
+const API_TAG: &str = "api";
+
+/// Adds stats service, policy and api inbound to generated config
+pub fn enable_stats(config: &mut Value, api_port: u16) {
+    config["stats"] = json!({});
+    config["api"] = json!({
+        "tag": API_TAG,
+        "services": ["StatsService"]
+    });
+    config["policy"] = json!({
+        "system": {
+            "statsOutboundUplink": true,
+            "statsOutboundDownlink": true
+        }
+    });
+
+    if let Some(inbounds) = config["inbounds"].as_array_mut() {
+        inbounds.push(json!({
+            "listen": "127.0.0.1",
+            "port": api_port,
+            "protocol": "dokodemo-door",
+            "settings": {"address": "127.0.0.1"},
+            "tag": API_TAG
+        }));
+    }
+
+    if let Some(rules) = config["routing"]["rules"].as_array_mut() {
+        rules.push(json!({
+            "type": "field",
+            "inboundTag": [API_TAG],
+            "outboundTag": API_TAG
+        }));
+    }
+}
+
+/// Returns uplink and downlink bytes per outbound index
+///
+/// # Errors
+/// Return error if xray api call failed or returned invalid json
+#[cfg(feature = "cli")]
+pub async fn query_outbound_traffic(
+    api_port: u16,
+) -> Result<HashMap<usize, (u64, u64)>, CheckError> {
+    let output = xray_command()
+        .args([
+            "api",
+            "statsquery",
+            &format!("--server=127.0.0.1:{api_port}"),
+            "-pattern",
+            "outbound>>>",
+        ])
+        .output()
+        .await
+        .map_err(CheckError::Api)?;
+
+    let stats: Value = serde_json::from_slice(&output.stdout).map_err(CheckError::InvalidStats)?;
+
+    let mut traffic = HashMap::new();
+    for stat in stats["stat"].as_array().into_iter().flatten() {
+        // outbound>>>vless-out-3>>>traffic>>>uplink
+        let Some(name) = stat["name"].as_str() else {
+            continue;
+        };
+        let mut parts = name.split(">>>");
+        let (Some(tag), Some(direction)) = (parts.nth(1), parts.nth(1)) else {
+            continue;
+        };
+        let Some(index) = tag
+            .rsplit_once("-out-")
+            .and_then(|(_, index)| index.parse().ok())
+        else {
+            continue;
+        };
+        // Xray returns int64 as string in json, and omits zero values
+        let value = stat["value"]
+            .as_u64()
+            .or_else(|| stat["value"].as_str()?.parse().ok())
+            .unwrap_or(0);
+
+        let entry: &mut (u64, u64) = traffic.entry(index).or_default();
+        match direction {
+            "uplink" => entry.0 = value,
+            "downlink" => entry.1 = value,
+            _ => {}
+        }
+    }
+
+    Ok(traffic)
+}
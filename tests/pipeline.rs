@@ -0,0 +1,111 @@
+//! Whole run of the checker binary against local fixtures: sources are
+//! fetched from a fixture HTTP server, proxies are checked through the mock
+//! engine and working ones must end up in the output.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+    process::Command,
+};
+
+const UUID: &str = "b831381d-6324-4d53-ad4f-8cda48b30811";
+
+// Serves `/sources` with `links`, `/country` with geo IP JSON and 200 on
+// every other path. Its address is also the server of working proxies
+async fn fixture_server(links: impl Fn(SocketAddr) -> String + Send + 'static) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let sources = links(addr);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(respond(stream, sources.clone()));
+        }
+    });
+    addr
+}
+
+async fn respond(mut stream: TcpStream, sources: String) {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => request.extend_from_slice(&buf[..read]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split(' ').nth(1).unwrap_or("/");
+    let body = match path {
+        "/sources" => sources,
+        "/country" => r#"{"country":"NL","ip":"127.0.0.1"}"#.to_owned(),
+        _ => "ok".to_owned(),
+    };
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await.ok();
+}
+
+// Port nothing listens on, server of dead proxies
+async fn closed_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+fn work_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("novaprox-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn keeps_working_proxies_only() {
+    let dead = closed_port().await;
+    let fixture = fixture_server(move |addr| {
+        format!(
+            "vless://{UUID}@127.0.0.1:{}?type=tcp&security=none#alive\n\
+             vless://{UUID}@127.0.0.1:{dead}?type=tcp&security=none#dead\n",
+            addr.port()
+        )
+    })
+    .await;
+
+    let dir = work_dir("pipeline");
+    let sources = dir.join("sources.txt");
+    std::fs::write(&sources, format!("http://{fixture}/sources\n")).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_novaprox"))
+        .arg("--mock-engine")
+        .args(["--sources-files", &sources.to_string_lossy()])
+        .args(["--artifacts-dir", &dir.to_string_lossy()])
+        .args(["--latency-checklist", &format!("http://{fixture}/check")])
+        .args(["--country-url", &format!("http://{fixture}/country")])
+        .args([
+            "--ping-count",
+            "0",
+            "--source-jitter-ms",
+            "0",
+            "--no-env-proxy",
+        ])
+        .args(["--chunk-size", "10", "--base-start-port", "24310"])
+        .output()
+        .await
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let results = std::fs::read_to_string(dir.join("out.txt")).unwrap();
+    assert!(
+        results.contains(&format!(":{}", fixture.port())),
+        "{results}{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    assert!(!results.contains(&format!(":{dead}")), "{results}");
+    std::fs::remove_dir_all(dir).ok();
+}